@@ -0,0 +1,238 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use colored::Colorize;
+use globset::GlobSet;
+use log::info;
+
+use crate::{
+    excludes::{ExcludeMatch, Excludes},
+    sync::{
+        build_excludes, build_includes, declared_directories, load_config, modrinth_sources,
+        read_signing_key, resolve_file, resolve_sync_root, walk_files, UploadConfig, CONFIG_FILE,
+    },
+};
+
+/// Check the sync config and show which files it matches, without contacting the server
+#[derive(Args, Debug)]
+pub struct CheckCommand {
+    /// Game directory to check
+    target_directory: Option<String>,
+
+    /// Check only files under this subdirectory, as if it was the game directory
+    #[arg(long)]
+    strip_prefix: Option<PathBuf>,
+
+    /// Sync config to use instead of modsync.sync.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+impl CheckCommand {
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let target = self.target_directory.clone().unwrap_or(".".to_string());
+        let target_path = Path::new(&target);
+
+        let (config, config_string) = load_config(&resolve_file(
+            target_path,
+            self.config.as_deref(),
+            CONFIG_FILE,
+        ))?;
+        let sync_root = resolve_sync_root(target_path, self.strip_prefix.as_deref())?;
+        let includes = build_includes(&config, &config_string)?;
+        let excludes = build_excludes(&sync_root, &config, &config_string)?;
+        if let Some(key_path) = &config.signing_key {
+            read_signing_key(&target_path.join(key_path))?;
+        }
+        let directories = declared_directories(&config)?;
+        let sources = modrinth_sources(&config)?;
+        info!("Config for modpack {} is valid.", config.modpack_id.0);
+
+        let (matches, synced) = count_matches(&sync_root, &config, &includes, &excludes);
+
+        for (glob, count) in config.include_globs.iter().zip(matches) {
+            if count == 0 {
+                info!("[{}] {} matches no files", "!".yellow(), glob.yellow());
+            } else {
+                info!(
+                    "[{}] {} matches {} file(s)",
+                    "+".green(),
+                    glob.green(),
+                    count
+                );
+            }
+        }
+        for directory in directories {
+            if sync_root.join(&directory).is_dir() {
+                info!(
+                    "[{}] {} is a declared directory",
+                    "+".green(),
+                    directory.green()
+                );
+            } else {
+                info!(
+                    "[{}] Declared directory {} doesn't exist",
+                    "!".yellow(),
+                    directory.yellow()
+                );
+            }
+        }
+        let mut sources: Vec<&String> = sources.keys().collect();
+        sources.sort();
+        for path in sources {
+            if sync_root.join(path).is_file() {
+                info!(
+                    "[{}] {} is downloaded from Modrinth",
+                    "+".green(),
+                    path.green()
+                );
+            } else {
+                info!(
+                    "[{}] Modrinth source {} doesn't exist",
+                    "!".yellow(),
+                    path.yellow()
+                );
+            }
+        }
+        info!("{} file(s) would be synced.", synced);
+        Ok(())
+    }
+}
+
+/// How many synced files each include glob matches and how many are synced in all,
+/// logging the files that are excluded
+fn count_matches(
+    sync_root: &Path,
+    config: &UploadConfig,
+    includes: &GlobSet,
+    excludes: &Excludes,
+) -> (Vec<usize>, usize) {
+    let mut matches = vec![0; config.include_globs.len()];
+    let mut synced = 0;
+    for (_, path) in walk_files(sync_root, config) {
+        let matched = includes.matches(&path);
+        if matched.is_empty() {
+            continue;
+        }
+        match excludes.matched(&path) {
+            Some(overridden @ ExcludeMatch::Overridden { .. }) => {
+                info!(
+                    "[{}] {} is {}",
+                    "!".yellow(),
+                    path.to_string_lossy().yellow(),
+                    overridden
+                );
+            }
+            Some(excluded) => {
+                info!(
+                    "[{}] {} is {}",
+                    "-".red(),
+                    path.to_string_lossy().red(),
+                    excluded
+                );
+                continue;
+            }
+            None => {}
+        }
+        for i in matched {
+            matches[i] += 1;
+        }
+        synced += 1;
+    }
+    (matches, synced)
+}
+
+#[cfg(test)]
+mod tests {
+    use modsync_core::exit::ExitError;
+
+    use super::*;
+
+    /// A game directory holding `files` and a sync config ending with `extra`
+    fn check_dir(name: &str, files: &[&str], extra: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("modsync-check-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for path in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let config = format!(
+            "modpack_id = \"a\"\nserver_url = \"http://127.0.0.1:1/\"\napi_key = \"secret\"\n{}",
+            extra
+        );
+        std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+        dir
+    }
+
+    fn check(dir: &Path) -> anyhow::Result<()> {
+        CheckCommand {
+            target_directory: Some(dir.to_string_lossy().to_string()),
+            strip_prefix: None,
+            config: None,
+        }
+        .run()
+    }
+
+    #[test]
+    fn invalid_patterns_and_paths_are_config_errors() {
+        for (extra, message) in [
+            (
+                "include_globs = [\"mods/**\",\n  \"config/[a\"]\nexcludes = []\n",
+                "Invalid pattern \"config/[a\" on line 5 of the sync config",
+            ),
+            (
+                "include_globs = [\"mods/**\"]\nexcludes = [\"mods/{a\"]\n",
+                "Invalid pattern \"mods/{a\" on line 5 of the sync config",
+            ),
+            (
+                "include_globs = []\nexcludes = []\ndirectories = [\"../saves\"]\n",
+                "Declared directory \"../saves\" must be a plain relative path",
+            ),
+            (
+                "include_globs = []\nexcludes = []\n\
+                [modrinth_sources]\n\"/mods/a.jar\" = \"https://cdn.modrinth.com/a.jar\"\n",
+                "Modrinth source \"/mods/a.jar\" must be a plain relative path",
+            ),
+        ] {
+            let dir = check_dir("invalid", &[], extra);
+            let err = check(&dir).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(ExitError::Config(_))),
+                "{:?}",
+                err
+            );
+            assert!(err.to_string().starts_with(message), "{}", err);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn each_include_glob_counts_the_files_it_syncs() {
+        let dir = check_dir(
+            "counts",
+            &[
+                "mods/a.jar",
+                "mods/b.jar",
+                "mods/b.txt",
+                "config/a.toml",
+                "saves/x.dat",
+            ],
+            "include_globs = [\"mods/*.jar\", \"mods/**\", \"config/**\", \"shaderpacks/**\"]\n\
+            excludes = [\"*.txt\"]\n",
+        );
+        check(&dir).unwrap();
+
+        let (config, config_string) = load_config(&dir.join(CONFIG_FILE)).unwrap();
+        let includes = build_includes(&config, &config_string).unwrap();
+        let excludes = build_excludes(&dir, &config, &config_string).unwrap();
+        // b.txt is excluded, saves aren't included and the config isn't a synced file
+        assert_eq!(
+            count_matches(&dir, &config, &includes, &excludes),
+            (vec![2, 2, 1, 0], 3)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use log::info;
+use modsync_core::{
+    api::ModpackCreateBody,
+    checksum::Checksum,
+    client::{ClientError, ModsyncApi},
+    exit::ExitError,
+    TryFromStr,
+};
+
+use crate::{
+    init::config_template,
+    sync::{load_config, resolve_file, CONFIG_FILE},
+};
+
+/// Create a modpack on the server and print its id
+#[derive(Args, Debug)]
+pub struct CreateCommand {
+    /// Game directory to read modsync.sync.toml from, and to write it to with --write-config
+    target_directory: Option<String>,
+
+    #[arg(long)]
+    name: String,
+
+    #[arg(long, default_value = "minecraft")]
+    game: String,
+
+    #[arg(long)]
+    game_version: String,
+
+    /// e.g. fabric, forge or neoforge
+    #[arg(long)]
+    modloader: String,
+
+    #[arg(long)]
+    modloader_version: String,
+
+    /// Server to create the modpack on, read from the sync config if not given
+    #[arg(long)]
+    server_url: Option<String>,
+
+    /// Master key of the server, read from the sync config if not given
+    #[arg(long, env = "MODSYNC_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Hash algorithm for the modpack's files, can't be changed later
+    #[arg(long, value_parser = parse_hash_algorithm, default_value = "sha256")]
+    hash_algorithm: Checksum,
+
+    /// Write a modsync.sync.toml for the new modpack, ready to sync
+    #[arg(long)]
+    write_config: bool,
+
+    /// Sync config to use instead of modsync.sync.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+fn parse_hash_algorithm(value: &str) -> Result<Checksum, String> {
+    Checksum::try_from_str(value).map_err(|err| err.to_string())
+}
+
+impl CreateCommand {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let target = self.target_directory.clone().unwrap_or(".".to_string());
+        let config_path = resolve_file(target.as_ref(), self.config.as_deref(), CONFIG_FILE);
+        if self.write_config && config_path.exists() {
+            return Err(anyhow::anyhow!(
+                "{} already exists, not creating a modpack",
+                config_path.to_string_lossy()
+            ));
+        }
+
+        let (server_url, api_key) = match (&self.server_url, &self.api_key) {
+            (Some(server_url), Some(api_key)) => (server_url.clone(), api_key.clone()),
+            (server_url, api_key) => {
+                let (config, _) = load_config(&config_path).map_err(|_| {
+                    ExitError::Config(format!(
+                        "Pass --server-url and --api-key, or create the modpack from a directory with a {}",
+                        CONFIG_FILE
+                    ))
+                })?;
+                (
+                    server_url.clone().unwrap_or(config.server_url),
+                    api_key.clone().unwrap_or(config.api_key),
+                )
+            }
+        };
+
+        let api = ModsyncApi::new(&server_url, Some(&api_key))?;
+        let response = api
+            .create_modpack(&ModpackCreateBody {
+                name: self.name.clone(),
+                game: self.game.clone(),
+                game_version: self.game_version.clone(),
+                modloader: self.modloader.clone(),
+                modloader_version: self.modloader_version.clone(),
+                webhook_url: None,
+                request_id: None,
+                allowed_roots: None,
+                hash_algorithm: self.hash_algorithm,
+            })
+            .await
+            .map_err(|err| match err {
+                ClientError::AlreadyExists => anyhow::anyhow!(
+                    "A modpack named {:?} already exists on {}, pick another name",
+                    self.name,
+                    server_url
+                ),
+                err => err.into(),
+            })?;
+        info!("Modpack {} created.", self.name);
+        println!("{}", response.modpack_id.0);
+
+        if self.write_config {
+            std::fs::write(
+                &config_path,
+                config_template(&response.modpack_id, &server_url, &api_key),
+            )?;
+            info!(
+                "Sync config written to {}, run `modsync_cli sync` to push the modpack.",
+                config_path.to_string_lossy()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use log::info;
+use modsync_core::{
+    api::ModpackId,
+    client::{ClientError, ModsyncApi},
+    exit::ExitError,
+};
+
+use crate::sync::{confirm, load_config, resolve_file, CONFIG_FILE, STATE_FILE};
+
+/// Delete a modpack and all its files from the server
+#[derive(Args, Debug)]
+pub struct DeleteCommand {
+    /// Game directory to read modsync.sync.toml from
+    target_directory: Option<String>,
+
+    /// Modpack to delete, read from the sync config if not given
+    #[arg(long)]
+    modpack_id: Option<String>,
+
+    /// Server the modpack is on, read from the sync config if not given
+    #[arg(long)]
+    server_url: Option<String>,
+
+    /// Master key of the server, read from the sync config if not given
+    #[arg(long, env = "MODSYNC_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Don't ask before deleting
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// Also delete the local sync state, which only makes sense for the deleted modpack
+    #[arg(long)]
+    remove_state: bool,
+
+    /// Sync config to use instead of modsync.sync.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Sync state to use instead of modsync.state.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_STATE")]
+    state: Option<PathBuf>,
+}
+
+impl DeleteCommand {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let target = self.target_directory.clone().unwrap_or(".".to_string());
+        let target_path: &Path = target.as_ref();
+
+        let (modpack_id, server_url, api_key) =
+            match (&self.modpack_id, &self.server_url, &self.api_key) {
+                (Some(modpack_id), Some(server_url), Some(api_key)) => (
+                    ModpackId(modpack_id.clone()),
+                    server_url.clone(),
+                    api_key.clone(),
+                ),
+                (modpack_id, server_url, api_key) => {
+                    let config_path =
+                        resolve_file(target_path, self.config.as_deref(), CONFIG_FILE);
+                    let (config, _) = load_config(&config_path)?;
+                    (
+                        modpack_id
+                            .clone()
+                            .map(ModpackId)
+                            .unwrap_or(config.modpack_id),
+                        server_url.clone().unwrap_or(config.server_url),
+                        api_key.clone().unwrap_or(config.api_key),
+                    )
+                }
+            };
+
+        let api = ModsyncApi::new(&server_url, Some(&api_key))?;
+        let modpack = api
+            .get_modpack(&modpack_id)
+            .await
+            .map_err(|err| match err {
+                ClientError::NotFound => {
+                    ExitError::Config(format!("No modpack {} on {}", modpack_id.0, server_url))
+                        .into()
+                }
+                err => anyhow::Error::from(err),
+            })?;
+
+        if !self.yes {
+            if !std::io::stdin().is_terminal() {
+                return Err(anyhow::anyhow!(
+                    "Refusing to delete modpack {} without a terminal to ask on, pass --yes to confirm",
+                    modpack.modpack.name
+                ));
+            }
+            if !confirm(&format!(
+                "Delete modpack {} ({}) and its {} file(s) from {}? This can't be undone.",
+                modpack.modpack.name,
+                modpack_id.0,
+                modpack.files.len(),
+                server_url
+            ))? {
+                return Err(anyhow::anyhow!("Delete aborted, nothing was changed."));
+            }
+        }
+
+        api.delete_modpack(&modpack_id).await?;
+        info!(
+            "Modpack {} ({}) deleted from {}.",
+            modpack.modpack.name, modpack_id.0, server_url
+        );
+
+        let state_path = resolve_file(target_path, self.state.as_deref(), STATE_FILE);
+        if state_path.exists() {
+            let remove = self.remove_state
+                || (!self.yes
+                    && std::io::stdin().is_terminal()
+                    && confirm(&format!(
+                        "Also delete the local sync state {}?",
+                        state_path.to_string_lossy()
+                    ))?);
+            if remove {
+                std::fs::remove_file(&state_path)?;
+                info!("Deleted {}.", state_path.to_string_lossy());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use ignore::{gitignore::Gitignore, Match};
+
+/// Per-directory exclude rules in .gitignore syntax, like the config's `excludes`
+pub const IGNORE_FILE: &str = ".modsyncignore";
+
+/// Excludes from the config and every `.modsyncignore` under the sync root.
+/// The config wins over the files, and deeper files win over the ones above them like in git.
+pub struct Excludes {
+    /// Where every rule is rooted, paths are matched once joined onto it
+    sync_root: PathBuf,
+    config: Gitignore,
+    /// Directory relative to the sync root and its rules, deepest first
+    files: Vec<(PathBuf, Gitignore)>,
+}
+
+/// Why a file is left out of the sync, or kept in spite of a `.modsyncignore`
+pub enum ExcludeMatch<'a> {
+    /// A pattern in the config's `excludes`
+    Config(&'a str),
+    /// A pattern in a `.modsyncignore`
+    File(&'a Path, &'a str),
+    /// A `!` pattern in the config's `excludes` re-included a file a `.modsyncignore` excluded
+    Overridden {
+        file: &'a Path,
+        pattern: &'a str,
+        config_pattern: &'a str,
+    },
+}
+
+impl Excludes {
+    pub fn new(sync_root: &Path, config: Gitignore, mut files: Vec<(PathBuf, Gitignore)>) -> Self {
+        files.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+        Excludes {
+            sync_root: sync_root.to_path_buf(),
+            config,
+            files,
+        }
+    }
+
+    /// `path` is relative to the sync root
+    pub fn matched(&self, path: &Path) -> Option<ExcludeMatch<'_>> {
+        let full_path = self.sync_root.join(path);
+        let config = self.config.matched(&full_path, false);
+        if let Match::Ignore(glob) = config {
+            return Some(ExcludeMatch::Config(glob.original()));
+        }
+        let (file, glob) = self
+            .files
+            .iter()
+            .filter(|(dir, _)| path.starts_with(dir))
+            .find_map(|(dir, rules)| {
+                match rules.matched_path_or_any_parents(&full_path, false) {
+                    Match::None => None,
+                    Match::Ignore(glob) => Some(Some((dir.as_path(), glob))),
+                    // A deeper file re-included it, the files above don't matter
+                    Match::Whitelist(_) => Some(None),
+                }
+            })??;
+        Some(match config {
+            Match::Whitelist(config_glob) => ExcludeMatch::Overridden {
+                file,
+                pattern: glob.original(),
+                config_pattern: config_glob.original(),
+            },
+            _ => ExcludeMatch::File(file, glob.original()),
+        })
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        matches!(
+            self.matched(path),
+            Some(ExcludeMatch::Config(_) | ExcludeMatch::File(..))
+        )
+    }
+}
+
+impl Display for ExcludeMatch<'_> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Config(pattern) => write!(fmt, "excluded by \"{}\" in the config", pattern),
+            Self::File(file, pattern) => write!(
+                fmt,
+                "excluded by \"{}\" in {}",
+                pattern,
+                file.join(IGNORE_FILE).to_string_lossy()
+            ),
+            Self::Overridden {
+                file,
+                pattern,
+                config_pattern,
+            } => write!(
+                fmt,
+                "kept by \"{}\" in the config, which wins over \"{}\" in {}",
+                config_pattern,
+                pattern,
+                file.join(IGNORE_FILE).to_string_lossy()
+            ),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use log::info;
+use modsync_core::{api::ModpackId, exit::ExitError};
+
+use crate::sync::{ask, resolve_file, CONFIG_FILE};
+
+/// Write a commented modsync.sync.toml to start syncing a modpack from
+#[derive(Args, Debug)]
+pub struct InitCommand {
+    /// Game directory to write modsync.sync.toml to
+    target_directory: Option<String>,
+
+    /// Modpack to sync, from `modsync_cli create` or `modsync_cli list`
+    #[arg(long)]
+    modpack_id: Option<String>,
+
+    #[arg(long)]
+    server_url: Option<String>,
+
+    /// Master key of the server
+    #[arg(long, env = "MODSYNC_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Overwrite an existing config
+    #[arg(short = 'f', long)]
+    force: bool,
+
+    /// Where to write the config instead of modsync.sync.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+impl InitCommand {
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let target = self.target_directory.clone().unwrap_or(".".to_string());
+        let config_path = resolve_file(Path::new(&target), self.config.as_deref(), CONFIG_FILE);
+        if !self.force && std::fs::exists(&config_path)? {
+            return Err(anyhow::anyhow!(
+                "{} already exists, use --force to overwrite it",
+                config_path.to_string_lossy()
+            ));
+        }
+
+        let modpack_id = value_or_ask(&self.modpack_id, "--modpack-id", "Modpack id")?;
+        let server_url = value_or_ask(&self.server_url, "--server-url", "Server URL")?;
+        let api_key = value_or_ask(&self.api_key, "--api-key", "API key")?;
+
+        std::fs::write(
+            &config_path,
+            config_template(&ModpackId(modpack_id), &server_url, &api_key),
+        )?;
+        info!(
+            "Sync config written to {}, review it and run `modsync_cli check` to see what it matches.",
+            config_path.to_string_lossy()
+        );
+        Ok(())
+    }
+}
+
+/// The flag's value, asked for on the terminal if it wasn't given
+fn value_or_ask(value: &Option<String>, flag: &str, question: &str) -> anyhow::Result<String> {
+    if let Some(value) = value {
+        return Ok(value.clone());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(ExitError::Config(format!("{} is required without a terminal", flag)).into());
+    }
+    loop {
+        let answer = ask(question)?;
+        if !answer.is_empty() {
+            return Ok(answer);
+        }
+    }
+}
+
+/// Sync config with every option explained, syncing mods and configs by default
+pub fn config_template(modpack_id: &ModpackId, server_url: &str, api_key: &str) -> String {
+    // Quoted and escaped as TOML strings
+    let quote = |x: &str| toml::Value::String(x.to_string()).to_string();
+    format!(
+        r#"# Modsync sync config, used by `modsync_cli sync` to push this directory to the server
+
+# Modpack to sync to
+modpack_id = {modpack_id}
+
+# Server the modpack is on
+server_url = {server_url}
+
+# Master key of the server, keep this file private
+api_key = {api_key}
+
+# Files to sync, as globs relative to this directory
+include_globs = ["mods/**", "config/**"]
+
+# Files to leave out even if an include glob matches them, in .gitignore syntax
+# A .modsyncignore in any directory adds rules for the files under it, these win over them
+excludes = [
+    "*.log",
+    "*.tmp",
+    "*.bak",
+    "*.disabled",
+]
+
+# Also sync dotfiles and OS junk like .DS_Store
+include_hidden = false
+
+# Directories created on clients even when empty
+# directories = ["saves", "screenshots"]
+
+# Private key from `modsync_cli keygen`, signs the file list after every sync
+# signing_key = "modsync.key"
+
+# Files clients download from Modrinth's CDN instead of the server, as path = URL
+# [modrinth_sources]
+# "mods/sodium.jar" = "https://cdn.modrinth.com/data/AANobbMI/versions/.../sodium.jar"
+"#,
+        modpack_id = quote(&modpack_id.0),
+        server_url = quote(server_url),
+        api_key = quote(api_key),
+    )
+}
@@ -0,0 +1,47 @@
+use std::{io::Write, path::PathBuf};
+
+use clap::Args;
+use log::info;
+use modsync_core::signing::generate_key;
+use rand::RngCore;
+
+/// Generate a key pair for signing the modpack file list
+#[derive(Args, Debug)]
+pub struct KeygenCommand {
+    /// Where to write the private key, point `signing_key` in modsync.sync.toml at it
+    #[arg(short = 'o', long, default_value = "modsync.key")]
+    output: PathBuf,
+
+    /// Overwrite an existing key file
+    #[arg(long)]
+    force: bool,
+}
+
+impl KeygenCommand {
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        if self.output.exists() && !self.force {
+            return Err(anyhow::anyhow!(
+                "{} already exists, pass --force to overwrite it",
+                self.output.to_string_lossy()
+            ));
+        }
+        let mut secret = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut secret);
+        let (private_key, public_key) = generate_key(secret);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&self.output)?;
+        file.write_all(private_key.as_bytes())?;
+
+        info!(
+            "Private key written to {}, keep it secret.",
+            self.output.to_string_lossy()
+        );
+        info!("Add this to the modsync.toml of clients to require signed modpacks:");
+        println!("public_key = \"{}\"", public_key);
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use colored::Colorize;
+use log::info;
+use modsync_core::{
+    api::{FEATURE_MODPACK_LIST, MODPACK_LIST_DEFAULT_LIMIT, MODPACK_LIST_MAX_LIMIT},
+    client::ModsyncApi,
+    exit::ExitError,
+};
+
+use crate::sync::{load_config, resolve_file, CONFIG_FILE};
+
+/// List the modpacks on the server
+#[derive(Args, Debug)]
+pub struct ListCommand {
+    /// Game directory to read modsync.sync.toml from
+    target_directory: Option<String>,
+
+    /// Server to list, read from the sync config if not given
+    #[arg(long)]
+    server_url: Option<String>,
+
+    /// Master key of the server, read from the sync config if not given
+    #[arg(long, env = "MODSYNC_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Modpacks to show
+    #[arg(
+        long,
+        default_value_t = MODPACK_LIST_DEFAULT_LIMIT,
+        value_parser = clap::value_parser!(i64).range(1..=MODPACK_LIST_MAX_LIMIT)
+    )]
+    limit: i64,
+
+    /// Modpacks to skip, for the pages after the first
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(i64).range(0..))]
+    offset: i64,
+
+    /// Sync config to use instead of modsync.sync.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+impl ListCommand {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let target = self.target_directory.clone().unwrap_or(".".to_string());
+        let (server_url, api_key) = match (&self.server_url, &self.api_key) {
+            (Some(server_url), Some(api_key)) => (server_url.clone(), api_key.clone()),
+            (server_url, api_key) => {
+                let config_path =
+                    resolve_file(Path::new(&target), self.config.as_deref(), CONFIG_FILE);
+                let (config, _) = load_config(&config_path)?;
+                (
+                    server_url.clone().unwrap_or(config.server_url),
+                    api_key.clone().unwrap_or(config.api_key),
+                )
+            }
+        };
+
+        let api = ModsyncApi::new(&server_url, Some(&api_key))?;
+        if let Some(capabilities) = api.capabilities().await? {
+            if !capabilities.supports(FEATURE_MODPACK_LIST) {
+                return Err(ExitError::Config(format!(
+                    "{} is too old to list its modpacks",
+                    server_url
+                ))
+                .into());
+            }
+        }
+        let list = api.list_modpacks(self.limit, self.offset).await?;
+        if list.modpacks.is_empty() {
+            info!("No modpacks on {}.", server_url);
+            return Ok(());
+        }
+
+        let rows: Vec<[String; 4]> = list
+            .modpacks
+            .into_iter()
+            .map(|x| {
+                [
+                    x.name,
+                    x.game.unwrap_or("-".to_string()),
+                    x.file_count.to_string(),
+                    x.id.0,
+                ]
+            })
+            .collect();
+        let header = ["NAME", "GAME", "FILES", "ID"];
+        let mut widths = header.map(str::len);
+        for row in rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        // Padded before coloring, so escape codes don't count towards the width
+        println!(
+            "{}  {}  {}  {}",
+            format!("{:<1$}", header[0], widths[0]).bold(),
+            format!("{:<1$}", header[1], widths[1]).bold(),
+            format!("{:>1$}", header[2], widths[2]).bold(),
+            header[3].bold()
+        );
+        for [name, game, files, id] in rows.iter() {
+            println!(
+                "{}  {:<4$}  {:>5$}  {}",
+                format!("{:<1$}", name, widths[0]).cyan(),
+                game,
+                files,
+                id.dimmed(),
+                widths[1],
+                widths[2]
+            );
+        }
+
+        let shown = rows.len() as i64;
+        let end = self.offset + shown;
+        if end < list.total {
+            info!(
+                "Showing {}-{} of {} modpacks, pass --offset {} for the next page.",
+                self.offset + 1,
+                end,
+                list.total,
+                end
+            );
+        } else if self.offset > 0 {
+            info!(
+                "Showing {}-{} of {} modpacks.",
+                self.offset + 1,
+                end,
+                list.total
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,30 +1,92 @@
+use std::process::ExitCode;
+
+use check::CheckCommand;
 use clap::{Parser, Subcommand};
+use create::CreateCommand;
+use delete::DeleteCommand;
+use init::InitCommand;
+use keygen::KeygenCommand;
+use list::ListCommand;
+use modsync_core::exit::{exit_code, EXIT_CONFIG};
+use pretty_env_logger::env_logger::WriteStyle;
 use sync::SyncCommand;
 
+mod check;
+mod create;
+mod delete;
+mod excludes;
+mod init;
+mod keygen;
+mod list;
 mod sync;
+#[cfg(test)]
+mod tests;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     commands: Commands,
+
+    /// Log without colors, also done when NO_COLOR is set
+    #[arg(long, global = true)]
+    no_color: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Sync(SyncCommand),
+    Check(CheckCommand),
+    Keygen(KeygenCommand),
+    Create(CreateCommand),
+    Delete(DeleteCommand),
+    List(ListCommand),
+    Init(InitCommand),
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    init_logging(args.no_color);
+
+    let result = match args.commands {
+        Commands::Sync(mut sync) => sync.run().await,
+        Commands::Check(mut check) => check.run(),
+        Commands::Keygen(mut keygen) => keygen.run(),
+        Commands::Create(mut create) => create.run().await,
+        Commands::Delete(mut delete) => delete.run().await,
+        Commands::List(mut list) => list.run().await,
+        Commands::Init(mut init) => init.run(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(error_exit_code(&err))
+        }
+    }
+}
+
+/// Sets up logging at `info` unless RUST_LOG says otherwise, colored unless asked not to be
+fn init_logging(no_color: bool) {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info")
     }
-    pretty_env_logger::init();
-
-    let args = Args::parse();
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if no_color || std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty()) {
+        colored::control::set_override(false);
+        builder.write_style(WriteStyle::Never);
+    }
+    builder.init();
+}
 
-    match args.commands {
-        Commands::Sync(mut sync) => sync.run().await,
+/// See `modsync_core::exit` for the meaning of each code
+fn error_exit_code(err: &anyhow::Error) -> u8 {
+    if err.chain().any(|x| x.is::<toml::de::Error>()) {
+        return EXIT_CONFIG;
     }
+    exit_code(err.chain())
 }
@@ -1,26 +1,40 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::{Read, Write},
+    future::Future,
+    io::{IsTerminal, Read, Seek, SeekFrom, Write},
     path::{Component, Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
     time::Instant,
 };
 
-use anyhow::anyhow;
 use clap::Args;
 use colored::Colorize;
-use globset::{Glob, GlobSetBuilder};
+use futures_util::StreamExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use ignore::gitignore::GitignoreBuilder;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use modsync_core::{
-    api::{FileSyncBody, FileSyncResponse, ModpackResponse},
-    FileState,
+    api::{
+        validate_modrinth_url, FileSyncBatchBody, FileSyncBody, ModpackId, UploadId,
+        UploadInitBody, FEATURE_BLOB_UPLOAD, FEATURE_DIRECTORIES, FEATURE_FILESYNC_BATCH,
+        FEATURE_MODRINTH_SOURCE, FEATURE_SIGNATURES, FILESYNC_BATCH_MAX_FILES,
+    },
+    checksum::Checksum,
+    client::{ClientError, ModsyncApi},
+    exit::ExitError,
+    signing::{manifest_payload, sign_payload, SignedFile},
+    DownloadSource, FileState, ModState,
 };
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
+use crate::excludes::{ExcludeMatch, Excludes, IGNORE_FILE};
+
 /// Command to sync local mods to the server
 #[derive(Args, Debug)]
 pub struct SyncCommand {
@@ -38,22 +52,88 @@ pub struct SyncCommand {
     /// Download server's state view into target directory
     #[arg(short = 'd', long)]
     download_state: bool,
+
+    /// Hash local files while downloading state, so files already matching the server stay clean
+    #[arg(long, requires = "download_state")]
+    verify: bool,
+
+    /// Upload and verify all content before publishing any file changes
+    #[arg(long)]
+    two_phase: bool,
+
+    /// Sync only files under this subdirectory, as if it was the game directory
+    #[arg(long)]
+    strip_prefix: Option<PathBuf>,
+
+    /// Delete server files that don't exist locally, even ones this directory never synced
+    #[arg(long, visible_alias = "mirror")]
+    authoritative: bool,
+
+    /// Don't ask before deleting files with --authoritative
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// Show what would be synchronized without changing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Abort if the server modpack isn't at this sync version,
+    /// defaults to the version seen by the last --download-state
+    #[arg(long)]
+    expect_version: Option<i32>,
+
+    /// Files uploaded at the same time
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    upload_concurrency: u32,
+
+    /// Files larger than this many bytes are uploaded in chunks, and an interrupted sync
+    /// resumes them where it left off
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    chunked_upload_threshold: u64,
+
+    /// Sync config to use instead of modsync.sync.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Sync state to use instead of modsync.state.toml in the game directory
+    #[arg(long, env = "MODSYNC_SYNC_STATE")]
+    state: Option<PathBuf>,
 }
 
+pub const CONFIG_FILE: &str = "modsync.sync.toml";
+pub const STATE_FILE: &str = "modsync.state.toml";
+
+/// Times a chunk is sent before giving up, the server rejects chunks that got corrupted on the way
+const CHUNK_ATTEMPTS: u32 = 3;
+
 #[derive(Serialize, Deserialize)]
 pub struct UploadConfig {
-    pub modpack_id: String,
+    pub modpack_id: ModpackId,
     pub server_url: String,
     pub api_key: String,
     pub include_globs: Vec<String>,
     pub excludes: Vec<String>,
+    /// Also sync dotfiles, hidden/system files and OS junk like `.DS_Store`
+    #[serde(default)]
+    pub include_hidden: bool,
+    /// Private key file from `modsync_cli keygen`, signs the file list after every sync
+    pub signing_key: Option<PathBuf>,
+    /// Directories created on clients even when empty, like `saves` or `screenshots`
+    #[serde(default)]
+    pub directories: Vec<String>,
+    /// Files clients download from Modrinth's CDN instead of this server, as path = URL.
+    /// They're still hashed locally so clients can verify them, but never uploaded.
+    #[serde(default)]
+    pub modrinth_sources: HashMap<String, String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum FileDirtyness {
     Clean,
     Created,
     Updated,
+    /// Only the permission bits changed, nothing to upload
+    ModeChanged,
     Deleted,
 }
 
@@ -62,6 +142,17 @@ pub struct SyncFile {
     pub hash: Option<String>,
     pub state: FileState,
     pub dirty: FileDirtyness,
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Modrinth URL clients download the file from, `None` if it's uploaded
+    #[serde(default)]
+    pub source_url: Option<String>,
+    /// Modification time in nanoseconds since the epoch when `hash` was computed
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    /// Size in bytes when `hash` was computed
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
 impl SyncFile {
@@ -70,6 +161,10 @@ impl SyncFile {
             hash,
             state: FileState::Exists,
             dirty: FileDirtyness::Created,
+            mode: None,
+            source_url: None,
+            mtime: None,
+            size: None,
         }
     }
 
@@ -78,21 +173,127 @@ impl SyncFile {
         self.dirty = FileDirtyness::Deleted;
     }
 
+    pub fn directory() -> Self {
+        SyncFile {
+            hash: None,
+            state: FileState::Directory,
+            dirty: FileDirtyness::Created,
+            mode: None,
+            source_url: None,
+            mtime: None,
+            size: None,
+        }
+    }
+
+    pub fn make_directory(&mut self) {
+        self.hash = None;
+        self.state = FileState::Directory;
+        self.dirty = FileDirtyness::Updated;
+    }
+
     pub fn make_updated(&mut self, hash: String) {
+        // A file coming back after being deleted is a new mod again
+        self.dirty = match self.state {
+            FileState::Deleted => FileDirtyness::Created,
+            _ => FileDirtyness::Updated,
+        };
         self.hash = Some(hash);
-        self.dirty = FileDirtyness::Updated;
+        self.state = FileState::Exists;
+    }
+
+    pub fn update_mode(&mut self, mode: Option<String>) {
+        if self.mode != mode {
+            self.mode = mode;
+            if self.dirty == FileDirtyness::Clean {
+                self.dirty = FileDirtyness::ModeChanged;
+            }
+        }
+    }
+
+    pub fn update_source(&mut self, source_url: Option<String>) {
+        if self.source_url != source_url {
+            self.source_url = source_url;
+            if self.dirty == FileDirtyness::Clean {
+                self.dirty = FileDirtyness::Updated;
+            }
+        }
+    }
+
+    /// Whether the file still has the mtime and size it was last hashed at
+    pub fn unchanged_since_hashed(&self, stamp: &FileStamp) -> bool {
+        self.hash.is_some()
+            && self.state == FileState::Exists
+            && self.mtime.is_some_and(|x| Some(x) == stamp.mtime)
+            && self.size == Some(stamp.size)
+    }
+
+    pub fn set_stamp(&mut self, stamp: FileStamp) {
+        self.mtime = stamp.mtime;
+        self.size = Some(stamp.size);
     }
 
     pub fn mark_synced(&mut self) {
         self.dirty = FileDirtyness::Clean;
     }
+
+    /// What the pending change does to the mod, `None` if nothing changed
+    pub fn mod_state(&self) -> Option<ModState> {
+        match (&self.dirty, self.state) {
+            (FileDirtyness::Clean, _) => None,
+            (_, FileState::Ignored) => Some(ModState::Ignored),
+            (FileDirtyness::Created, _) => Some(ModState::Created),
+            (FileDirtyness::Updated | FileDirtyness::ModeChanged, _) => Some(ModState::Updated),
+            (FileDirtyness::Deleted, _) => Some(ModState::Deleted),
+        }
+    }
+}
+
+/// Cheap to read metadata that tells whether a file may have changed since it was hashed
+pub struct FileStamp {
+    /// `None` where the platform or filesystem doesn't record it
+    pub mtime: Option<i64>,
+    pub size: u64,
+}
+
+impl FileStamp {
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        FileStamp {
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+                .and_then(|x| i64::try_from(x.as_nanos()).ok()),
+            size: metadata.len(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SyncState {
     pub state_version: u32,
     pub upload_version: u32,
+    /// Server change cursor of the last downloaded state
+    #[serde(default)]
+    pub cursor: Option<i64>,
+    /// Modpack sync version of the last downloaded state, later pushes abort if the server moved on
+    #[serde(default)]
+    pub modpack_version: Option<i32>,
+    /// Hash algorithm of the modpack, only looked up once as it never changes
+    #[serde(default)]
+    pub hash_algorithm: Option<Checksum>,
     pub files: HashMap<String, SyncFile>,
+    /// Chunked uploads an interrupted sync left unfinished, by path
+    #[serde(default)]
+    pub uploads: HashMap<String, PendingUpload>,
+}
+
+/// A chunked upload in progress, only resumed while the file still has the same hash
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingUpload {
+    pub upload_id: UploadId,
+    pub hash: String,
+    /// First chunk the server hasn't acknowledged yet
+    pub next_chunk: i32,
 }
 
 impl SyncState {
@@ -100,7 +301,11 @@ impl SyncState {
         SyncState {
             state_version: 0,
             upload_version: 0,
+            cursor: None,
+            modpack_version: None,
+            hash_algorithm: None,
             files: HashMap::new(),
+            uploads: HashMap::new(),
         }
     }
 }
@@ -110,33 +315,16 @@ impl SyncCommand {
         let target = self.target_directory.clone().unwrap_or(".".to_string());
         let target_path = Path::new(&target);
 
-        let config_string = std::fs::read_to_string(target_path.join("modsync.sync.toml"))
-            .map_err(|_| {
-                anyhow::anyhow!(
-                    "No sync config found at {}",
-                    target_path.join("modsync.sync.toml").to_string_lossy()
-                )
-            })?;
-        let config: UploadConfig = toml::from_str(&config_string)?;
-
-        let mut auth_value =
-            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", config.api_key))?;
-        auth_value.set_sensitive(true);
-        let mut default_headers = reqwest::header::HeaderMap::new();
-        default_headers.append(reqwest::header::AUTHORIZATION, auth_value);
-        let client = reqwest::Client::builder()
-            .default_headers(default_headers)
-            .build()?;
-
-        client
-            .post(format!("{}/hello", config.server_url))
-            .send()
-            .await?
-            .error_for_status()
-            .map_err(|x| match x.status() {
-                Some(reqwest::StatusCode::UNAUTHORIZED) => anyhow!("Invalid API key"),
-                _ => x.into(),
-            })?;
+        let (config, config_string) = load_config(&resolve_file(
+            target_path,
+            self.config.as_deref(),
+            CONFIG_FILE,
+        ))?;
+        let state_path = resolve_file(target_path, self.state.as_deref(), STATE_FILE);
+        let sync_root = resolve_sync_root(target_path, self.strip_prefix.as_deref())?;
+
+        let api = ModsyncApi::new(&config.server_url, Some(&config.api_key))?;
+        api.hello().await?;
         info!(
             "Server ({}) authentication successful! Starting synchronization...",
             config.server_url
@@ -144,121 +332,222 @@ impl SyncCommand {
 
         let instant = Instant::now();
 
-        // Includes globset
-        let mut builder = GlobSetBuilder::new();
-        for i in config.include_globs.iter() {
-            builder.add(Glob::new(i)?);
-        }
-        let includes = builder.build()?;
+        let includes = build_includes(&config, &config_string)?;
+        let excludes = build_excludes(&sync_root, &config, &config_string)?;
+        let signing_key = match &config.signing_key {
+            Some(path) => Some(read_signing_key(&target_path.join(path))?),
+            None => None,
+        };
 
-        // Excludes
-        let mut builder = GitignoreBuilder::new(".");
-        for i in config.excludes.iter() {
-            builder.add_line(None, i)?;
+        let capabilities = api.capabilities().await?;
+        // Older servers don't report capabilities, let their requests fail on their own
+        let supports = |feature| capabilities.as_ref().is_none_or(|x| x.supports(feature));
+        if self.two_phase && !supports(FEATURE_BLOB_UPLOAD) {
+            return Err(anyhow::anyhow!("Server doesn't support two-phase syncs"));
+        }
+        if signing_key.is_some() && !supports(FEATURE_SIGNATURES) {
+            return Err(anyhow::anyhow!("Server doesn't support signed modpacks"));
+        }
+        let directories = declared_directories(&config)?;
+        if !directories.is_empty() && !supports(FEATURE_DIRECTORIES) {
+            return Err(anyhow::anyhow!(
+                "Server doesn't support syncing directories"
+            ));
+        }
+        let sources = modrinth_sources(&config)?;
+        if !sources.is_empty() && !supports(FEATURE_MODRINTH_SOURCE) {
+            return Err(anyhow::anyhow!("Server doesn't support Modrinth sources"));
         }
-        let excludes = builder.build()?;
+
+        let saved_state = {
+            let state_file = File::open(&state_path);
+            if let Ok(mut state_file) = state_file {
+                let mut state_string = String::new();
+                state_file.read_to_string(&mut state_string)?;
+                let upload_state: SyncState = toml::from_str(&state_string)?;
+                upload_state
+            } else {
+                SyncState::new()
+            }
+        };
 
         let mut state = if self.download_state {
-            let modpack: ModpackResponse = client
-                .get(format!(
-                    "{}/modpack/{}",
-                    config.server_url, config.modpack_id
-                ))
-                .send()
-                .await?
-                .error_for_status()?
-                .json()
+            let changes = api
+                .get_changes(&config.modpack_id, saved_state.cursor)
                 .await?;
-            let mut files: HashMap<String, SyncFile> = HashMap::new();
-            for (path, sync_file) in modpack.files.into_iter().map(|x| (x.path.clone(), x)) {
-                files.insert(
+            let mut state = if changes.full {
+                SyncState::new()
+            } else {
+                info!(
+                    "Server reported {} changed file(s) since last state download",
+                    changes.files.len()
+                );
+                saved_state
+            };
+            let mut downloaded: Vec<String> = Vec::new();
+            for (path, sync_file) in changes.files.into_iter().map(|x| (x.path.clone(), x)) {
+                downloaded.push(path.clone());
+                state.files.insert(
                     path,
                     SyncFile {
                         hash: sync_file.hash,
                         state: sync_file.state,
                         dirty: FileDirtyness::Updated,
+                        mode: sync_file.mode,
+                        source_url: sync_file.source_url,
+                        mtime: None,
+                        size: None,
                     },
                 );
             }
-            SyncState {
-                upload_version: 0,
-                state_version: 0,
-                files,
+            if self.verify {
+                info!("Verifying {} local file(s)...", downloaded.len());
+                let matching = matching_local_files(
+                    &sync_root,
+                    &state.files,
+                    &downloaded,
+                    changes.modpack.hash_algorithm,
+                );
+                info!("{} file(s) already match the server", matching.len());
+                for path in matching {
+                    if let Some(sync_file) = state.files.get_mut(&path) {
+                        sync_file.mark_synced();
+                    }
+                }
             }
+            state.cursor = Some(changes.cursor);
+            state.modpack_version = Some(changes.modpack.sync_version);
+            state.hash_algorithm = Some(changes.modpack.hash_algorithm);
+            state
         } else {
-            let state_file = File::open(target_path.join("modsync.state.toml"));
-            if let Ok(mut state_file) = state_file {
-                let mut state_string = String::new();
-                state_file.read_to_string(&mut state_string)?;
-                let upload_state: SyncState = toml::from_str(&state_string)?;
-                upload_state
-            } else {
-                SyncState::new()
+            saved_state
+        };
+        let checksum = match state.hash_algorithm {
+            Some(checksum) => checksum,
+            None => {
+                api.get_modpack(&config.modpack_id)
+                    .await?
+                    .modpack
+                    .hash_algorithm
             }
         };
+        state.hash_algorithm = Some(checksum);
 
         let mut checked_files: Vec<PathBuf> = Vec::new();
-        for (entry, path) in WalkDir::new(target_path)
-            .into_iter()
-            .filter_map(|x| x.ok())
-            .filter(|x| relativize_path(target_path, x.path()).is_some())
-            .filter_map(|x| relativize_path(target_path, x.path()).map(|path| (x, path)))
+        // Walked first, so everything that needs hashing can be hashed in parallel
+        let mut local_files = Vec::new();
+        for (entry, path) in walk_files(&sync_root, &config)
             .filter(|(_, path)| includes.is_match(path))
-            .filter(|(_, path)| !excludes.matched(path, false).is_ignore())
+            .filter(|(_, path)| match excludes.matched(path) {
+                // Where the config and a .modsyncignore disagree, say which one won
+                Some(overridden @ ExcludeMatch::Overridden { .. }) => {
+                    info!(
+                        "[{}] {} is {}",
+                        "!".yellow(),
+                        path.to_string_lossy(),
+                        overridden
+                    );
+                    true
+                }
+                Some(excluded) => {
+                    debug!("[-] {} is {}", path.to_string_lossy(), excluded);
+                    false
+                }
+                None => true,
+            })
         {
             let path_str = match path.to_str() {
-                Some(s) => s,
+                Some(s) => s.to_string(),
                 None => {
                     error!("Invalid filename: {}", path.to_string_lossy().red());
                     continue;
                 }
             };
-            let mut file = File::open(entry.path())?;
-            checked_files.push(path.clone());
+            checked_files.push(path);
+            let stamp = FileStamp::of(&entry.metadata()?);
+            local_files.push((entry, path_str, stamp));
+        }
+
+        // Skipped when neither mtime nor size moved, --force-sync rehashes to be sure
+        let to_hash: Vec<PathBuf> = local_files
+            .iter()
+            .filter(|(_, path, stamp)| {
+                self.force_sync
+                    || !state
+                        .files
+                        .get(path)
+                        .is_some_and(|x| x.unchanged_since_hashed(stamp))
+            })
+            .map(|(entry, _, _)| entry.path().to_path_buf())
+            .collect();
+        if !to_hash.is_empty() {
+            info!("Hashing {} file(s)...", to_hash.len());
+        }
+        let hashes = hash_files(&to_hash, checksum);
+        let mut hashes: HashMap<PathBuf, String> = to_hash
+            .into_iter()
+            .zip(hashes)
+            .map(|(path, hash)| hash.map(|hash| (path, hash)))
+            .collect::<std::io::Result<_>>()?;
+
+        for (entry, path_str, stamp) in local_files {
+            let path_str = path_str.as_str();
+            let hash = hashes.remove(entry.path());
             let sync_file = state.files.get_mut(path_str);
-            match sync_file {
-                Some(sync_file) => {
+            match (sync_file, hash) {
+                (Some(sync_file), None) => {
+                    debug!("[{}] Unchanged since last sync: {}", "/".cyan(), path_str);
+                    sync_file.update_mode(file_mode(&entry));
+                    sync_file.update_source(sources.get(path_str).cloned());
+                }
+                (Some(sync_file), Some(hash)) => {
                     info!(
                         "[{}] Checking file {} for changes...",
                         "/".cyan(),
                         path_str.cyan()
                     );
 
-                    // Hashing
-                    let mut hasher = Sha256::new();
-                    std::io::copy(&mut file, &mut hasher)?;
-                    let hash_bytes = hasher.finalize();
-                    let hash = hash_bytes
-                        .iter()
-                        .map(|x| format!("{:02x}", x))
-                        .collect::<Vec<String>>()
-                        .join("");
                     let hash_mismatch = match &sync_file.hash {
                         Some(sync_hash) => hash != *sync_hash,
                         None => false,
                     };
 
-                    if hash_mismatch {
+                    // Also catches paths that were a deleted file or a directory before
+                    if hash_mismatch || sync_file.state != FileState::Exists {
                         info!("[{}] File changed: {}", "*".yellow(), path_str.yellow());
                         sync_file.make_updated(hash);
                     }
+                    sync_file.set_stamp(stamp);
+                    sync_file.update_mode(file_mode(&entry));
+                    sync_file.update_source(sources.get(path_str).cloned());
                 }
-                None => {
+                (None, hash) => {
                     info!("[{}] New file: {}", "+".green(), path_str.green());
 
-                    // Hashing
-                    let mut hasher = Sha256::new();
-                    std::io::copy(&mut file, &mut hasher)?;
-                    let hash_bytes = hasher.finalize();
-                    let hash = hash_bytes
-                        .iter()
-                        .map(|x| format!("{:02x}", x))
-                        .collect::<Vec<String>>()
-                        .join("");
-
-                    state
-                        .files
-                        .insert(path_str.to_string(), SyncFile::created(Some(hash)));
+                    let mut sync_file = SyncFile::created(hash);
+                    sync_file.set_stamp(stamp);
+                    sync_file.mode = file_mode(&entry);
+                    sync_file.source_url = sources.get(path_str).cloned();
+                    state.files.insert(path_str.to_string(), sync_file);
+                }
+            }
+        }
+
+        for directory in directories {
+            if !sync_root.join(&directory).is_dir() {
+                // Missing, so removed below like a file
+                continue;
+            }
+            checked_files.push(PathBuf::from(&directory));
+            match state.files.get_mut(&directory) {
+                Some(sync_file) if sync_file.state == FileState::Directory => {}
+                Some(sync_file) => {
+                    info!("[{}] Now a directory: {}", "*".yellow(), directory.yellow());
+                    sync_file.make_directory();
+                }
+                None => {
+                    info!("[{}] New directory: {}", "+".green(), directory.green());
+                    state.files.insert(directory, SyncFile::directory());
                 }
             }
         }
@@ -267,83 +556,324 @@ impl SyncCommand {
         for (path, sync_file) in state
             .files
             .iter_mut()
-            .filter(|(_, x)| x.state == FileState::Exists)
+            .filter(|(_, x)| matches!(x.state, FileState::Exists | FileState::Directory))
             .filter(|(x, _)| !checked_files.contains(&PathBuf::from_str(x).unwrap()))
         {
             info!("[{}] File removed: {}", "x".red(), path.red());
             sync_file.make_deleted();
         }
 
+        if self.authoritative {
+            self.delete_untracked(
+                &api,
+                &config,
+                &includes,
+                &excludes,
+                &checked_files,
+                &mut state,
+            )
+            .await?;
+        }
+
+        if let Some(expected) = self.expect_version.or(state.modpack_version) {
+            let current = api
+                .get_modpack(&config.modpack_id)
+                .await?
+                .modpack
+                .sync_version;
+            if current != expected {
+                return Err(anyhow::anyhow!(
+                    "Modpack changed on the server since version {} (now {}), run with --download-state to pick up those changes first",
+                    expected,
+                    current
+                ));
+            }
+        }
+
+        if self.dry_run {
+            let mut paths: Vec<(&String, &SyncFile)> = state
+                .files
+                .iter()
+                .filter(|(_, x)| x.dirty != FileDirtyness::Clean || self.force_sync)
+                .collect();
+            paths.sort_by(|a, b| a.0.cmp(b.0));
+            for (path, sync_file) in paths {
+                info!(
+                    "[{}] Would synchronize {} ({:?})",
+                    "?".blue(),
+                    path.blue(),
+                    sync_file.dirty
+                );
+            }
+            info!("Dry run, nothing was changed.");
+            return Ok(());
+        }
+
         info!("Starting server synchronization...");
 
         // Synchronize to server
         let force_sync = self.force_sync;
         let force_upload = self.force_upload;
+        let needs_upload = |x: &SyncFile| {
+            x.state == FileState::Exists
+                && x.source_url.is_none()
+                && (force_upload
+                    || x.dirty == FileDirtyness::Created
+                    || x.dirty == FileDirtyness::Updated)
+        };
+
+        if let Some(capabilities) = &capabilities {
+            for (path, _) in state
+                .files
+                .iter()
+                .filter(|(_, x)| x.dirty != FileDirtyness::Clean || force_sync)
+                .filter(|(_, x)| needs_upload(x))
+            {
+                let size = std::fs::metadata(sync_root.join(path))?.len();
+                if size > capabilities.max_upload_size as u64 {
+                    return Err(anyhow::anyhow!(
+                        "{} is {} bytes, over the server's upload limit of {} bytes",
+                        path,
+                        size,
+                        capabilities.max_upload_size
+                    ));
+                }
+            }
+        }
+
+        let chunked_upload = capabilities.as_ref().is_some_and(|x| x.chunked_upload);
+        let chunked_upload_threshold = self.chunked_upload_threshold;
+        let is_chunked = |local_path: &Path| -> std::io::Result<bool> {
+            Ok(chunked_upload && std::fs::metadata(local_path)?.len() > chunked_upload_threshold)
+        };
+
+        if self.two_phase {
+            // Clients only see changes after filesync, so upload everything first
+            let to_upload: Vec<String> = state
+                .files
+                .iter()
+                .filter(|(_, x)| x.dirty != FileDirtyness::Clean || force_sync)
+                .filter(|(_, x)| needs_upload(x))
+                .map(|(path, _)| path.clone())
+                .collect();
+            let upload_count = to_upload.len();
+            let hashes = upload_hashes(&state, &to_upload);
+            let progress = UploadProgress::new(&mut state, &state_path);
+            let (api, sync_root, modpack_id, hashes, progress, is_chunked) = (
+                &api,
+                &sync_root,
+                &config.modpack_id,
+                &hashes,
+                &progress,
+                &is_chunked,
+            );
+            let results = self
+                .upload_all(to_upload, |path| async move {
+                    let local_path = sync_root.join(&path);
+                    let hash = &hashes[&path];
+                    let uploaded = if is_chunked(&local_path)? {
+                        upload_chunked(api, modpack_id, &local_path, &path, None, hash, progress)
+                            .await?
+                    } else {
+                        let data = std::fs::read(&local_path)?;
+                        api.upload_blob(data, checksum).await?.hash
+                    };
+                    if &uploaded != hash {
+                        return Err(anyhow::anyhow!(
+                            "{} changed while syncing, please run the sync again",
+                            path
+                        ));
+                    }
+                    Ok(uploaded)
+                })
+                .await;
+            let (uploaded_hashes, failure) = collect_uploads(results);
+            if let Some((failed, err)) = failure {
+                return Err(err.context(format!(
+                    "{} of {} upload(s) failed, nothing was published",
+                    failed, upload_count
+                )));
+            }
+
+            info!("Verifying uploaded content...");
+            for hashes in uploaded_hashes.chunks(500) {
+                let missing = api.missing_blobs(hashes.to_vec()).await?;
+                if !missing.is_empty() {
+                    return Err(ExitError::Verify(format!(
+                        "Server is missing {} uploaded file(s), nothing was published",
+                        missing.len()
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        let mut bodies: Vec<FileSyncBody> = Vec::new();
         for (path, sync_file) in state
             .files
-            .iter_mut()
+            .iter()
             .filter(|(_, x)| x.dirty != FileDirtyness::Clean || force_sync)
         {
-            info!("[{}] Synchronizing {}...", "%".blue(), path.blue());
-            let _sync_result = client
-                .post(format!(
-                    "{}/modpack/{}/filesync",
-                    config.server_url, config.modpack_id
-                ))
-                .json(&FileSyncBody {
-                    path: path.clone(),
-                    state: sync_file.state,
-                    hash: sync_file.hash.clone(),
-                })
-                .send()
-                .await?
-                .error_for_status()
-                .map_err(|x| match x.status() {
-                    Some(reqwest::StatusCode::UNAUTHORIZED) => anyhow!("Invalid API key"),
-                    _ => x.into(),
-                })?
-                .json::<FileSyncResponse>()
-                .await?;
+            bodies.push(FileSyncBody {
+                path: path.clone(),
+                state: sync_file.state,
+                hash: sync_file.hash.clone(),
+                mode: sync_file.mode.clone(),
+                mod_state: sync_file.mod_state(),
+                download_source: sync_file
+                    .source_url
+                    .as_ref()
+                    .map(|_| DownloadSource::Modrinth),
+                source_url: sync_file.source_url.clone(),
+                size: match &sync_file.source_url {
+                    Some(_) => Some(std::fs::metadata(sync_root.join(path))?.len() as i64),
+                    None => None,
+                },
+            });
+        }
+        let paths: Vec<String> = bodies.iter().map(|x| x.path.clone()).collect();
 
-            if sync_file.state == FileState::Exists
-                && (force_upload
-                    || sync_file.dirty == FileDirtyness::Created
-                    || sync_file.dirty == FileDirtyness::Updated)
-            {
-                info!("[{}] Uploading {}...", "@".purple(), path.purple());
-                let mut file = File::open(target_path.join(path))?;
-                let mut data = Vec::new();
-                file.read_to_end(&mut data)?;
-                drop(file);
-                let part = reqwest::multipart::Part::bytes(data).file_name("upload");
-                let multipart = reqwest::multipart::Form::new().part("upload", part);
-                let _upload_result = client
-                    .post(format!(
-                        "{}/modpack/{}/upload",
-                        config.server_url, config.modpack_id,
-                    ))
-                    .query(&[("file_path", path)])
-                    .multipart(multipart)
-                    .send()
-                    .await?
-                    .error_for_status()
-                    .map_err(|x| match x.status() {
-                        Some(reqwest::StatusCode::UNAUTHORIZED) => anyhow!("Invalid API key"),
-                        _ => x.into(),
-                    })?;
-                // .json::<FileUploadResponse>()
-                // .await?;
+        let mut published = 0;
+        let batch_limit = capabilities
+            .as_ref()
+            .filter(|x| x.supports(FEATURE_FILESYNC_BATCH))
+            .map(|x| x.max_json_body_size);
+        let batches = match batch_limit {
+            Some(max_body_size) => filesync_batches(bodies, max_body_size),
+            // One file per request on servers without batches
+            None => bodies.into_iter().map(|x| vec![x]).collect(),
+        };
+        for batch in batches {
+            let result = match batch_limit {
+                Some(_) => {
+                    info!("[{}] Synchronizing {} file(s)...", "%".blue(), batch.len());
+                    let count = batch.len();
+                    api.filesync_batch(&config.modpack_id, &FileSyncBatchBody { files: batch })
+                        .await
+                        .map(|_| count)
+                }
+                None => {
+                    info!("[{}] Synchronizing {}...", "%".blue(), batch[0].path.blue());
+                    api.filesync(&config.modpack_id, &batch[0]).await.map(|_| 1)
+                }
+            };
+            match result {
+                Ok(count) => published += count,
+                Err(err) if published == 0 => return Err(err.into()),
+                Err(err) => {
+                    return Err(ExitError::Partial {
+                        message: format!("Sync failed after publishing {} file(s)", published),
+                        source: err.into(),
+                    }
+                    .into())
+                }
             }
+        }
 
-            sync_file.mark_synced();
+        let mut to_upload: Vec<String> = Vec::new();
+        for path in paths {
+            let sync_file = state.files.get_mut(&path).unwrap();
+            // Only synced once its content is on the server too
+            if !self.two_phase && needs_upload(sync_file) {
+                to_upload.push(path);
+            } else {
+                sync_file.mark_synced();
+            }
         }
 
+        let upload_count = to_upload.len();
+        let hashes = upload_hashes(&state, &to_upload);
+        let progress = UploadProgress::new(&mut state, &state_path);
+        let results = {
+            let (api, sync_root, modpack_id, hashes, progress, is_chunked) = (
+                &api,
+                &sync_root,
+                &config.modpack_id,
+                &hashes,
+                &progress,
+                &is_chunked,
+            );
+            self.upload_all(to_upload, |path| async move {
+                let local_path = sync_root.join(&path);
+                if is_chunked(&local_path)? {
+                    let hash = &hashes[&path];
+                    upload_chunked(
+                        api,
+                        modpack_id,
+                        &local_path,
+                        &path,
+                        Some(&path),
+                        hash,
+                        progress,
+                    )
+                    .await?;
+                } else {
+                    let data = std::fs::read(&local_path)?;
+                    let hash = hashes.get(&path).map(|x| x.as_str());
+                    api.upload(modpack_id, &path, hash, data).await?;
+                }
+                Ok(path)
+            })
+            .await
+        };
+        let (uploaded, upload_failure) = collect_uploads(results);
+        for path in uploaded {
+            if let Some(sync_file) = state.files.get_mut(&path) {
+                sync_file.mark_synced();
+            }
+        }
+
+        if state.modpack_version.is_some() {
+            // Our own changes moved the version on, keep checking from there
+            state.modpack_version = Some(
+                api.get_modpack(&config.modpack_id)
+                    .await?
+                    .modpack
+                    .sync_version,
+            );
+        }
         state.upload_version += 1;
+        // Uploads of files that changed or went away since can't be resumed anymore
+        state.uploads.retain(|path, upload| {
+            state
+                .files
+                .get(path)
+                .is_some_and(|x| x.hash.as_ref() == Some(&upload.hash))
+        });
 
         info!("Saving local state...");
-        let state_toml = toml::to_string(&state)?;
-        let mut state_file = File::create(target_path.join("modsync.state.toml"))?;
-        state_file.write_all(state_toml.as_bytes())?;
+        save_state(&state_path, &state)?;
+
+        // Saved first, so the next sync only retries the files that failed
+        if let Some((failed, err)) = upload_failure {
+            return Err(ExitError::Partial {
+                message: format!(
+                    "{} of {} upload(s) failed, run the sync again to retry them",
+                    failed, upload_count
+                ),
+                source: err.into(),
+            }
+            .into());
+        }
+
+        if let Some(private_key) = &signing_key {
+            info!("Signing file list...");
+            let payload = manifest_payload(
+                &config.modpack_id,
+                state
+                    .files
+                    .iter()
+                    .filter(|(_, x)| x.state == FileState::Exists)
+                    .map(|(path, x)| SignedFile {
+                        path,
+                        hash: x.hash.as_deref(),
+                        mode: x.mode.as_deref(),
+                    }),
+            );
+            let signature = sign_payload(private_key, &payload)?;
+            api.set_signature(&config.modpack_id, Some(signature))
+                .await?;
+        }
 
         info!(
             "{} Sync completed in {:.2}s",
@@ -355,6 +885,614 @@ impl SyncCommand {
     }
 }
 
+/// Splits file syncs into batches the server accepts: at most `FILESYNC_BATCH_MAX_FILES`
+/// files, serialized within `max_body_size`. A file too large for any batch goes alone.
+fn filesync_batches(bodies: Vec<FileSyncBody>, max_body_size: usize) -> Vec<Vec<FileSyncBody>> {
+    // `{"files":[]}` around the entries, plus a comma between each
+    const ENVELOPE: usize = 12;
+    let mut batches: Vec<Vec<FileSyncBody>> = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_size = ENVELOPE;
+    for body in bodies {
+        let size = serde_json::to_vec(&body).map_or(0, |x| x.len()) + 1;
+        if !batch.is_empty()
+            && (batch.len() == FILESYNC_BATCH_MAX_FILES || batch_size + size > max_body_size)
+        {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = ENVELOPE;
+        }
+        batch_size += size;
+        batch.push(body);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// `path` if given, otherwise the default `name` inside the game directory
+pub fn resolve_file(target_path: &Path, path: Option<&Path>, name: &str) -> PathBuf {
+    match path {
+        Some(path) => path.to_path_buf(),
+        None => target_path.join(name),
+    }
+}
+
+pub fn load_config(config_path: &Path) -> anyhow::Result<(UploadConfig, String)> {
+    let config_string = std::fs::read_to_string(config_path).map_err(|_| {
+        ExitError::Config(format!(
+            "No sync config found at {}",
+            config_path.to_string_lossy()
+        ))
+    })?;
+    let config: UploadConfig = toml::from_str(&config_string)?;
+    Ok((config, config_string))
+}
+
+/// Asks a yes/no question on the terminal, anything but yes is a no
+pub fn confirm(question: &str) -> std::io::Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Asks for a line of text on the terminal, trimmed
+pub fn ask(question: &str) -> std::io::Result<String> {
+    print!("{}: ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+pub fn read_signing_key(path: &Path) -> anyhow::Result<String> {
+    let key = std::fs::read_to_string(path).map_err(|err| {
+        ExitError::Config(format!(
+            "Couldn't read signing key {}: {}",
+            path.to_string_lossy(),
+            err
+        ))
+    })?;
+    // Fail before anything is synced rather than after
+    sign_payload(&key, &[]).map_err(|err| {
+        ExitError::Config(format!(
+            "Invalid signing key {}: {}",
+            path.to_string_lossy(),
+            err
+        ))
+    })?;
+    Ok(key)
+}
+
+/// Which of `paths` are already on disk the way the server has them, files are hashed in parallel
+fn matching_local_files(
+    sync_root: &Path,
+    files: &HashMap<String, SyncFile>,
+    paths: &[String],
+    checksum: Checksum,
+) -> Vec<String> {
+    let (hashed, others): (Vec<&String>, Vec<&String>) = paths.iter().partition(|path| {
+        files
+            .get(*path)
+            .is_some_and(|x| x.state == FileState::Exists && x.hash.is_some())
+    });
+    let local_paths: Vec<PathBuf> = hashed.iter().map(|x| sync_root.join(x)).collect();
+    let hashes = hash_files(&local_paths, checksum);
+    let matching_files = hashed
+        .into_iter()
+        .zip(hashes)
+        .filter(|(path, hash)| {
+            hash.as_ref()
+                .is_ok_and(|hash| files[*path].hash.as_ref() == Some(hash))
+        })
+        .map(|(path, _)| path);
+    let matching_others = others.into_iter().filter(|path| {
+        let local = sync_root.join(path);
+        match files.get(*path) {
+            Some(x) if x.state == FileState::Directory => local.is_dir(),
+            Some(x) if x.state == FileState::Deleted => !local.exists(),
+            _ => false,
+        }
+    });
+    matching_files.chain(matching_others).cloned().collect()
+}
+
+/// Hashes of `paths` in the same order, computed on as many threads as there are cores.
+/// Threads take the next file as they finish one, so a few large files don't hold up the rest.
+pub fn hash_files(paths: &[PathBuf], checksum: Checksum) -> Vec<std::io::Result<String>> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |x| x.get())
+        .min(paths.len());
+    let next = AtomicUsize::new(0);
+    let mut hashes: Vec<(usize, std::io::Result<String>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut hashes = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(path) = paths.get(i) else {
+                            return hashes;
+                        };
+                        hashes.push((i, hash_file(path, checksum)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            // Hashing doesn't panic, short of a bug
+            .flat_map(|x| x.join().unwrap())
+            .collect()
+    });
+    hashes.sort_by_key(|(i, _)| *i);
+    hashes.into_iter().map(|(_, hash)| hash).collect()
+}
+
+/// Local hash of every path about to be uploaded, chunked uploads are checked against it
+fn upload_hashes(state: &SyncState, paths: &[String]) -> HashMap<String, String> {
+    paths
+        .iter()
+        .filter_map(|path| Some((path.clone(), state.files.get(path)?.hash.clone()?)))
+        .collect()
+}
+
+/// Writes the state next to `state_path` and renames it into place, so a sync interrupted
+/// while saving never leaves a truncated state behind
+fn save_state(state_path: &Path, state: &SyncState) -> anyhow::Result<()> {
+    let state_toml = toml::to_string(state)?;
+    let mut temp_name = state_path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = state_path.with_file_name(temp_name);
+    let mut state_file = File::create(&temp_path)?;
+    state_file.write_all(state_toml.as_bytes())?;
+    state_file.sync_all()?;
+    std::fs::rename(&temp_path, state_path)?;
+    Ok(())
+}
+
+/// Chunked upload progress shared between concurrent uploads. It's saved with the rest of
+/// the state after every chunk, so an interrupted sync resumes where it left off.
+struct UploadProgress<'a> {
+    state: Mutex<&'a mut SyncState>,
+    state_path: &'a Path,
+}
+
+impl<'a> UploadProgress<'a> {
+    fn new(state: &'a mut SyncState, state_path: &'a Path) -> Self {
+        UploadProgress {
+            state: Mutex::new(state),
+            state_path,
+        }
+    }
+
+    fn pending(&self, path: &str) -> Option<PendingUpload> {
+        self.state.lock().unwrap().uploads.get(path).cloned()
+    }
+
+    /// Records where the upload of `path` is at, `None` once there's nothing left to resume
+    fn record(&self, path: &str, upload: Option<PendingUpload>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match upload {
+            Some(upload) => state.uploads.insert(path.to_string(), upload),
+            None => state.uploads.remove(path),
+        };
+        save_state(self.state_path, &state)
+    }
+}
+
+/// Uploads a file in chunks, resuming the upload an earlier sync left unfinished if the
+/// server still has it. `file_path` is `None` to only store the content. Returns its hash.
+async fn upload_chunked(
+    api: &ModsyncApi,
+    modpack_id: &ModpackId,
+    local_path: &Path,
+    path: &str,
+    file_path: Option<&str>,
+    hash: &str,
+    progress: &UploadProgress<'_>,
+) -> anyhow::Result<String> {
+    let mut file = File::open(local_path)?;
+    let size = file.metadata()?.len();
+
+    let resumed = match progress.pending(path).filter(|x| x.hash == hash) {
+        Some(pending) => match api.upload_status(modpack_id, &pending.upload_id).await {
+            Ok(status) => {
+                info!(
+                    "[{}] Resuming {} at chunk {}...",
+                    "@".purple(),
+                    path.purple(),
+                    status.next_chunk
+                );
+                Some(status)
+            }
+            Err(ClientError::NotFound) => {
+                info!(
+                    "[{}] Upload of {} expired on the server, starting over...",
+                    "@".purple(),
+                    path.purple()
+                );
+                None
+            }
+            Err(err) => return Err(err.into()),
+        },
+        None => None,
+    };
+    let mut status = match resumed {
+        Some(status) => status,
+        None => {
+            let body = UploadInitBody {
+                file_path: file_path.map(|x| x.to_string()),
+                size: size as i64,
+                hash: hash.to_string(),
+            };
+            api.init_upload(modpack_id, &body).await?
+        }
+    };
+    let upload_id = status.upload_id.clone();
+    let pending = |next_chunk| PendingUpload {
+        upload_id: upload_id.clone(),
+        hash: hash.to_string(),
+        next_chunk,
+    };
+    progress.record(path, Some(pending(status.next_chunk)))?;
+
+    let mut chunk = Vec::new();
+    while status.received_bytes < status.size {
+        let n = status.next_chunk;
+        chunk.resize(
+            status.chunk_size.min(status.size - status.received_bytes) as usize,
+            0,
+        );
+        file.seek(SeekFrom::Start(status.received_bytes as u64))?;
+        file.read_exact(&mut chunk)?;
+        let mut attempt = 1;
+        status = loop {
+            match api
+                .upload_chunk(modpack_id, &upload_id, n, chunk.clone())
+                .await
+            {
+                Ok(status) => break status,
+                Err(ClientError::Status(x)) if x.as_u16() == 400 && attempt < CHUNK_ATTEMPTS => {
+                    warn!("Chunk {} of {} was rejected, sending it again", n, path);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        progress.record(path, Some(pending(status.next_chunk)))?;
+    }
+
+    match api.finish_upload(modpack_id, &upload_id).await {
+        Ok(finished) => {
+            progress.record(path, None)?;
+            Ok(finished.hash)
+        }
+        Err(err) => {
+            // The server discards uploads it rejects, only a failed request is worth resuming
+            if matches!(err, ClientError::Status(x) if x.as_u16() == 400) {
+                progress.record(path, None)?;
+            }
+            Err(err.into())
+        }
+    }
+}
+
+/// Successful uploads, and how many failed with the first failure.
+/// Every failure is logged with its path, so none go unnoticed behind the first.
+fn collect_uploads<T>(
+    results: Vec<(String, anyhow::Result<T>)>,
+) -> (Vec<T>, Option<(usize, anyhow::Error)>) {
+    let mut uploaded = Vec::new();
+    let mut failed = 0;
+    let mut first_failure = None;
+    for (path, result) in results {
+        match result {
+            Ok(x) => uploaded.push(x),
+            Err(err) => {
+                error!("[{}] Failed to upload {}: {:#}", "!".red(), path.red(), err);
+                failed += 1;
+                first_failure.get_or_insert(err.context(format!("Failed to upload {}", path)));
+            }
+        }
+    }
+    (uploaded, first_failure.map(|err| (failed, err)))
+}
+
+pub fn hash_file(path: &Path, checksum: Checksum) -> std::io::Result<String> {
+    checksum.hash_reader(&mut File::open(path)?)
+}
+
+/// Declared directories as plain relative paths, without trailing slashes
+pub fn declared_directories(config: &UploadConfig) -> anyhow::Result<Vec<String>> {
+    config
+        .directories
+        .iter()
+        .map(|directory| {
+            let path = Path::new(directory);
+            if directory.is_empty() || !path.components().all(|x| matches!(x, Component::Normal(_)))
+            {
+                return Err(ExitError::Config(format!(
+                    "Declared directory {:?} must be a plain relative path",
+                    directory
+                ))
+                .into());
+            }
+            Ok(directory.trim_end_matches('/').to_string())
+        })
+        .collect()
+}
+
+/// Modrinth sources by plain relative path, with every URL checked to be on Modrinth's CDN
+pub fn modrinth_sources(config: &UploadConfig) -> anyhow::Result<HashMap<String, String>> {
+    config
+        .modrinth_sources
+        .iter()
+        .map(|(path, url)| {
+            if path.is_empty()
+                || !Path::new(path)
+                    .components()
+                    .all(|x| matches!(x, Component::Normal(_)))
+            {
+                return Err(ExitError::Config(format!(
+                    "Modrinth source {:?} must be a plain relative path",
+                    path
+                ))
+                .into());
+            }
+            validate_modrinth_url(url).map_err(|err| ExitError::Config(err.to_string()))?;
+            Ok((path.clone(), url.clone()))
+        })
+        .collect()
+}
+
+/// Directory files are synced relative to, state and config stay in the target
+pub fn resolve_sync_root(
+    target_path: &Path,
+    strip_prefix: Option<&Path>,
+) -> anyhow::Result<PathBuf> {
+    let sync_root = match strip_prefix {
+        Some(prefix) => {
+            if !prefix
+                .components()
+                .all(|x| matches!(x, Component::Normal(_)))
+            {
+                return Err(ExitError::Config(format!(
+                    "--strip-prefix must be a plain relative path, got {}",
+                    prefix.to_string_lossy()
+                ))
+                .into());
+            }
+            target_path.join(prefix)
+        }
+        None => target_path.to_path_buf(),
+    };
+    // A missing root would look like every file was deleted
+    if !sync_root.is_dir() {
+        return Err(ExitError::Config(format!(
+            "{} is not a directory",
+            sync_root.to_string_lossy()
+        ))
+        .into());
+    }
+    Ok(sync_root)
+}
+
+pub fn build_includes(config: &UploadConfig, config_string: &str) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for i in config.include_globs.iter() {
+        builder.add(Glob::new(i).map_err(|x| pattern_error(config_string, i, x))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Excludes from the config and every `.modsyncignore` under `sync_root`
+pub fn build_excludes(
+    sync_root: &Path,
+    config: &UploadConfig,
+    config_string: &str,
+) -> anyhow::Result<Excludes> {
+    // Anchored patterns are relative to the sync root, not the game directory
+    let mut builder = GitignoreBuilder::new(sync_root);
+    for i in config.excludes.iter() {
+        builder
+            .add_line(None, i)
+            .map_err(|x| pattern_error(config_string, i, x))?;
+    }
+
+    let mut files = Vec::new();
+    for entry in WalkDir::new(sync_root)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file() && x.file_name() == IGNORE_FILE)
+    {
+        let Some(dir) = entry
+            .path()
+            .parent()
+            .and_then(|x| relativize_path(sync_root, x))
+        else {
+            continue;
+        };
+        // Patterns are relative to the directory the file is in
+        let mut file_builder = GitignoreBuilder::new(sync_root.join(&dir));
+        if let Some(err) = file_builder.add(entry.path()) {
+            // The error already names the file and line
+            return Err(ExitError::Config(err.to_string()).into());
+        }
+        files.push((dir, file_builder.build()?));
+    }
+    Ok(Excludes::new(sync_root, builder.build()?, files))
+}
+
+/// Points an invalid pattern out by its line in the config
+fn pattern_error<E>(config_string: &str, pattern: &str, err: E) -> anyhow::Error
+where
+    E: std::fmt::Display,
+{
+    match config_string
+        .lines()
+        .position(|x| x.contains(&format!("\"{}\"", pattern)))
+    {
+        Some(line) => ExitError::Config(format!(
+            "Invalid pattern \"{}\" on line {} of the sync config: {}",
+            pattern,
+            line + 1,
+            err
+        ))
+        .into(),
+        None => ExitError::Config(format!("Invalid pattern \"{}\": {}", pattern, err)).into(),
+    }
+}
+
+/// Files under `sync_root` with their relative paths, hidden files are skipped unless configured
+pub fn walk_files<'a>(
+    sync_root: &'a Path,
+    config: &'a UploadConfig,
+) -> impl Iterator<Item = (walkdir::DirEntry, PathBuf)> + 'a {
+    WalkDir::new(sync_root)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file())
+        .filter_map(move |x| relativize_path(sync_root, x.path()).map(|path| (x, path)))
+        .filter(move |(entry, path)| config.include_hidden || !is_hidden(entry, path))
+}
+
+impl SyncCommand {
+    /// Runs `upload` for every path with up to `--upload-concurrency` in flight, carrying on
+    /// past failures. Results come back in completion order.
+    async fn upload_all<T, F, Fut>(
+        &self,
+        paths: Vec<String>,
+        upload: F,
+    ) -> Vec<(String, anyhow::Result<T>)>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        futures_util::stream::iter(paths)
+            .map(|path| {
+                let upload = &upload;
+                async move {
+                    info!("[{}] Uploading {}...", "@".purple(), path.purple());
+                    let result = upload(path.clone()).await;
+                    (path, result)
+                }
+            })
+            .buffer_unordered(self.upload_concurrency as usize)
+            .collect()
+            .await
+    }
+
+    /// Marks server files in the include scope that don't exist locally as deleted
+    async fn delete_untracked(
+        &self,
+        api: &ModsyncApi,
+        config: &UploadConfig,
+        includes: &GlobSet,
+        excludes: &Excludes,
+        checked_files: &[PathBuf],
+        state: &mut SyncState,
+    ) -> anyhow::Result<()> {
+        if checked_files.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No local files matched, refusing to delete every file on the server"
+            ));
+        }
+        let server = api.get_modpack(&config.modpack_id).await?;
+        let mut untracked: Vec<modsync_core::models::files::File> = server
+            .files
+            .into_iter()
+            .filter(|x| x.state == FileState::Exists)
+            .filter(|x| includes.is_match(&x.path))
+            .filter(|x| !excludes.is_excluded(Path::new(&x.path)))
+            .filter(|x| !checked_files.contains(&PathBuf::from(&x.path)))
+            .collect();
+        if untracked.is_empty() {
+            return Ok(());
+        }
+        untracked.sort_by(|a, b| a.path.cmp(&b.path));
+        for file in untracked.iter() {
+            info!("[{}] Not present locally: {}", "x".red(), file.path.red());
+        }
+
+        if !self.yes && !self.dry_run {
+            if !std::io::stdin().is_terminal() {
+                return Err(anyhow::anyhow!(
+                    "--authoritative would delete {} server file(s), pass --yes to confirm",
+                    untracked.len()
+                ));
+            }
+            if !confirm(&format!(
+                "Delete {} file(s) from the server?",
+                untracked.len()
+            ))? {
+                return Err(anyhow::anyhow!("Sync aborted, nothing was changed."));
+            }
+        }
+
+        for file in untracked {
+            state
+                .files
+                .entry(file.path)
+                .or_insert_with(|| SyncFile::created(file.hash))
+                .make_deleted();
+        }
+        Ok(())
+    }
+}
+
+/// Files created by operating systems and file managers, never worth syncing
+const JUNK_FILES: &[&str] = &["Thumbs.db", "ehthumbs.db", "desktop.ini", "Icon\r"];
+
+/// Whether a file is a dotfile, lives in a dot-directory, is OS junk
+/// or is marked hidden/system by the filesystem.
+pub fn is_hidden(entry: &walkdir::DirEntry, relative_path: &Path) -> bool {
+    let dotted = relative_path.components().any(|x| match x {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+    let junk = relative_path
+        .file_name()
+        .map(|x| JUNK_FILES.iter().any(|junk| x.eq_ignore_ascii_case(junk)))
+        .unwrap_or(false);
+    dotted || junk || has_hidden_attribute(entry)
+}
+
+/// Permission bits of a file as an octal string, only tracked on unix
+#[cfg(unix)]
+fn file_mode(entry: &walkdir::DirEntry) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    entry
+        .metadata()
+        .ok()
+        .map(|x| format!("{:04o}", x.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn file_mode(_entry: &walkdir::DirEntry) -> Option<String> {
+    None
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(entry: &walkdir::DirEntry) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    entry
+        .metadata()
+        .map(|x| x.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_entry: &walkdir::DirEntry) -> bool {
+    false
+}
+
 pub fn relativize_path<T, P>(target: T, path: P) -> Option<PathBuf>
 where
     T: AsRef<Path>,
@@ -378,3 +1516,353 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use modsync_core::api::{verify_digest, UploadStatusResponse, DIGEST_HEADER};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Uploaded in three chunks of `CHUNK_SIZE`, the last one shorter
+    const CONTENT: &[u8] = b"first second third";
+    const CHUNK_SIZE: i64 = 7;
+
+    #[derive(Default)]
+    struct Upload {
+        size: i64,
+        received: Vec<u8>,
+        next_chunk: i32,
+    }
+
+    impl Upload {
+        fn status(&self, upload_id: &str) -> UploadStatusResponse {
+            UploadStatusResponse {
+                upload_id: UploadId(upload_id.to_string()),
+                size: self.size,
+                chunk_size: CHUNK_SIZE,
+                next_chunk: self.next_chunk,
+                received_bytes: self.received.len() as i64,
+            }
+        }
+    }
+
+    /// A chunked upload endpoint for modpack `a`, keeping every upload in memory
+    #[derive(Default)]
+    struct FakeServer {
+        uploads: HashMap<String, Upload>,
+        /// Every request received, as `<method> <path>`
+        requests: Vec<String>,
+        /// Chunk to answer with a server error once, as if the sync was interrupted there
+        fail_chunk: Option<i32>,
+        /// Chunk to flip a byte of once it arrives, as if it got corrupted on the way
+        corrupt_chunk: Option<i32>,
+    }
+
+    impl FakeServer {
+        fn handle(
+            &mut self,
+            method: &str,
+            path: &str,
+            digest: Option<&str>,
+            body: &[u8],
+        ) -> (u16, String) {
+            self.requests.push(format!("{} {}", method, path));
+            let path = path.trim_start_matches("/modpack/a/upload/");
+            let parts: Vec<&str> = path.split('/').collect();
+            match (method, parts.as_slice()) {
+                ("POST", ["init"]) => {
+                    let body: UploadInitBody = serde_json::from_slice(body).unwrap();
+                    let upload_id = format!("up-{}", self.uploads.len());
+                    let upload = Upload {
+                        size: body.size,
+                        ..Default::default()
+                    };
+                    let status = upload.status(&upload_id);
+                    self.uploads.insert(upload_id, upload);
+                    (200, serde_json::to_string(&status).unwrap())
+                }
+                ("GET", [upload_id]) => match self.uploads.get(*upload_id) {
+                    Some(upload) => (
+                        200,
+                        serde_json::to_string(&upload.status(upload_id)).unwrap(),
+                    ),
+                    None => (404, String::new()),
+                },
+                ("PUT", [upload_id, "chunk", n]) => {
+                    let n: i32 = n.parse().unwrap();
+                    if self.fail_chunk == Some(n) {
+                        self.fail_chunk = None;
+                        return (500, String::new());
+                    }
+                    let mut body = body.to_vec();
+                    if self.corrupt_chunk == Some(n) {
+                        self.corrupt_chunk = None;
+                        body[0] ^= 1;
+                    }
+                    if digest.and_then(|x| verify_digest(x, &body)) != Some(true) {
+                        return (400, String::new());
+                    }
+                    let upload = self.uploads.get_mut(*upload_id).unwrap();
+                    assert_eq!(n, upload.next_chunk, "chunk sent out of order");
+                    upload.received.extend_from_slice(&body);
+                    upload.next_chunk += 1;
+                    (
+                        200,
+                        serde_json::to_string(&upload.status(upload_id)).unwrap(),
+                    )
+                }
+                ("POST", [upload_id, "finish"]) => {
+                    let upload = self.uploads.remove(*upload_id).unwrap();
+                    let hash = Checksum::Sha256.hash_bytes(&upload.received);
+                    (200, format!("{{\"hash\":\"{}\",\"file_id\":null}}", hash))
+                }
+                _ => (404, String::new()),
+            }
+        }
+    }
+
+    async fn respond(mut socket: TcpStream, server: &Mutex<FakeServer>) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let head_end = loop {
+            let read = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            if let Some(x) = request.windows(4).position(|x| x == b"\r\n\r\n") {
+                break x + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+        let header = |name: &str| {
+            head.lines().find_map(|x| {
+                let (key, value) = x.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+        };
+        let length = header("content-length").map_or(0, |x| x.parse::<usize>().unwrap());
+        while request.len() < head_end + length {
+            let read = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+        let mut request_line = head.split_whitespace();
+        let (method, path) = (request_line.next().unwrap(), request_line.next().unwrap());
+        let (status, body) = server.lock().unwrap().handle(
+            method,
+            path,
+            header(DIGEST_HEADER),
+            &request[head_end..],
+        );
+        let response = format!(
+            "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    /// Serves `server` on a local port, returning a client for it
+    async fn serve(server: Arc<Mutex<FakeServer>>) -> ModsyncApi {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                respond(socket, &server).await;
+            }
+        });
+        ModsyncApi::new(&url, Some("secret")).unwrap()
+    }
+
+    /// A fresh directory holding the file to upload, as `big.bin`
+    fn sync_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("modsync-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("big.bin"), CONTENT).unwrap();
+        dir
+    }
+
+    async fn upload(api: &ModsyncApi, dir: &Path, state: &mut SyncState) -> anyhow::Result<String> {
+        let state_path = dir.join("state.toml");
+        let progress = UploadProgress::new(state, &state_path);
+        let hash = Checksum::Sha256.hash_bytes(CONTENT);
+        let modpack_id = ModpackId("a".to_string());
+        let local_path = dir.join("big.bin");
+        upload_chunked(
+            api,
+            &modpack_id,
+            &local_path,
+            "big.bin",
+            None,
+            &hash,
+            &progress,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn interrupted_upload_resumes_with_the_missing_chunk() {
+        let server = Arc::new(Mutex::new(FakeServer {
+            fail_chunk: Some(2),
+            ..Default::default()
+        }));
+        let api = serve(server.clone()).await;
+        let dir = sync_dir("resumed-upload");
+        let mut state = SyncState::new();
+
+        assert!(upload(&api, &dir, &mut state).await.is_err());
+        assert_eq!(state.uploads["big.bin"].next_chunk, 2);
+        // The next sync reads what the interrupted one saved
+        let saved = std::fs::read_to_string(dir.join("state.toml")).unwrap();
+        let mut state: SyncState = toml::from_str(&saved).unwrap();
+        assert_eq!(state.uploads["big.bin"].next_chunk, 2);
+
+        server.lock().unwrap().requests.clear();
+        let hash = upload(&api, &dir, &mut state).await.unwrap();
+
+        assert_eq!(hash, Checksum::Sha256.hash_bytes(CONTENT));
+        assert_eq!(
+            server.lock().unwrap().requests,
+            [
+                "GET /modpack/a/upload/up-0",
+                "PUT /modpack/a/upload/up-0/chunk/2",
+                "POST /modpack/a/upload/up-0/finish",
+            ]
+        );
+        assert!(state.uploads.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunk_is_sent_again() {
+        let server = Arc::new(Mutex::new(FakeServer {
+            corrupt_chunk: Some(1),
+            ..Default::default()
+        }));
+        let api = serve(server.clone()).await;
+        let dir = sync_dir("corrupted-chunk");
+        let mut state = SyncState::new();
+
+        let hash = upload(&api, &dir, &mut state).await.unwrap();
+
+        assert_eq!(hash, Checksum::Sha256.hash_bytes(CONTENT));
+        // Rejected as soon as it arrived, not once the whole file was sent
+        assert_eq!(
+            server.lock().unwrap().requests,
+            [
+                "POST /modpack/a/upload/init",
+                "PUT /modpack/a/upload/up-0/chunk/0",
+                "PUT /modpack/a/upload/up-0/chunk/1",
+                "PUT /modpack/a/upload/up-0/chunk/1",
+                "PUT /modpack/a/upload/up-0/chunk/2",
+                "POST /modpack/a/upload/up-0/finish",
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_upload_starts_over() {
+        let server = Arc::new(Mutex::new(FakeServer::default()));
+        let api = serve(server.clone()).await;
+        let dir = sync_dir("expired-upload");
+        let mut state = SyncState::new();
+        state.uploads.insert(
+            "big.bin".to_string(),
+            PendingUpload {
+                upload_id: UploadId("expired".to_string()),
+                hash: Checksum::Sha256.hash_bytes(CONTENT),
+                next_chunk: 2,
+            },
+        );
+
+        let hash = upload(&api, &dir, &mut state).await.unwrap();
+
+        assert_eq!(hash, Checksum::Sha256.hash_bytes(CONTENT));
+        assert_eq!(
+            server.lock().unwrap().requests,
+            [
+                "GET /modpack/a/upload/expired",
+                "POST /modpack/a/upload/init",
+                "PUT /modpack/a/upload/up-0/chunk/0",
+                "PUT /modpack/a/upload/up-0/chunk/1",
+                "PUT /modpack/a/upload/up-0/chunk/2",
+                "POST /modpack/a/upload/up-0/finish",
+            ]
+        );
+        assert!(state.uploads.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Config for modpack `a` with `extra` TOML appended
+    fn upload_config(extra: &str) -> UploadConfig {
+        let config = format!(
+            "modpack_id = \"a\"\nserver_url = \"http://127.0.0.1:1/\"\napi_key = \"secret\"\n\
+            include_globs = [\"**\"]\nexcludes = []\n{}",
+            extra
+        );
+        toml::from_str(&config).unwrap()
+    }
+
+    #[test]
+    fn hidden_files_are_only_walked_when_included() {
+        let dir = sync_dir("hidden-files");
+        for path in [".git/config", "mods/.DS_Store", "mods/Thumbs.db", ".env"] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::write(dir.join("mods/a.jar"), b"").unwrap();
+        let walked = |config: &UploadConfig| {
+            let mut paths: Vec<String> = walk_files(&dir, config)
+                .map(|(_, path)| path.to_string_lossy().replace('\\', "/"))
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(walked(&upload_config("")), ["big.bin", "mods/a.jar"]);
+        assert_eq!(
+            walked(&upload_config("include_hidden = true")),
+            [
+                ".env",
+                ".git/config",
+                "big.bin",
+                "mods/.DS_Store",
+                "mods/Thumbs.db",
+                "mods/a.jar"
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn anchored_excludes_are_relative_to_the_stripped_root() {
+        let dir = sync_dir("anchored-excludes");
+        let root = dir.join(".minecraft/config");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(IGNORE_FILE), "/client.toml\n").unwrap();
+        let config = upload_config("");
+        let config = UploadConfig {
+            excludes: vec!["/mods/skip.jar".to_string()],
+            ..config
+        };
+
+        let sync_root = resolve_sync_root(&dir, Some(Path::new(".minecraft"))).unwrap();
+        let excludes = build_excludes(&sync_root, &config, "").unwrap();
+        let excluded = |path: &str| excludes.is_excluded(Path::new(path));
+        assert!(excluded("mods/skip.jar"));
+        assert!(!excluded("config/mods/skip.jar"));
+        assert!(!excluded("mods/keep.jar"));
+        assert!(excluded("config/client.toml"));
+        assert!(!excluded("config/sub/client.toml"));
+        assert!(!excluded("client.toml"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
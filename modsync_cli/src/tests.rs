@@ -0,0 +1,601 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use clap::Parser;
+use modsync_core::{
+    api::{
+        BlobExistsBody, BlobExistsResponse, BlobUploadResponse, FileDeleteBody, FileId,
+        FileSyncBatchBody, FileSyncBatchResponse, FileSyncBody, FileSyncResult, FileUploadResponse,
+        ModpackId, FEATURE_BLOB_UPLOAD, FEATURE_DIRECTORIES, FEATURE_FILESYNC_BATCH,
+    },
+    checksum::Checksum,
+    models::{files::File, modpacks::Modpack},
+    FileState,
+};
+use tokio::net::TcpListener;
+
+use crate::sync::{FileDirtyness, SyncCommand, SyncState, CONFIG_FILE, STATE_FILE};
+
+/// Answers a request before the fake server's routes do, `None` lets them answer
+type Intercept = Box<dyn FnMut(&Method, &str) -> Option<Response> + Send>;
+
+/// What the fake server holds and saw, tests change it between syncs
+struct FakeState {
+    files: BTreeMap<String, File>,
+    blobs: HashMap<String, Vec<u8>>,
+    sync_version: i32,
+    /// Features listed in `/capabilities`, which is missing like on old servers when empty
+    features: Vec<&'static str>,
+    /// Method and path with query of every request
+    requests: Vec<String>,
+    intercept: Option<Intercept>,
+}
+
+/// A modsync server holding modpack `a`, to run whole syncs against
+#[derive(Clone)]
+struct FakeServer {
+    url: String,
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeServer {
+    async fn start() -> Self {
+        let state = Arc::new(Mutex::new(FakeState {
+            files: BTreeMap::new(),
+            blobs: HashMap::new(),
+            sync_version: 0,
+            features: vec![
+                FEATURE_BLOB_UPLOAD,
+                FEATURE_DIRECTORIES,
+                FEATURE_FILESYNC_BATCH,
+            ],
+            requests: Vec::new(),
+            intercept: None,
+        }));
+        let app = axum::Router::new()
+            .fallback(fake_route)
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        FakeServer { url, state }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Paths of the server's files in `state`
+    fn paths(&self, state: FileState) -> Vec<String> {
+        let files = &self.state().files;
+        let files = files.values().filter(|x| x.state == state);
+        files.map(|x| x.path.clone()).collect()
+    }
+
+    /// Content of the server's file at `path`, if it was uploaded
+    fn content(&self, path: &str) -> Option<Vec<u8>> {
+        let state = self.state();
+        let file = state.files.get(path).filter(|x| x.uploaded)?;
+        state.blobs.get(file.hash.as_ref()?).cloned()
+    }
+}
+
+impl FakeState {
+    fn modpack(&self) -> Modpack {
+        Modpack {
+            id: ModpackId("a".to_string()),
+            name: "Pack".to_string(),
+            modloader: None,
+            modloader_version: None,
+            game_version: None,
+            sync_version: self.sync_version,
+            signature: None,
+            hash_algorithm: Checksum::Sha256,
+        }
+    }
+
+    fn filesync(&mut self, body: FileSyncBody) -> FileSyncResult {
+        self.sync_version += 1;
+        let uploaded = body
+            .hash
+            .as_ref()
+            .is_some_and(|x| self.blobs.contains_key(x));
+        let sync_version = self.files.get(&body.path).map_or(0, |x| x.sync_version + 1);
+        let now = chrono::Utc::now();
+        let file = File {
+            id: FileId(body.path.clone()),
+            modpack: ModpackId("a".to_string()),
+            created_at: now,
+            updated_at: now,
+            path: body.path.clone(),
+            state: body.state,
+            sync_version,
+            hash: body.hash,
+            uploaded,
+            size: body.size,
+            mode: body.mode,
+            mod_state: body.mod_state,
+            download_source: body.download_source,
+            source_url: body.source_url,
+        };
+        self.files.insert(body.path.clone(), file);
+        FileSyncResult {
+            path: body.path,
+            sync_version,
+        }
+    }
+
+    /// Stores a blob, returning its hash
+    fn store(&mut self, content: Vec<u8>) -> String {
+        let hash = Checksum::Sha256.hash_bytes(&content);
+        self.blobs.insert(hash.clone(), content);
+        hash
+    }
+
+    /// Adds an uploaded file, as if another directory synced it
+    fn put(&mut self, path: &str, content: &[u8]) {
+        let hash = self.store(content.to_vec());
+        self.filesync(FileSyncBody {
+            path: path.to_string(),
+            state: FileState::Exists,
+            hash: Some(hash),
+            mode: None,
+            mod_state: None,
+            download_source: None,
+            source_url: None,
+            size: Some(content.len() as i64),
+        });
+    }
+}
+
+/// Content of a single file multipart body
+fn multipart_content(body: &[u8]) -> Vec<u8> {
+    let start = body.windows(4).position(|x| x == b"\r\n\r\n").unwrap() + 4;
+    let end = body.windows(4).rposition(|x| x == b"\r\n--").unwrap();
+    body[start..end].to_vec()
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(body: &[u8]) -> T {
+    serde_json::from_slice(body).unwrap()
+}
+
+fn query<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let query = uri.split_once('?')?.1;
+    let prefix = format!("{}=", name);
+    query
+        .split('&')
+        .find_map(|x| x.strip_prefix(prefix.as_str()))
+}
+
+async fn fake_route(State(state): State<Arc<Mutex<FakeState>>>, request: Request) -> Response {
+    let method = request.method().clone();
+    let uri = request.uri().to_string();
+    let path = request.uri().path().to_string();
+    let body = axum::body::to_bytes(request.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!("{} {}", method, uri));
+    if let Some(response) = state.intercept.as_mut().and_then(|x| x(&method, &uri)) {
+        return response;
+    }
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (&method, &segments[..]) {
+        (&Method::POST, ["hello"]) => Json(serde_json::json!({
+            "version": "0.0.0",
+            "version_number": 1,
+        }))
+        .into_response(),
+        (&Method::GET, ["capabilities"]) if !state.features.is_empty() => Json(serde_json::json!({
+            "protocol_version": 1,
+            "version": "0.0.0",
+            "features": state.features,
+            "hash_algorithms": ["sha256"],
+            "chunked_upload": false,
+            "max_upload_size": 1 << 20,
+            "max_json_body_size": 65536,
+            "max_path_length": 200,
+            "max_path_components": 16,
+            "batch_download_max_hashes": 256,
+        }))
+        .into_response(),
+        (&Method::GET, ["modpack", "a"]) => Json(serde_json::json!({
+            "modpack": state.modpack(),
+            "files": state.files.values().filter(|x| x.state != FileState::Deleted).collect::<Vec<_>>(),
+        }))
+        .into_response(),
+        (&Method::GET, ["modpack", "a", "changes"]) => Json(serde_json::json!({
+            "modpack": state.modpack(),
+            "cursor": state.sync_version,
+            "full": true,
+            "files": state.files.values().collect::<Vec<_>>(),
+        }))
+        .into_response(),
+        (&Method::POST, ["modpack", "a", "filesync"]) => {
+            state.filesync(from_json(&body));
+            Json(serde_json::json!({})).into_response()
+        }
+        (&Method::POST, ["modpack", "a", "filesync", "batch"]) => {
+            let batch: FileSyncBatchBody = from_json(&body);
+            let results = batch.files.into_iter().map(|x| state.filesync(x)).collect();
+            Json(FileSyncBatchResponse { results }).into_response()
+        }
+        (&Method::POST, ["modpack", "a", "file", "delete"]) => {
+            let delete: FileDeleteBody = from_json(&body);
+            let Some(file) = state.files.get_mut(&delete.path) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            file.state = FileState::Deleted;
+            state.sync_version += 1;
+            Json(serde_json::json!({})).into_response()
+        }
+        (&Method::POST, ["modpack", "a", "upload"]) => {
+            let file_path = query(&uri, "file_path").unwrap().replace("%2F", "/");
+            let hash = state.store(multipart_content(&body));
+            let Some(file) = state.files.get_mut(&file_path) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            if file.hash.as_ref() != Some(&hash) {
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            file.uploaded = true;
+            Json(FileUploadResponse {
+                file_id: file.id.clone(),
+            })
+            .into_response()
+        }
+        (&Method::POST, ["blob", "upload"]) => {
+            let hash = state.store(multipart_content(&body));
+            Json(BlobUploadResponse { hash }).into_response()
+        }
+        (&Method::POST, ["blob", "exists"]) => {
+            let exists: BlobExistsBody = from_json(&body);
+            let missing = exists.hashes.into_iter();
+            let missing = missing.filter(|x| !state.blobs.contains_key(x)).collect();
+            Json(BlobExistsResponse { missing }).into_response()
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Parser)]
+struct SyncArgs {
+    #[command(flatten)]
+    sync: SyncCommand,
+}
+
+/// Runs `modsync_cli sync` on `dir` with `args` after the directory
+async fn sync(dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+    let mut all = vec!["sync", dir.to_str().unwrap()];
+    all.extend_from_slice(args);
+    SyncArgs::parse_from(all).sync.run().await
+}
+
+/// A fresh game directory syncing modpack `a` to `server`
+fn sync_dir(name: &str, server: &FakeServer) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("modsync-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = format!(
+        "modpack_id = \"a\"\nserver_url = \"{}\"\napi_key = \"secret\"\n\
+        include_globs = [\"mods/**\", \"config/**\"]\nexcludes = []\n",
+        server.url
+    );
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+    dir
+}
+
+fn write(dir: &Path, path: &str, content: &[u8]) {
+    let path = dir.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, content).unwrap();
+}
+
+#[tokio::test]
+async fn two_phase_sync_publishes_nothing_until_every_upload_succeeds() {
+    let server = FakeServer::start().await;
+    let dir = sync_dir("two-phase", &server);
+    write(&dir, "mods/a.jar", b"mod a");
+    write(&dir, "mods/b.jar", b"mod b");
+    let mut failed_once = false;
+    server.state().intercept = Some(Box::new(move |_, uri| {
+        (uri.starts_with("/blob/upload") && !std::mem::replace(&mut failed_once, true))
+            .then(|| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }));
+
+    let err = sync(&dir, &["--two-phase"]).await.unwrap_err();
+    assert!(
+        err.to_string().contains("nothing was published"),
+        "{:?}",
+        err
+    );
+    assert!(server.state().files.is_empty());
+    let requests = server.state().requests.clone();
+    assert!(
+        !requests.iter().any(|x| x.contains("filesync")),
+        "{:?}",
+        requests
+    );
+
+    sync(&dir, &["--two-phase"]).await.unwrap();
+    assert_eq!(
+        server.paths(FileState::Exists),
+        ["mods/a.jar", "mods/b.jar"]
+    );
+    assert_eq!(server.content("mods/a.jar").unwrap(), b"mod a");
+    assert_eq!(server.content("mods/b.jar").unwrap(), b"mod b");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn authoritative_sync_deletes_untracked_server_files_unless_dry_run() {
+    let server = FakeServer::start().await;
+    let dir = sync_dir("authoritative", &server);
+    write(&dir, "mods/a.jar", b"mod a");
+    sync(&dir, &[]).await.unwrap();
+    {
+        let mut state = server.state();
+        // Uploaded from elsewhere, and a file outside the include globs
+        state.put("mods/other.jar", b"other mod");
+        state.put("saves/world.dat", b"world");
+    }
+
+    write(&dir, "mods/a.jar", b"mod a, updated");
+    let saved_state = std::fs::read(dir.join(STATE_FILE)).unwrap();
+    let dry_run = server.state().requests.len();
+    sync(&dir, &["--authoritative", "--dry-run"]).await.unwrap();
+    assert_eq!(std::fs::read(dir.join(STATE_FILE)).unwrap(), saved_state);
+    let requests = server.state().requests[dry_run..].to_vec();
+    assert!(
+        !requests
+            .iter()
+            .any(|x| x.contains("filesync") || x.contains("delete") || x.contains("upload")),
+        "{:?}",
+        requests
+    );
+    assert_eq!(
+        server.paths(FileState::Exists),
+        ["mods/a.jar", "mods/other.jar", "saves/world.dat"]
+    );
+
+    // Never deletes from a script without --yes
+    if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        let err = sync(&dir, &["--authoritative"]).await.unwrap_err();
+        assert!(err.to_string().contains("--yes"), "{:?}", err);
+        assert_eq!(server.paths(FileState::Deleted), Vec::<String>::new());
+    }
+
+    sync(&dir, &["--authoritative", "--yes"]).await.unwrap();
+    assert_eq!(
+        server.paths(FileState::Exists),
+        ["mods/a.jar", "saves/world.dat"]
+    );
+    assert_eq!(server.paths(FileState::Deleted), ["mods/other.jar"]);
+    assert_eq!(server.content("mods/a.jar").unwrap(), b"mod a, updated");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// A game directory with two mods, synced to `server`
+async fn sync_two_mods(name: &str, server: &FakeServer) -> PathBuf {
+    let dir = sync_dir(name, server);
+    write(&dir, "mods/a.jar", b"mod a");
+    write(&dir, "mods/b.jar", b"mod b");
+    sync(&dir, &[]).await.unwrap();
+    assert_eq!(server.content("mods/a.jar").unwrap(), b"mod a");
+    assert_eq!(server.content("mods/b.jar").unwrap(), b"mod b");
+    dir
+}
+
+/// Whether `server` got batched filesyncs, and whether it got single ones
+fn filesyncs(server: &FakeServer) -> (bool, bool) {
+    let requests = &server.state().requests;
+    let batched = requests.iter().any(|x| x.ends_with("/filesync/batch"));
+    let single = requests.iter().any(|x| x.ends_with("/filesync"));
+    (batched, single)
+}
+
+#[tokio::test]
+async fn optional_requests_follow_the_server_capabilities() {
+    let server = FakeServer::start().await;
+    let dir = sync_two_mods("capabilities-all", &server).await;
+    assert_eq!(filesyncs(&server), (true, false));
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // From before batches and blob uploads
+    let server = FakeServer::start().await;
+    server.state().features = vec![FEATURE_DIRECTORIES];
+    let dir = sync_dir("capabilities-old", &server);
+    write(&dir, "mods/a.jar", b"mod a");
+    let err = sync(&dir, &["--two-phase"]).await.unwrap_err();
+    assert!(err.to_string().contains("two-phase"), "{:?}", err);
+    assert!(server.state().files.is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+    let dir = sync_two_mods("capabilities-old", &server).await;
+    assert_eq!(filesyncs(&server), (false, true));
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // Older still, without the endpoint
+    let server = FakeServer::start().await;
+    server.state().features.clear();
+    let dir = sync_two_mods("capabilities-none", &server).await;
+    assert_eq!(filesyncs(&server), (false, true));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn custom_config_and_state_leave_the_default_files_alone() {
+    let server = FakeServer::start().await;
+    let dir = sync_dir("custom-config", &server);
+    write(&dir, "mods/a.jar", b"mod a");
+    let config = std::fs::read(dir.join(CONFIG_FILE)).unwrap();
+    write(&dir, "profiles/server.toml", &config);
+    // Another modpack's, syncing with it would fail
+    let default_config = b"modpack_id = \"b\"\nserver_url = \"http://127.0.0.1:1/\"\n\
+        api_key = \"other\"\ninclude_globs = []\nexcludes = []\n";
+    std::fs::write(dir.join(CONFIG_FILE), default_config).unwrap();
+    let config_path = dir.join("profiles/server.toml");
+    let state_path = dir.join("profiles/server.state.toml");
+    let args = [
+        "--config",
+        config_path.to_str().unwrap(),
+        "--state",
+        state_path.to_str().unwrap(),
+    ];
+
+    sync(&dir, &args).await.unwrap();
+    assert_eq!(server.content("mods/a.jar").unwrap(), b"mod a");
+    assert!(state_path.is_file());
+    assert!(!dir.join(STATE_FILE).exists());
+    assert_eq!(
+        std::fs::read(dir.join(CONFIG_FILE)).unwrap(),
+        default_config
+    );
+
+    // The saved state is picked up again, so nothing is left to sync
+    let synced = server.state().requests.len();
+    sync(&dir, &args).await.unwrap();
+    let requests = server.state().requests[synced..].to_vec();
+    assert!(
+        !requests
+            .iter()
+            .any(|x| x.contains("filesync") || x.contains("upload")),
+        "{:?}",
+        requests
+    );
+    assert!(!dir.join(STATE_FILE).exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn declared_directories_are_synced_as_directories() {
+    let server = FakeServer::start().await;
+    let dir = sync_dir("directories", &server);
+    let mut config = std::fs::read_to_string(dir.join(CONFIG_FILE)).unwrap();
+    config.push_str("directories = [\"saves/backups/\", \"resourcepacks\"]\n");
+    std::fs::write(dir.join(CONFIG_FILE), &config).unwrap();
+    std::fs::create_dir_all(dir.join("saves/backups")).unwrap();
+    std::fs::create_dir(dir.join("resourcepacks")).unwrap();
+    write(&dir, "mods/a.jar", b"mod a");
+
+    sync(&dir, &[]).await.unwrap();
+    assert_eq!(
+        server.paths(FileState::Directory),
+        ["resourcepacks", "saves/backups"]
+    );
+    assert_eq!(server.paths(FileState::Exists), ["mods/a.jar"]);
+
+    // No longer declared, so clients can remove it
+    let config = config.replace("\"saves/backups/\", ", "");
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+    sync(&dir, &[]).await.unwrap();
+    assert_eq!(server.paths(FileState::Directory), ["resourcepacks"]);
+    assert_eq!(server.paths(FileState::Deleted), ["saves/backups"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn sync_aborts_when_the_server_moved_past_the_downloaded_state() {
+    let server = FakeServer::start().await;
+    server.state().put("mods/a.jar", b"mod a");
+    let dir = sync_dir("expect-version", &server);
+    write(&dir, "mods/a.jar", b"mod a");
+    sync(&dir, &["--download-state", "--verify"]).await.unwrap();
+    let baseline = server.state().sync_version;
+
+    // Another maintainer pushed in the meantime
+    server.state().put("mods/b.jar", b"mod b");
+    write(&dir, "mods/a.jar", b"mod a, edited");
+    let pushed = server.state().requests.len();
+    let err = sync(&dir, &[]).await.unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.contains(&format!("since version {}", baseline))
+            && message.contains("--download-state"),
+        "{}",
+        message
+    );
+    let requests = server.state().requests[pushed..].to_vec();
+    assert!(
+        !requests
+            .iter()
+            .any(|x| x.contains("filesync") || x.contains("upload")),
+        "{:?}",
+        requests
+    );
+    assert_eq!(server.content("mods/a.jar").unwrap(), b"mod a");
+
+    let current = server.state().sync_version.to_string();
+    sync(&dir, &["--expect-version", &current]).await.unwrap();
+    assert_eq!(server.content("mods/a.jar").unwrap(), b"mod a, edited");
+    // Its own push moved the baseline on
+    write(&dir, "mods/a.jar", b"mod a, edited twice");
+    sync(&dir, &[]).await.unwrap();
+    assert_eq!(
+        server.content("mods/a.jar").unwrap(),
+        b"mod a, edited twice"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn download_state_on_a_matching_directory_leaves_nothing_dirty() {
+    let server = FakeServer::start().await;
+    let first = sync_dir("download-state-first", &server);
+    let files: [(&str, &[u8]); 4] = [
+        ("mods/a.jar", b"mod a"),
+        ("mods/b.jar", b"mod b"),
+        ("mods/gone.jar", b"removed later"),
+        ("config/a.toml", b"config"),
+    ];
+    for (path, content) in files {
+        write(&first, path, content);
+    }
+    sync(&first, &[]).await.unwrap();
+    std::fs::remove_file(first.join("mods/gone.jar")).unwrap();
+    sync(&first, &[]).await.unwrap();
+    std::fs::remove_dir_all(first).unwrap();
+
+    // Another maintainer's copy of the same files, without a saved state
+    let dir = sync_dir("download-state", &server);
+    for (path, content) in files.into_iter().filter(|x| x.0 != "mods/gone.jar") {
+        write(&dir, path, content);
+    }
+    write(&dir, "mods/b.jar", b"mod b, edited");
+    let synced = server.state().requests.len();
+    sync(&dir, &["--download-state", "--verify"]).await.unwrap();
+
+    let state: SyncState =
+        toml::from_str(&std::fs::read_to_string(dir.join(STATE_FILE)).unwrap()).unwrap();
+    let mut dirty: Vec<&String> = state
+        .files
+        .iter()
+        .filter(|(_, x)| x.dirty != FileDirtyness::Clean)
+        .map(|(path, _)| path)
+        .collect();
+    dirty.sort();
+    assert!(dirty.is_empty(), "{:?}", dirty);
+    assert_eq!(state.files.len(), 4);
+    // Only the edited file was pushed
+    let requests = server.state().requests[synced..].to_vec();
+    let pushed: Vec<&String> = requests
+        .iter()
+        .filter(|x| x.contains("upload") || x.contains("filesync"))
+        .collect();
+    assert_eq!(pushed.len(), 2, "{:?}", pushed);
+    assert!(
+        pushed.iter().any(|x| x.contains("mods%2Fb.jar")),
+        "{:?}",
+        pushed
+    );
+    assert_eq!(server.content("mods/b.jar").unwrap(), b"mod b, edited");
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,42 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use modsync_core::exit::ExitError;
+
+/// Which of the modpack's files a sync looks at, from `--only`/`--exclude` or the config.
+/// Files it leaves out are not touched and keep their saved state.
+pub struct SyncFilter {
+    only: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl SyncFilter {
+    /// An empty `only` lets every file through
+    pub fn new(only: &[String], exclude: &[String]) -> anyhow::Result<Self> {
+        Ok(SyncFilter {
+            only: match only.is_empty() {
+                true => None,
+                false => Some(build_globs(only)?),
+            },
+            exclude: build_globs(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.only.as_ref().is_none_or(|x| x.is_match(path)) && !self.exclude.is_match(path)
+    }
+
+    /// Whether any file can be left out
+    pub fn is_active(&self) -> bool {
+        self.only.is_some() || !self.exclude.is_empty()
+    }
+}
+
+pub fn build_globs(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            Glob::new(pattern)
+                .map_err(|x| ExitError::Config(format!("Invalid glob {}: {}", pattern, x)))?,
+        );
+    }
+    Ok(builder.build()?)
+}
@@ -1,20 +1,43 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::Write,
+    io::{IsTerminal, Read, Write},
     path::{Path, PathBuf},
-    time::Duration,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
 
 use clap::Parser;
 use colored::Colorize;
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{error, info};
-use modsync_core::{api::ModpackResponse, FileState};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use filter::SyncFilter;
 use futures_util::StreamExt;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use manifest::{Manifest, ManifestMismatch};
+use mirrors::MirrorPool;
+use modsync_core::{
+    api::{
+        is_contained_path, parse_dir_mode, parse_mode, ModpackId, ModpackResponse,
+        FEATURE_BATCH_DOWNLOAD, FEATURE_CHANGES,
+    },
+    checksum::Checksum,
+    client::{ClientError, ModsyncApi},
+    exit::{exit_code, ExitError, EXIT_CONFIG},
+    signing::{modpack_payload, verify_payload},
+    DownloadSource, FileState, ModState,
+};
+use output::{FileAction, OutputFormat, Report};
+use pretty_env_logger::env_logger::WriteStyle;
+use serde::{Deserialize, Serialize};
+use staging::Staging;
+use walkdir::WalkDir;
+
+mod filter;
+mod manifest;
+mod mirrors;
+mod output;
+mod staging;
 
 /// Synchronize your client's mods with the server!
 #[derive(Parser)]
@@ -26,14 +49,123 @@ struct Args {
     /// Force check all mods for mismatches
     #[arg(short = 'f', long)]
     force_check: bool,
+
+    /// Move files removed on the server into the trash directory instead of deleting them
+    #[arg(long)]
+    trash: bool,
+
+    /// Trash directory, relative to the game directory
+    #[arg(long, default_value = ".modsync-trash")]
+    trash_directory: String,
+
+    /// Permanently remove trashed files older than this many days
+    #[arg(long)]
+    trash_max_age: Option<u64>,
+
+    /// List known files and their sync status, then exit without syncing
+    #[arg(short = 'l', long)]
+    list: bool,
+
+    /// Use a profile from the [profiles] section of modsync.toml
+    #[arg(short = 'p', long)]
+    profile: Option<String>,
+
+    /// Ask before downloading more than this much (e.g. 500MB, 2GB), refuse if not interactive
+    #[arg(long, value_parser = parse_size)]
+    max_download: Option<u64>,
+
+    /// Also check files whose size differs from the server's, e.g. truncated by an interrupted download
+    #[arg(long)]
+    deep_verify: bool,
+
+    /// Write the paths, hashes and sizes of synced files to this JSON file after syncing
+    #[arg(long)]
+    export_manifest: Option<PathBuf>,
+
+    /// Compare the game directory against an exported manifest, then exit without syncing
+    #[arg(long)]
+    check_manifest: Option<PathBuf>,
+
+    /// Octal mode for directories the client creates (e.g. 0700), overrides `dir_mode` in modsync.toml
+    #[arg(long, value_parser = parse_dir_mode_arg)]
+    dir_mode: Option<String>,
+
+    /// Octal mode for downloaded files without a mode from the server, overrides `file_mode`
+    #[arg(long, value_parser = parse_mode_arg)]
+    file_mode: Option<String>,
+
+    /// Download changed files to a staging directory and only apply them once all succeeded
+    #[arg(long)]
+    staged: bool,
+
+    /// Config to use instead of modsync.toml in the game directory, file states are saved to it too
+    #[arg(long, env = "MODSYNC_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Times a failed download is retried, overrides `max_retries` in modsync.toml
+    #[arg(long)]
+    max_retries: Option<u32>,
+
+    /// Files downloaded at the same time
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: u32,
+
+    /// List a modpack's files and download size, then exit without needing or writing any config
+    #[arg(long, num_args = 2, value_names = ["MODPACK_ID", "SERVER_URL"])]
+    preview: Option<Vec<String>>,
+
+    /// Read token for --preview, for servers that don't let anyone read modpacks
+    #[arg(long, env = "MODSYNC_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Don't check that the game directory's drive has room for the download before syncing
+    #[arg(long)]
+    skip_space_check: bool,
+
+    /// Report what a sync would download and delete, without changing any files or modsync.toml
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Log without colors, also done when NO_COLOR is set
+    #[arg(long)]
+    no_color: bool,
+
+    /// How to report file actions, `json` prints them to stdout for launchers and scripts
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+
+    /// Only sync files matching this glob (e.g. "config/**"), can be repeated, overrides `only` in modsync.toml
+    #[arg(long)]
+    only: Vec<String>,
+
+    /// Leave files matching this glob alone, can be repeated, overrides `exclude` in modsync.toml
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Stop syncing files matching this glob (e.g. "shaderpacks/**"), then exit without syncing
+    #[arg(long, conflicts_with = "enable")]
+    disable: Vec<String>,
+
+    /// Sync files matching this glob again, then exit without syncing
+    #[arg(long)]
+    enable: Vec<String>,
 }
 
+const CONFIG_FILE: &str = "modsync.toml";
+
+/// Staging directory for --staged, relative to the game directory
+const STAGING_DIRECTORY: &str = ".modsync-staging";
+
 #[derive(Serialize, Deserialize)]
 pub struct FileInfo {
     pub sync_version: i32,
     pub hash: Option<String>,
     pub dirty: bool,
+    /// Set by the user to stop syncing this file
     pub disable_sync: Option<bool>,
+    /// Set when the server marks this file as ignored
+    #[serde(default)]
+    pub ignored: bool,
 }
 
 impl FileInfo {
@@ -42,38 +174,124 @@ impl FileInfo {
             sync_version,
             hash,
             dirty: true,
-            disable_sync: None
+            disable_sync: None,
+            ignored: false,
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        if self.disable_sync.unwrap_or(false) {
+            "disabled"
+        } else if self.ignored {
+            "ignored"
+        } else if self.dirty {
+            "pending"
+        } else {
+            "synced"
         }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
-    pub modpack_id: String,
+    pub modpack_id: ModpackId,
     pub server_url: String,
+    /// Read token, for servers that don't let anyone read modpacks
+    pub api_key: Option<String>,
+    /// Extra servers to download files from, alongside `server_url`
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// Publisher's key from `modsync_cli keygen`, refuse to sync modpacks not signed with it
+    pub public_key: Option<String>,
+    /// Octal mode for directories the client creates, defaults to the umask
+    pub dir_mode: Option<String>,
+    /// Octal mode for downloaded files the server declares no mode for, defaults to the umask
+    pub file_mode: Option<String>,
+    /// Times a failed download is retried, with exponential backoff
+    pub max_retries: Option<u32>,
+    /// ETag of the modpack as of the last complete sync
+    pub etag: Option<String>,
+    /// Change cursor of the modpack as of the last complete sync, only changes after it get fetched
+    pub cursor: Option<i64>,
+    /// Only sync files matching one of these globs, all files if empty
+    #[serde(default)]
+    pub only: Vec<String>,
+    /// Leave files matching one of these globs alone, even if `only` matches them
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub files: HashMap<String, FileInfo>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Overrides for the top level config, each profile keeps its own file state. A profile with
+/// its own `server_url` doesn't inherit the top level `api_key` and `mirrors`, they belong to
+/// another server.
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    pub modpack_id: Option<ModpackId>,
+    pub server_url: Option<String>,
+    pub api_key: Option<String>,
+    pub mirrors: Option<Vec<String>>,
+    pub public_key: Option<String>,
+    pub etag: Option<String>,
+    pub cursor: Option<i64>,
     #[serde(default)]
     pub files: HashMap<String, FileInfo>,
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    if let Err(err) = run().await {
-        error!("{} {}", "Error:".bright_red(), err);
-    }
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    init_logging(args.no_color);
+    let mut report = Report::new(args.format);
+
+    let code = match run(&args, &mut report).await {
+        Ok(()) => {
+            report.finish(args.dry_run);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("{} {:#}", "Error:".bright_red(), err);
+            let code = error_exit_code(&err);
+            report.error(&format!("{:#}", err), code);
+            ExitCode::from(code)
+        }
+    };
 
     info!("Modsync will exit in 10 seconds...");
     tokio::time::sleep(Duration::from_secs(10)).await;
 
-    Ok(())
+    code
 }
 
-async fn run() -> anyhow::Result<()> {
+/// See `modsync_core::exit` for the meaning of each code
+fn error_exit_code(err: &anyhow::Error) -> u8 {
+    if err.chain().any(|x| x.is::<toml::de::Error>()) {
+        return EXIT_CONFIG;
+    }
+    exit_code(err.chain())
+}
+
+/// Sets up logging at `info` unless RUST_LOG says otherwise, colored unless asked not to be
+fn init_logging(no_color: bool) {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info")
     }
-    pretty_env_logger::init();
-    let args = Args::parse();
-    let target_directory = args.target_directory.unwrap_or(".".to_string());
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if no_color || std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty()) {
+        colored::control::set_override(false);
+        builder.write_style(WriteStyle::Never);
+    }
+    builder.init();
+}
+
+async fn run(args: &Args, report: &mut Report) -> anyhow::Result<()> {
+    let target_directory = args.target_directory.clone().unwrap_or(".".to_string());
     let base = Path::new(&target_directory);
 
     info!(
@@ -85,177 +303,1734 @@ async fn run() -> anyhow::Result<()> {
         .red()
     );
 
-    let config_string = tokio::fs::read_to_string(base.join("modsync.toml"))
+    if let Some(manifest_path) = &args.check_manifest {
+        return check_manifest(manifest_path, base);
+    }
+    if let Some(preview_args) = &args.preview {
+        let modpack_id = ModpackId(preview_args[0].clone());
+        preview(&modpack_id, &preview_args[1], args.api_key.as_deref()).await?;
+        return Ok(());
+    }
+
+    let config_path = args.config.clone().unwrap_or(base.join(CONFIG_FILE));
+    let config_string = tokio::fs::read_to_string(&config_path)
         .await
-        .map_err(|_| anyhow::anyhow!("No modsync.toml found!"))?;
+        .map_err(|_| ExitError::Config(format!("No {} found!", config_path.to_string_lossy())))?;
     let mut config: Config = toml::from_str(&config_string)?;
 
-    let client = Client::new();
+    let dir_mode = match args.dir_mode.as_ref().or(config.dir_mode.as_ref()) {
+        Some(mode) => Some(parse_dir_mode(mode)?),
+        None => None,
+    };
+    let file_mode = args.file_mode.clone().or(config.file_mode.clone());
+    if let Some(mode) = &file_mode {
+        parse_mode(mode)?;
+    }
+    let max_retries = args
+        .max_retries
+        .or(config.max_retries)
+        .unwrap_or(DEFAULT_MAX_RETRIES);
+    let filter = SyncFilter::new(
+        match args.only.is_empty() {
+            true => &config.only,
+            false => &args.only,
+        },
+        match args.exclude.is_empty() {
+            true => &config.exclude,
+            false => &args.exclude,
+        },
+    )?;
 
-    let modpack: ModpackResponse = client
-        .get(format!(
-            "{}/modpack/{}",
-            config.server_url, config.modpack_id
-        ))
-        .send()
-        .await?
-        .json()
-        .await?;
+    let (server_url, api_key, mirror_urls, modpack_id, public_key, etag, cursor, files) =
+        match &args.profile {
+            Some(name) => {
+                let profile = config.profiles.get_mut(name).ok_or_else(|| {
+                    ExitError::Config(format!(
+                        "No profile named {} in {}!",
+                        name,
+                        config_path.to_string_lossy()
+                    ))
+                })?;
+                let same_server = profile.server_url.is_none();
+                (
+                    profile
+                        .server_url
+                        .clone()
+                        .unwrap_or(config.server_url.clone()),
+                    profile
+                        .api_key
+                        .clone()
+                        .or(config.api_key.clone().filter(|_| same_server)),
+                    profile.mirrors.clone().unwrap_or(match same_server {
+                        true => config.mirrors.clone(),
+                        false => Vec::new(),
+                    }),
+                    profile
+                        .modpack_id
+                        .clone()
+                        .unwrap_or(config.modpack_id.clone()),
+                    profile.public_key.clone().or(config.public_key.clone()),
+                    &mut profile.etag,
+                    &mut profile.cursor,
+                    &mut profile.files,
+                )
+            }
+            None => (
+                config.server_url.clone(),
+                config.api_key.clone(),
+                config.mirrors.clone(),
+                config.modpack_id.clone(),
+                config.public_key.clone(),
+                &mut config.etag,
+                &mut config.cursor,
+                &mut config.files,
+            ),
+        };
+
+    if args.list {
+        for line in file_list(files) {
+            info!("{}", line);
+        }
+        return Ok(());
+    }
+
+    if !args.disable.is_empty() || !args.enable.is_empty() {
+        let disable = !args.disable.is_empty();
+        let patterns = match disable {
+            true => &args.disable,
+            false => &args.enable,
+        };
+        let changed = set_sync_disabled(files, patterns, disable)?;
+        let config_string = toml::to_string(&config)?;
+        tokio::fs::write(&config_path, config_string.as_bytes()).await?;
+        let verb = match disable {
+            true => "Disabled",
+            false => "Enabled",
+        };
+        info!("[{}] {} syncing of {} file(s).", "=".green(), verb, changed);
+        return Ok(());
+    }
+
+    let api = ModsyncApi::new(&server_url, api_key.as_deref())?.with_modpack(&modpack_id);
+
+    if let Some(max_age) = args.trash_max_age.filter(|_| !args.dry_run) {
+        let pruned = prune_trash(
+            &base.join(&args.trash_directory),
+            Duration::from_secs(max_age * 24 * 60 * 60),
+        )?;
+        if pruned > 0 {
+            info!(
+                "[{}] Pruned {} old file(s) from trash.",
+                "T".purple(),
+                pruned
+            );
+        }
+    }
+
+    // Anything that has to look at the files again can't skip an unchanged pack
+    let full_check = args.force_check
+        || args.deep_verify
+        || args.export_manifest.is_some()
+        || files
+            .values()
+            .any(|x| x.dirty && !x.disable_sync.unwrap_or(false));
+    // One small request settles the common case of nothing to do
+    let version = api.get_modpack_version(&modpack_id).await?;
+    if !full_check && version.as_ref().is_some_and(|x| Some(x.cursor) == *cursor) {
+        info!(
+            "[{}] Modpack is already up to date, nothing to do.",
+            "=".green()
+        );
+        if !args.dry_run {
+            remove_stale_parts(base, files.keys());
+        }
+        return Ok(());
+    }
+    let capabilities = api.capabilities().await?;
+    // Signatures cover the whole file list, so verifying one needs all of it
+    let use_changes = public_key.is_none()
+        && capabilities
+            .as_ref()
+            .is_some_and(|x| x.supports(FEATURE_CHANGES));
+    let fetched = fetch_modpack(
+        &api,
+        &modpack_id,
+        use_changes,
+        full_check,
+        *cursor,
+        etag.as_deref(),
+        version.map(|x| x.cursor),
+    )
+    .await?;
+    let Some(modpack) = fetched.modpack else {
+        info!(
+            "[{}] Modpack is unchanged since the last sync, nothing to do.",
+            "=".green()
+        );
+        if !args.dry_run {
+            remove_stale_parts(base, files.keys());
+            // Nothing changed after the saved cursor, so moving it on is safe even with a filter
+            if fetched.cursor.is_some() && fetched.cursor != *cursor {
+                *cursor = fetched.cursor;
+                let config_string = toml::to_string(&config)?;
+                tokio::fs::write(&config_path, config_string.as_bytes()).await?;
+            }
+        }
+        return Ok(());
+    };
+    let (new_etag, new_cursor) = (fetched.etag, fetched.cursor);
+    if let Some(public_key) = &public_key {
+        // Checked before touching anything, a tampered file list could point anywhere
+        verify_payload(
+            public_key,
+            modpack.modpack.signature.as_deref(),
+            &modpack_payload(&modpack_id, &modpack.files),
+        )
+        .map_err(|err| ExitError::Verify(format!("Refusing to sync: {}", err)))?;
+        info!("[{}] Modpack signature verified.", "S".green());
+    }
+    let checksum = modpack.modpack.hash_algorithm;
+
+    let mut mirror_apis = vec![api.clone()];
+    // Mirrors serve the same modpack, so they take the same read token
+    for mirror in mirror_urls.iter() {
+        mirror_apis.push(ModsyncApi::new(mirror, api_key.as_deref())?.with_modpack(&modpack_id));
+    }
+    let mirrors = MirrorPool::new(mirror_apis);
     info!(
         "{}",
-        format!(
-            "Modpack {} from {}",
-            modpack.modpack.name, config.server_url
-        )
-        .italic()
+        format!("Modpack {} from {}", modpack.modpack.name, server_url).italic()
     );
 
+    info!(
+        "{}",
+        pack_summary(&modpack, fetched.delta, files, base, args, &filter)
+    );
+
+    if !args.dry_run && (args.max_download.is_some() || !args.skip_space_check) {
+        let required = estimate_download(&modpack.files, files, base, args, &filter);
+        if let Some(max_download) = args.max_download {
+            if required > max_download {
+                confirm_download(required, max_download)?;
+            }
+        }
+        if !args.skip_space_check {
+            check_free_space(base, required)?;
+        }
+    }
+
+    let batch_download = capabilities.is_some_and(|x| x.supports(FEATURE_BATCH_DOWNLOAD));
+    // Files the batches wrote, they're up to date by the time the loop gets to them
+    let mut batched = HashSet::new();
+    let mut staging = match args.staged {
+        _ if args.dry_run => None,
+        true => Some(Staging::new(base, STAGING_DIRECTORY, dir_mode)?),
+        // Batches write straight into the game directory
+        false if batch_download => {
+            batched =
+                batch_download_new_files(&api, &modpack, files, base, args, &filter, dir_mode)
+                    .await;
+            if !batched.is_empty() {
+                info!(
+                    "[{}] Downloaded {} small file(s) in batches.",
+                    "+".green(),
+                    batched.len()
+                );
+            }
+            None
+        }
+        false => None,
+    };
+    // Modes of staged syncs are applied once the files are in place
+    let mut deferred_modes: Vec<(String, String)> = Vec::new();
+    // Same for directories, a staged file may still be in their way
+    let mut deferred_directories: Vec<String> = Vec::new();
+    // Declared directories that are gone, left in place until the staged sync is applied
+    let mut deferred_directory_removals: Vec<String> = Vec::new();
+    // Fetched concurrently once every file was looked at
+    let mut downloads: Vec<PendingDownload> = Vec::new();
+    // Deleted once the staged sync is applied
+    let mut staged_deletions: Vec<(String, Option<ModState>)> = Vec::new();
+    // Only counted for the --dry-run summary
+    let mut deletions = 0;
+    let mut new_directories = 0;
+    let mut unchanged = 0;
+    let mut filtered = 0;
+
     let mut synced_files = 0;
     for (path, sync_file) in modpack.files.iter().map(|x| (x.path.clone(), x)) {
-        if sync_file.state == FileState::Ignored {
+        if Path::new(&path).starts_with(&args.trash_directory)
+            || Path::new(&path).starts_with(STAGING_DIRECTORY)
+        {
+            // Never let the server touch our own trash or staging files
+            continue;
+        }
+        if !is_inside(base, &path) {
+            error!(
+                "[{}] Refusing to sync {}, it points outside the game directory.",
+                "!".red(),
+                path.red()
+            );
+            continue;
+        }
+        if !filter.matches(&path) {
+            // Left as it was, so a sync without the filter still picks it up
+            filtered += 1;
+            report.file(
+                &path,
+                FileAction::Skip,
+                sync_file.hash.as_deref(),
+                sync_file.mod_state,
+            );
             continue;
         }
-        if !config.files.contains_key(&path) {
-            config
-                .files
-                .insert(path.clone(), FileInfo::new(sync_file.sync_version, None));
+        if !files.contains_key(&path) {
+            files.insert(path.clone(), FileInfo::new(sync_file.sync_version, None));
+        }
+        let saved_state = files.get_mut(&path).unwrap();
+        // Remember server-ignored files so they show up in --list, but never download them
+        saved_state.ignored = sync_file.state == FileState::Ignored;
+        if saved_state.ignored {
+            saved_state.sync_version = sync_file.sync_version;
+            saved_state.dirty = false;
+            report.file(
+                &path,
+                FileAction::Skip,
+                sync_file.hash.as_deref(),
+                sync_file.mod_state,
+            );
+            continue;
         }
-        let saved_state = config.files.get_mut(&path).unwrap();
         if saved_state.disable_sync.unwrap_or(false) {
+            report.file(
+                &path,
+                FileAction::Skip,
+                sync_file.hash.as_deref(),
+                sync_file.mod_state,
+            );
             continue;
         }
         info!("Synchronizing {}...", path.blue());
+        if sync_file.state == FileState::Exists {
+            warn_long_path(base, &path);
+        }
         synced_files += 1;
-        saved_state.hash = sync_file.hash.clone();
+        if sync_file.state == FileState::Directory {
+            saved_state.hash = None;
+            if args.dry_run {
+                if !base.join(&path).is_dir() {
+                    info!(
+                        "[{}] Directory {} would be created.",
+                        "+".green(),
+                        path.green()
+                    );
+                    new_directories += 1;
+                }
+                continue;
+            }
+            if staging.is_some() {
+                deferred_directories.push(path.clone());
+            } else {
+                create_declared_directory(base, &path, args, dir_mode)?;
+            }
+            saved_state.sync_version = sync_file.sync_version;
+            saved_state.dirty = false;
+            continue;
+        }
+        if base.join(&path).is_dir() && args.dry_run {
+            if sync_file.state == FileState::Exists {
+                info!(
+                    "[{}] Directory {} would be replaced by a file.",
+                    "+".green(),
+                    path.green()
+                );
+                downloads.push(PendingDownload {
+                    path: path.clone(),
+                    hash: sync_file.hash.clone().unwrap_or_default(),
+                    target: base.join(&path),
+                    sync_version: sync_file.sync_version,
+                    mode: None,
+                    mod_state: sync_file.mod_state,
+                    source_url: modrinth_url(sync_file),
+                    size: sync_file.size.map(|x| x as u64),
+                });
+            }
+            continue;
+        }
+        if base.join(&path).is_dir() {
+            if let Some(staging) = &mut staging {
+                // A failed download must leave the game directory as it was, so the directory
+                // only makes way once the staged sync is applied
+                if sync_file.state == FileState::Exists {
+                    if std::fs::read_dir(base.join(&path))?.next().is_some() {
+                        return Err(anyhow::anyhow!(
+                            "{} is now a file, but the directory there isn't empty",
+                            path
+                        ));
+                    }
+                    info!(
+                        "[{}] Directory {} will be replaced by a file.",
+                        "+".green(),
+                        path.green()
+                    );
+                    downloads.push(PendingDownload {
+                        path: path.clone(),
+                        hash: sync_file.hash.clone().unwrap_or_default(),
+                        target: staging.stage_download(&path),
+                        sync_version: sync_file.sync_version,
+                        mode: sync_file.mode.clone().or(file_mode.clone()),
+                        mod_state: sync_file.mod_state,
+                        source_url: modrinth_url(sync_file),
+                        size: sync_file.size.map(|x| x as u64),
+                    });
+                    continue;
+                }
+                deferred_directory_removals.push(path.clone());
+                saved_state.hash = sync_file.hash.clone();
+                saved_state.sync_version = sync_file.sync_version;
+                saved_state.dirty = false;
+                continue;
+            }
+            // Was declared as a directory, only an empty one can make way
+            if let Err(err) = std::fs::remove_dir(base.join(&path)) {
+                if sync_file.state == FileState::Exists {
+                    return Err(anyhow::anyhow!(
+                        "{} is now a file, but the directory there couldn't be removed: {}",
+                        path,
+                        err
+                    ));
+                }
+                warn!("Keeping directory {}: {}", path, err);
+            } else {
+                info!("[{}] Directory {} is removed.", "-".red(), path.red());
+            }
+        }
         let server_hash = sync_file.hash.clone().unwrap_or("".to_string());
         let file = File::open(base.join(&path));
         if let Ok(mut file) = file {
+            // A stored hash can't tell that the file on disk was cut short, its size can
+            let size_mismatch = args.deep_verify
+                && sync_file.state == FileState::Exists
+                && sync_file
+                    .size
+                    .is_some_and(|size| file.metadata().map_or(true, |x| x.len() as i64 != size));
+            if size_mismatch {
+                warn!(
+                    "[{}] {} doesn't have the expected size.",
+                    "!".yellow(),
+                    path.yellow()
+                );
+            }
             if sync_file.state == FileState::Exists
                 && (saved_state.dirty
                     || sync_file.sync_version > saved_state.sync_version
-                    || args.force_check)
+                    || args.force_check
+                    || size_mismatch)
             {
                 // Verify file's hash and redownload if needed
                 info!("[{}] Checking file {}...", "*".yellow(), path.yellow());
 
                 // Hashing
-                let mut hasher = Sha256::new();
-                std::io::copy(&mut file, &mut hasher)?;
-                let hash = hasher.finalize();
-                // FIXME: doesn't sound efficient tbf
-                let hash_str = hash
-                    .into_iter()
-                    .map(|x| format!("{:02x}", x))
-                    .collect::<Vec<String>>()
-                    .join("");
+                let hash_str = checksum.hash_reader(&mut file)?;
 
                 if server_hash != hash_str {
-                    info!(
-                        "[{}] {} was updated, redownloading...",
-                        "#".yellow(),
-                        path.yellow()
-                    );
-                    download_file(&client, &config.server_url, &server_hash, &base.join(&path)).await?;
-                    info!("[{}] {} redownloaded!", "#".green(), path.green());
+                    match args.dry_run {
+                        true => info!(
+                            "[{}] {} would be redownloaded.",
+                            "#".yellow(),
+                            path.yellow()
+                        ),
+                        false => info!(
+                            "[{}] {} was updated, redownloading...",
+                            "#".yellow(),
+                            path.yellow()
+                        ),
+                    }
+                    let target = match &mut staging {
+                        Some(staging) => staging.stage_download(&path),
+                        None => base.join(&path),
+                    };
+                    downloads.push(PendingDownload {
+                        path: path.clone(),
+                        hash: server_hash,
+                        target,
+                        sync_version: sync_file.sync_version,
+                        mode: sync_file.mode.clone().or(file_mode.clone()),
+                        mod_state: sync_file.mod_state,
+                        source_url: modrinth_url(sync_file),
+                        size: sync_file.size.map(|x| x as u64),
+                    });
+                    continue;
                 }
             } else if sync_file.state == FileState::Deleted {
                 // Remove the file
-                std::fs::remove_file(base.join(&path))?;
-                info!("[{}] {} is removed.", "-".red(), path.red());
+                drop(file);
+                deletions += 1;
+                if args.dry_run {
+                    info!("[{}] {} would be removed.", "-".red(), path.red());
+                } else if let Some(staging) = &mut staging {
+                    staging.stage_deletion(&path);
+                    staged_deletions.push((path.clone(), sync_file.mod_state));
+                    info!("[{}] {} will be removed.", "-".red(), path.red());
+                } else if args.trash {
+                    move_to_trash(base, &args.trash_directory, &path)?;
+                    info!("[{}] {} is moved to trash.", "-".red(), path.red());
+                } else {
+                    std::fs::remove_file(base.join(&path))?;
+                    info!("[{}] {} is removed.", "-".red(), path.red());
+                }
+                if staging.is_none() {
+                    report.file(&path, FileAction::Delete, None, sync_file.mod_state);
+                }
             }
         } else if sync_file.state == FileState::Exists {
             // Download the file
-            info!(
-                "[{}] File {} added, downloading...",
-                "+".green(),
-                path.green()
+            match args.dry_run {
+                true => info!("[{}] {} would be downloaded.", "+".green(), path.green()),
+                false => info!(
+                    "[{}] File {} added, downloading...",
+                    "+".green(),
+                    path.green()
+                ),
+            }
+            let target = match &mut staging {
+                Some(staging) => staging.stage_download(&path),
+                None => base.join(&path),
+            };
+            downloads.push(PendingDownload {
+                path: path.clone(),
+                hash: server_hash,
+                target,
+                sync_version: sync_file.sync_version,
+                mode: sync_file.mode.clone().or(file_mode.clone()),
+                mod_state: sync_file.mod_state,
+                source_url: modrinth_url(sync_file),
+                size: sync_file.size.map(|x| x as u64),
+            });
+            continue;
+        }
+        if sync_file.state == FileState::Exists {
+            unchanged += 1;
+            let action = match batched.contains(&path) {
+                true => FileAction::Download,
+                false => FileAction::Skip,
+            };
+            report.file(
+                &path,
+                action,
+                sync_file.hash.as_deref(),
+                sync_file.mod_state,
             );
-            download_file(&client, &config.server_url, &server_hash, &base.join(&path)).await?;
-            info!("[{}] {} downloaded!", "+".green(), path.green());
         }
+        if args.dry_run {
+            continue;
+        }
+        if sync_file.state == FileState::Exists {
+            if let Some(mode) = sync_file.mode.as_ref().or(file_mode.as_ref()) {
+                if staging.is_some() {
+                    deferred_modes.push((path.clone(), mode.clone()));
+                } else if let Err(err) = apply_mode(&base.join(&path), mode) {
+                    warn!("Failed to set mode {} on {}: {}", mode, path, err);
+                }
+            }
+        }
+        saved_state.hash = sync_file.hash.clone();
         saved_state.sync_version = sync_file.sync_version;
         saved_state.dirty = false;
     }
 
+    if filtered > 0 {
+        info!(
+            "[{}] Skipped {} file(s) left out by the only/exclude globs.",
+            "F".purple(),
+            filtered
+        );
+    }
+
+    if args.dry_run {
+        let mut summary = format!(
+            "{} to download, {} to delete, {} unchanged",
+            downloads.len(),
+            deletions,
+            unchanged
+        );
+        if new_directories > 0 {
+            let noun = if new_directories == 1 {
+                "directory"
+            } else {
+                "directories"
+            };
+            summary.push_str(&format!(", {} {} to create", new_directories, noun));
+        }
+        info!(
+            "[{}] Dry run: {}. Nothing was changed.",
+            "D".purple(),
+            summary
+        );
+        for download in downloads.iter() {
+            report.file(
+                &download.path,
+                FileAction::Download,
+                Some(&download.hash),
+                download.mod_state,
+            );
+        }
+        return Ok(());
+    }
+
+    let (completed, failure) = download_all(
+        &mirrors,
+        downloads,
+        checksum,
+        dir_mode,
+        max_retries,
+        args.concurrency as usize,
+    )
+    .await;
+    if let Some(err) = failure {
+        // Staged downloads never reached the game directory, so there's nothing to record
+        if staging.is_none() {
+            finish_downloads(files, &completed, base, report);
+            let config_string = toml::to_string(&config)?;
+            tokio::fs::write(&config_path, config_string.as_bytes()).await?;
+        }
+        return Err(err);
+    }
+    if let Some(staging) = staging.filter(|x| !x.is_empty()) {
+        info!(
+            "[{}] All files downloaded, applying changes...",
+            "S".green()
+        );
+        let trash = base.join(&args.trash_directory);
+        staging.commit(args.trash.then_some(trash.as_path()))?;
+        for (path, mod_state) in staged_deletions.iter() {
+            report.file(path, FileAction::Delete, None, *mod_state);
+        }
+    }
+    finish_downloads(files, &completed, base, report);
+    remove_stale_parts(base, files.keys());
+    for path in deferred_directory_removals {
+        match std::fs::remove_dir(base.join(&path)) {
+            Ok(()) => info!("[{}] Directory {} is removed.", "-".red(), path.red()),
+            Err(err) => warn!("Keeping directory {}: {}", path, err),
+        }
+    }
+    for path in deferred_directories {
+        create_declared_directory(base, &path, args, dir_mode)?;
+    }
+    for (path, mode) in deferred_modes {
+        if let Err(err) = apply_mode(&base.join(&path), &mode) {
+            warn!("Failed to set mode {} on {}: {}", mode, path, err);
+        }
+    }
+
+    if mirrors.len() > 1 {
+        for (i, health) in mirrors.health().iter().enumerate() {
+            info!(
+                "Mirror {}: {} download(s), {} failure(s)",
+                mirrors.api(i).server_url(),
+                health.successes,
+                health.failures
+            );
+        }
+    }
+
+    if let Some(summary) = report.mod_summary() {
+        info!("[{}] Mods: {}.", "M".green(), summary);
+    }
     if synced_files == 0 {
         info!("[{}] No files required synchronization! You can force resync everything using the --force-check (-f) flag.", "W".yellow());
     }
 
+    // Filtered files may still be behind, the next sync must not take the pack as unchanged
+    if !filter.is_active() {
+        *etag = new_etag;
+        *cursor = new_cursor;
+    }
+
+    if let Some(manifest_path) = &args.export_manifest {
+        Manifest::from_state(&modpack_id, checksum, files, base).write(manifest_path)?;
+        info!("Manifest written to {}", manifest_path.to_string_lossy());
+    }
+
     let config_string = toml::to_string(&config)?;
-    tokio::fs::write(base.join("modsync.toml"), config_string.as_bytes()).await?;
+    tokio::fs::write(&config_path, config_string.as_bytes()).await?;
 
     info!("{}", "Sync complete! Have fun.".green());
 
     Ok(())
 }
 
-pub async fn download_file<'a, P>(
-    client: &Client,
-    url: &'a str,
-    hash: &'a str,
-    path: P,
-) -> anyhow::Result<()>
-where
-    P: AsRef<Path>,
-{
-    make_parent_directories(path.as_ref())?;
-    let mut file = File::create(path.as_ref())?;
+/// Sets `disable_sync` on the files matching `patterns`, returns how many changed.
+/// A plain path nothing matches gets an entry, so a file can be disabled before its first sync.
+fn set_sync_disabled(
+    files: &mut HashMap<String, FileInfo>,
+    patterns: &[String],
+    disable: bool,
+) -> anyhow::Result<usize> {
+    let mut changed = 0;
+    for pattern in patterns {
+        let globs = filter::build_globs(std::slice::from_ref(pattern))?;
+        let mut matched = false;
+        for (path, info) in files
+            .iter_mut()
+            .filter(|(path, _)| globs.is_match(path.as_str()))
+        {
+            matched = true;
+            if info.disable_sync.unwrap_or(false) == disable {
+                continue;
+            }
+            info!(
+                "{} {}",
+                if disable { "Disabling" } else { "Enabling" },
+                path.blue()
+            );
+            info.disable_sync = disable.then_some(true);
+            // Could have changed while it wasn't synced
+            info.dirty = true;
+            changed += 1;
+        }
+        if matched {
+            continue;
+        }
+        if disable && !pattern.contains(['*', '?', '[', '{']) {
+            info!("Disabling {}", pattern.blue());
+            let mut info = FileInfo::new(0, None);
+            info.disable_sync = Some(true);
+            files.insert(pattern.clone(), info);
+            changed += 1;
+        } else {
+            warn!("[{}] No known file matches {}.", "!".yellow(), pattern);
+        }
+    }
+    if !disable {
+        // Entries only made to disable a file before its first sync aren't needed anymore
+        files.retain(|_, x| x.disable_sync.is_some() || x.hash.is_some() || x.sync_version > 0);
+    }
+    Ok(changed)
+}
+
+/// Shows what a first sync of a modpack would download, read-only
+/// What `--preview` listed
+struct Preview {
+    /// Directories end with `/`
+    paths: Vec<String>,
+    /// Bytes to download, not counting files of unknown size
+    total_size: u64,
+}
+
+async fn preview(
+    modpack_id: &ModpackId,
+    server_url: &str,
+    api_key: Option<&str>,
+) -> anyhow::Result<Preview> {
+    let api = ModsyncApi::new(server_url, api_key)?;
+    let modpack = api.get_modpack(modpack_id).await?;
+    info!(
+        "{}",
+        format!("Modpack {} from {}", modpack.modpack.name, server_url).italic()
+    );
+
+    let mut server_files: Vec<&modsync_core::models::files::File> = modpack
+        .files
+        .iter()
+        .filter(|x| matches!(x.state, FileState::Exists | FileState::Directory))
+        .collect();
+    server_files.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut total = 0;
+    let mut unknown = 0;
+    for file in server_files.iter() {
+        match (file.state, file.size) {
+            (FileState::Directory, _) => info!("[{:>10}] {}/", "dir", file.path),
+            (_, Some(size)) => {
+                total += size as u64;
+                info!("[{:>10}] {}", format_size(size as u64), file.path);
+            }
+            (_, None) => {
+                unknown += 1;
+                info!("[{:>10}] {}", "?", file.path);
+            }
+        }
+    }
+    let preview = Preview {
+        paths: server_files
+            .iter()
+            .map(|x| match x.state {
+                FileState::Directory => format!("{}/", x.path),
+                _ => x.path.clone(),
+            })
+            .collect(),
+        total_size: total,
+    };
+    info!(
+        "{} file(s), {} to download",
+        preview.paths.iter().filter(|x| !x.ends_with('/')).count(),
+        format_size(preview.total_size)
+    );
+    if unknown > 0 {
+        info!(
+            "[{}] {} file(s) have an unknown size and aren't counted.",
+            "W".yellow(),
+            unknown
+        );
+    }
+    Ok(preview)
+}
+
+fn check_manifest(manifest_path: &Path, base: &Path) -> anyhow::Result<()> {
+    let manifest = Manifest::read(manifest_path)?;
+    let mismatches = manifest.check(base)?;
+    for mismatch in mismatches.iter() {
+        match mismatch {
+            ManifestMismatch::Missing(path) => info!("[{}] {} is missing", "-".red(), path.red()),
+            ManifestMismatch::Size(path) | ManifestMismatch::Hash(path) => {
+                info!("[{}] {} differs", "#".yellow(), path.yellow())
+            }
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err(ExitError::Verify(format!(
+            "{} of {} file(s) don't match the manifest",
+            mismatches.len(),
+            manifest.files.len()
+        ))
+        .into());
+    }
+    info!(
+        "{}",
+        format!("All {} file(s) match the manifest.", manifest.files.len()).green()
+    );
+    Ok(())
+}
+
+/// File list fetched for a sync
+struct FetchedModpack {
+    /// `None` if nothing changed since the last sync
+    modpack: Option<ModpackResponse>,
+    /// Whether `modpack` only holds the files changed since the last sync
+    delta: bool,
+    etag: Option<String>,
+    cursor: Option<i64>,
+}
+
+/// Fetches the files changed after `cursor` if the server can tell, otherwise the whole list
+/// unless it still matches `etag`. With `full_check` every file is fetched either way.
+async fn fetch_modpack(
+    api: &ModsyncApi,
+    modpack_id: &ModpackId,
+    use_changes: bool,
+    full_check: bool,
+    cursor: Option<i64>,
+    etag: Option<&str>,
+    version_cursor: Option<i64>,
+) -> anyhow::Result<FetchedModpack> {
+    if !use_changes {
+        let fetched = api
+            .get_modpack_if_changed(modpack_id, etag.filter(|_| !full_check))
+            .await?;
+        return Ok(match fetched {
+            Some((modpack, new_etag)) => FetchedModpack {
+                modpack: Some(modpack),
+                delta: false,
+                etag: new_etag,
+                cursor: version_cursor,
+            },
+            None => FetchedModpack {
+                modpack: None,
+                delta: false,
+                etag: etag.map(|x| x.to_string()),
+                cursor: version_cursor,
+            },
+        });
+    }
+
+    let since = cursor.filter(|_| !full_check);
+    let changes = api.get_changes(modpack_id, since).await?;
+    if changes.full && since.is_some() {
+        info!(
+            "[{}] Server no longer has the changes since the last sync, checking every file.",
+            "=".yellow()
+        );
+    } else if !changes.full {
+        info!(
+            "[{}] {} file(s) changed since the last sync.",
+            "=".green(),
+            changes.files.len()
+        );
+    }
+    let unchanged = !changes.full && changes.files.is_empty();
+    Ok(FetchedModpack {
+        modpack: (!unchanged).then_some(ModpackResponse {
+            modpack: changes.modpack,
+            files: changes.files,
+        }),
+        delta: !changes.full,
+        etag: None,
+        cursor: Some(changes.cursor),
+    })
+}
+
+/// One line on the scope of the sync, before the per-file log scrolls past
+fn pack_summary(
+    modpack: &ModpackResponse,
+    delta: bool,
+    files: &HashMap<String, FileInfo>,
+    base: &Path,
+    args: &Args,
+    filter: &SyncFilter,
+) -> String {
+    format!(
+        "Pack {}: {} files, {} need update, {} to delete",
+        modpack.modpack.name,
+        pack_file_count(&modpack.files, delta, files, base),
+        modpack
+            .files
+            .iter()
+            .filter(|x| may_need_download(x, files, base, args, filter))
+            .count(),
+        modpack
+            .files
+            .iter()
+            .filter(|x| will_delete(x, files, base, args, filter))
+            .count()
+    )
+}
 
-    let response = client
-        .get(format!("{}/dl/hash/{}", url, hash))
-        .send()
-        .await?
-        .error_for_status()?;
-    let total_size = response.content_length();
+/// Files the pack has once `server_files` are applied. A delta only lists the changed files,
+/// the others are counted from the tracked files still on disk.
+fn pack_file_count(
+    server_files: &[modsync_core::models::files::File],
+    delta: bool,
+    files: &HashMap<String, FileInfo>,
+    base: &Path,
+) -> usize {
+    let existing = server_files
+        .iter()
+        .filter(|x| x.state == FileState::Exists)
+        .count();
+    if !delta {
+        return existing;
+    }
+    let changed: HashSet<&str> = server_files.iter().map(|x| x.path.as_str()).collect();
+    let unchanged = files
+        .keys()
+        .filter(|x| !changed.contains(x.as_str()) && is_inside(base, x))
+        .filter(|x| base.join(x).is_file())
+        .count();
+    existing + unchanged
+}
+
+/// Whether the main loop may download this file, without hashing anything.
+/// Files that might have changed count, even if their content turns out to be up to date.
+fn may_need_download(
+    server_file: &modsync_core::models::files::File,
+    files: &HashMap<String, FileInfo>,
+    base: &Path,
+    args: &Args,
+    filter: &SyncFilter,
+) -> bool {
+    if server_file.state != FileState::Exists
+        || Path::new(&server_file.path).starts_with(&args.trash_directory)
+        || !filter.matches(&server_file.path)
+        || !is_inside(base, &server_file.path)
+    {
+        return false;
+    }
+    match files.get(&server_file.path) {
+        Some(saved_state) if saved_state.disable_sync.unwrap_or(false) => false,
+        Some(saved_state) => {
+            args.force_check
+                || saved_state.dirty
+                || server_file.sync_version > saved_state.sync_version
+                || !base.join(&server_file.path).exists()
+        }
+        None => true,
+    }
+}
+
+/// Whether the main loop will remove this file
+fn will_delete(
+    server_file: &modsync_core::models::files::File,
+    files: &HashMap<String, FileInfo>,
+    base: &Path,
+    args: &Args,
+    filter: &SyncFilter,
+) -> bool {
+    server_file.state == FileState::Deleted
+        && !Path::new(&server_file.path).starts_with(&args.trash_directory)
+        && filter.matches(&server_file.path)
+        && is_inside(base, &server_file.path)
+        && !files
+            .get(&server_file.path)
+            .is_some_and(|x| x.disable_sync.unwrap_or(false))
+        && base.join(&server_file.path).exists()
+}
+
+/// Upper bound on the bytes this sync will download, files that may have changed count in full.
+/// Files with an unknown size are not counted.
+fn estimate_download(
+    server_files: &[modsync_core::models::files::File],
+    files: &HashMap<String, FileInfo>,
+    base: &Path,
+    args: &Args,
+    filter: &SyncFilter,
+) -> u64 {
+    server_files
+        .iter()
+        .filter(|x| may_need_download(x, files, base, args, filter))
+        .filter_map(|x| x.size)
+        .map(|x| x as u64)
+        .sum()
+}
+
+/// Asks the user whether to go over the download limit, errors if they don't agree or can't be asked
+fn confirm_download(required: u64, max_download: u64) -> anyhow::Result<()> {
+    let message = format!(
+        "This sync may download up to {}, over the --max-download limit of {}",
+        format_size(required),
+        format_size(max_download)
+    );
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!("{}, aborting.", message));
+    }
+    warn!("[{}] {}.", "!".yellow(), message);
+    eprint!("Continue anyway? [y/N] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Err(anyhow::anyhow!("Sync aborted, nothing was downloaded."));
+    }
+    Ok(())
+}
+
+/// Errors if the drive holding `base` has less than `required` bytes free
+fn check_free_space(base: &Path, required: u64) -> anyhow::Result<()> {
+    let available = match fs2::available_space(base) {
+        Ok(available) => available,
+        Err(err) => {
+            warn!("Couldn't check free disk space, syncing anyway: {}", err);
+            return Ok(());
+        }
+    };
+    if required > available {
+        return Err(anyhow::anyhow!(
+            "Not enough disk space: this sync may download up to {} but only {} is free, {} short. \
+            Free up some space or pass --skip-space-check.",
+            format_size(required),
+            format_size(available),
+            format_size(required - available)
+        ));
+    }
+    Ok(())
+}
+
+fn parse_mode_arg(value: &str) -> Result<String, String> {
+    parse_mode(value).map_err(|err| err.to_string())?;
+    Ok(value.to_string())
+}
+
+fn parse_dir_mode_arg(value: &str) -> Result<String, String> {
+    parse_dir_mode(value).map_err(|err| err.to_string())?;
+    Ok(value.to_string())
+}
+
+/// Parses sizes like `2GB`, `500 MiB` or a plain byte count
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|x: char| !x.is_ascii_digit() && x != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {}", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1000,
+        "M" | "MB" => 1000 * 1000,
+        "G" | "GB" => 1000 * 1000 * 1000,
+        "KIB" => 1024,
+        "MIB" => 1024 * 1024,
+        "GIB" => 1024 * 1024 * 1024,
+        unit => return Err(format!("unknown size unit: {}", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1_000_000 => format!("{:.1} KB", bytes as f64 / 1000.0),
+        1_000_000..1_000_000_000 => format!("{:.1} MB", bytes as f64 / 1_000_000.0),
+        _ => format!("{:.2} GB", bytes as f64 / 1_000_000_000.0),
+    }
+}
+
+/// Files up to this size are fetched through the batch endpoint
+const BATCH_FILE_SIZE: i64 = 1_000_000;
+/// Files fetched per batch request
+const BATCH_LENGTH: usize = 50;
+
+/// Downloads small files missing locally in batches, returns the paths that were written.
+/// Failures are only logged, the files are then downloaded one by one by the main loop.
+async fn batch_download_new_files(
+    api: &ModsyncApi,
+    modpack: &ModpackResponse,
+    files: &HashMap<String, FileInfo>,
+    base: &Path,
+    args: &Args,
+    filter: &SyncFilter,
+    dir_mode: Option<u32>,
+) -> HashSet<String> {
+    let wanted: HashMap<&String, Vec<&String>> = modpack
+        .files
+        .iter()
+        .filter(|x| x.state == FileState::Exists)
+        .filter(|x| x.size.is_some_and(|x| x <= BATCH_FILE_SIZE))
+        .filter(|x| modrinth_url(x).is_none())
+        .filter(|x| !Path::new(&x.path).starts_with(&args.trash_directory))
+        .filter(|x| filter.matches(&x.path))
+        .filter(|x| is_inside(base, &x.path))
+        .filter(|x| {
+            !files
+                .get(&x.path)
+                .is_some_and(|x| x.disable_sync.unwrap_or(false))
+        })
+        .filter(|x| !base.join(&x.path).exists())
+        .filter_map(|x| x.hash.as_ref().map(|hash| (hash, &x.path)))
+        .fold(HashMap::new(), |mut wanted, (hash, path)| {
+            wanted.entry(hash).or_insert_with(Vec::new).push(path);
+            wanted
+        });
+
+    let hashes: Vec<String> = wanted.keys().map(|x| x.to_string()).collect();
+    let mut written = HashSet::new();
+    for chunk in hashes.chunks(BATCH_LENGTH) {
+        let blobs = match api
+            .download_batch(chunk, modpack.modpack.hash_algorithm)
+            .await
+        {
+            Ok(blobs) => blobs,
+            Err(err) => {
+                warn!("Batch download failed, downloading one by one: {}", err);
+                return written;
+            }
+        };
+        for (hash, data) in blobs {
+            let (Some(data), Some(paths)) = (data, wanted.get(&hash)) else {
+                continue;
+            };
+            for path in paths {
+                let full_path = base.join(path);
+                let part_path = part_path(&full_path);
+                if let Err(err) = make_parent_directories(&full_path, dir_mode)
+                    .and_then(|_| std::fs::write(&part_path, &data))
+                    .and_then(|_| std::fs::rename(&part_path, &full_path))
+                {
+                    warn!("Failed to write {}: {}", path, err);
+                    // Don't leave a partial file that looks synced
+                    let _ = std::fs::remove_file(&part_path);
+                    continue;
+                }
+                info!("[{}] {} downloaded!", "+".green(), path.green());
+                written.insert(path.to_string());
+            }
+        }
+    }
+    written
+}
+
+/// Sets the permission bits declared by the server, does nothing on Windows
+#[cfg(unix)]
+fn apply_mode(path: &Path, mode: &str) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = parse_mode(mode)?;
+    let permissions = std::fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o7777 != mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_mode(_path: &Path, _mode: &str) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Longest full path the platform reliably supports, Windows needs long paths enabled to go over it
+const MAX_LOCAL_PATH_LENGTH: usize = if cfg!(windows) { 260 } else { 4096 };
+
+/// Warns if `path` inside `base` is likely too long to be written on this platform
+fn warn_long_path(base: &Path, path: &str) {
+    let full_path = std::path::absolute(base.join(path)).unwrap_or(base.join(path));
+    let length = full_path.to_string_lossy().chars().count();
+    if length > MAX_LOCAL_PATH_LENGTH {
+        warn!(
+            "[{}] {} is {} characters long in full, over the {} supported here, writing it may fail.",
+            "!".yellow(),
+            path.yellow(),
+            length,
+            MAX_LOCAL_PATH_LENGTH
+        );
+    }
+}
+
+/// A file to fetch once every local check is done
+struct PendingDownload {
+    path: String,
+    hash: String,
+    /// Where the content goes, the game directory or the staging tree
+    target: PathBuf,
+    sync_version: i32,
+    mode: Option<String>,
+    mod_state: Option<ModState>,
+    /// Downloaded from here instead of the mirrors, for files on Modrinth
+    source_url: Option<String>,
+    /// Size of the content in bytes, if the server knows it
+    size: Option<u64>,
+}
+
+/// Where a Modrinth-backed file is downloaded from, `None` for blobs on the server
+fn modrinth_url(file: &modsync_core::models::files::File) -> Option<String> {
+    match file.download_source {
+        Some(DownloadSource::Modrinth) => file.source_url.clone(),
+        _ => None,
+    }
+}
+
+/// Downloads with up to `concurrency` in flight. After the first failure no new downloads
+/// start, the ones in flight still finish. Returns the completed downloads and the failure.
+async fn download_all(
+    mirrors: &MirrorPool,
+    downloads: Vec<PendingDownload>,
+    checksum: Checksum,
+    dir_mode: Option<u32>,
+    max_retries: u32,
+    concurrency: usize,
+) -> (Vec<PendingDownload>, Option<anyhow::Error>) {
+    let progress = MultiProgress::new();
+    let aborted = AtomicBool::new(false);
+    // For source URLs, which must not get the modsync API key
+    let http = reqwest::Client::builder()
+        .user_agent(SOURCE_USER_AGENT)
+        .build()
+        .unwrap_or_default();
+    let mut results = futures_util::stream::iter(downloads)
+        .map(|download| {
+            let progress = &progress;
+            let aborted = &aborted;
+            let http = &http;
+            async move {
+                if aborted.load(Ordering::SeqCst) {
+                    return (download, None);
+                }
+                let result = download_file(
+                    mirrors,
+                    http,
+                    &download,
+                    checksum,
+                    dir_mode,
+                    max_retries,
+                    progress,
+                )
+                .await;
+                if result.is_err() {
+                    aborted.store(true, Ordering::SeqCst);
+                }
+                (download, Some(result))
+            }
+        })
+        .buffer_unordered(concurrency);
+
+    let mut completed = Vec::new();
+    let mut failure = None;
+    while let Some((download, result)) = results.next().await {
+        match result {
+            Some(Ok(())) => {
+                info!("[{}] {} downloaded!", "+".green(), download.path.green());
+                completed.push(download);
+            }
+            Some(Err(err)) => {
+                if failure.is_none() {
+                    failure = Some(err.context(format!("Failed to download {}", download.path)));
+                } else {
+                    warn!("Failed to download {}: {}", download.path, err);
+                }
+            }
+            // Skipped after an earlier failure
+            None => {}
+        }
+    }
+    (completed, failure)
+}
+
+/// Applies modes of downloaded files and marks them synced
+fn finish_downloads(
+    files: &mut HashMap<String, FileInfo>,
+    downloads: &[PendingDownload],
+    base: &Path,
+    report: &mut Report,
+) {
+    for download in downloads {
+        report.file(
+            &download.path,
+            FileAction::Download,
+            Some(&download.hash),
+            download.mod_state,
+        );
+        if let Some(mode) = &download.mode {
+            if let Err(err) = apply_mode(&base.join(&download.path), mode) {
+                warn!("Failed to set mode {} on {}: {}", mode, download.path, err);
+            }
+        }
+        if let Some(saved_state) = files.get_mut(&download.path) {
+            saved_state.hash = Some(download.hash.clone());
+            saved_state.sync_version = download.sync_version;
+            saved_state.dirty = false;
+        }
+    }
+}
+
+/// Retries of a failed download when neither the config nor the arguments set it
+const DEFAULT_MAX_RETRIES: u32 = 3;
+/// Wait before the first retry, doubled for every further one
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Sent to Modrinth's CDN, which asks clients to identify themselves
+const SOURCE_USER_AGENT: &str = concat!("modsync_client/", env!("CARGO_PKG_VERSION"));
+
+/// Downloads a file to its target, from its source URL if it has one and otherwise trying
+/// every mirror in turn. If that fails with a transient error the download starts over,
+/// up to `max_retries` times with backoff.
+async fn download_file(
+    mirrors: &MirrorPool,
+    http: &reqwest::Client,
+    download: &PendingDownload,
+    checksum: Checksum,
+    dir_mode: Option<u32>,
+    max_retries: u32,
+    progress: &MultiProgress,
+) -> anyhow::Result<()> {
+    let hash = &download.hash;
+    let path = download.target.as_path();
+    make_parent_directories(path, dir_mode)?;
+
+    let mut attempt = 0;
+    loop {
+        let mut result = Ok(());
+        if let Some(url) = &download.source_url {
+            let origin = Origin::Url(http, url);
+            result = download_from(origin, hash, download.size, checksum, path, progress).await;
+            if let Err(err) = &result {
+                warn!("Download from {} failed: {}", url, err);
+            }
+        } else {
+            for _ in 0..mirrors.len() {
+                let mirror = mirrors.pick();
+                let started = Instant::now();
+                let origin = Origin::Server(mirrors.api(mirror));
+                result = download_from(origin, hash, download.size, checksum, path, progress).await;
+                match &result {
+                    Ok(()) => {
+                        mirrors.report_success(mirror, started);
+                        break;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Download from {} failed: {}",
+                            mirrors.api(mirror).server_url(),
+                            err
+                        );
+                        mirrors.report_failure(mirror);
+                    }
+                }
+            }
+        }
+        let Err(err) = &result else {
+            return result;
+        };
+        if attempt >= max_retries || !is_transient(err) {
+            return result;
+        }
+        attempt += 1;
+        let delay = RETRY_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(RETRY_MAX_DELAY);
+        warn!(
+            "Retrying {} in {:.1}s (attempt {} of {})",
+            path.to_string_lossy(),
+            delay.as_secs_f32(),
+            attempt,
+            max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether retrying a failed download might help: dropped connections, timeouts and
+/// server errors are, a missing blob or content not matching its hash are not
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|x| {
+        if let Some(err) = x.downcast_ref::<ClientError>() {
+            return match err {
+                ClientError::Request(_) => true,
+                ClientError::Status(status) => status.is_server_error(),
+                _ => false,
+            };
+        }
+        x.downcast_ref::<reqwest::Error>()
+            .is_some_and(|x| x.status().is_none_or(|status| status.is_server_error()))
+    })
+}
+
+/// Whether `err` is a `416 Range Not Satisfiable`, answered when resuming past the end
+fn is_unsatisfiable_range(err: &anyhow::Error) -> bool {
+    const STATUS: reqwest::StatusCode = reqwest::StatusCode::RANGE_NOT_SATISFIABLE;
+    err.chain().any(|x| {
+        matches!(
+            x.downcast_ref::<ClientError>(),
+            Some(ClientError::Status(STATUS))
+        ) || x
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|x| x.status() == Some(STATUS))
+    })
+}
+
+/// Where a file's content is downloaded from
+#[derive(Clone, Copy)]
+enum Origin<'a> {
+    /// A modsync server or mirror, by hash
+    Server(&'a ModsyncApi),
+    /// The file's own source URL, e.g. on Modrinth's CDN
+    Url(&'a reqwest::Client, &'a str),
+}
+
+impl Origin<'_> {
+    /// Starts downloading from byte `offset`. Origins ignoring the range answer with the
+    /// whole content, check for `206 Partial Content` before appending.
+    async fn download(&self, hash: &str, offset: u64) -> anyhow::Result<reqwest::Response> {
+        Ok(match self {
+            Origin::Server(api) if offset == 0 => api.download(hash).await?,
+            Origin::Server(api) => api.download_range(hash, offset).await?,
+            Origin::Url(http, url) => {
+                let mut request = http.get(*url);
+                if offset > 0 {
+                    request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+                }
+                request.send().await?.error_for_status()?
+            }
+        })
+    }
+}
+
+/// Times a download may be resumed after the connection drops mid-transfer
+const DOWNLOAD_RECONNECTS: u32 = 3;
+
+/// Suffix of files still being downloaded, an interrupted download resumes from it next time
+const PART_SUFFIX: &str = ".part";
+
+/// Where the content of `path` is written to until it's complete
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(PART_SUFFIX);
+    PathBuf::from(part_path)
+}
+
+/// Removes partial downloads of `paths`, left by syncs that didn't finish them
+fn remove_stale_parts<'a>(base: &Path, paths: impl Iterator<Item = &'a String>) {
+    for path in paths.filter(|x| is_inside(base, x)) {
+        let part_path = part_path(&base.join(path));
+        if part_path.is_file() {
+            if let Err(err) = std::fs::remove_file(&part_path) {
+                warn!("Failed to remove {}: {}", part_path.to_string_lossy(), err);
+            }
+        }
+    }
+}
+
+/// Whether `path` from the server resolves to somewhere in `base`, following symlinks of
+/// whatever part of it exists already. Anything else could have a sync write or delete files
+/// outside the game directory.
+fn is_inside(base: &Path, path: &str) -> bool {
+    if !is_contained_path(path) {
+        return false;
+    }
+    let Ok(base) = base.canonicalize() else {
+        return false;
+    };
+    let mut existing = base.join(path);
+    loop {
+        match existing.canonicalize() {
+            Ok(resolved) => return resolved.starts_with(&base),
+            Err(_) if existing.pop() => continue,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Downloads into `<path>.part`, picking up where an earlier download of it stopped,
+/// and moves it into place once the content matches `hash`
+async fn download_from(
+    origin: Origin<'_>,
+    hash: &str,
+    size: Option<u64>,
+    checksum: Checksum,
+    path: &Path,
+    progress: &MultiProgress,
+) -> anyhow::Result<()> {
+    let part_path = part_path(path);
+    let mut offset = std::fs::metadata(&part_path).map_or(0, |x| x.len());
+    // Nothing left to resume, the server would only answer the range with a 416
+    if size.is_some_and(|x| offset >= x) {
+        offset = 0;
+    }
+    let mut downloaded_hash =
+        match fetch_part(origin, hash, checksum, &part_path, offset, progress).await {
+            Err(err) if offset > 0 && is_unsatisfiable_range(&err) => None,
+            result => Some(result?),
+        };
+    if offset > 0 && downloaded_hash.as_deref() != Some(hash) {
+        // The partial file may be left over from an older version of this file
+        warn!(
+            "Couldn't resume the download of {}, downloading it again",
+            path.to_string_lossy()
+        );
+        downloaded_hash = Some(fetch_part(origin, hash, checksum, &part_path, 0, progress).await?);
+    }
+    if downloaded_hash.as_deref() != Some(hash) {
+        let _ = std::fs::remove_file(&part_path);
+        return Err(
+            ExitError::Verify(format!("downloaded content doesn't match hash {}", hash)).into(),
+        );
+    }
+
+    std::fs::rename(&part_path, path)?;
+    Ok(())
+}
+
+/// Downloads a blob into `part_path` from byte `offset` on, keeping the bytes before it.
+/// Starts over if the server doesn't answer the range. Returns the hash of the whole file.
+async fn fetch_part(
+    origin: Origin<'_>,
+    hash: &str,
+    checksum: Checksum,
+    part_path: &Path,
+    offset: u64,
+    progress: &MultiProgress,
+) -> anyhow::Result<String> {
+    let mut response = origin.download(hash, offset).await?;
+    // Appending, so resumed bytes land after the kept ones even after truncating
+    let mut file = File::options().create(true).append(true).open(part_path)?;
+    let mut hasher = checksum.hasher();
+    let mut bar_progress: u64 = 0;
+    if offset > 0 && response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        std::io::copy(&mut File::open(part_path)?.take(offset), &mut hasher)?;
+        bar_progress = offset;
+    } else {
+        file.set_len(0)?;
+    }
+    let total_size = response.content_length().map(|x| x + bar_progress);
 
     let bar = if let Some(size) = total_size {
         let bar = ProgressBar::new(size);
         bar.set_style(
-            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {bytes}/{total_bytes}")?
-                .progress_chars("#>-")
+            ProgressStyle::with_template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {bytes}/{total_bytes}",
+            )?
+            .progress_chars("#>-"),
         );
         bar
     } else {
         ProgressBar::new_spinner()
     };
+    let bar = progress.add(bar);
 
-    let mut bar_progress: u64 = 0;
     bar.set_position(bar_progress);
     bar.tick();
 
-    let mut file_stream = response
-        .bytes_stream();
+    let mut reconnects = 0;
+    loop {
+        let mut file_stream = response.bytes_stream();
+        let mut interrupted = None;
+        while let Some(chunk) = file_stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    interrupted = Some(err);
+                    break;
+                }
+            };
+            file.write_all(&chunk)?;
+            hasher.update(&chunk);
+            bar_progress += chunk.len() as u64;
+            bar.set_position(bar_progress);
+            bar.tick();
+        }
 
-    while let Some(chunk) = file_stream.next().await {
-        let chunk = chunk?;
-        file.write_all(&chunk)?;
-        bar_progress += chunk.len() as u64;
-        bar.set_position(bar_progress);
-        bar.tick();
+        let Some(err) = interrupted else {
+            break;
+        };
+        if reconnects >= DOWNLOAD_RECONNECTS {
+            return Err(err.into());
+        }
+        reconnects += 1;
+        warn!(
+            "Download interrupted after {} bytes, resuming: {}",
+            bar_progress, err
+        );
+        // Errors here, like a 404, are not worth retrying
+        response = origin.download(hash, bar_progress).await?;
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            // The server sent the whole file again
+            file.set_len(0)?;
+            hasher = checksum.hasher();
+            bar_progress = 0;
+        }
     }
 
     bar.finish();
 
-    Ok(())
+    Ok(hasher.finalize())
+}
+
+/// What `--list` prints, every known file with its sync status
+fn file_list(files: &HashMap<String, FileInfo>) -> Vec<String> {
+    let mut paths: Vec<&String> = files.keys().collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|x| format!("[{:>8}] {}", files[x].status(), x))
+        .collect()
 }
 
-pub fn make_parent_directories<P>(path: P) -> Result<(), std::io::Error>
+/// Moves `path` (relative to `base`) into the trash directory, preserving its relative path.
+pub fn move_to_trash<P>(base: &Path, trash_directory: &str, path: P) -> Result<(), std::io::Error>
 where
     P: AsRef<Path>,
 {
-    let path = path.as_ref();
-    let mut components = path.components();
-    let mut new_path_components: Vec<std::path::Component> = Vec::new();
-    loop {
-        match (components.next(), components.next()) {
-            (Some(a), Some(_)) => {
-                new_path_components.push(a);
-                let new_path = new_path_components.iter().collect::<PathBuf>();
-                if !std::fs::exists(&new_path)? {
-                    std::fs::create_dir(new_path)?;
-                }
-            }
-            _ => return Ok(()),
+    move_into_trash(
+        &base.join(path.as_ref()),
+        &base.join(trash_directory).join(path.as_ref()),
+    )
+}
+
+/// Moves `source` to `destination` inside the trash, replacing an older trashed copy
+pub fn move_into_trash(source: &Path, destination: &Path) -> Result<(), std::io::Error> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::exists(destination)? {
+        std::fs::remove_file(destination)?;
+    }
+    std::fs::rename(source, destination)?;
+    // Renaming keeps the old mtime, reset it so pruning counts from the trashing time
+    File::options()
+        .write(true)
+        .open(destination)?
+        .set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+/// Removes files in the trash directory that were trashed more than `max_age` ago.
+/// Returns the number of removed files.
+pub fn prune_trash(trash: &Path, max_age: Duration) -> Result<usize, std::io::Error> {
+    if !std::fs::exists(trash)? {
+        return Ok(0);
+    }
+    let mut pruned = 0;
+    for entry in WalkDir::new(trash)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file())
+    {
+        let age = entry
+            .metadata()?
+            .modified()?
+            .elapsed()
+            .unwrap_or(Duration::ZERO);
+        if age > max_age {
+            std::fs::remove_file(entry.path())?;
+            pruned += 1;
         }
     }
+    Ok(pruned)
 }
+
+/// Creates a directory declared by the server, replacing a file synced at its path before
+fn create_declared_directory(
+    base: &Path,
+    path: &str,
+    args: &Args,
+    dir_mode: Option<u32>,
+) -> anyhow::Result<()> {
+    let full_path = base.join(path);
+    if full_path.is_dir() {
+        return Ok(());
+    }
+    if full_path.exists() {
+        if args.trash {
+            move_to_trash(base, &args.trash_directory, path)?;
+        } else {
+            std::fs::remove_file(&full_path)?;
+        }
+        info!(
+            "[{}] {} is now a directory, removed the file.",
+            "-".red(),
+            path.red()
+        );
+    }
+    make_parent_directories(&full_path, dir_mode)?;
+    create_directory(&full_path, dir_mode)?;
+    info!("[{}] Directory {} created!", "+".green(), path.green());
+    Ok(())
+}
+
+/// Creates the missing parent directories of `path`, with `mode` on unix if given
+pub fn make_parent_directories<P>(path: P, mode: Option<u32>) -> Result<(), std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    let Some(parent) = path.as_ref().parent() else {
+        return Ok(());
+    };
+    let missing: Vec<&Path> = parent
+        .ancestors()
+        .take_while(|x| !x.as_os_str().is_empty() && !x.exists())
+        .collect();
+    for directory in missing.into_iter().rev() {
+        create_directory(directory, mode)?;
+    }
+    Ok(())
+}
+
+/// Creates a single directory with exactly `mode`, not masked by the umask
+#[cfg(unix)]
+fn create_directory(path: &Path, mode: Option<u32>) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut builder = std::fs::DirBuilder::new();
+    if let Some(mode) = mode {
+        builder.mode(mode);
+    }
+    match builder.create(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        result => result?,
+    }
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_directory(path: &Path, _mode: Option<u32>) -> Result<(), std::io::Error> {
+    match std::fs::create_dir(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,92 @@
+use std::{collections::HashMap, fs::File, path::Path};
+
+use modsync_core::{api::ModpackId, checksum::Checksum};
+use serde::{Deserialize, Serialize};
+
+use crate::FileInfo;
+
+/// Paths, hashes and sizes of a synced directory, to compare directories without a server
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub modpack_id: ModpackId,
+    /// Manifests written before the algorithm was recorded are sha256
+    #[serde(default)]
+    pub hash_algorithm: Checksum,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// How a local file differs from its manifest entry
+pub enum ManifestMismatch {
+    Missing(String),
+    Size(String),
+    Hash(String),
+}
+
+impl Manifest {
+    /// Builds a manifest out of the synced files that are present in `base`
+    pub fn from_state(
+        modpack_id: &ModpackId,
+        hash_algorithm: Checksum,
+        files: &HashMap<String, FileInfo>,
+        base: &Path,
+    ) -> Self {
+        let mut manifest_files: Vec<ManifestFile> = files
+            .iter()
+            .filter(|(_, info)| info.status() == "synced")
+            .filter_map(|(path, info)| {
+                let size = std::fs::metadata(base.join(path)).ok()?.len();
+                Some(ManifestFile {
+                    path: path.clone(),
+                    hash: info.hash.clone()?,
+                    size,
+                })
+            })
+            .collect();
+        manifest_files.sort_by(|a, b| a.path.cmp(&b.path));
+        Manifest {
+            modpack_id: modpack_id.clone(),
+            hash_algorithm,
+            files: manifest_files,
+        }
+    }
+
+    pub fn read<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn write<P>(&self, path: P) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Compares every file in the manifest against `base`, only hashing files of the right size
+    pub fn check(&self, base: &Path) -> Result<Vec<ManifestMismatch>, std::io::Error> {
+        let mut mismatches = Vec::new();
+        for file in self.files.iter() {
+            let path = base.join(&file.path);
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                mismatches.push(ManifestMismatch::Missing(file.path.clone()));
+                continue;
+            };
+            if metadata.len() != file.size {
+                mismatches.push(ManifestMismatch::Size(file.path.clone()));
+            } else if self.hash_algorithm.hash_reader(&mut File::open(&path)?)? != file.hash {
+                mismatches.push(ManifestMismatch::Hash(file.path.clone()));
+            }
+        }
+        Ok(mismatches)
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use modsync_core::client::ModsyncApi;
+
+/// Consecutive failures after which a mirror is left alone for the rest of the run
+const DEMOTE_AFTER_FAILURES: u32 = 3;
+
+#[derive(Default, Clone)]
+pub struct MirrorHealth {
+    pub picks: u32,
+    pub successes: u32,
+    pub failures: u32,
+    pub consecutive_failures: u32,
+    pub total_latency: Duration,
+}
+
+impl MirrorHealth {
+    pub fn demoted(&self) -> bool {
+        self.consecutive_failures >= DEMOTE_AFTER_FAILURES
+    }
+
+    fn average_latency(&self) -> Duration {
+        if self.successes == 0 {
+            return Duration::ZERO;
+        }
+        self.total_latency / self.successes
+    }
+
+    /// Lower is better: spreads picks over mirrors, favoring fast and reliable ones
+    fn score(&self) -> u128 {
+        let latency = self.average_latency().as_millis().max(1);
+        (self.picks as u128 + 1) * latency * (self.failures as u128 + 1)
+    }
+}
+
+/// Download servers for blobs, the main server first.
+/// Tracks per-mirror health within a single run, circuit-breaker style.
+pub struct MirrorPool {
+    mirrors: Vec<ModsyncApi>,
+    health: Mutex<Vec<MirrorHealth>>,
+}
+
+impl MirrorPool {
+    pub fn new(mirrors: Vec<ModsyncApi>) -> Self {
+        let health = vec![MirrorHealth::default(); mirrors.len()];
+        MirrorPool {
+            mirrors,
+            health: Mutex::new(health),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mirrors.len()
+    }
+
+    pub fn api(&self, mirror: usize) -> &ModsyncApi {
+        &self.mirrors[mirror]
+    }
+
+    /// Picks the healthiest mirror, falling back to demoted ones only if nothing else is left
+    pub fn pick(&self) -> usize {
+        let mut health = self.health.lock().unwrap();
+        let all_demoted = health.iter().all(|x| x.demoted());
+        let mirror = health
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| all_demoted || !x.demoted())
+            .min_by_key(|(_, x)| x.score())
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        health[mirror].picks += 1;
+        mirror
+    }
+
+    pub fn report_success(&self, mirror: usize, started: Instant) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[mirror];
+        health.successes += 1;
+        health.consecutive_failures = 0;
+        health.total_latency += started.elapsed();
+    }
+
+    pub fn report_failure(&self, mirror: usize) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[mirror];
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        if health.consecutive_failures == DEMOTE_AFTER_FAILURES {
+            warn!(
+                "Mirror {} failed {} times in a row, avoiding it from now on",
+                self.mirrors[mirror].server_url(),
+                DEMOTE_AFTER_FAILURES
+            );
+        }
+    }
+
+    pub fn health(&self) -> Vec<MirrorHealth> {
+        self.health.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(count: usize) -> MirrorPool {
+        let mirrors = (0..count)
+            .map(|x| ModsyncApi::new(&format!("http://mirror-{}/", x), None).unwrap())
+            .collect();
+        MirrorPool::new(mirrors)
+    }
+
+    #[test]
+    fn failing_mirror_is_demoted() {
+        let pool = pool(2);
+        for _ in 0..DEMOTE_AFTER_FAILURES {
+            pool.report_failure(1);
+        }
+        assert!(pool.health()[1].demoted());
+        for _ in 0..10 {
+            let mirror = pool.pick();
+            assert_eq!(mirror, 0);
+            pool.report_success(mirror, Instant::now());
+        }
+        assert_eq!(pool.health()[0].successes, 10);
+    }
+
+    #[test]
+    fn success_clears_the_failure_streak() {
+        let pool = pool(1);
+        for _ in 0..DEMOTE_AFTER_FAILURES - 1 {
+            pool.report_failure(0);
+        }
+        pool.report_success(0, Instant::now());
+        pool.report_failure(0);
+        let health = &pool.health()[0];
+        assert!(!health.demoted());
+        assert_eq!(health.failures, DEMOTE_AFTER_FAILURES);
+    }
+
+    #[test]
+    fn demoted_mirrors_are_still_tried_when_nothing_else_is_left() {
+        let pool = pool(2);
+        for mirror in [0, 1] {
+            for _ in 0..DEMOTE_AFTER_FAILURES {
+                pool.report_failure(mirror);
+            }
+        }
+        let picked = [pool.pick(), pool.pick()];
+        assert!(picked.contains(&0) && picked.contains(&1), "{:?}", picked);
+    }
+}
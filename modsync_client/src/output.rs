@@ -0,0 +1,140 @@
+use std::time::Instant;
+
+use clap::ValueEnum;
+use modsync_core::ModState;
+use serde::Serialize;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Colored log lines only
+    Human,
+    /// One JSON object per line on stdout for every file action and a summary, logs stay on stderr
+    Json,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileAction {
+    Download,
+    Delete,
+    Skip,
+}
+
+#[derive(Serialize)]
+struct FileEvent<'a> {
+    path: &'a str,
+    action: FileAction,
+    hash: Option<&'a str>,
+    mod_state: Option<ModState>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    downloaded: usize,
+    deleted: usize,
+    skipped: usize,
+    mods_added: usize,
+    mods_updated: usize,
+    mods_removed: usize,
+    dry_run: bool,
+    elapsed_secs: f64,
+}
+
+#[derive(Serialize)]
+struct Failure<'a> {
+    error: &'a str,
+    exit_code: u8,
+}
+
+/// Counts what a sync did to each file, and prints it for scripts with `--format json`
+pub struct Report {
+    format: OutputFormat,
+    started: Instant,
+    downloaded: usize,
+    deleted: usize,
+    skipped: usize,
+    mods_added: usize,
+    mods_updated: usize,
+    mods_removed: usize,
+}
+
+impl Report {
+    pub fn new(format: OutputFormat) -> Self {
+        Report {
+            format,
+            started: Instant::now(),
+            downloaded: 0,
+            deleted: 0,
+            skipped: 0,
+            mods_added: 0,
+            mods_updated: 0,
+            mods_removed: 0,
+        }
+    }
+
+    /// `mod_state` is what the server says the last push did to the file, files pushed by
+    /// older clis don't have one and only count as file changes
+    pub fn file(
+        &mut self,
+        path: &str,
+        action: FileAction,
+        hash: Option<&str>,
+        mod_state: Option<ModState>,
+    ) {
+        match action {
+            FileAction::Download => self.downloaded += 1,
+            FileAction::Delete => self.deleted += 1,
+            FileAction::Skip => self.skipped += 1,
+        }
+        match (action, mod_state) {
+            (FileAction::Download, Some(ModState::Created)) => self.mods_added += 1,
+            (FileAction::Download, Some(ModState::Updated)) => self.mods_updated += 1,
+            (FileAction::Delete, _) => self.mods_removed += 1,
+            _ => {}
+        }
+        self.emit(&FileEvent {
+            path,
+            action,
+            hash,
+            mod_state,
+        });
+    }
+
+    /// e.g. `2 added, 1 updated`, `None` if no mod changed
+    pub fn mod_summary(&self) -> Option<String> {
+        let parts: Vec<String> = [
+            (self.mods_added, "added"),
+            (self.mods_updated, "updated"),
+            (self.mods_removed, "removed"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, change)| format!("{} {}", count, change))
+        .collect();
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+
+    pub fn finish(&self, dry_run: bool) {
+        self.emit(&Summary {
+            downloaded: self.downloaded,
+            deleted: self.deleted,
+            skipped: self.skipped,
+            mods_added: self.mods_added,
+            mods_updated: self.mods_updated,
+            mods_removed: self.mods_removed,
+            dry_run,
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        });
+    }
+
+    pub fn error(&self, error: &str, exit_code: u8) {
+        self.emit(&Failure { error, exit_code });
+    }
+
+    fn emit<T: Serialize>(&self, event: &T) {
+        if self.format == OutputFormat::Json {
+            // Serializing these plain structs can't fail
+            println!("{}", serde_json::to_string(event).unwrap());
+        }
+    }
+}
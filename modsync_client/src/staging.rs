@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use modsync_core::exit::ExitError;
+
+use crate::{make_parent_directories, move_into_trash};
+
+/// Changed files are downloaded here before being swapped into the game directory
+const FILES_DIRECTORY: &str = "files";
+/// Live files replaced or deleted by the swap, kept until it completes
+const BACKUP_DIRECTORY: &str = "backup";
+
+/// Downloads into a staging tree and applies them to the game directory all at once
+pub struct Staging {
+    base: PathBuf,
+    directory: PathBuf,
+    dir_mode: Option<u32>,
+    downloads: Vec<String>,
+    deletions: Vec<String>,
+}
+
+/// A step of the swap, undone in reverse order on failure
+enum Applied {
+    BackedUp(String),
+    Placed(String),
+}
+
+impl Staging {
+    /// Prepares an empty staging tree at `directory`, relative to `base`
+    pub fn new(base: &Path, directory: &str, dir_mode: Option<u32>) -> anyhow::Result<Self> {
+        let directory = base.join(directory);
+        if directory.join(BACKUP_DIRECTORY).exists() {
+            return Err(anyhow::anyhow!(
+                "A previous staged sync was interrupted while applying, restore the files in {} first",
+                directory.join(BACKUP_DIRECTORY).to_string_lossy()
+            ));
+        }
+        if directory.exists() {
+            std::fs::remove_dir_all(&directory)?;
+        }
+        Ok(Staging {
+            base: base.to_path_buf(),
+            directory,
+            dir_mode,
+            downloads: Vec::new(),
+            deletions: Vec::new(),
+        })
+    }
+
+    /// Where the new content of `path` is downloaded to, the download is applied on commit
+    pub fn stage_download(&mut self, path: &str) -> PathBuf {
+        self.downloads.push(path.to_string());
+        self.directory.join(FILES_DIRECTORY).join(path)
+    }
+
+    pub fn stage_deletion(&mut self, path: &str) {
+        self.deletions.push(path.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.downloads.is_empty() && self.deletions.is_empty()
+    }
+
+    /// Moves the staged files into place and removes deleted ones, restoring the previous
+    /// state of the game directory if any step fails. Deleted files go to `trash` if given.
+    pub fn commit(self, trash: Option<&Path>) -> anyhow::Result<()> {
+        let mut applied = Vec::new();
+        if let Err(err) = self.apply(&mut applied) {
+            let mut restored = true;
+            for step in applied.iter().rev() {
+                if let Err(rollback_err) = self.undo(step) {
+                    warn!("Failed to roll back staged sync: {}", rollback_err);
+                    restored = false;
+                }
+            }
+            if !restored {
+                // Keep the backup around, the next staged sync refuses to run until it's dealt with
+                return Err(ExitError::Partial {
+                    message: format!(
+                        "Applying staged files failed and some files couldn't be restored from {}",
+                        self.directory.join(BACKUP_DIRECTORY).to_string_lossy()
+                    ),
+                    source: Box::new(err),
+                }
+                .into());
+            }
+            let _ = std::fs::remove_dir_all(&self.directory);
+            return Err(anyhow::anyhow!(
+                "Applying staged files failed, the game directory was restored: {}",
+                err
+            ));
+        }
+
+        for path in self.deletions.iter() {
+            let backup = self.directory.join(BACKUP_DIRECTORY).join(path);
+            let result = match trash {
+                Some(trash) => move_into_trash(&backup, &trash.join(path)),
+                None => std::fs::remove_file(&backup),
+            };
+            if let Err(err) = result {
+                warn!("Failed to remove {}: {}", backup.to_string_lossy(), err);
+            }
+        }
+        std::fs::remove_dir_all(&self.directory)?;
+        Ok(())
+    }
+
+    fn apply(&self, applied: &mut Vec<Applied>) -> std::io::Result<()> {
+        for path in self.downloads.iter() {
+            if self.base.join(path).exists() {
+                self.back_up(path)?;
+                applied.push(Applied::BackedUp(path.clone()));
+            }
+            let live = self.base.join(path);
+            make_parent_directories(&live, self.dir_mode)?;
+            std::fs::rename(self.directory.join(FILES_DIRECTORY).join(path), &live)?;
+            applied.push(Applied::Placed(path.clone()));
+        }
+        for path in self.deletions.iter() {
+            self.back_up(path)?;
+            applied.push(Applied::BackedUp(path.clone()));
+        }
+        Ok(())
+    }
+
+    fn back_up(&self, path: &str) -> std::io::Result<()> {
+        let backup = self.directory.join(BACKUP_DIRECTORY).join(path);
+        make_parent_directories(&backup, None)?;
+        std::fs::rename(self.base.join(path), backup)
+    }
+
+    fn undo(&self, step: &Applied) -> std::io::Result<()> {
+        match step {
+            Applied::BackedUp(path) => std::fs::rename(
+                self.directory.join(BACKUP_DIRECTORY).join(path),
+                self.base.join(path),
+            ),
+            Applied::Placed(path) => std::fs::remove_file(self.base.join(path)),
+        }
+    }
+}
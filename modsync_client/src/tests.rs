@@ -0,0 +1,1032 @@
+use std::sync::{Arc, Mutex};
+
+use axum::response::IntoResponse;
+use indicatif::ProgressDrawTarget;
+use modsync_core::exit::{EXIT_AUTH, EXIT_FAILURE, EXIT_NETWORK};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use super::*;
+
+const CONTENT: &[u8] = b"the whole content of the file";
+
+/// Answers a request for a path and query, given the offset of its `Range` header
+type Handler = fn(&str, Option<u64>) -> (&'static str, Vec<u8>);
+
+/// Serves `handler` over plain HTTP/1.1. Returns its URL and every request it got.
+async fn serve(handler: Handler) -> (String, Arc<Mutex<Vec<(String, Option<u64>)>>>) {
+    serve_interrupted(handler, None).await
+}
+
+/// Like `serve`, but drops the connection after sending the first `cut` bytes of the first body
+async fn serve_interrupted(
+    handler: Handler,
+    mut cut: Option<usize>,
+) -> (String, Arc<Mutex<Vec<(String, Option<u64>)>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = socket.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let path = request.split_whitespace().nth(1).unwrap().to_string();
+            let offset = request.lines().find_map(|x| {
+                let (name, value) = x.split_once(':')?;
+                name.eq_ignore_ascii_case("range").then(|| {
+                    let value = value.trim().trim_start_matches("bytes=");
+                    value.trim_end_matches('-').parse::<u64>().unwrap()
+                })
+            });
+            let (status, body) = handler(&path, offset);
+            seen.lock().unwrap().push((path, offset));
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            let sent = cut.take().map_or(body.len(), |x| x.min(body.len()));
+            socket.write_all(&body[..sent]).await.unwrap();
+        }
+    });
+    (url, requests)
+}
+
+/// Serves `CONTENT`, honouring `Range: bytes=<offset>-` like a modsync server would
+fn content(_: &str, offset: Option<u64>) -> (&'static str, Vec<u8>) {
+    match offset {
+        None => ("200 OK", CONTENT.to_vec()),
+        Some(x) if x < CONTENT.len() as u64 => {
+            ("206 Partial Content", CONTENT[x as usize..].to_vec())
+        }
+        Some(_) => ("416 Range Not Satisfiable", Vec::new()),
+    }
+}
+
+/// Offsets the content was requested from, `None` for the whole of it
+fn ranges(requests: &Mutex<Vec<(String, Option<u64>)>>) -> Vec<Option<u64>> {
+    requests.lock().unwrap().iter().map(|x| x.1).collect()
+}
+
+/// A fresh directory to download into
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("modsync-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+async fn download(url: &str, path: &Path, size: Option<u64>) -> anyhow::Result<()> {
+    let http = reqwest::Client::new();
+    let hash = Checksum::Sha256.hash_bytes(CONTENT);
+    let progress = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    let origin = Origin::Url(&http, url);
+    download_from(origin, &hash, size, Checksum::Sha256, path, &progress).await
+}
+
+#[tokio::test]
+async fn part_as_large_as_the_file_is_refetched_from_the_start() {
+    let (url, requests) = serve(content).await;
+    let dir = temp_dir("oversized-part");
+    let path = dir.join("mod.jar");
+    std::fs::write(part_path(&path), [b'x'; 64]).unwrap();
+
+    download(&format!("{}file", url), &path, Some(CONTENT.len() as u64))
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+    assert!(!part_path(&path).exists());
+    assert_eq!(ranges(&requests), [None]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn unsatisfiable_range_is_refetched_from_the_start() {
+    let (url, requests) = serve(content).await;
+    let dir = temp_dir("unsatisfiable-range");
+    let path = dir.join("mod.jar");
+    std::fs::write(part_path(&path), CONTENT).unwrap();
+
+    // Without a known size, only the server can tell the part can't be resumed
+    download(&format!("{}file", url), &path, None)
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+    assert!(!part_path(&path).exists());
+    let resumed_from = CONTENT.len() as u64;
+    assert_eq!(ranges(&requests), [Some(resumed_from), None]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn partial_download_is_resumed() {
+    let (url, requests) = serve(content).await;
+    let dir = temp_dir("resumed");
+    let path = dir.join("mod.jar");
+    std::fs::write(part_path(&path), &CONTENT[..10]).unwrap();
+
+    download(&format!("{}file", url), &path, Some(CONTENT.len() as u64))
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+    assert_eq!(ranges(&requests), [Some(10)]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn download_dropped_midway_resumes_from_the_last_byte() {
+    let (url, requests) = serve_interrupted(content, Some(12)).await;
+    let dir = temp_dir("reconnected");
+    let path = dir.join("mod.jar");
+
+    download(&format!("{}file", url), &path, Some(CONTENT.len() as u64))
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+    assert!(!part_path(&path).exists());
+    assert_eq!(ranges(&requests), [None, Some(12)]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn stale_part_failing_the_hash_is_refetched_from_the_start() {
+    let (url, requests) = serve(content).await;
+    let dir = temp_dir("stale-part");
+    let path = dir.join("mod.jar");
+    // Left over from an older version of the file, the resumed bytes don't add up to the hash
+    std::fs::write(part_path(&path), b"an old ver").unwrap();
+
+    download(&format!("{}file", url), &path, Some(CONTENT.len() as u64))
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+    assert_eq!(ranges(&requests), [Some(10), None]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Serves content that doesn't match the hash the client expects
+fn corrupted(path: &str, offset: Option<u64>) -> (&'static str, Vec<u8>) {
+    let (status, mut body) = content(path, offset);
+    if let Some(last) = body.last_mut() {
+        *last ^= 1;
+    }
+    (status, body)
+}
+
+#[tokio::test]
+async fn content_not_matching_the_hash_is_a_verify_error() {
+    let (url, _) = serve(corrupted).await;
+    let dir = temp_dir("corrupted");
+    let path = dir.join("mod.jar");
+    std::fs::write(part_path(&path), &CONTENT[..10]).unwrap();
+
+    let err = download(&format!("{}file", url), &path, Some(CONTENT.len() as u64))
+        .await
+        .unwrap_err();
+
+    assert!(
+        matches!(err.downcast_ref(), Some(ExitError::Verify(_))),
+        "{:?}",
+        err
+    );
+    assert!(!path.exists());
+    assert!(!part_path(&path).exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+fn server_file(path: &str, state: &str) -> serde_json::Value {
+    serde_json::json!({
+        "id": path,
+        "modpack": "a",
+        "created_at": "2026-01-01T00:00:00Z",
+        "updated_at": "2026-01-01T00:00:00Z",
+        "path": path,
+        "state": state,
+        "sync_version": 1,
+        "hash": null,
+        "uploaded": true,
+    })
+}
+
+/// Changes of a modpack at cursor 9 whose change history only reaches back to cursor 5.
+/// Nothing changed after cursor 8.
+fn changes(path: &str, _: Option<u64>) -> (&'static str, Vec<u8>) {
+    let since = path
+        .split_once("since=")
+        .map(|(_, x)| x.parse::<i64>().unwrap());
+    let (full, files) = match since {
+        None | Some(..5) => (
+            true,
+            vec![
+                server_file("a.jar", "Exists"),
+                server_file("b.jar", "Exists"),
+            ],
+        ),
+        Some(8) => (false, vec![]),
+        Some(_) => (false, vec![server_file("b.jar", "Exists")]),
+    };
+    let body = serde_json::json!({
+        "modpack": {
+            "id": "a",
+            "name": "Pack",
+            "modloader": null,
+            "modloader_version": null,
+            "game_version": null,
+            "sync_version": 1,
+        },
+        "cursor": 9,
+        "full": full,
+        "files": files,
+    });
+    ("200 OK", body.to_string().into_bytes())
+}
+
+async fn fetch_changes(url: &str, cursor: Option<i64>) -> FetchedModpack {
+    let api = ModsyncApi::new(url, None).unwrap();
+    let modpack_id = ModpackId("a".to_string());
+    fetch_modpack(&api, &modpack_id, true, false, cursor, None, Some(9))
+        .await
+        .unwrap()
+}
+
+fn paths(fetched: &FetchedModpack) -> Vec<&str> {
+    let modpack = fetched.modpack.as_ref().unwrap();
+    modpack.files.iter().map(|x| x.path.as_str()).collect()
+}
+
+#[tokio::test]
+async fn too_old_cursor_resyncs_every_file() {
+    let (url, requests) = serve(changes).await;
+
+    let fetched = fetch_changes(&url, Some(3)).await;
+
+    assert_eq!(requests.lock().unwrap()[0].0, "/modpack/a/changes?since=3");
+    assert!(!fetched.delta);
+    assert_eq!(paths(&fetched), ["a.jar", "b.jar"]);
+    assert_eq!(fetched.cursor, Some(9));
+}
+
+#[tokio::test]
+async fn recent_cursor_fetches_only_the_changes() {
+    let (url, _) = serve(changes).await;
+
+    let fetched = fetch_changes(&url, Some(6)).await;
+
+    assert!(fetched.delta);
+    assert_eq!(paths(&fetched), ["b.jar"]);
+    assert_eq!(fetched.cursor, Some(9));
+}
+
+#[tokio::test]
+async fn empty_delta_still_moves_the_cursor() {
+    let (url, _) = serve(changes).await;
+
+    let fetched = fetch_changes(&url, Some(8)).await;
+
+    assert!(fetched.modpack.is_none());
+    assert_eq!(fetched.cursor, Some(9));
+}
+
+#[test]
+fn delta_counts_the_tracked_files_it_leaves_alone() {
+    let dir = temp_dir("file-count");
+    for path in ["a.jar", "b.jar", "d.jar"] {
+        std::fs::write(dir.join(path), b"").unwrap();
+    }
+    std::fs::create_dir(dir.join("config")).unwrap();
+    let files: HashMap<String, FileInfo> = ["a.jar", "b.jar", "d.jar", "gone.jar", "config"]
+        .into_iter()
+        .map(|x| (x.to_string(), FileInfo::new(1, None)))
+        .collect();
+    let delta: Vec<modsync_core::models::files::File> = [
+        server_file("c.jar", "Exists"),
+        server_file("d.jar", "Deleted"),
+    ]
+    .into_iter()
+    .map(|x| serde_json::from_value(x).unwrap())
+    .collect();
+
+    // a.jar and b.jar were left alone, c.jar is new and d.jar is on its way out
+    assert_eq!(pack_file_count(&delta, true, &files, &dir), 3);
+    assert_eq!(pack_file_count(&delta, false, &files, &dir), 1);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn summary_counts_pending_updates_and_deletions() {
+    let dir = temp_dir("summary");
+    for path in ["a.jar", "b.jar", "d.jar", "f.jar"] {
+        std::fs::write(dir.join(path), b"").unwrap();
+    }
+    let mut files: HashMap<String, FileInfo> = ["a.jar", "b.jar", "d.jar", "f.jar"]
+        .into_iter()
+        .map(|x| (x.to_string(), FileInfo::new(1, None)))
+        .collect();
+    for file in files.values_mut() {
+        file.dirty = false;
+    }
+    files.get_mut("f.jar").unwrap().disable_sync = Some(true);
+    let updated = |path| {
+        let mut file = server_file(path, "Exists");
+        file["sync_version"] = 2.into();
+        file
+    };
+    let modpack: ModpackResponse = serde_json::from_value(serde_json::json!({
+        "modpack": FakeState::default().modpack("a"),
+        "files": [
+            server_file("a.jar", "Exists"),
+            updated("b.jar"),
+            server_file("c.jar", "Exists"),
+            server_file("d.jar", "Deleted"),
+            server_file("e.jar", "Deleted"),
+            updated("f.jar"),
+        ],
+    }))
+    .unwrap();
+    let args = Args::parse_from(["modsync_client", dir.to_str().unwrap()]);
+    let filter = SyncFilter::new(&[], &[]).unwrap();
+
+    // b.jar changed and c.jar is new, d.jar is still there but e.jar is already gone,
+    // and f.jar is left alone
+    assert_eq!(
+        pack_summary(&modpack, false, &files, &dir, &args, &filter),
+        "Pack Pack: 4 files, 2 need update, 1 to delete"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Answers a request before the fake server's routes do, `None` lets them answer
+type Intercept =
+    Box<dyn FnMut(&axum::http::Method, &str) -> Option<axum::response::Response> + Send>;
+
+/// What the fake server holds and saw, tests change it between syncs
+#[derive(Default)]
+struct FakeState {
+    files: Vec<serde_json::Value>,
+    blobs: HashMap<String, Vec<u8>>,
+    cursor: i64,
+    /// Features listed in `/capabilities`, which is missing like on old servers when empty
+    features: Vec<&'static str>,
+    /// Read token every request needs, if any
+    api_key: Option<String>,
+    /// Method, path with query and `Authorization` header of every request
+    requests: Vec<(String, String, Option<String>)>,
+    intercept: Option<Intercept>,
+}
+
+/// A modsync server holding one modpack, to run whole syncs against
+#[derive(Clone)]
+struct FakeServer {
+    url: String,
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeServer {
+    async fn start() -> Self {
+        let state = Arc::new(Mutex::new(FakeState::default()));
+        let app = axum::Router::new()
+            .fallback(fake_route)
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        FakeServer { url, state }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Adds or replaces a file with `content`, moving the modpack's cursor on
+    fn put_file(&self, path: &str, content: &[u8]) -> String {
+        let hash = Checksum::Sha256.hash_bytes(content);
+        let mut file = server_file(path, "Exists");
+        file["hash"] = hash.clone().into();
+        file["size"] = content.len().into();
+        let mut state = self.state();
+        state.blobs.insert(hash.clone(), content.to_vec());
+        state.put(file);
+        hash
+    }
+
+    /// Paths of the requests so far, with their query
+    fn paths(&self) -> Vec<String> {
+        self.state().requests.iter().map(|x| x.1.clone()).collect()
+    }
+}
+
+impl FakeState {
+    fn put(&mut self, mut file: serde_json::Value) {
+        self.cursor += 1;
+        let path = file["path"].as_str().unwrap().to_string();
+        let previous = self.files.iter().position(|x| x["path"] == path.as_str());
+        let version = previous.map_or(0, |x| self.files[x]["sync_version"].as_i64().unwrap() + 1);
+        file["sync_version"] = version.into();
+        match previous {
+            Some(i) => self.files[i] = file,
+            None => self.files.push(file),
+        }
+    }
+
+    fn modpack(&self, id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "name": "Pack",
+            "modloader": null,
+            "modloader_version": null,
+            "game_version": null,
+            "sync_version": self.cursor,
+        })
+    }
+}
+
+async fn fake_route(
+    axum::extract::State(state): axum::extract::State<Arc<Mutex<FakeState>>>,
+    request: axum::extract::Request,
+) -> axum::response::Response {
+    use axum::{
+        http::{header, StatusCode},
+        Json,
+    };
+
+    let mut state = state.lock().unwrap();
+    let method = request.method().clone();
+    let uri = request.uri().to_string();
+    let header = |name| {
+        let value = request.headers().get(name)?;
+        Some(value.to_str().unwrap().to_string())
+    };
+    let authorization = header(header::AUTHORIZATION);
+    state
+        .requests
+        .push((method.to_string(), uri.clone(), authorization.clone()));
+    if let Some(response) = state.intercept.as_mut().and_then(|x| x(&method, &uri)) {
+        return response;
+    }
+    if let Some(key) = &state.api_key {
+        if authorization != Some(format!("Bearer {}", key)) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    let path = request.uri().path();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments[..] {
+        ["capabilities"] if !state.features.is_empty() => Json(serde_json::json!({
+            "protocol_version": 1,
+            "version": "0.0.0",
+            "features": state.features,
+            "hash_algorithms": ["sha256"],
+            "chunked_upload": false,
+            "max_upload_size": 0,
+            "max_json_body_size": 0,
+            "max_path_length": 255,
+            "max_path_components": 32,
+            "batch_download_max_hashes": 256,
+        }))
+        .into_response(),
+        ["modpack", _, "version"] => Json(serde_json::json!({
+            "sync_version": state.cursor,
+            "cursor": state.cursor,
+        }))
+        .into_response(),
+        ["modpack", id, "changes"] => Json(serde_json::json!({
+            "modpack": state.modpack(id),
+            "cursor": state.cursor,
+            "full": true,
+            "files": state.files,
+        }))
+        .into_response(),
+        ["modpack", id] => {
+            let etag = format!("\"{}\"", state.cursor);
+            if header(header::IF_NONE_MATCH) == Some(etag.clone()) {
+                return StatusCode::NOT_MODIFIED.into_response();
+            }
+            let body = Json(serde_json::json!({
+                "modpack": state.modpack(id),
+                "files": state.files,
+            }));
+            ([(header::ETAG, etag)], body).into_response()
+        }
+        ["dl", "hash", hash] => {
+            let Some(blob) = state.blobs.get(hash) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let offset = header(header::RANGE).map(|x| {
+                let x = x.trim_start_matches("bytes=").trim_end_matches('-');
+                x.parse::<usize>().unwrap()
+            });
+            match offset {
+                None => blob.clone().into_response(),
+                Some(x) if x < blob.len() => {
+                    (StatusCode::PARTIAL_CONTENT, blob[x..].to_vec()).into_response()
+                }
+                Some(_) => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+            }
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Runs a whole sync of `dir` like the command line would, with `args` after the directory
+async fn sync(dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+    let mut all = vec![
+        "modsync_client",
+        dir.to_str().unwrap(),
+        "--skip-space-check",
+    ];
+    all.extend_from_slice(args);
+    run(
+        &Args::parse_from(all),
+        &mut Report::new(OutputFormat::Human),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn each_profile_syncs_from_its_own_server_with_its_own_key() {
+    let main = FakeServer::start().await;
+    let mirror = FakeServer::start().await;
+    let other = FakeServer::start().await;
+    main.put_file("main.jar", b"main content");
+    mirror.put_file("main.jar", b"main content");
+    other.put_file("other.jar", b"other content");
+    main.state().api_key = Some("main-key".to_string());
+    mirror.state().api_key = Some("main-key".to_string());
+    other.state().api_key = Some("other-key".to_string());
+    // Only the mirror can serve the content
+    main.state().intercept = Some(Box::new(|_, uri| {
+        uri.starts_with("/dl/")
+            .then(|| axum::http::StatusCode::BAD_GATEWAY.into_response())
+    }));
+    let dir = temp_dir("profiles");
+    let config = format!(
+        r#"
+modpack_id = "a"
+server_url = "{}"
+api_key = "main-key"
+mirrors = ["{}"]
+
+[profiles.other]
+server_url = "{}"
+api_key = "other-key"
+"#,
+        main.url, mirror.url, other.url
+    );
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+
+    sync(&dir, &["--max-retries", "0"]).await.unwrap();
+    assert_eq!(
+        std::fs::read(dir.join("main.jar")).unwrap(),
+        b"main content"
+    );
+    assert!(mirror.paths().iter().any(|x| x.starts_with("/dl/hash/")));
+    assert!(other.paths().is_empty());
+
+    let (main_seen, mirror_seen) = (main.paths().len(), mirror.paths().len());
+    sync(&dir, &["--profile", "other", "--max-retries", "0"])
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read(dir.join("other.jar")).unwrap(),
+        b"other content"
+    );
+    assert_eq!(main.paths().len(), main_seen);
+    assert_eq!(mirror.paths().len(), mirror_seen);
+    // Every request carried the key, or it would have been turned away
+    for server in [&main, &mirror, &other] {
+        let requests = server.state().requests.clone();
+        assert!(requests.iter().all(|x| x.2.is_some()), "{:?}", requests);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn preview_lists_files_and_size_without_a_config() {
+    let server = FakeServer::start().await;
+    server.state().api_key = Some("read-token".to_string());
+    server.put_file("mods/a.jar", &[0; 1000]);
+    server.put_file("config/b.toml", &[0; 24]);
+    server.put_file("mods/gone.jar", b"gone");
+    server.state().put(server_file("mods/gone.jar", "Deleted"));
+    server.state().put(server_file("saves", "Directory"));
+    let dir = temp_dir("preview");
+
+    let preview = preview(&ModpackId("a".to_string()), &server.url, Some("read-token"))
+        .await
+        .unwrap();
+    assert_eq!(preview.paths, ["config/b.toml", "mods/a.jar", "saves/"]);
+    assert_eq!(preview.total_size, 1024);
+
+    sync(
+        &dir,
+        &["--preview", "a", &server.url, "--api-key", "read-token"],
+    )
+    .await
+    .unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Writes a config syncing modpack `a` from `server` into `dir`
+fn write_config(dir: &Path, server: &FakeServer) {
+    let config = format!("modpack_id = \"a\"\nserver_url = \"{}\"\n", server.url);
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+}
+
+#[tokio::test]
+async fn file_deleted_on_the_server_is_moved_to_the_trash() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"old mod");
+    let dir = temp_dir("trash");
+    write_config(&dir, &server);
+    sync(&dir, &[]).await.unwrap();
+    assert!(dir.join("mods/a.jar").is_file());
+
+    server.state().put(server_file("mods/a.jar", "Deleted"));
+    sync(&dir, &["--trash"]).await.unwrap();
+    assert!(!dir.join("mods/a.jar").exists());
+    let trashed = std::fs::read(dir.join(".modsync-trash/mods/a.jar")).unwrap();
+    assert_eq!(trashed, b"old mod");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn downloads_fail_over_to_a_mirror_and_stop_trying_the_broken_server() {
+    let main = FakeServer::start().await;
+    let mirror = FakeServer::start().await;
+    let paths: Vec<String> = (0..6).map(|x| format!("mods/{}.jar", x)).collect();
+    for path in &paths {
+        main.put_file(path, path.as_bytes());
+        mirror.put_file(path, path.as_bytes());
+    }
+    main.state().intercept = Some(Box::new(|_, uri| {
+        uri.starts_with("/dl/")
+            .then(|| axum::http::StatusCode::BAD_GATEWAY.into_response())
+    }));
+    let dir = temp_dir("failover");
+    let config = format!(
+        "modpack_id = \"a\"\nserver_url = \"{}\"\nmirrors = [\"{}\"]\n",
+        main.url, mirror.url
+    );
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+
+    sync(&dir, &["--max-retries", "0", "--concurrency", "1"])
+        .await
+        .unwrap();
+    for path in &paths {
+        assert_eq!(std::fs::read(dir.join(path)).unwrap(), path.as_bytes());
+    }
+    // Demoted after three failures in a row, the mirror served everything after that
+    let failed = main
+        .paths()
+        .iter()
+        .filter(|x| x.starts_with("/dl/"))
+        .count();
+    assert!((1..=3).contains(&failed), "{}", failed);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn server_ignored_files_are_listed_as_ignored() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"mod");
+    server.state().put(server_file("options.txt", "Ignored"));
+    let dir = temp_dir("list-ignored");
+    write_config(&dir, &server);
+    sync(&dir, &[]).await.unwrap();
+    assert!(!dir.join("options.txt").exists());
+
+    let config: Config =
+        toml::from_str(&std::fs::read_to_string(dir.join(CONFIG_FILE)).unwrap()).unwrap();
+    assert_eq!(
+        file_list(&config.files),
+        ["[  synced] mods/a.jar", "[ ignored] options.txt"]
+    );
+    sync(&dir, &["--list"]).await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn pack_over_max_download_is_refused() {
+    // Interactive runs get asked instead
+    if std::io::stdin().is_terminal() {
+        return;
+    }
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", &[1; 1500]);
+    server.put_file("mods/b.jar", &[2; 1500]);
+    let dir = temp_dir("max-download");
+    write_config(&dir, &server);
+
+    let err = sync(&dir, &["--max-download", "2KB"]).await.unwrap_err();
+    assert!(err.to_string().contains("--max-download"), "{:?}", err);
+    assert!(!dir.join("mods").exists());
+    assert!(!server.paths().iter().any(|x| x.starts_with("/dl/")));
+
+    sync(&dir, &["--max-download", "3KB"]).await.unwrap();
+    assert!(dir.join("mods/a.jar").is_file() && dir.join("mods/b.jar").is_file());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn deep_verify_redownloads_a_file_of_the_wrong_size() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"the whole mod");
+    let dir = temp_dir("deep-verify");
+    write_config(&dir, &server);
+    sync(&dir, &[]).await.unwrap();
+    // Cut short, as if a copy was interrupted
+    std::fs::write(dir.join("mods/a.jar"), b"the who").unwrap();
+
+    sync(&dir, &[]).await.unwrap();
+    assert_eq!(std::fs::read(dir.join("mods/a.jar")).unwrap(), b"the who");
+    sync(&dir, &["--deep-verify"]).await.unwrap();
+    assert_eq!(
+        std::fs::read(dir.join("mods/a.jar")).unwrap(),
+        b"the whole mod"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn offline_manifest_check_catches_a_modified_file() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"mod a");
+    server.put_file("mods/b.jar", b"mod b");
+    let dir = temp_dir("check-manifest");
+    write_config(&dir, &server);
+    let manifest = dir.join("manifest.json");
+    let manifest = manifest.to_str().unwrap();
+    sync(&dir, &["--export-manifest", manifest]).await.unwrap();
+    let requests = server.state().requests.len();
+
+    sync(&dir, &["--check-manifest", manifest]).await.unwrap();
+    // Same size, so only the hash tells them apart
+    std::fs::write(dir.join("mods/b.jar"), b"mod c").unwrap();
+    let err = sync(&dir, &["--check-manifest", manifest])
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(ExitError::Verify(_))),
+        "{:?}",
+        err
+    );
+    let mismatches = Manifest::read(Path::new(manifest))
+        .unwrap()
+        .check(&dir)
+        .unwrap();
+    assert!(
+        matches!(&mismatches[..], [ManifestMismatch::Hash(x)] if x == "mods/b.jar"),
+        "{:?}",
+        mismatches.len()
+    );
+    assert_eq!(server.state().requests.len(), requests);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn synced_modes_are_applied_exactly() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = FakeServer::start().await;
+    for (path, mode) in [
+        ("launch.sh", "0755"),
+        ("mods/a.jar", "0644"),
+        ("key", "0600"),
+    ] {
+        server.put_file(path, path.as_bytes());
+        let mut file = server.state().files.pop().unwrap();
+        file["mode"] = mode.into();
+        server.state().files.push(file);
+    }
+    let dir = temp_dir("modes");
+    write_config(&dir, &server);
+    sync(&dir, &[]).await.unwrap();
+
+    let mode = |path| {
+        let metadata = std::fs::metadata(dir.join(path)).unwrap();
+        metadata.permissions().mode() & 0o7777
+    };
+    assert_eq!(mode("launch.sh"), 0o755);
+    assert_eq!(mode("mods/a.jar"), 0o644);
+    assert_eq!(mode("key"), 0o600);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn created_directories_get_the_configured_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = FakeServer::start().await;
+    server.put_file("mods/fabric/a.jar", b"mod");
+    let dir = temp_dir("dir-mode");
+    write_config(&dir, &server);
+    let config = std::fs::read_to_string(dir.join(CONFIG_FILE)).unwrap();
+
+    // The owner couldn't create the files in these
+    let Err(err) = Args::try_parse_from(["modsync_client", ".", "--dir-mode", "0600"]) else {
+        panic!("--dir-mode 0600 was accepted");
+    };
+    assert!(err.to_string().contains("directory mode"), "{}", err);
+    std::fs::write(
+        dir.join(CONFIG_FILE),
+        format!("{}dir_mode = \"0600\"\n", config),
+    )
+    .unwrap();
+    let err = sync(&dir, &[]).await.unwrap_err();
+    assert!(err.to_string().contains("directory mode"), "{:?}", err);
+    assert!(!dir.join("mods").exists());
+
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+    sync(&dir, &["--dir-mode", "0700"]).await.unwrap();
+    for path in ["mods", "mods/fabric"] {
+        let metadata = std::fs::metadata(dir.join(path)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o700, "{}", path);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_staged_download_leaves_a_directory_turned_file_in_place() {
+    let server = FakeServer::start().await;
+    server.state().put(server_file("config/extra", "Directory"));
+    server.state().put(server_file("config/gone", "Directory"));
+    server.put_file("mods/a.jar", b"old mod");
+    let dir = temp_dir("staged-directory");
+    write_config(&dir, &server);
+    sync(&dir, &[]).await.unwrap();
+    assert!(dir.join("config/extra").is_dir());
+
+    server.put_file("config/extra", b"now a file");
+    server.state().put(server_file("config/gone", "Deleted"));
+    let hash = server.put_file("mods/a.jar", b"new mod");
+    let blob = server.state().blobs.remove(&hash).unwrap();
+    let args = ["--staged", "--max-retries", "0"];
+    sync(&dir, &args).await.unwrap_err();
+    assert!(dir.join("config/extra").is_dir() && dir.join("config/gone").is_dir());
+    assert_eq!(std::fs::read(dir.join("mods/a.jar")).unwrap(), b"old mod");
+
+    server.state().blobs.insert(hash, blob);
+    sync(&dir, &args).await.unwrap();
+    assert_eq!(
+        std::fs::read(dir.join("config/extra")).unwrap(),
+        b"now a file"
+    );
+    assert_eq!(std::fs::read(dir.join("mods/a.jar")).unwrap(), b"new mod");
+    assert!(!dir.join("config/gone").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn custom_config_leaves_the_default_one_alone() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"mod");
+    let dir = temp_dir("custom-config");
+    write_config(&dir, &server);
+    let profile = dir.join("profiles/server.toml");
+    std::fs::create_dir(dir.join("profiles")).unwrap();
+    std::fs::rename(dir.join(CONFIG_FILE), &profile).unwrap();
+    // Another modpack's, syncing with it would fail
+    let default_config = "modpack_id = \"b\"\nserver_url = \"http://127.0.0.1:1/\"\n";
+    std::fs::write(dir.join(CONFIG_FILE), default_config).unwrap();
+
+    sync(&dir, &["--config", profile.to_str().unwrap()])
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(dir.join("mods/a.jar")).unwrap(), b"mod");
+    let config: Config = toml::from_str(&std::fs::read_to_string(&profile).unwrap()).unwrap();
+    assert_eq!(file_list(&config.files), ["[  synced] mods/a.jar"]);
+    assert_eq!(
+        std::fs::read_to_string(dir.join(CONFIG_FILE)).unwrap(),
+        default_config
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn sync_waits_out_maintenance_and_then_succeeds() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"mod");
+    let mut maintenance = 2;
+    server.state().intercept = Some(Box::new(move |_, _| {
+        (maintenance > 0).then(|| {
+            maintenance -= 1;
+            let headers = [(axum::http::header::RETRY_AFTER, "1")];
+            let status = axum::http::StatusCode::SERVICE_UNAVAILABLE;
+            (status, headers, "MAINTENANCE").into_response()
+        })
+    }));
+    let dir = temp_dir("maintenance");
+    write_config(&dir, &server);
+
+    let started = std::time::Instant::now();
+    sync(&dir, &["--max-retries", "0"]).await.unwrap();
+    // The advertised second, twice over
+    let waited = started.elapsed();
+    assert!(waited >= Duration::from_secs(2), "{:?}", waited);
+    assert_eq!(std::fs::read(dir.join("mods/a.jar")).unwrap(), b"mod");
+    let paths = server.paths();
+    assert_eq!(paths[0], paths[1]);
+    assert_eq!(paths[1], paths[2]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn declared_directories_are_created_and_swap_with_files() {
+    let server = FakeServer::start().await;
+    server
+        .state()
+        .put(server_file("saves/backups", "Directory"));
+    server
+        .state()
+        .put(server_file("resourcepacks", "Directory"));
+    server.put_file("config/options", b"old options");
+    let dir = temp_dir("directories");
+    write_config(&dir, &server);
+    sync(&dir, &[]).await.unwrap();
+    for path in ["saves/backups", "resourcepacks"] {
+        let entries = std::fs::read_dir(dir.join(path)).unwrap().count();
+        assert_eq!(entries, 0, "{}", path);
+    }
+    assert!(dir.join("config/options").is_file());
+
+    // Each turns into the other
+    server
+        .state()
+        .put(server_file("config/options", "Directory"));
+    server.put_file("saves/backups", b"not a directory anymore");
+    sync(&dir, &["--trash"]).await.unwrap();
+    assert!(dir.join("config/options").is_dir());
+    let trashed = std::fs::read(dir.join(".modsync-trash/config/options")).unwrap();
+    assert_eq!(trashed, b"old options");
+    assert_eq!(
+        std::fs::read(dir.join("saves/backups")).unwrap(),
+        b"not a directory anymore"
+    );
+    assert!(dir.join("resourcepacks").is_dir());
+
+    // Only an empty directory makes way for a file
+    std::fs::write(dir.join("config/options/user.txt"), b"kept").unwrap();
+    server.put_file("config/options", b"new options");
+    let err = sync(&dir, &[]).await.unwrap_err();
+    assert!(err.to_string().contains("config/options"), "{:?}", err);
+    assert_eq!(
+        std::fs::read(dir.join("config/options/user.txt")).unwrap(),
+        b"kept"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_syncs_exit_with_the_code_of_their_cause() {
+    let server = FakeServer::start().await;
+    server.put_file("mods/a.jar", b"mod");
+    let dir = temp_dir("exit-codes");
+    let code = |result: anyhow::Result<()>| error_exit_code(&result.unwrap_err());
+
+    std::fs::write(dir.join(CONFIG_FILE), "modpack_id = [").unwrap();
+    assert_eq!(code(sync(&dir, &[]).await), EXIT_CONFIG);
+
+    write_config(&dir, &server);
+    server.state().api_key = Some("key".to_string());
+    assert_eq!(code(sync(&dir, &["--max-retries", "0"]).await), EXIT_AUTH);
+    server.state().api_key = None;
+
+    server.state().intercept = Some(Box::new(|_, uri| {
+        uri.starts_with("/dl/")
+            .then(|| axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }));
+    let result = sync(&dir, &["--max-retries", "0"]).await;
+    assert_eq!(code(result), EXIT_FAILURE);
+    server.state().intercept = None;
+
+    // Nothing listens on port 1
+    let config = "modpack_id = \"a\"\nserver_url = \"http://127.0.0.1:1/\"\n";
+    std::fs::write(dir.join(CONFIG_FILE), config).unwrap();
+    assert_eq!(
+        code(sync(&dir, &["--max-retries", "0"]).await),
+        EXIT_NETWORK
+    );
+    assert!(!dir.join("mods/a.jar").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
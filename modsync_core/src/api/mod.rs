@@ -1,7 +1,14 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::sqlx_macros::Type;
+use url::Url;
 
-use crate::{models::{self, modpacks::Modpack}, FileState};
+use crate::{
+    checksum::Checksum,
+    models::{self, modpacks::Modpack},
+    DownloadSource, FileState, ModState,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, Eq, PartialOrd, Ord, Type)]
 #[serde(transparent)]
@@ -18,35 +25,485 @@ pub struct FileId(pub String);
 #[sqlx(transparent)]
 pub struct UploadId(pub String);
 
+/// A request body that doesn't pass validation, holds a human readable reason
+#[derive(Debug)]
+pub struct ValidationError(pub String);
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Parses the configured server URL into a base that API paths can be joined onto.
+/// Keeps any path prefix the server is hosted under, e.g. `https://host/modsync`.
+pub fn server_base_url(server_url: &str) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(server_url)?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// Header carrying a checksum of the request body, as in RFC 3230
+pub const DIGEST_HEADER: &str = "Digest";
+
+/// Value of the `Digest` header for `body`
+pub fn body_digest(body: &[u8]) -> String {
+    format!("sha-256={}", BASE64_STANDARD.encode(Sha256::digest(body)))
+}
+
+/// Checks `body` against a `Digest` header value, `None` if it has no algorithm we support
+pub fn verify_digest(header: &str, body: &[u8]) -> Option<bool> {
+    let mut verifier = DigestVerifier::new(header)?;
+    verifier.update(body);
+    Some(verifier.verify())
+}
+
+/// Checks a body that arrives in pieces against a `Digest` header value
+pub struct DigestVerifier {
+    expected: String,
+    hasher: Sha256,
+}
+
+impl DigestVerifier {
+    /// `None` if the header has no algorithm we support
+    pub fn new(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .filter_map(|x| x.trim().split_once('='))
+            .find(|(algorithm, _)| algorithm.eq_ignore_ascii_case("sha-256"))
+            .map(|(_, digest)| DigestVerifier {
+                expected: digest.trim().to_string(),
+                hasher: Sha256::new(),
+            })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Whether everything passed to `update` matches the header
+    pub fn verify(self) -> bool {
+        BASE64_STANDARD.encode(self.hasher.finalize()) == self.expected
+    }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct HelloResponse {
     pub version: String,
     pub version_number: u32,
 }
 
+// Maintenance
+/// Body of the `503` answered during maintenance, together with a `Retry-After` header
+pub const MAINTENANCE_ERROR: &str = "MAINTENANCE";
+
 #[derive(Serialize, Deserialize)]
+pub struct MaintenanceBody {
+    pub enabled: bool,
+    /// Seconds clients are told to wait before retrying, keeps the current value if `None`
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MaintenanceResponse {
+    pub enabled: bool,
+    pub retry_after: u64,
+}
+
+// Health
+#[derive(Serialize, Deserialize)]
+pub struct HealthResponse {
+    /// `ok`, or `unavailable` along with a `503`
+    pub status: String,
+    /// Whether the database answered
+    pub db: bool,
+}
+
+// Capabilities
+/// Bumped on breaking changes to the API
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// `POST /dl/batch`
+pub const FEATURE_BATCH_DOWNLOAD: &str = "batch_download";
+/// `Range` requests on `/dl/hash`
+pub const FEATURE_RANGE_DOWNLOAD: &str = "range_download";
+/// `POST /blob/upload` and `/blob/exists`, used by two-phase syncs
+pub const FEATURE_BLOB_UPLOAD: &str = "blob_upload";
+/// `Digest` header checks on request bodies
+pub const FEATURE_BODY_DIGEST: &str = "body_digest";
+/// `POST /modpack/:id/signature`
+pub const FEATURE_SIGNATURES: &str = "signatures";
+/// `POST /modpack/:id/webhook`
+pub const FEATURE_WEBHOOKS: &str = "webhooks";
+/// `POST /modpack/:id/roots`
+pub const FEATURE_ALLOWED_ROOTS: &str = "allowed_roots";
+/// `GET /modpack/:id/changes`
+pub const FEATURE_CHANGES: &str = "changes";
+/// `Directory` file states
+pub const FEATURE_DIRECTORIES: &str = "directories";
+/// `POST /modpack/:id/file/delete`
+pub const FEATURE_FILE_DELETE: &str = "file_delete";
+/// Files downloaded from Modrinth's CDN instead of the server
+pub const FEATURE_MODRINTH_SOURCE: &str = "modrinth_source";
+/// `GET /modpacks`
+pub const FEATURE_MODPACK_LIST: &str = "modpack_list";
+/// `POST /modpack/:id/filesync/batch`
+pub const FEATURE_FILESYNC_BATCH: &str = "filesync_batch";
+/// `GET /modpack/:id/version`
+pub const FEATURE_MODPACK_VERSION: &str = "modpack_version";
+
+/// Host every Modrinth `source_url` must point to
+pub const MODRINTH_CDN_HOST: &str = "cdn.modrinth.com";
+
+/// What a server supports, servers that predate it answer 404
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CapabilitiesResponse {
+    pub protocol_version: u32,
+    pub version: String,
+    pub features: Vec<String>,
+    pub hash_algorithms: Vec<String>,
+    pub chunked_upload: bool,
+    pub max_upload_size: usize,
+    pub max_json_body_size: usize,
+    pub max_path_length: usize,
+    pub max_path_components: usize,
+    pub batch_download_max_hashes: usize,
+}
+
+impl CapabilitiesResponse {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|x| x == feature)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ModpackResponse {
     pub modpack: Modpack,
     pub files: Vec<models::files::File>,
 }
 
+// Modpack changes
+#[derive(Serialize, Deserialize)]
+pub struct ModpackChangesResponse {
+    pub modpack: Modpack,
+    /// Pass back as `since` to get only the changes after this response
+    pub cursor: i64,
+    /// `true` if `files` holds every file because the given cursor was unknown or too old
+    pub full: bool,
+    pub files: Vec<models::files::File>,
+}
+
+// Modpack version
+#[derive(Serialize, Deserialize)]
+pub struct ModpackVersionResponse {
+    pub sync_version: i32,
+    /// Same as the `cursor` of `/changes`, it moves on with any change to the files
+    pub cursor: i64,
+}
+
 // File sync
 #[derive(Serialize, Deserialize)]
 pub struct FileSyncBody {
     pub path: String,
     pub state: FileState,
     pub hash: Option<String>,
+    /// Unix permission bits as an octal string, e.g. `0755`
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Whether the mod was added, updated or removed, `None` keeps what the server has
+    #[serde(default)]
+    pub mod_state: Option<ModState>,
+    /// Where clients download the file from, `None` is the server's own blob
+    #[serde(default)]
+    pub download_source: Option<DownloadSource>,
+    /// Content URL for files with a `download_source`, nothing gets uploaded for those
+    #[serde(default)]
+    pub source_url: Option<String>,
+    /// Size of source-backed files, the server measures uploaded blobs itself
+    #[serde(default)]
+    pub size: Option<i64>,
+}
+
+impl FileSyncBody {
+    pub fn validate(
+        &self,
+        max_path_length: usize,
+        max_path_components: usize,
+    ) -> Result<(), ValidationError> {
+        if self.state == FileState::Directory && self.hash.is_some() {
+            return Err(ValidationError(
+                "directories can't have content".to_string(),
+            ));
+        }
+        if let Some(mode) = &self.mode {
+            parse_mode(mode)?;
+        }
+        match (self.download_source, &self.source_url) {
+            (Some(DownloadSource::Modrinth), Some(url)) => {
+                if self.state == FileState::Exists && self.hash.is_none() {
+                    return Err(ValidationError(
+                        "Modrinth files need a hash to be verified with".to_string(),
+                    ));
+                }
+                validate_modrinth_url(url)?;
+            }
+            (Some(DownloadSource::Modrinth), None) => {
+                return Err(ValidationError(
+                    "Modrinth files need a source_url".to_string(),
+                ));
+            }
+            (_, Some(_)) => {
+                return Err(ValidationError(
+                    "source_url is only allowed for Modrinth files".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        validate_path(&self.path, max_path_length, max_path_components)
+    }
+}
+
+/// Whether `path` stays inside the directory it's joined onto: relative on every platform,
+/// without `..` components
+pub fn is_contained_path(path: &str) -> bool {
+    let mut components = path.split(['/', '\\']);
+    let drive =
+        path.len() >= 2 && path.as_bytes()[0].is_ascii_alphabetic() && path.as_bytes()[1] == b':';
+    !path.starts_with(['/', '\\']) && !drive && components.all(|x| x != "..")
+}
+
+/// Modrinth sources must be https URLs on Modrinth's CDN
+pub fn validate_modrinth_url(url: &str) -> Result<(), ValidationError> {
+    match Url::parse(url) {
+        Ok(parsed)
+            if parsed.scheme() == "https" && parsed.host_str() == Some(MODRINTH_CDN_HOST) =>
+        {
+            Ok(())
+        }
+        _ => Err(ValidationError(format!("invalid Modrinth url {:?}", url))),
+    }
+}
+
+/// Parses an octal permission string like `0755` or `644`, anything else is rejected
+pub fn parse_mode(mode: &str) -> Result<u32, ValidationError> {
+    if !(3..=4).contains(&mode.len()) || !mode.chars().all(|x| matches!(x, '0'..='7')) {
+        return Err(ValidationError(format!("invalid file mode {:?}", mode)));
+    }
+    u32::from_str_radix(mode, 8)
+        .map_err(|_| ValidationError(format!("invalid file mode {:?}", mode)))
+}
+
+/// Like `parse_mode`, but also rejects modes that would lock the owner out of the directory,
+/// files can't be created in a directory without write and execute permission
+pub fn parse_dir_mode(mode: &str) -> Result<u32, ValidationError> {
+    let parsed = parse_mode(mode)?;
+    if parsed & 0o700 != 0o700 {
+        return Err(ValidationError(format!(
+            "directory mode {:?} must let the owner read, write and enter it",
+            mode
+        )));
+    }
+    Ok(parsed)
+}
+
+/// Checks a synced file path against length (in characters) and depth limits
+pub fn validate_path(
+    path: &str,
+    max_length: usize,
+    max_components: usize,
+) -> Result<(), ValidationError> {
+    if !is_contained_path(path) {
+        return Err(ValidationError(
+            "path must be relative and can't contain .. components".to_string(),
+        ));
+    }
+    if path.chars().count() > max_length {
+        return Err(ValidationError(format!(
+            "path must be at most {} characters long",
+            max_length
+        )));
+    }
+    if path.split('/').count() > max_components {
+        return Err(ValidationError(format!(
+            "path must have at most {} components",
+            max_components
+        )));
+    }
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct FileSyncResponse {}
 
+// Batch file sync
+/// Most files accepted by a single `/filesync/batch` request
+pub const FILESYNC_BATCH_MAX_FILES: usize = 1000;
+
+/// Applied all at once, or not at all if any file is rejected
+#[derive(Serialize, Deserialize)]
+pub struct FileSyncBatchBody {
+    pub files: Vec<FileSyncBody>,
+}
+
+impl FileSyncBatchBody {
+    pub fn validate(
+        &self,
+        max_path_length: usize,
+        max_path_components: usize,
+    ) -> Result<(), ValidationError> {
+        if self.files.is_empty() || self.files.len() > FILESYNC_BATCH_MAX_FILES {
+            return Err(ValidationError(format!(
+                "a batch must have between 1 and {} files",
+                FILESYNC_BATCH_MAX_FILES
+            )));
+        }
+        let mut paths = std::collections::HashSet::new();
+        for file in self.files.iter() {
+            file.validate(max_path_length, max_path_components)
+                .map_err(|err| ValidationError(format!("{}: {}", file.path, err.0)))?;
+            if !paths.insert(&file.path) {
+                return Err(ValidationError(format!(
+                    "{} is in the batch twice",
+                    file.path
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One result per file of the batch, in the same order
+#[derive(Serialize, Deserialize)]
+pub struct FileSyncBatchResponse {
+    pub results: Vec<FileSyncResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FileSyncResult {
+    pub path: String,
+    /// Version of the file after the sync, 0 for files new to the modpack
+    pub sync_version: i32,
+}
+
+// File delete
+#[derive(Serialize, Deserialize)]
+pub struct FileDeleteBody {
+    pub path: String,
+}
+
 // File upload
 #[derive(Serialize, Deserialize)]
 pub struct FileUploadResponse {
     pub file_id: FileId,
 }
 
+// Blobs
+#[derive(Serialize, Deserialize)]
+pub struct BlobUploadResponse {
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlobExistsBody {
+    pub hashes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlobExistsResponse {
+    pub missing: Vec<String>,
+}
+
+// Chunked upload
+/// Starts an upload sent in `chunk_size` pieces through `PUT /modpack/:id/upload/:upload_id/chunk/:n`,
+/// for files too large to send in one request
+#[derive(Serialize, Deserialize)]
+pub struct UploadInitBody {
+    /// File to attach the content to once finished, `None` only stores it like `/blob/upload`
+    #[serde(default)]
+    pub file_path: Option<String>,
+    pub size: i64,
+    /// Hash of the whole content under the modpack's algorithm, finishing fails if it doesn't match
+    pub hash: String,
+}
+
+impl UploadInitBody {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.size < 0 {
+            return Err(ValidationError("size can't be negative".to_string()));
+        }
+        if self.hash.len() != 64
+            || !self
+                .hash
+                .chars()
+                .all(|x| matches!(x, '0'..='9' | 'a'..='f'))
+        {
+            return Err(ValidationError(
+                "hash must be 64 lowercase hex characters".to_string(),
+            ));
+        }
+        if self
+            .file_path
+            .as_deref()
+            .is_some_and(|x| !is_contained_path(x))
+        {
+            return Err(ValidationError(
+                "file_path must be relative and can't contain .. components".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Where an upload is at, chunks may be sent with a `Digest` header to have each checked on arrival
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UploadStatusResponse {
+    pub upload_id: UploadId,
+    pub size: i64,
+    /// Every chunk but the last must be exactly this long
+    pub chunk_size: i64,
+    /// Index of the chunk the server expects next, earlier ones were received
+    pub next_chunk: i32,
+    pub received_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadFinishResponse {
+    pub hash: String,
+    /// File the content was attached to, `None` for uploads without a `file_path`
+    pub file_id: Option<FileId>,
+}
+
+// Batch download
+/// Most hashes accepted by a single `/dl/batch` request
+pub const BATCH_DOWNLOAD_MAX_HASHES: usize = 256;
+/// Length sent in place of the content length for hashes the server doesn't have
+pub const BATCH_DOWNLOAD_MISSING: u64 = u64::MAX;
+
+/// The response is, for each requested hash in order, the 64 byte hex hash,
+/// the content length as a big-endian u64 (or `BATCH_DOWNLOAD_MISSING`) and the content
+#[derive(Serialize, Deserialize)]
+pub struct BatchDownloadBody {
+    pub hashes: Vec<String>,
+    /// Modpack the download is for, see `DownloadQuery`
+    #[serde(default)]
+    pub modpack: Option<ModpackId>,
+}
+
+/// Query of `GET /dl/hash/:hash`
+#[derive(Serialize, Deserialize, Default)]
+pub struct DownloadQuery {
+    /// Modpack the download is for, its usage counts the bytes. Without it, or a modpack key
+    /// naming one, a blob shared by several modpacks isn't counted toward any of them.
+    #[serde(default)]
+    pub modpack: Option<ModpackId>,
+}
+
 // Modpack Create
 #[derive(Serialize, Deserialize)]
 pub struct ModpackCreateBody {
@@ -55,6 +512,144 @@ pub struct ModpackCreateBody {
     pub game_version: String,
     pub modloader: String,
     pub modloader_version: String,
+    /// Receives a POST for every change to the modpack
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// Idempotency key, retrying a create with the same key returns the modpack it created
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Top level directories files may be synced into, `None` allows any path
+    #[serde(default)]
+    pub allowed_roots: Option<Vec<String>>,
+    /// Algorithm files of the modpack are hashed with, can't be changed later
+    #[serde(default)]
+    pub hash_algorithm: Checksum,
+}
+
+/// Upper bound for every modpack text field, matches the `modpacks.name` column
+pub const MODPACK_FIELD_MAX_LENGTH: usize = 128;
+
+impl ModpackCreateBody {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.name.trim().is_empty() {
+            return Err(ValidationError("name must not be empty".to_string()));
+        }
+        for (field, value) in [
+            ("name", &self.name),
+            ("game", &self.game),
+            ("game_version", &self.game_version),
+            ("modloader", &self.modloader),
+            ("modloader_version", &self.modloader_version),
+        ] {
+            if value.chars().count() > MODPACK_FIELD_MAX_LENGTH {
+                return Err(ValidationError(format!(
+                    "{} must be at most {} characters long",
+                    field, MODPACK_FIELD_MAX_LENGTH
+                )));
+            }
+            if value.chars().any(|x| x.is_control()) {
+                return Err(ValidationError(format!(
+                    "{} must not contain control characters",
+                    field
+                )));
+            }
+        }
+        if let Some(url) = &self.webhook_url {
+            validate_webhook_url(url)?;
+        }
+        if let Some(request_id) = &self.request_id {
+            if request_id.is_empty() || request_id.chars().count() > MODPACK_FIELD_MAX_LENGTH {
+                return Err(ValidationError(format!(
+                    "request_id must be 1 to {} characters long",
+                    MODPACK_FIELD_MAX_LENGTH
+                )));
+            }
+        }
+        if let Some(roots) = &self.allowed_roots {
+            validate_roots(roots)?;
+        }
+        Ok(())
+    }
+}
+
+/// Only absolute http(s) URLs can be used as webhooks
+pub fn validate_webhook_url(url: &str) -> Result<(), ValidationError> {
+    match Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err(ValidationError(format!("invalid webhook url {:?}", url))),
+    }
+}
+
+/// Roots must be plain directory names, e.g. `mods` or `config`
+pub fn validate_roots(roots: &[String]) -> Result<(), ValidationError> {
+    for root in roots {
+        if root.is_empty()
+            || root == "."
+            || root == ".."
+            || root.contains(['/', '\\'])
+            || root.chars().any(|x| x.is_control())
+            || root.chars().count() > MODPACK_FIELD_MAX_LENGTH
+        {
+            return Err(ValidationError(format!("invalid root {:?}", root)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `path` lies under one of `roots`
+pub fn path_in_roots(path: &str, roots: &[String]) -> bool {
+    match path.split_once('/') {
+        Some((root, _)) => roots.iter().any(|x| x == root),
+        None => false,
+    }
+}
+
+// Modpack allowed roots
+#[derive(Serialize, Deserialize)]
+pub struct ModpackRootsBody {
+    /// `None` allows any path again
+    pub allowed_roots: Option<Vec<String>>,
+}
+
+// Modpack signature
+#[derive(Serialize, Deserialize)]
+pub struct ModpackSignatureBody {
+    /// `None` removes the signature
+    pub signature: Option<String>,
+}
+
+/// Longer than any base64 encoded ed25519 signature
+pub const SIGNATURE_MAX_LENGTH: usize = 128;
+
+// Modpack webhook
+#[derive(Serialize, Deserialize)]
+pub struct ModpackWebhookBody {
+    /// `None` removes the webhook
+    pub webhook_url: Option<String>,
+}
+
+/// Sent to a modpack's webhook after a successful change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookEvent {
+    pub modpack_id: ModpackId,
+    /// `filesync`, `delete`, or `batch` for a batch filesync
+    pub event: String,
+    /// Empty for `batch` events, their paths are in `files`
+    pub path: String,
+    /// The file's sync version, or the modpack's for `batch` events
+    pub sync_version: i32,
+    /// Every file a `batch` event synced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<WebhookFile>,
+}
+
+/// One file of a `batch` webhook event
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookFile {
+    /// `filesync` or `delete`
+    pub event: String,
+    pub path: String,
+    pub sync_version: i32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -62,3 +657,203 @@ pub struct ModpackCreateResponse {
     pub modpack_id: ModpackId,
 }
 
+// Modpack usage
+#[derive(Serialize, Deserialize)]
+pub struct ModpackUsageResponse {
+    pub modpack_id: ModpackId,
+    pub download_bytes: i64,
+    pub upload_bytes: i64,
+}
+
+// Modpack list
+/// Page size of `GET /modpacks` when no `limit` is given
+pub const MODPACK_LIST_DEFAULT_LIMIT: i64 = 100;
+/// Largest `limit` `GET /modpacks` accepts
+pub const MODPACK_LIST_MAX_LIMIT: i64 = 1000;
+
+#[derive(Serialize, Deserialize)]
+pub struct ModpackListResponse {
+    /// Sorted by name
+    pub modpacks: Vec<ModpackSummary>,
+    /// Modpacks on the server, including the ones outside this page
+    pub total: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModpackSummary {
+    pub id: ModpackId,
+    pub name: String,
+    pub game: Option<String>,
+    /// Files currently in the modpack, deleted ones don't count
+    pub file_count: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_body() -> ModpackCreateBody {
+        ModpackCreateBody {
+            name: "Survival".to_string(),
+            game: "minecraft".to_string(),
+            game_version: "1.20.1".to_string(),
+            modloader: "fabric".to_string(),
+            modloader_version: "0.15.7".to_string(),
+            webhook_url: None,
+            request_id: None,
+            allowed_roots: None,
+            hash_algorithm: Checksum::default(),
+        }
+    }
+
+    #[test]
+    fn valid_create_body_passes() {
+        assert!(create_body().validate().is_ok());
+        let body = ModpackCreateBody {
+            webhook_url: Some("https://example.com/hook".to_string()),
+            request_id: Some("retry-1".to_string()),
+            allowed_roots: Some(vec!["mods".to_string(), "config".to_string()]),
+            ..create_body()
+        };
+        assert!(body.validate().is_ok());
+    }
+
+    #[test]
+    fn empty_name_is_rejected() {
+        for name in ["", "   "] {
+            let body = ModpackCreateBody {
+                name: name.to_string(),
+                ..create_body()
+            };
+            assert!(body.validate().is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn overlong_fields_are_rejected() {
+        let long = "a".repeat(MODPACK_FIELD_MAX_LENGTH + 1);
+        let bodies = [
+            ModpackCreateBody {
+                name: long.clone(),
+                ..create_body()
+            },
+            ModpackCreateBody {
+                game: long.clone(),
+                ..create_body()
+            },
+            ModpackCreateBody {
+                modloader_version: long.clone(),
+                ..create_body()
+            },
+        ];
+        for body in bodies {
+            assert!(body.validate().is_err());
+        }
+        // The bound is in characters, not bytes
+        let body = ModpackCreateBody {
+            name: "é".repeat(MODPACK_FIELD_MAX_LENGTH),
+            ..create_body()
+        };
+        assert!(body.validate().is_ok());
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        let body = ModpackCreateBody {
+            game_version: "1.20\n1".to_string(),
+            ..create_body()
+        };
+        assert!(body.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_request_ids_are_rejected() {
+        for request_id in [String::new(), "a".repeat(MODPACK_FIELD_MAX_LENGTH + 1)] {
+            let body = ModpackCreateBody {
+                request_id: Some(request_id),
+                ..create_body()
+            };
+            assert!(body.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_webhook_and_roots_are_rejected() {
+        let body = ModpackCreateBody {
+            webhook_url: Some("ftp://example.com".to_string()),
+            ..create_body()
+        };
+        assert!(body.validate().is_err());
+        for root in ["", "..", "mods/sub"] {
+            let body = ModpackCreateBody {
+                allowed_roots: Some(vec![root.to_string()]),
+                ..create_body()
+            };
+            assert!(body.validate().is_err(), "{:?}", root);
+        }
+    }
+
+    #[test]
+    fn modes_are_parsed_strictly() {
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        for mode in ["", "75", "07555", "0855", "rwx", "-755", "0o755"] {
+            assert!(parse_mode(mode).is_err(), "{:?}", mode);
+        }
+        assert_eq!(parse_dir_mode("0700").unwrap(), 0o700);
+        assert_eq!(parse_dir_mode("2775").unwrap(), 0o2775);
+        // Files can't be created in these
+        for mode in ["0600", "0500", "0300", "0077", "0888"] {
+            assert!(parse_dir_mode(mode).is_err(), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn traversing_paths_are_not_contained() {
+        for path in [
+            "..",
+            "../mods/a.jar",
+            "mods/../../etc/passwd",
+            "mods/..",
+            "..\\mods\\a.jar",
+            "mods\\..\\..\\a.jar",
+            "/etc/passwd",
+            "\\server\\share\\a.jar",
+            "C:\\Windows\\a.jar",
+            "c:/a.jar",
+        ] {
+            assert!(!is_contained_path(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn encoded_traversal_is_caught_once_decoded() {
+        // How a query string like `file_path=..%2F..%2Fetc` reaches the server
+        for query in [
+            "file_path=..%2F..%2Fetc%2Fpasswd",
+            "file_path=%2E%2E%2Fmods",
+            "file_path=mods%5C..%5C..%5Ca.jar",
+            "file_path=%2Fetc%2Fpasswd",
+        ] {
+            let (_, path) = url::form_urlencoded::parse(query.as_bytes())
+                .next()
+                .unwrap();
+            assert!(!is_contained_path(&path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn nested_paths_are_contained() {
+        for path in [
+            "a.jar",
+            "mods/a.jar",
+            "config/sub/dir/a.toml",
+            "mods/..a.jar",
+            "mods/a..jar",
+            // Only decoded once, this stays a literal directory name
+            "mods/%2E%2E/a.jar",
+        ] {
+            assert!(is_contained_path(path), "{:?}", path);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{ParseError, StrConversion, TryFromStr};
+
+/// Hash algorithm a modpack's file hashes are computed with, and blobs are named by
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Checksum {
+    /// What every modpack used before the algorithm could be picked
+    #[default]
+    Sha256,
+    /// Several times faster than sha256 on large files
+    Blake3,
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.as_str())
+    }
+}
+impl StrConversion for Checksum {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        }
+    }
+}
+impl TryFromStr for Checksum {
+    fn try_from_str(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(ParseError::new("hash algorithm", value)),
+        }
+    }
+}
+
+impl Checksum {
+    pub const ALL: [Checksum; 2] = [Checksum::Sha256, Checksum::Blake3];
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Lowercase hex hash of everything left in `reader`
+    pub fn hash_reader(&self, reader: &mut impl Read) -> std::io::Result<String> {
+        let mut hasher = self.hasher();
+        std::io::copy(reader, &mut hasher)?;
+        Ok(hasher.finalize())
+    }
+
+    pub fn hash_bytes(&self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// Incremental hashing, for content that arrives in chunks
+pub enum Hasher {
+    Sha256(Sha256),
+    // Boxed as the blake3 state is much larger than sha256's
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Lowercase hex hash of everything passed to `update`
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Self::Blake3(hasher) => hex::encode(hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_round_trips() {
+        for checksum in [Checksum::Sha256, Checksum::Blake3] {
+            assert_eq!(
+                Checksum::try_from_str(checksum.as_str()).ok(),
+                Some(checksum)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_checksum_is_an_error() {
+        for value in ["", "md5", "SHA256"] {
+            assert!(Checksum::try_from_str(value).is_err(), "{:?}", value);
+        }
+    }
+}
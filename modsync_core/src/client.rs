@@ -0,0 +1,808 @@
+use std::time::Duration;
+
+use log::warn;
+use reqwest::{header, redirect, Method, Request, RequestBuilder, Response, StatusCode};
+use url::Url;
+
+use crate::{
+    api::{
+        body_digest, server_base_url, BatchDownloadBody, BlobExistsBody, BlobExistsResponse,
+        BlobUploadResponse, CapabilitiesResponse, FileSyncBatchBody, FileSyncBatchResponse,
+        FileSyncBody, FileSyncResponse, FileUploadResponse, HelloResponse, ModpackChangesResponse,
+        ModpackCreateBody, ModpackCreateResponse, ModpackId, ModpackListResponse, ModpackResponse,
+        ModpackSignatureBody, ModpackUsageResponse, ModpackVersionResponse, UploadFinishResponse,
+        UploadId, UploadInitBody, UploadStatusResponse, BATCH_DOWNLOAD_MISSING, DIGEST_HEADER,
+    },
+    checksum::Checksum,
+    StrConversion,
+};
+
+/// Longest the client keeps waiting out a server in maintenance, or rate limiting it, before
+/// giving up
+const MAINTENANCE_MAX_WAIT: Duration = Duration::from_secs(60 * 60);
+/// Redirects followed for a single GET request
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("invalid API key")]
+    Unauthorized,
+    #[error("not found")]
+    NotFound,
+    #[error("server responded with {0}")]
+    Status(StatusCode),
+    #[error("request error: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("invalid server URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("invalid API key format: {0}")]
+    ApiKey(#[from] header::InvalidHeaderValue),
+    #[error("malformed batch download response")]
+    MalformedBatch,
+    #[error("downloaded content doesn't match hash {0}")]
+    HashMismatch(String),
+    #[error("failed to encode request: {0}")]
+    Encode(#[from] serde_json::Error),
+    #[error("server is still in maintenance after waiting {0:?}")]
+    Maintenance(Duration),
+    #[error("server redirected, update your server_url to {0}")]
+    Redirected(String),
+    #[error("a modpack with that name already exists")]
+    AlreadyExists,
+}
+
+/// Typed client for the modsync server API
+#[derive(Clone)]
+pub struct ModsyncApi {
+    client: reqwest::Client,
+    server_url: Url,
+    /// Only sent to `server_url`'s origin, never to where a redirect points
+    authorization: Option<header::HeaderValue>,
+    /// Named in downloads, so they count toward this modpack's usage
+    modpack: Option<ModpackId>,
+}
+
+impl ModsyncApi {
+    /// Creates an API client, `api_key` is only needed for authenticated endpoints
+    pub fn new(server_url: &str, api_key: Option<&str>) -> Result<Self, ClientError> {
+        let authorization = match api_key {
+            Some(api_key) => {
+                let mut auth_value = header::HeaderValue::from_str(&format!("Bearer {}", api_key))?;
+                auth_value.set_sensitive(true);
+                Some(auth_value)
+            }
+            None => None,
+        };
+        // Redirects are handled in `send`, reqwest would silently turn POSTs into GETs
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .build()?;
+        Ok(ModsyncApi {
+            client,
+            server_url: server_base_url(server_url)?,
+            authorization,
+            modpack: None,
+        })
+    }
+
+    /// Names `modpack` in downloads, so the server counts them toward it even when the
+    /// content is shared with other modpacks
+    pub fn with_modpack(mut self, modpack: &ModpackId) -> Self {
+        self.modpack = Some(modpack.clone());
+        self
+    }
+
+    pub fn server_url(&self) -> &Url {
+        &self.server_url
+    }
+
+    pub async fn hello(&self) -> Result<HelloResponse, ClientError> {
+        let response = self.send(self.client.post(self.url("hello")?)).await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// `None` if the server is too old to report its capabilities
+    pub async fn capabilities(&self) -> Result<Option<CapabilitiesResponse>, ClientError> {
+        let response = self
+            .send(self.client.get(self.url("capabilities")?))
+            .await?;
+        match check(response) {
+            Ok(response) => Ok(Some(response.json().await?)),
+            Err(ClientError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn get_modpack(&self, id: &ModpackId) -> Result<ModpackResponse, ClientError> {
+        let response = self
+            .send(self.client.get(self.url(&format!("modpack/{}", id.0))?))
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// `None` if the modpack still matches `etag`, otherwise the modpack and its new ETag
+    pub async fn get_modpack_if_changed(
+        &self,
+        id: &ModpackId,
+        etag: Option<&str>,
+    ) -> Result<Option<(ModpackResponse, Option<String>)>, ClientError> {
+        let mut request = self.client.get(self.url(&format!("modpack/{}", id.0))?);
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = self.send(request).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let response = check(response)?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string());
+        Ok(Some((response.json().await?, etag)))
+    }
+
+    /// `None` if there's no such modpack, or the server is too old to tell its version
+    pub async fn get_modpack_version(
+        &self,
+        id: &ModpackId,
+    ) -> Result<Option<ModpackVersionResponse>, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .get(self.url(&format!("modpack/{}/version", id.0))?),
+            )
+            .await?;
+        match check(response) {
+            Ok(response) => Ok(Some(response.json().await?)),
+            Err(ClientError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Files changed after `since`, or every file if `since` is `None` or no longer valid
+    pub async fn get_changes(
+        &self,
+        id: &ModpackId,
+        since: Option<i64>,
+    ) -> Result<ModpackChangesResponse, ClientError> {
+        let mut request = self
+            .client
+            .get(self.url(&format!("modpack/{}/changes", id.0))?);
+        if let Some(since) = since {
+            request = request.query(&[("since", since)]);
+        }
+        Ok(check(self.send(request).await?)?.json().await?)
+    }
+
+    pub async fn create_modpack(
+        &self,
+        body: &ModpackCreateBody,
+    ) -> Result<ModpackCreateResponse, ClientError> {
+        let response = self
+            .send(self.client.post(self.url("modpack/create")?).json(body))
+            .await?;
+        if response.status() == StatusCode::BAD_REQUEST {
+            // The server answers with its error code as plain text
+            let status = response.status();
+            return Err(match response.text().await {
+                Ok(error) if error.trim() == "ALREADY_EXISTS" => ClientError::AlreadyExists,
+                _ => ClientError::Status(status),
+            });
+        }
+        Ok(check(response)?.json().await?)
+    }
+
+    pub async fn delete_modpack(&self, id: &ModpackId) -> Result<(), ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/delete", id.0))?),
+            )
+            .await?;
+        check(response)?;
+        Ok(())
+    }
+
+    pub async fn set_signature(
+        &self,
+        id: &ModpackId,
+        signature: Option<String>,
+    ) -> Result<(), ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/signature", id.0))?)
+                    .json(&ModpackSignatureBody { signature }),
+            )
+            .await?;
+        check(response)?;
+        Ok(())
+    }
+
+    pub async fn filesync(
+        &self,
+        id: &ModpackId,
+        body: &FileSyncBody,
+    ) -> Result<FileSyncResponse, ClientError> {
+        let body = serde_json::to_vec(body)?;
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/filesync", id.0))?)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(DIGEST_HEADER, body_digest(&body))
+                    .body(body),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Syncs several files in one transaction, for servers whose capabilities have `filesync_batch`
+    pub async fn filesync_batch(
+        &self,
+        id: &ModpackId,
+        body: &FileSyncBatchBody,
+    ) -> Result<FileSyncBatchResponse, ClientError> {
+        let body = serde_json::to_vec(body)?;
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/filesync/batch", id.0))?)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(DIGEST_HEADER, body_digest(&body))
+                    .body(body),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Uploads the content of a synced file. With `expected_hash`, the server rejects content
+    /// that doesn't hash to it instead of storing whatever arrived.
+    pub async fn upload(
+        &self,
+        id: &ModpackId,
+        file_path: &str,
+        expected_hash: Option<&str>,
+        data: Vec<u8>,
+    ) -> Result<FileUploadResponse, ClientError> {
+        let (content_type, body) = multipart_body(&data);
+        let mut query = vec![("file_path", file_path)];
+        if let Some(hash) = expected_hash {
+            query.push(("expected_hash", hash));
+        }
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/upload", id.0))?)
+                    .query(&query)
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(DIGEST_HEADER, body_digest(&body))
+                    .body(body),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Uploads content without attaching it to any file yet, named by its `checksum` hash
+    pub async fn upload_blob(
+        &self,
+        data: Vec<u8>,
+        checksum: Checksum,
+    ) -> Result<BlobUploadResponse, ClientError> {
+        let (content_type, body) = multipart_body(&data);
+        let response = self
+            .send(
+                self.client
+                    .post(self.url("blob/upload")?)
+                    .query(&[("algorithm", checksum.as_str())])
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(DIGEST_HEADER, body_digest(&body))
+                    .body(body),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Starts a chunked upload, for servers whose capabilities have `chunked_upload`
+    pub async fn init_upload(
+        &self,
+        id: &ModpackId,
+        body: &UploadInitBody,
+    ) -> Result<UploadStatusResponse, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/upload/init", id.0))?)
+                    .json(body),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Which chunks the server has, `NotFound` if the upload expired or never existed
+    pub async fn upload_status(
+        &self,
+        id: &ModpackId,
+        upload_id: &UploadId,
+    ) -> Result<UploadStatusResponse, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .get(self.url(&format!("modpack/{}/upload/{}", id.0, upload_id.0))?),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Sends chunk `n` with a digest, so the server rejects it right away if it got corrupted
+    pub async fn upload_chunk(
+        &self,
+        id: &ModpackId,
+        upload_id: &UploadId,
+        n: i32,
+        data: Vec<u8>,
+    ) -> Result<UploadStatusResponse, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .put(self.url(&format!(
+                        "modpack/{}/upload/{}/chunk/{}",
+                        id.0, upload_id.0, n
+                    ))?)
+                    .header(header::CONTENT_TYPE, "application/octet-stream")
+                    .header(DIGEST_HEADER, body_digest(&data))
+                    .body(data),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Completes a chunked upload once every chunk was sent
+    pub async fn finish_upload(
+        &self,
+        id: &ModpackId,
+        upload_id: &UploadId,
+    ) -> Result<UploadFinishResponse, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("modpack/{}/upload/{}/finish", id.0, upload_id.0))?),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Returns which of the given hashes the server has no content for
+    pub async fn missing_blobs(&self, hashes: Vec<String>) -> Result<Vec<String>, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url("blob/exists")?)
+                    .json(&BlobExistsBody { hashes }),
+            )
+            .await?;
+        Ok(check(response)?.json::<BlobExistsResponse>().await?.missing)
+    }
+
+    pub async fn usage(&self, id: &ModpackId) -> Result<ModpackUsageResponse, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .get(self.url(&format!("modpack/{}/usage", id.0))?),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// One page of the server's modpacks, needs a master key
+    pub async fn list_modpacks(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<ModpackListResponse, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .get(self.url("modpacks")?)
+                    .query(&[("limit", limit), ("offset", offset)]),
+            )
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Starts downloading a blob, the body is left to the caller to stream
+    pub async fn download(&self, hash: &str) -> Result<Response, ClientError> {
+        let response = self.send(self.download_request(hash)?).await?;
+        check(response)
+    }
+
+    fn download_request(&self, hash: &str) -> Result<RequestBuilder, ClientError> {
+        let mut request = self.client.get(self.url(&format!("dl/hash/{}", hash))?);
+        if let Some(modpack) = &self.modpack {
+            request = request.query(&[("modpack", &modpack.0)]);
+        }
+        Ok(request)
+    }
+
+    /// Downloads several small blobs in one request, in the order of `hashes`.
+    /// Every blob is checked against its `checksum` hash, blobs missing on the server are `None`.
+    pub async fn download_batch(
+        &self,
+        hashes: &[String],
+        checksum: Checksum,
+    ) -> Result<Vec<(String, Option<Vec<u8>>)>, ClientError> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url("dl/batch")?)
+                    .json(&BatchDownloadBody {
+                        hashes: hashes.to_vec(),
+                        modpack: self.modpack.clone(),
+                    }),
+            )
+            .await?;
+        let body = check(response)?.bytes().await?;
+
+        let mut blobs = Vec::new();
+        let mut rest = &body[..];
+        while !rest.is_empty() {
+            if rest.len() < 72 {
+                return Err(ClientError::MalformedBatch);
+            }
+            let hash = std::str::from_utf8(&rest[..64])
+                .map_err(|_| ClientError::MalformedBatch)?
+                .to_string();
+            let length = u64::from_be_bytes(rest[64..72].try_into().unwrap());
+            rest = &rest[72..];
+            if length == BATCH_DOWNLOAD_MISSING {
+                blobs.push((hash, None));
+                continue;
+            }
+            let length = usize::try_from(length).map_err(|_| ClientError::MalformedBatch)?;
+            if rest.len() < length {
+                return Err(ClientError::MalformedBatch);
+            }
+            let (data, remaining) = rest.split_at(length);
+            rest = remaining;
+            if checksum.hash_bytes(data) != hash {
+                return Err(ClientError::HashMismatch(hash));
+            }
+            blobs.push((hash, Some(data.to_vec())));
+        }
+        Ok(blobs)
+    }
+
+    /// Same as `download`, starting at byte `offset`. Servers ignoring the range answer
+    /// with the whole blob, check for `206 Partial Content` before appending.
+    pub async fn download_range(&self, hash: &str, offset: u64) -> Result<Response, ClientError> {
+        let response = self
+            .send(
+                self.download_request(hash)?
+                    .header(header::RANGE, format!("bytes={}-", offset)),
+            )
+            .await?;
+        check(response)
+    }
+
+    /// Sends a request, waiting out server maintenance and rate limits. Maintenance is a `503`
+    /// with a `Retry-After` header, any other `503` is returned right away like other errors.
+    /// GETs follow redirects, anything else fails with the URL the server moved to.
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let mut request = request.build()?;
+        let mut waited = Duration::ZERO;
+        let mut redirects = 0;
+        loop {
+            // Requests with a streamed body can't be cloned, and so can't be retried
+            let Some(attempt) = request.try_clone() else {
+                return self.execute(request).await;
+            };
+            let response = self.execute(attempt).await?;
+            if let Some(location) = redirect_location(&request, &response) {
+                if !matches!(*request.method(), Method::GET | Method::HEAD)
+                    || redirects >= MAX_REDIRECTS
+                {
+                    return Err(ClientError::Redirected(
+                        self.moved_server_url(&request, location),
+                    ));
+                }
+                redirects += 1;
+                *request.url_mut() = location;
+                continue;
+            }
+            let Some(retry_after) = retry_after(&response) else {
+                return Ok(response);
+            };
+            let limited = response.status() == StatusCode::TOO_MANY_REQUESTS;
+            if waited >= MAINTENANCE_MAX_WAIT {
+                return match limited {
+                    true => Ok(response),
+                    false => Err(ClientError::Maintenance(waited)),
+                };
+            }
+            let wait = retry_after.min(MAINTENANCE_MAX_WAIT - waited);
+            match limited {
+                true => warn!(
+                    "Rate limited by the server, retrying in {} seconds",
+                    wait.as_secs()
+                ),
+                false => warn!(
+                    "Server is in maintenance, retrying in {} seconds",
+                    wait.as_secs()
+                ),
+            }
+            tokio::time::sleep(wait).await;
+            waited += wait;
+        }
+    }
+
+    async fn execute(&self, mut request: Request) -> Result<Response, ClientError> {
+        if let Some(authorization) = &self.authorization {
+            if request.url().origin() == self.server_url.origin() {
+                request
+                    .headers_mut()
+                    .insert(header::AUTHORIZATION, authorization.clone());
+            }
+        }
+        Ok(self.client.execute(request).await?)
+    }
+
+    /// Where the server seems to live now, going by where it redirected `request` to
+    fn moved_server_url(&self, request: &Request, location: Url) -> String {
+        let endpoint = request
+            .url()
+            .as_str()
+            .strip_prefix(self.server_url.as_str())
+            .map(|x| x.split(['?', '#']).next().unwrap_or(x));
+        let mut base = location.clone();
+        base.set_query(None);
+        base.set_fragment(None);
+        match endpoint.and_then(|x| base.as_str().strip_suffix(x)) {
+            Some(moved) => moved.to_string(),
+            None => location.to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> Result<Url, ClientError> {
+        Ok(self.server_url.join(path)?)
+    }
+}
+
+/// Builds a single file multipart form by hand, so a digest of the exact body can be sent.
+/// Returns the content type and the body.
+fn multipart_body(data: &[u8]) -> (String, Vec<u8>) {
+    // Derived from the content, so the boundary practically can't occur in it
+    let boundary = format!("modsync-{}", &Checksum::Sha256.hash_bytes(data)[..32]);
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"upload\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        boundary
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// Target of a redirect response, resolved against the request URL
+fn redirect_location(request: &Request, response: &Response) -> Option<Url> {
+    if !response.status().is_redirection() || response.status() == StatusCode::NOT_MODIFIED {
+        return None;
+    }
+    let location = response.headers().get(header::LOCATION)?.to_str().ok()?;
+    request.url().join(location).ok()
+}
+
+/// How long to wait if the response says the server is in maintenance or rate limiting us
+fn retry_after(response: &Response) -> Option<Duration> {
+    if !matches!(
+        response.status(),
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+    ) {
+        return None;
+    }
+    let seconds = response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+fn check(response: Response) -> Result<Response, ClientError> {
+    match response.status() {
+        x if x.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err(ClientError::Unauthorized),
+        StatusCode::NOT_FOUND => Err(ClientError::NotFound),
+        x => Err(ClientError::Status(x)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers every request with `status`, extra `headers` lines and `body`,
+    /// returning a client for it
+    async fn answering(status: &str, headers: &str, body: &str) -> ModsyncApi {
+        let url = serve(status, headers, body).await;
+        ModsyncApi::new(&url, Some("secret")).unwrap()
+    }
+
+    /// Like `answering`, but returns the server's URL
+    async fn serve(status: &str, headers: &str, body: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let response = format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        );
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                // Every request here fits in one read
+                let mut buf = [0; 4096];
+                let _ = socket.read(&mut buf).await.unwrap();
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    fn modpack() -> ModpackId {
+        ModpackId("a".to_string())
+    }
+
+    fn create_body() -> ModpackCreateBody {
+        serde_json::from_value(serde_json::json!({
+            "name": "Pack",
+            "game": "minecraft",
+            "game_version": "1.20.1",
+            "modloader": "fabric",
+            "modloader_version": "0.15.0",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn statuses_map_to_their_errors() {
+        let api = answering("401 Unauthorized", "", "").await;
+        let result = api.get_modpack(&modpack()).await;
+        assert!(matches!(result, Err(ClientError::Unauthorized)));
+
+        let api = answering("404 Not Found", "", "").await;
+        let result = api.get_modpack(&modpack()).await;
+        assert!(matches!(result, Err(ClientError::NotFound)));
+
+        let api = answering("500 Internal Server Error", "", "").await;
+        let result = api.get_modpack(&modpack()).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+        ));
+
+        // Only maintenance and rate limiting say when to come back
+        let api = answering("503 Service Unavailable", "", "").await;
+        let result = api.get_modpack(&modpack()).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Status(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_optional_endpoints_arent_errors() {
+        let api = answering("404 Not Found", "", "").await;
+        assert!(api.capabilities().await.unwrap().is_none());
+        assert!(api.get_modpack_version(&modpack()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn taken_modpack_name_is_already_exists() {
+        let api = answering("400 Bad Request", "", "ALREADY_EXISTS").await;
+        let result = api.create_modpack(&create_body()).await;
+        assert!(matches!(result, Err(ClientError::AlreadyExists)));
+
+        let api = answering("400 Bad Request", "", "BAD_REQUEST").await;
+        let result = api.create_modpack(&create_body()).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Status(StatusCode::BAD_REQUEST))
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_body_is_a_request_error() {
+        let api = answering("200 OK", "Content-Type: application/json\r\n", "{").await;
+        let result = api.get_modpack(&modpack()).await;
+        assert!(matches!(result, Err(ClientError::Request(_))));
+    }
+
+    #[tokio::test]
+    async fn moved_server_tells_where_to_for_writes_and_is_followed_for_reads() {
+        let location = "Location: https://new.example/modsync/modpack/a/filesync?x=1\r\n";
+        let api = answering("301 Moved Permanently", location, "").await;
+        let body = FileSyncBody {
+            path: "mods/a.jar".to_string(),
+            state: crate::FileState::Exists,
+            hash: None,
+            mode: None,
+            mod_state: None,
+            download_source: None,
+            source_url: None,
+            size: None,
+        };
+        let Err(err) = api.filesync(&modpack(), &body).await else {
+            panic!("followed a redirect of a write");
+        };
+        assert_eq!(
+            err.to_string(),
+            "server redirected, update your server_url to https://new.example/modsync/"
+        );
+        assert_eq!(
+            crate::exit::exit_code([&err as &dyn std::error::Error]),
+            crate::exit::EXIT_CONFIG
+        );
+
+        let moved = serve(
+            "200 OK",
+            "Content-Type: application/json\r\n",
+            r#"{"sync_version":3,"cursor":7}"#,
+        )
+        .await;
+        let location = format!("Location: {}modpack/a/version\r\n", moved);
+        let api = answering("308 Permanent Redirect", &location, "").await;
+        let version = api.get_modpack_version(&modpack()).await.unwrap().unwrap();
+        assert_eq!((version.sync_version, version.cursor), (3, 7));
+    }
+
+    fn url(server_url: &str, path: &str) -> String {
+        let api = ModsyncApi::new(server_url, None).unwrap();
+        api.url(path).unwrap().to_string()
+    }
+
+    #[test]
+    fn joins_onto_bare_host() {
+        for server_url in ["https://host", "https://host/"] {
+            assert_eq!(
+                url(server_url, "modpack/abc/upload"),
+                "https://host/modpack/abc/upload"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_path_prefix() {
+        for server_url in ["https://host/prefix", "https://host/prefix/"] {
+            assert_eq!(url(server_url, "hello"), "https://host/prefix/hello");
+            assert_eq!(
+                url(server_url, "modpack/abc/upload"),
+                "https://host/prefix/modpack/abc/upload"
+            );
+        }
+        assert_eq!(
+            url("http://host:8080/a/b/", "dl/hash/ff"),
+            "http://host:8080/a/b/dl/hash/ff"
+        );
+    }
+
+    #[test]
+    fn no_double_slashes() {
+        for server_url in ["https://host/", "https://host/prefix/"] {
+            assert!(!url(server_url, "capabilities")
+                .trim_start_matches("https://")
+                .contains("//"));
+        }
+    }
+}
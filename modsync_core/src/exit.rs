@@ -0,0 +1,152 @@
+//! Exit codes shared by the client and the cli, so wrapping scripts can tell failures apart
+//!
+//! | Code | Meaning |
+//! |------|---------|
+//! | 0 | Success |
+//! | 1 | Any other error |
+//! | 2 | Invalid or missing config, or a wrong server URL or modpack |
+//! | 3 | The server rejected the API key or read token |
+//! | 4 | The server couldn't be reached or stayed unavailable |
+//! | 5 | Content or a signature didn't verify |
+//! | 6 | Failed halfway, some changes were already applied |
+
+use std::error::Error;
+
+use crate::{client::ClientError, signing::SignatureError};
+
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_CONFIG: u8 = 2;
+pub const EXIT_AUTH: u8 = 3;
+pub const EXIT_NETWORK: u8 = 4;
+pub const EXIT_VERIFY: u8 = 5;
+pub const EXIT_PARTIAL: u8 = 6;
+
+/// Failures without a typed error of their own, tagged with the exit code they map to
+#[derive(thiserror::Error, Debug)]
+pub enum ExitError {
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Verify(String),
+    #[error("{message}")]
+    Partial {
+        message: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+/// Exit code for an error, going by the outermost error in `chain` that has one
+pub fn exit_code<'a, I>(chain: I) -> u8
+where
+    I: IntoIterator<Item = &'a (dyn Error + 'static)>,
+{
+    chain.into_iter().find_map(classify).unwrap_or(EXIT_FAILURE)
+}
+
+fn classify(err: &(dyn Error + 'static)) -> Option<u8> {
+    if let Some(err) = err.downcast_ref::<ExitError>() {
+        return Some(match err {
+            ExitError::Config(_) => EXIT_CONFIG,
+            ExitError::Verify(_) => EXIT_VERIFY,
+            ExitError::Partial { .. } => EXIT_PARTIAL,
+        });
+    }
+    if err.is::<SignatureError>() {
+        return Some(EXIT_VERIFY);
+    }
+    if let Some(err) = err.downcast_ref::<ClientError>() {
+        return match err {
+            ClientError::Unauthorized => Some(EXIT_AUTH),
+            ClientError::NotFound
+            | ClientError::Url(_)
+            | ClientError::ApiKey(_)
+            | ClientError::Redirected(_) => Some(EXIT_CONFIG),
+            ClientError::MalformedBatch | ClientError::HashMismatch(_) => Some(EXIT_VERIFY),
+            ClientError::Maintenance(_) => Some(EXIT_NETWORK),
+            // Bad gateway, unavailable and gateway timeout, but not errors of the server itself
+            ClientError::Status(status) if (502..=504).contains(&status.as_u16()) => {
+                Some(EXIT_NETWORK)
+            }
+            ClientError::Request(err)
+                if err.is_connect() || err.is_timeout() || err.is_request() =>
+            {
+                Some(EXIT_NETWORK)
+            }
+            _ => None,
+        };
+    }
+    if err.is::<reqwest::Error>() {
+        return Some(EXIT_NETWORK);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    /// Code of `err`, with its sources
+    fn code(err: &(dyn Error + 'static)) -> u8 {
+        exit_code(std::iter::successors(Some(err), |&x| x.source()))
+    }
+
+    #[derive(thiserror::Error, Debug)]
+    #[error("while syncing")]
+    struct Context(#[source] ClientError);
+
+    #[test]
+    fn typed_errors_map_to_their_codes() {
+        for (err, expected) in [
+            (ExitError::Config("no config".to_string()), EXIT_CONFIG),
+            (ExitError::Verify("mismatch".to_string()), EXIT_VERIFY),
+        ] {
+            assert_eq!(code(&err), expected, "{}", err);
+        }
+        assert_eq!(code(&SignatureError::Mismatch), EXIT_VERIFY);
+        for (err, expected) in [
+            (ClientError::Unauthorized, EXIT_AUTH),
+            (ClientError::NotFound, EXIT_CONFIG),
+            (
+                ClientError::Redirected("https://new/".to_string()),
+                EXIT_CONFIG,
+            ),
+            (ClientError::HashMismatch("ff".to_string()), EXIT_VERIFY),
+            (ClientError::MalformedBatch, EXIT_VERIFY),
+            (ClientError::Maintenance(Default::default()), EXIT_NETWORK),
+            (ClientError::Status(StatusCode::BAD_GATEWAY), EXIT_NETWORK),
+            (
+                ClientError::Status(StatusCode::GATEWAY_TIMEOUT),
+                EXIT_NETWORK,
+            ),
+            (
+                ClientError::Status(StatusCode::INTERNAL_SERVER_ERROR),
+                EXIT_FAILURE,
+            ),
+            (ClientError::AlreadyExists, EXIT_FAILURE),
+        ] {
+            assert_eq!(code(&err), expected, "{}", err);
+        }
+        let err = std::io::Error::other("disk on fire");
+        assert_eq!(code(&err), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn outermost_error_with_a_code_wins() {
+        // Found through the sources of an error without a code
+        assert_eq!(code(&Context(ClientError::Unauthorized)), EXIT_AUTH);
+        let partial = ExitError::Partial {
+            message: "some files were applied".to_string(),
+            source: Box::new(ClientError::Unauthorized),
+        };
+        assert_eq!(code(&partial), EXIT_PARTIAL);
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_is_a_network_error() {
+        // Nothing listens on port 1
+        let err = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        assert_eq!(code(&ClientError::Request(err)), EXIT_NETWORK);
+    }
+}
@@ -1,16 +1,53 @@
 use serde::{Deserialize, Serialize};
 
 pub mod api;
+pub mod checksum;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod exit;
 pub mod models;
+pub mod signing;
 
 pub trait StrConversion {
-    fn from_str(value: &str) -> Self;
     fn as_str(&self) -> &'static str;
 }
 
-// pub trait TryFromStr {
-//     pub fn try_from_str(value: &str) -> Result<Self>;
-// }
+/// Parses the `StrConversion::as_str` form back, rejecting anything it doesn't know
+pub trait TryFromStr: Sized {
+    fn try_from_str(value: &str) -> Result<Self, ParseError>;
+}
+
+/// A string that isn't any variant of the enum it was parsed as
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: &'static str,
+    pub value: String,
+}
+
+impl ParseError {
+    pub fn new(kind: &'static str, value: &str) -> Self {
+        ParseError {
+            kind,
+            value: value.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "unknown {} {:?}", self.kind, self.value)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Lets database models use `?` on values read from text columns
+impl From<ParseError> for sqlx::Error {
+    fn from(err: ParseError) -> Self {
+        sqlx::Error::Decode(Box::new(err))
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum DownloadSource {
@@ -24,13 +61,6 @@ impl std::fmt::Display for DownloadSource {
     }
 }
 impl StrConversion for DownloadSource {
-    fn from_str(value: &str) -> Self {
-        match value {
-            "Modrinth" => Self::Modrinth,
-            _ => Self::ModsyncDl,
-        }
-    }
-
     fn as_str(&self) -> &'static str {
         match self {
             Self::ModsyncDl => "ModsyncDl",
@@ -38,6 +68,15 @@ impl StrConversion for DownloadSource {
         }
     }
 }
+impl TryFromStr for DownloadSource {
+    fn try_from_str(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "ModsyncDl" => Ok(Self::ModsyncDl),
+            "Modrinth" => Ok(Self::Modrinth),
+            _ => Err(ParseError::new("download source", value)),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum ModState {
@@ -53,15 +92,6 @@ impl std::fmt::Display for ModState {
     }
 }
 impl StrConversion for ModState {
-    fn from_str(value: &str) -> Self {
-        match value {
-            "Created" => Self::Created,
-            "Updated" => Self::Updated,
-            "Deleted" => Self::Deleted,
-            _ => Self::Ignored,
-        }
-    }
-
     fn as_str(&self) -> &'static str {
         match self {
             Self::Created => "Created",
@@ -71,12 +101,25 @@ impl StrConversion for ModState {
         }
     }
 }
+impl TryFromStr for ModState {
+    fn try_from_str(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "Created" => Ok(Self::Created),
+            "Updated" => Ok(Self::Updated),
+            "Deleted" => Ok(Self::Deleted),
+            "Ignored" => Ok(Self::Ignored),
+            _ => Err(ParseError::new("mod state", value)),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
 pub enum FileState {
     Exists,
     Deleted,
     Ignored,
+    /// An empty directory the pack needs, has no content
+    Directory,
 }
 
 impl std::fmt::Display for FileState {
@@ -85,20 +128,63 @@ impl std::fmt::Display for FileState {
     }
 }
 impl StrConversion for FileState {
-    fn from_str(value: &str) -> Self {
-        match value {
-            "Exists" => Self::Exists,
-            "Deleted" => Self::Deleted,
-            _ => Self::Ignored,
-        }
-    }
-
     fn as_str(&self) -> &'static str {
         match self {
             Self::Exists => "Exists",
             Self::Deleted => "Deleted",
             Self::Ignored => "Ignored",
+            Self::Directory => "Directory",
+        }
+    }
+}
+impl TryFromStr for FileState {
+    fn try_from_str(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "Exists" => Ok(Self::Exists),
+            "Deleted" => Ok(Self::Deleted),
+            "Ignored" => Ok(Self::Ignored),
+            "Directory" => Ok(Self::Directory),
+            _ => Err(ParseError::new("file state", value)),
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: StrConversion + TryFromStr + PartialEq>(variants: &[T]) {
+        for variant in variants {
+            let parsed = T::try_from_str(variant.as_str()).ok();
+            assert!(parsed.as_ref() == Some(variant), "{}", variant.as_str());
+        }
+    }
+
+    #[test]
+    fn stored_enums_round_trip() {
+        round_trip(&[DownloadSource::ModsyncDl, DownloadSource::Modrinth]);
+        round_trip(&[
+            ModState::Created,
+            ModState::Updated,
+            ModState::Deleted,
+            ModState::Ignored,
+        ]);
+        round_trip(&[
+            FileState::Exists,
+            FileState::Deleted,
+            FileState::Ignored,
+            FileState::Directory,
+        ]);
+    }
+
+    #[test]
+    fn unknown_strings_are_errors() {
+        for value in ["", "Bogus", "exists", "Exists ", "Modrinth2"] {
+            assert!(DownloadSource::try_from_str(value).is_err(), "{:?}", value);
+            assert!(ModState::try_from_str(value).is_err(), "{:?}", value);
+            let err = FileState::try_from_str(value).err().unwrap();
+            assert_eq!(err.kind, "file state");
+            assert_eq!(err.value, value);
+        }
+    }
+}
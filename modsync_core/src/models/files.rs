@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{api::{FileId, ModpackId}, FileState};
+use crate::{
+    api::{FileId, ModpackId},
+    DownloadSource, FileState, ModState,
+};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct File {
     pub id: FileId,
     pub modpack: ModpackId,
@@ -13,5 +16,19 @@ pub struct File {
     pub sync_version: i32,
     pub hash: Option<String>,
     pub uploaded: bool,
+    /// Size of the content in bytes, if known
+    #[serde(default)]
+    pub size: Option<i64>,
+    /// Unix permission bits as an octal string, e.g. `0755`
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// What the last push did to this file as a mod, unknown for files pushed by older clis
+    #[serde(default)]
+    pub mod_state: Option<ModState>,
+    /// Where to download the content from, `None` is the server's own blob
+    #[serde(default)]
+    pub download_source: Option<DownloadSource>,
+    /// Content URL for files with a `download_source`
+    #[serde(default)]
+    pub source_url: Option<String>,
 }
-
@@ -1,2 +1,2 @@
-pub mod modpacks;
 pub mod files;
+pub mod modpacks;
@@ -1,8 +1,8 @@
 use serde::{Deserialize, Serialize};
 
-use crate::api::ModpackId;
+use crate::{api::ModpackId, checksum::Checksum};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Modpack {
     pub id: ModpackId,
     pub name: String,
@@ -10,5 +10,10 @@ pub struct Modpack {
     pub modloader_version: Option<String>,
     pub game_version: Option<String>,
     pub sync_version: i32,
+    /// Base64 ed25519 signature over the file list, see `signing::modpack_payload`
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// How file hashes in this modpack are computed, older servers only use sha256
+    #[serde(default)]
+    pub hash_algorithm: Checksum,
 }
-
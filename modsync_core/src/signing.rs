@@ -0,0 +1,242 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::{api::ModpackId, models, FileState};
+
+/// Bumped whenever the signed payload layout changes
+const MANIFEST_PAYLOAD_VERSION: &[u8] = b"modsync-manifest-v1";
+
+#[derive(Debug)]
+pub enum SignatureError {
+    InvalidKey,
+    InvalidSignature,
+    Missing,
+    Mismatch,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidKey => write!(fmt, "invalid ed25519 key"),
+            Self::InvalidSignature => write!(fmt, "malformed modpack signature"),
+            Self::Missing => write!(fmt, "modpack is not signed"),
+            Self::Mismatch => write!(fmt, "modpack signature doesn't match its file list"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// A file as covered by the manifest signature
+pub struct SignedFile<'a> {
+    pub path: &'a str,
+    pub hash: Option<&'a str>,
+    pub mode: Option<&'a str>,
+}
+
+impl<'a> From<&'a models::files::File> for SignedFile<'a> {
+    fn from(x: &'a models::files::File) -> Self {
+        SignedFile {
+            path: &x.path,
+            hash: x.hash.as_deref(),
+            mode: x.mode.as_deref(),
+        }
+    }
+}
+
+/// Canonical bytes signed for a modpack: its id and every existing file sorted by path,
+/// each field length-prefixed so no path or hash can be crafted to collide with another list
+pub fn manifest_payload<'a, I>(modpack_id: &ModpackId, files: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = SignedFile<'a>>,
+{
+    let mut files: Vec<SignedFile> = files.into_iter().collect();
+    files.sort_by(|a, b| a.path.cmp(b.path));
+    let mut payload = MANIFEST_PAYLOAD_VERSION.to_vec();
+    push_field(&mut payload, Some(&modpack_id.0));
+    for file in files {
+        push_field(&mut payload, Some(file.path));
+        push_field(&mut payload, file.hash);
+        push_field(&mut payload, file.mode);
+    }
+    payload
+}
+
+fn push_field(payload: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            payload.extend_from_slice(&(value.len() as u64).to_be_bytes());
+            payload.extend_from_slice(value.as_bytes());
+        }
+        None => payload.extend_from_slice(&u64::MAX.to_be_bytes()),
+    }
+}
+
+/// Payload of the files a client would act on, deleted and ignored files aren't signed
+pub fn modpack_payload(modpack_id: &ModpackId, files: &[models::files::File]) -> Vec<u8> {
+    manifest_payload(
+        modpack_id,
+        files
+            .iter()
+            .filter(|x| x.state == FileState::Exists)
+            .map(SignedFile::from),
+    )
+}
+
+/// Derives a key pair from 32 random bytes, as base64 encoded (private, public) keys
+pub fn generate_key(secret: [u8; 32]) -> (String, String) {
+    let key = SigningKey::from_bytes(&secret);
+    (
+        BASE64_STANDARD.encode(key.to_bytes()),
+        BASE64_STANDARD.encode(key.verifying_key().to_bytes()),
+    )
+}
+
+pub fn sign_payload(private_key: &str, payload: &[u8]) -> Result<String, SignatureError> {
+    let key = SigningKey::from_bytes(&decode_key(private_key)?);
+    Ok(BASE64_STANDARD.encode(key.sign(payload).to_bytes()))
+}
+
+pub fn verify_payload(
+    public_key: &str,
+    signature: Option<&str>,
+    payload: &[u8],
+) -> Result<(), SignatureError> {
+    let key = VerifyingKey::from_bytes(&decode_key(public_key)?)
+        .map_err(|_| SignatureError::InvalidKey)?;
+    let signature = BASE64_STANDARD
+        .decode(signature.ok_or(SignatureError::Missing)?.trim())
+        .ok()
+        .and_then(|x| Signature::from_slice(&x).ok())
+        .ok_or(SignatureError::InvalidSignature)?;
+    key.verify(payload, &signature)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32], SignatureError> {
+    BASE64_STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|x| x.try_into().ok())
+        .ok_or(SignatureError::InvalidKey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::FileId;
+
+    fn file(path: &str, hash: &str, mode: Option<&str>, state: FileState) -> models::files::File {
+        models::files::File {
+            id: FileId(path.to_string()),
+            modpack: ModpackId("pack".to_string()),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            path: path.to_string(),
+            state,
+            sync_version: 1,
+            hash: Some(hash.to_string()),
+            uploaded: true,
+            size: None,
+            mode: mode.map(|x| x.to_string()),
+            mod_state: None,
+            download_source: None,
+            source_url: None,
+        }
+    }
+
+    fn files() -> Vec<models::files::File> {
+        vec![
+            file("mods/b.jar", "bbbb", None, FileState::Exists),
+            file("mods/a.jar", "aaaa", None, FileState::Exists),
+            file("run.sh", "cccc", Some("0755"), FileState::Exists),
+            file("mods/old.jar", "dddd", None, FileState::Deleted),
+        ]
+    }
+
+    /// Signs the file list the way the cli does on push, returns (public key, signature)
+    fn sign(files: &[models::files::File]) -> (String, String) {
+        let (private_key, public_key) = generate_key([7; 32]);
+        let id = ModpackId("pack".to_string());
+        let payload = manifest_payload(
+            &id,
+            files
+                .iter()
+                .filter(|x| x.state == FileState::Exists)
+                .map(|x| SignedFile {
+                    path: &x.path,
+                    hash: x.hash.as_deref(),
+                    mode: x.mode.as_deref(),
+                }),
+        );
+        (public_key, sign_payload(&private_key, &payload).unwrap())
+    }
+
+    fn verify(public_key: &str, signature: &str, files: &[models::files::File]) -> bool {
+        let payload = modpack_payload(&ModpackId("pack".to_string()), files);
+        verify_payload(public_key, Some(signature), &payload).is_ok()
+    }
+
+    #[test]
+    fn signed_file_list_verifies() {
+        let (public_key, signature) = sign(&files());
+        assert!(verify(&public_key, &signature, &files()));
+        // Order doesn't matter, the payload is sorted by path
+        let mut reversed = files();
+        reversed.reverse();
+        assert!(verify(&public_key, &signature, &reversed));
+    }
+
+    #[test]
+    fn changed_path_fails_verification() {
+        let (public_key, signature) = sign(&files());
+        let mut files = files();
+        files[0].path = "mods/evil.jar".to_string();
+        assert!(!verify(&public_key, &signature, &files));
+    }
+
+    #[test]
+    fn changed_hash_fails_verification() {
+        let (public_key, signature) = sign(&files());
+        let mut files = files();
+        files[1].hash = Some("eeee".to_string());
+        assert!(!verify(&public_key, &signature, &files));
+    }
+
+    #[test]
+    fn changed_mode_fails_verification() {
+        let (public_key, signature) = sign(&files());
+        let mut files = files();
+        files[2].mode = Some("0777".to_string());
+        assert!(!verify(&public_key, &signature, &files));
+        files[2].mode = None;
+        assert!(!verify(&public_key, &signature, &files));
+    }
+
+    #[test]
+    fn added_or_revived_file_fails_verification() {
+        let (public_key, signature) = sign(&files());
+        let mut files = files();
+        files[3].state = FileState::Exists;
+        assert!(!verify(&public_key, &signature, &files));
+    }
+
+    #[test]
+    fn other_key_or_missing_signature_fails() {
+        let (_, signature) = sign(&files());
+        let (_, other_key) = generate_key([8; 32]);
+        let payload = modpack_payload(&ModpackId("pack".to_string()), &files());
+        assert!(matches!(
+            verify_payload(&other_key, Some(&signature), &payload),
+            Err(SignatureError::Mismatch)
+        ));
+        assert!(matches!(
+            verify_payload(&other_key, None, &payload),
+            Err(SignatureError::Missing)
+        ));
+        assert!(matches!(
+            verify_payload(&other_key, Some("not base64!"), &payload),
+            Err(SignatureError::InvalidSignature)
+        ));
+    }
+}
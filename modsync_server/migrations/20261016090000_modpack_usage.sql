@@ -0,0 +1,5 @@
+CREATE TABLE modpack_usage (
+    modpack varchar(128) PRIMARY KEY REFERENCES modpacks(id) ON DELETE CASCADE,
+    download_bytes bigint NOT NULL DEFAULT 0,
+    upload_bytes bigint NOT NULL DEFAULT 0
+);
@@ -0,0 +1,17 @@
+CREATE SEQUENCE file_change_seq;
+
+ALTER TABLE files ADD COLUMN change_seq bigint NOT NULL DEFAULT nextval('file_change_seq');
+CREATE INDEX i_files_modpack_change_seq ON files (modpack, change_seq);
+
+-- Cursors older than this can't be answered with a delta (files were hard-deleted since)
+ALTER TABLE modpacks ADD COLUMN cursor_floor bigint NOT NULL DEFAULT 0;
+
+CREATE FUNCTION files_bump_change_seq() RETURNS trigger AS $$
+BEGIN
+    NEW.change_seq := nextval('file_change_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER t_files_change_seq BEFORE UPDATE ON files
+    FOR EACH ROW EXECUTE FUNCTION files_bump_change_seq();
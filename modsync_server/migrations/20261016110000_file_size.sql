@@ -0,0 +1,2 @@
+-- Size in bytes of the uploaded content, unknown for rows uploaded before this was tracked
+ALTER TABLE files ADD COLUMN size bigint;
@@ -0,0 +1,2 @@
+-- Unix permission bits as an octal string, e.g. "0755"
+ALTER TABLE files ADD COLUMN mode text;
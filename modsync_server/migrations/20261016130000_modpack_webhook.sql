@@ -0,0 +1,2 @@
+-- Receives a POST with a JSON event after every change to the modpack
+ALTER TABLE modpacks ADD COLUMN webhook_url text;
@@ -0,0 +1,2 @@
+-- Base64 ed25519 signature over the file list, made by the cli with the publisher's key
+ALTER TABLE modpacks ADD COLUMN signature text;
@@ -0,0 +1,2 @@
+-- Client supplied idempotency key of the create request, lets a retried create return the same modpack
+ALTER TABLE modpacks ADD COLUMN create_request_id varchar(128) UNIQUE;
@@ -0,0 +1,2 @@
+-- Top level directories files may be synced into, NULL allows any path
+ALTER TABLE modpacks ADD COLUMN allowed_roots text[];
@@ -0,0 +1,9 @@
+-- Keys limited to a single modpack, only their hash is stored
+CREATE TABLE modpack_keys (
+    id varchar(128) PRIMARY KEY,
+    modpack varchar(128) NOT NULL REFERENCES modpacks(id) ON DELETE CASCADE,
+    key_hash text UNIQUE NOT NULL,
+    scope text NOT NULL,
+    created_at timestamp with time zone NOT NULL
+);
+CREATE INDEX i_modpack_keys_modpack ON modpack_keys (modpack);
@@ -0,0 +1,2 @@
+-- Existing modpacks keep sha256, new ones may pick blake3
+ALTER TABLE modpacks ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'sha256';
@@ -0,0 +1,2 @@
+-- What the last push did to the file as a mod, NULL for files pushed by older clis
+ALTER TABLE files ADD COLUMN mod_state text;
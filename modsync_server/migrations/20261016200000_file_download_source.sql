@@ -0,0 +1,3 @@
+-- Files clients download from elsewhere than this server, e.g. Modrinth's CDN
+ALTER TABLE files ADD COLUMN download_source text;
+ALTER TABLE files ADD COLUMN source_url text;
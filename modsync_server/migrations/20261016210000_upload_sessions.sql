@@ -0,0 +1,13 @@
+-- Chunked uploads in progress, their content is in `<id>.part` in the uploads directory
+CREATE TABLE upload_sessions (
+    id varchar(128) PRIMARY KEY,
+    modpack varchar(128) NOT NULL REFERENCES modpacks(id) ON DELETE CASCADE,
+    file_path text,
+    hash text NOT NULL,
+    size bigint NOT NULL,
+    chunk_size bigint NOT NULL,
+    next_chunk integer NOT NULL DEFAULT 0,
+    received_bytes bigint NOT NULL DEFAULT 0,
+    created_at timestamp with time zone NOT NULL DEFAULT now(),
+    updated_at timestamp with time zone NOT NULL DEFAULT now()
+);
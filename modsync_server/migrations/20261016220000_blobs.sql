@@ -0,0 +1,37 @@
+-- Stored blobs and how many uploaded files point to each, `gc` removes those at zero
+CREATE TABLE blobs (
+    hash text PRIMARY KEY,
+    refcount integer NOT NULL DEFAULT 0,
+    size bigint,
+    -- When the last reference went away, or the blob was stored ahead of its filesync
+    released_at timestamp with time zone
+);
+CREATE INDEX i_blobs_released_at ON blobs (released_at) WHERE refcount = 0;
+
+INSERT INTO blobs (hash, refcount, size)
+    SELECT hash, count(*), max(size) FROM files
+    WHERE uploaded = true AND hash IS NOT NULL GROUP BY hash;
+
+CREATE FUNCTION files_count_blob_references() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        IF OLD.uploaded AND OLD.hash IS NOT NULL THEN
+            UPDATE blobs SET refcount = refcount - 1,
+                released_at = CASE WHEN refcount = 1 THEN now() ELSE released_at END
+            WHERE hash = OLD.hash;
+        END IF;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        IF NEW.uploaded AND NEW.hash IS NOT NULL THEN
+            INSERT INTO blobs (hash, refcount, size) VALUES (NEW.hash, 1, NEW.size)
+            ON CONFLICT (hash) DO UPDATE SET refcount = blobs.refcount + 1,
+                size = coalesce(blobs.size, EXCLUDED.size), released_at = NULL;
+        END IF;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Also fires for files removed along with their modpack
+CREATE TRIGGER t_files_blob_references AFTER INSERT OR UPDATE OF hash, uploaded OR DELETE ON files
+    FOR EACH ROW EXECUTE FUNCTION files_count_blob_references();
@@ -0,0 +1,169 @@
+use clap::Parser;
+
+use crate::server::{config_path, load_config, ConfigSources, ServerConfig};
+
+/// Print the resolved server config and where each value came from, without starting the server
+#[derive(Parser, Debug)]
+pub struct ConfigCommand {}
+
+impl ConfigCommand {
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let (config, sources) = load_config()?;
+
+        println!("# Resolved from env, {} and defaults", config_path());
+        for line in config_lines(&config, &sources) {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+}
+
+/// Every value of `config` as `name = value (source)`, secrets masked
+fn config_lines(config: &ServerConfig, sources: &ConfigSources) -> Vec<String> {
+    let values = [
+        ("database_url", redact_url(&config.database_url)),
+        (
+            "master_key",
+            format!("*** ({} key(s))", config.master_keys.len()),
+        ),
+        ("port", config.port.to_string()),
+        ("bind_address", config.bind_address.to_string()),
+        ("uploads_directory", config.uploads_directory.clone()),
+        ("file_size_limit", config.file_size_limit.to_string()),
+        ("json_body_limit", config.json_body_limit.to_string()),
+        ("modpack_cache_ttl", config.modpack_cache_ttl.to_string()),
+        (
+            "verify_blobs_on_startup",
+            config.verify_blobs_on_startup.to_string(),
+        ),
+        (
+            "read_tokens",
+            format!("{} token(s)", config.read_tokens.len()),
+        ),
+        ("require_read_token", config.require_read_token.to_string()),
+        ("max_path_length", config.max_path_length.to_string()),
+        (
+            "max_path_components",
+            config.max_path_components.to_string(),
+        ),
+        ("blob_extensions", config.blob_extensions.to_string()),
+        ("maintenance", config.maintenance.to_string()),
+        (
+            "maintenance_retry_after",
+            config.maintenance_retry_after.to_string(),
+        ),
+        (
+            "download_concurrency",
+            config.download_concurrency.to_string(),
+        ),
+        (
+            "download_buffer_size",
+            config.download_buffer_size.to_string(),
+        ),
+        ("upload_chunk_size", config.upload_chunk_size.to_string()),
+        ("shutdown_timeout", config.shutdown_timeout.to_string()),
+        (
+            "request_timeout_secs",
+            config.request_timeout_secs.to_string(),
+        ),
+        (
+            "tls_cert_path",
+            config.tls_cert_path.clone().unwrap_or("none".to_string()),
+        ),
+        (
+            "tls_key_path",
+            config.tls_key_path.clone().unwrap_or("none".to_string()),
+        ),
+        (
+            "transfer_timeout_secs",
+            config
+                .transfer_timeout_secs
+                .map_or("none".to_string(), |x| x.to_string()),
+        ),
+        ("metrics", config.metrics.to_string()),
+        (
+            "metrics_require_key",
+            config.metrics_require_key.to_string(),
+        ),
+        (
+            "allowed_origins",
+            match config.allowed_origins.is_empty() {
+                true => "none".to_string(),
+                false => config.allowed_origins.join(", "),
+            },
+        ),
+        (
+            "rate_limit_requests",
+            config.rate_limit_requests.to_string(),
+        ),
+        (
+            "rate_limit_window_secs",
+            config.rate_limit_window_secs.to_string(),
+        ),
+        (
+            "webhook_allow_private",
+            config.webhook_allow_private.to_string(),
+        ),
+    ];
+    values
+        .into_iter()
+        .map(|(name, value)| match sources.get(name) {
+            Some(source) => format!("{} = {} ({})", name, value, source),
+            None => format!("{} = {}", name, value),
+        })
+        .collect()
+}
+
+/// Masks the password of a database URL
+pub fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        Ok(_) => url.to_string(),
+        // Don't risk printing a password we failed to find
+        Err(_) => "***".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::{config_from_file, ConfigSource, MasterKeys, ServerConfigFile};
+
+    use super::*;
+
+    #[test]
+    fn every_value_is_printed_with_its_source() {
+        let file = ServerConfigFile {
+            database_url: Some("postgres://modsync:hunter2@db/modsync".to_string()),
+            master_key: Some(MasterKeys::One("secret".to_string())),
+            json_body_limit: Some(1234),
+            ..Default::default()
+        };
+        let (config, mut sources) = config_from_file(file).unwrap();
+        // As if MODSYNC_PORT was set
+        sources
+            .0
+            .insert(0, ("port", ConfigSource::Env("MODSYNC_PORT")));
+
+        let lines = config_lines(&config, &sources);
+        let line = |name: &str| {
+            let prefix = format!("{} = ", name);
+            lines
+                .iter()
+                .find(|x| x.starts_with(&prefix))
+                .unwrap()
+                .clone()
+        };
+        assert!(line("port").ends_with("(env MODSYNC_PORT)"));
+        assert_eq!(
+            line("json_body_limit"),
+            format!("json_body_limit = 1234 ({})", config_path())
+        );
+        assert_eq!(line("modpack_cache_ttl"), "modpack_cache_ttl = 5 (default)");
+        assert!(!lines
+            .iter()
+            .any(|x| x.contains("hunter2") || x.contains("secret")));
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::server::{
+    blobs, connect_database, load_config,
+    models::{blobs::Blob, uploads::UploadSession},
+};
+
+/// Remove blobs no file has referred to for a while and expired chunked uploads, safe to run
+/// while the server is up
+#[derive(Parser, Debug)]
+pub struct GcCommand {
+    /// List the blobs that would be removed without removing them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keep blobs unreferenced for less than this many minutes, they may be part of a sync in progress
+    #[arg(long, default_value_t = 60)]
+    min_age: u64,
+
+    /// Also look through the uploads directory, for blobs stored before they were counted and
+    /// leftovers of failed uploads
+    #[arg(long)]
+    scan: bool,
+}
+
+impl GcCommand {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let (config, _) = load_config()?;
+        let pool = connect_database(&config, 1).await?;
+        let min_age = Duration::from_secs(self.min_age * 60);
+        let cutoff = chrono::Utc::now() - min_age;
+
+        let mut removed = 0;
+        let mut reclaimed: u64 = 0;
+        if self.scan {
+            // Read before listing the directory, so a blob stored in between is still recent
+            let known = Blob::hashes(&pool).await?;
+            let uploads = UploadSession::live_ids(&pool)
+                .await?
+                .into_iter()
+                .map(|x| x.0)
+                .collect();
+            let strays = blobs::stray_files(&config.uploads_directory, &known, &uploads, min_age)?;
+            let mut adopted = 0;
+            for (path, size, modified) in strays {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if let Some(hash) = blobs::blob_hash(&name) {
+                    // Counted from now on, and removed below with the other released blobs
+                    if self.dry_run {
+                        println!("{} ({} bytes)", path.to_string_lossy(), size);
+                        removed += 1;
+                        reclaimed += size;
+                    } else if Blob::adopt(hash, size as i64, modified.into(), &pool).await? {
+                        adopted += 1;
+                    }
+                    continue;
+                }
+                if self.dry_run {
+                    println!("{} ({} bytes)", path.to_string_lossy(), size);
+                } else if let Err(err) = std::fs::remove_file(&path) {
+                    eprintln!("Failed to remove {}: {}", path.to_string_lossy(), err);
+                    continue;
+                }
+                removed += 1;
+                reclaimed += size;
+            }
+            if adopted > 0 {
+                println!("Found {} blob(s) that weren't counted yet", adopted);
+            }
+        }
+
+        if !self.dry_run {
+            let expired = UploadSession::delete_expired(&pool).await?;
+            for id in expired.iter() {
+                let path = blobs::partial_upload_path(&config.uploads_directory, &id.0);
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    if std::fs::remove_file(&path).is_ok() {
+                        reclaimed += metadata.len();
+                    }
+                }
+            }
+            if !expired.is_empty() {
+                println!("Removed {} expired chunked upload(s)", expired.len());
+            }
+        }
+
+        for blob in Blob::released_before(cutoff, &pool).await? {
+            let size = match blob.size {
+                Some(size) => size as u64,
+                None => blobs::search_blob(&config.uploads_directory, &blob.hash)?
+                    .iter()
+                    .map(|x| std::fs::metadata(x).map(|x| x.len()))
+                    .sum::<Result<u64, _>>()?,
+            };
+            if self.dry_run {
+                println!("{} ({} bytes)", blob.hash, size);
+                removed += 1;
+                reclaimed += size;
+                continue;
+            }
+            // The row stays locked until the blob is gone, nothing can reference it meanwhile
+            let mut tx = pool.begin().await?;
+            if !Blob::delete_released(&blob.hash, cutoff, &mut *tx).await? {
+                continue;
+            }
+            if let Err(err) = blobs::remove_blob(&config.uploads_directory, &blob.hash) {
+                eprintln!("Failed to remove blob {}: {}", blob.hash, err);
+                continue;
+            }
+            tx.commit().await?;
+            removed += 1;
+            reclaimed += size;
+        }
+
+        match self.dry_run {
+            true => println!(
+                "{} unreferenced blob(s), {} bytes would be reclaimed",
+                removed, reclaimed
+            ),
+            false => println!("Removed {} blob(s), reclaimed {} bytes", removed, reclaimed),
+        }
+        Ok(())
+    }
+}
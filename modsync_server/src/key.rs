@@ -0,0 +1,78 @@
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use modsync_core::api::ModpackId;
+use sqlx::PgPool;
+
+use crate::{
+    init::generate_master_key,
+    server::{
+        connect_database, load_config,
+        models::{
+            keys::{KeyScope, ModpackKey},
+            modpacks::Modpack,
+        },
+    },
+};
+
+/// Manage keys limited to a single modpack
+#[derive(Parser, Debug)]
+pub struct KeyCommand {
+    #[command(subcommand)]
+    action: KeyAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyAction {
+    /// Mint a key for a modpack and print it, it can't be shown again
+    Create {
+        modpack_id: String,
+        #[arg(long, value_enum, default_value_t = KeyScope::Write)]
+        scope: KeyScope,
+    },
+    /// Revoke a key by its id, as shown by `list`
+    Revoke { key_id: String },
+    /// List the keys of a modpack
+    List { modpack_id: String },
+}
+
+impl KeyCommand {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let (config, _) = load_config()?;
+        let pool = connect_database(&config, 1).await?;
+
+        match &self.action {
+            KeyAction::Create { modpack_id, scope } => create(&pool, modpack_id, *scope).await,
+            KeyAction::Revoke { key_id } => {
+                if !ModpackKey::delete(key_id, &pool).await? {
+                    return Err(anyhow!("No key with id {}", key_id));
+                }
+                println!("Key {} revoked", key_id);
+                Ok(())
+            }
+            KeyAction::List { modpack_id } => {
+                for key in ModpackKey::list(&ModpackId(modpack_id.clone()), &pool).await? {
+                    println!(
+                        "{} {} created {}",
+                        key.id,
+                        key.scope.as_str(),
+                        key.created_at.to_rfc3339()
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn create(pool: &PgPool, modpack_id: &str, scope: KeyScope) -> anyhow::Result<()> {
+    let modpack_id = ModpackId(modpack_id.to_string());
+    if Modpack::get_optional(&modpack_id, pool).await?.is_none() {
+        return Err(anyhow!("No modpack with id {}", modpack_id.0));
+    }
+    let key = generate_master_key();
+    let id = ModpackKey::insert(&modpack_id, &key, scope, pool).await?;
+    println!("Key {} created with {} scope", id, scope.as_str());
+    println!("The key is: {}", key);
+    println!("Save it now, only its hash is stored.");
+    Ok(())
+}
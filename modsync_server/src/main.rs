@@ -1,9 +1,33 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use config::ConfigCommand;
+use gc::GcCommand;
+use init::InitCommand;
+use key::KeyCommand;
 use server::ServeCommand;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+mod config;
+mod gc;
+mod init;
+mod key;
 mod server;
 
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    commands: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    Serve(ServeCommand),
+    Init(InitCommand),
+    Config(ConfigCommand),
+    Key(KeyCommand),
+    Gc(GcCommand),
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
@@ -12,6 +36,13 @@ async fn main() -> anyhow::Result<()> {
         .with(EnvFilter::from_default_env())
         .init();
 
-    let mut serve = ServeCommand::parse();
-    serve.run().await
+    let args = Args::parse();
+
+    match args.commands.unwrap_or(Commands::Serve(ServeCommand {})) {
+        Commands::Serve(mut serve) => serve.run().await,
+        Commands::Init(mut init) => init.run(),
+        Commands::Config(mut config) => config.run(),
+        Commands::Key(mut key) => key.run().await,
+        Commands::Gc(mut gc) => gc.run().await,
+    }
 }
@@ -0,0 +1,450 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use modsync_core::checksum::{Checksum, Hasher};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// Checks that every blob in the uploads directory hashes to its filename, under any of
+/// the algorithms modpacks may use. Hashing is spread over all available cores. Returns the paths of mismatching blobs.
+pub fn verify_blobs<P>(uploads_directory: P) -> Result<Vec<PathBuf>, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    let mut blobs: Vec<PathBuf> = Vec::new();
+    for entry in std::fs::read_dir(uploads_directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && blob_hash(&entry.file_name().to_string_lossy()).is_some()
+        {
+            blobs.push(entry.path());
+        }
+    }
+
+    let threads = std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1);
+    let chunk_size = blobs.len().div_ceil(threads).max(1);
+    let mismatches: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = blobs
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let mismatches = &mismatches;
+                scope.spawn(move || -> Result<(), std::io::Error> {
+                    for blob in chunk {
+                        let name = blob.file_name().unwrap_or_default().to_string_lossy();
+                        let mut matched = false;
+                        for checksum in Checksum::ALL {
+                            if Some(hash_file(blob, checksum)?.as_str()) == blob_hash(&name) {
+                                matched = true;
+                                break;
+                            }
+                        }
+                        if !matched {
+                            mismatches.lock().unwrap().push(blob.clone());
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|x| x.join().expect("blob verification thread panicked"))
+    })?;
+
+    Ok(mismatches.into_inner().unwrap())
+}
+
+/// Blobs whose hash isn't in `known`, temporary files of failed uploads and partial content
+/// of chunked uploads not in `uploads`, with their sizes and modification times. Anything
+/// modified within `min_age` is left out, it may belong to an upload in progress.
+pub fn stray_files<P>(
+    uploads_directory: P,
+    known: &HashSet<String>,
+    uploads: &HashSet<String>,
+    min_age: Duration,
+) -> Result<Vec<(PathBuf, u64, SystemTime)>, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    let now = SystemTime::now();
+    let mut strays = Vec::new();
+    for entry in std::fs::read_dir(uploads_directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let orphaned = match blob_hash(&name) {
+            Some(hash) => !known.contains(hash),
+            None => {
+                is_temporary_upload(&name)
+                    || partial_upload_id(&name).is_some_and(|x| !uploads.contains(x))
+            }
+        };
+        let modified = metadata.modified()?;
+        let age = now.duration_since(modified).unwrap_or_default();
+        if orphaned && age >= min_age {
+            strays.push((entry.path(), metadata.len(), modified));
+        }
+    }
+    Ok(strays)
+}
+
+/// `<uuid>.tmp`, as written by `BlobWriter` before renaming into place, or
+/// `<hash>.<uuid>.tmp` as older versions wrote them
+fn is_temporary_upload(file_name: &str) -> bool {
+    let Some(name) = file_name.strip_suffix(".tmp") else {
+        return false;
+    };
+    match name.split_once('.') {
+        Some((hash, id)) => is_blob_name(hash) && Uuid::parse_str(id).is_ok(),
+        None => Uuid::parse_str(name).is_ok(),
+    }
+}
+
+/// Where the chunks of an upload in progress are appended to
+pub fn partial_upload_path<P>(uploads_directory: P, upload_id: &str) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    uploads_directory
+        .as_ref()
+        .join(format!("{}.part", upload_id))
+}
+
+/// Upload id of a `<uuid>.part` file name
+fn partial_upload_id(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_suffix(".part")
+        .filter(|x| Uuid::parse_str(x).is_ok())
+}
+
+/// Moves a file already hashed to `hash`, e.g. a finished chunked upload, into the
+/// uploads directory under `extension`. It's removed instead if the blob is already stored
+/// under it, bare, or under one of `known_extensions`, see `File::blob_extensions`.
+pub async fn store_blob_file<P>(
+    uploads_directory: P,
+    source: &Path,
+    hash: &str,
+    extension: Option<&str>,
+    known_extensions: &[String],
+) -> Result<(), std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    let extensions: Vec<String> = known_extensions
+        .iter()
+        .cloned()
+        .chain(extension.map(|x| x.to_string()))
+        .collect();
+    if find_blob(&uploads_directory, hash, &extensions)?.is_some() {
+        return tokio::fs::remove_file(source).await;
+    }
+    let path =
+        uploads_directory
+            .as_ref()
+            .join(format!("{}{}", hash, extension.unwrap_or_default()));
+    tokio::fs::rename(source, &path).await
+}
+
+/// Streams an upload into a temporary file in the uploads directory, hashing it on the way,
+/// so memory use doesn't grow with the upload. The file is removed if it's never finished.
+pub struct BlobWriter {
+    uploads_directory: PathBuf,
+    temp_file: TempFile,
+    file: tokio::fs::File,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub async fn create<P>(uploads_directory: P, checksum: Checksum) -> Result<Self, std::io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        // The hash isn't known yet, `finish` renames it once it is
+        let temp_path = uploads_directory
+            .as_ref()
+            .join(format!("{}.tmp", Uuid::new_v4()));
+        let file = tokio::fs::File::create(&temp_path).await?;
+        Ok(BlobWriter {
+            uploads_directory: uploads_directory.as_ref().to_path_buf(),
+            temp_file: TempFile(Some(temp_path)),
+            file,
+            hasher: checksum.hasher(),
+            size: 0,
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        self.file.write_all(data).await
+    }
+
+    /// Hashes the content, it stays in the temporary file until `HashedBlob::store`
+    pub async fn finish(self) -> Result<HashedBlob, std::io::Error> {
+        self.file.sync_all().await?;
+        Ok(HashedBlob {
+            uploads_directory: self.uploads_directory,
+            temp_file: self.temp_file,
+            hash: self.hasher.finalize(),
+            size: self.size,
+        })
+    }
+}
+
+/// Content written by `BlobWriter`, hashed but not stored yet
+pub struct HashedBlob {
+    uploads_directory: PathBuf,
+    temp_file: TempFile,
+    pub hash: String,
+    pub size: u64,
+}
+
+impl HashedBlob {
+    /// Moves the content into place under its hash and `extension`, unless it's already
+    /// stored, see `store_blob_file`. Register the blob first, see `models::blobs::Blob::register`.
+    pub async fn store(
+        mut self,
+        extension: Option<&str>,
+        known_extensions: &[String],
+    ) -> Result<(), std::io::Error> {
+        let temp_path = self
+            .temp_file
+            .0
+            .as_deref()
+            .expect("temporary file is kept until here");
+        store_blob_file(
+            &self.uploads_directory,
+            temp_path,
+            &self.hash,
+            extension,
+            known_extensions,
+        )
+        .await?;
+        self.temp_file.0 = None;
+        Ok(())
+    }
+}
+
+/// Removes a temporary file when dropped, unless its path was taken out
+struct TempFile(Option<PathBuf>);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Extension to store a blob for `path` under, e.g. `.jar`. Only short alphanumeric
+/// extensions are kept, anything else is stored without one.
+pub fn blob_extension(path: &str) -> Option<String> {
+    let extension = Path::new(path).extension()?.to_str()?;
+    if extension.is_empty()
+        || extension.len() > 16
+        || !extension.chars().all(|x| x.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+    Some(format!(".{}", extension))
+}
+
+/// Finds where a blob is stored: under its bare hash or with one of `extensions`, e.g. those
+/// of the files with it, see `File::blob_extensions`. Invalid hashes are never stored.
+pub fn find_blob<P>(
+    uploads_directory: P,
+    hash: &str,
+    extensions: &[String],
+) -> Result<Option<PathBuf>, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    if !is_blob_name(hash) {
+        return Ok(None);
+    }
+    let uploads_directory = uploads_directory.as_ref();
+    let bare = uploads_directory.join(hash);
+    if std::fs::exists(&bare)? {
+        return Ok(Some(bare));
+    }
+    for extension in extensions {
+        let path = uploads_directory.join(format!("{}{}", hash, extension));
+        if std::fs::exists(&path)? {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Every stored copy of a blob, whatever its extension. Reads the whole uploads directory,
+/// so it's only for maintenance like `gc`.
+pub fn search_blob<P>(uploads_directory: P, hash: &str) -> Result<Vec<PathBuf>, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(uploads_directory)? {
+        let entry = entry?;
+        if blob_hash(&entry.file_name().to_string_lossy()) == Some(hash) {
+            found.push(entry.path());
+        }
+    }
+    Ok(found)
+}
+
+/// Removes every stored copy of a blob, see `search_blob`
+pub fn remove_blob<P>(uploads_directory: P, hash: &str) -> Result<(), std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    for path in search_blob(uploads_directory, hash)? {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Size of a stored blob, `None` if there is no such blob. See `find_blob` for `extensions`.
+pub fn blob_size<P>(
+    uploads_directory: P,
+    hash: &str,
+    extensions: &[String],
+) -> Result<Option<u64>, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    match find_blob(uploads_directory, hash, extensions)? {
+        Some(path) => Ok(Some(std::fs::metadata(path)?.len())),
+        None => Ok(None),
+    }
+}
+
+pub fn hash_file<P>(path: P, checksum: Checksum) -> Result<String, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    checksum.hash_reader(&mut File::open(path)?)
+}
+
+/// Hash of a stored blob from its file name, which may carry an extension
+pub fn blob_hash(file_name: &str) -> Option<&str> {
+    let (hash, extension) = match file_name.split_once('.') {
+        Some((hash, extension)) => (hash, Some(extension)),
+        None => (file_name, None),
+    };
+    let valid_extension =
+        extension.is_none_or(|x| !x.is_empty() && x.chars().all(|x| x.is_ascii_alphanumeric()));
+    (is_blob_name(hash) && valid_extension).then_some(hash)
+}
+
+/// Blobs are named by their hash as 64 lowercase hex digits, which is what every
+/// `Checksum` algorithm produces
+pub fn is_blob_name(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|x| matches!(x, '0'..='9' | 'a'..='f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn plain_hashes_are_blob_names() {
+        assert!(is_blob_name(HASH));
+        assert_eq!(blob_hash(HASH), Some(HASH));
+        assert_eq!(blob_hash(&format!("{}.jar", HASH)), Some(HASH));
+    }
+
+    #[test]
+    fn traversal_payloads_are_not_blob_names() {
+        let payloads = [
+            "../../etc/passwd".to_string(),
+            "..\\..\\etc\\passwd".to_string(),
+            "/etc/passwd".to_string(),
+            format!("../{}", &HASH[3..]),
+            format!("{}/..", &HASH[..61]),
+            format!("%2e%2e%2f{}", &HASH[9..]),
+            HASH.to_uppercase(),
+            HASH[1..].to_string(),
+            String::new(),
+        ];
+        for payload in payloads {
+            assert!(!is_blob_name(&payload), "{:?}", payload);
+            assert_eq!(blob_hash(&payload), None, "{:?}", payload);
+        }
+        assert_eq!(blob_hash(&format!("{}./../x", HASH)), None);
+        assert_eq!(blob_hash(&format!("{}.", HASH)), None);
+    }
+
+    #[test]
+    fn corrupted_blobs_fail_verification() {
+        let dir = std::env::temp_dir().join(format!("modsync-verify-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sha256 = Checksum::Sha256.hash_bytes(b"sha256 blob");
+        let blake3 = Checksum::Blake3.hash_bytes(b"blake3 blob");
+        std::fs::write(dir.join(&sha256), b"sha256 blob").unwrap();
+        std::fs::write(dir.join(format!("{}.jar", blake3)), b"blake3 blob").unwrap();
+        let corrupted = dir.join(format!("{}.jar", Checksum::Sha256.hash_bytes(b"mod")));
+        std::fs::write(&corrupted, b"mod, but bitrotten").unwrap();
+        // Not a blob, so not checked
+        std::fs::write(dir.join("notes.txt"), b"anything").unwrap();
+
+        assert_eq!(verify_blobs(&dir).unwrap(), [corrupted]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn uploads_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("modsync-{}-{}", name, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn stored_names(dir: &Path) -> Vec<String> {
+        let entries = std::fs::read_dir(dir).unwrap().map(|x| x.unwrap());
+        entries
+            .map(|x| x.file_name().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn unfinished_blobs_leave_nothing_behind() {
+        let dir = uploads_dir("unfinished-blob");
+        let mut blob = BlobWriter::create(&dir, Checksum::Sha256).await.unwrap();
+        blob.write(b"half of it").await.unwrap();
+        // Like an upload cut off midway
+        drop(blob);
+        assert!(stored_names(&dir).is_empty());
+
+        let mut blob = BlobWriter::create(&dir, Checksum::Sha256).await.unwrap();
+        blob.write(b"all of it").await.unwrap();
+        // Like an upload rejected once hashed
+        drop(blob.finish().await.unwrap());
+        assert!(stored_names(&dir).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stored_blob_appears_whole_under_its_hash() {
+        let dir = uploads_dir("stored-blob");
+        let mut blob = BlobWriter::create(&dir, Checksum::Sha256).await.unwrap();
+        blob.write(b"all ").await.unwrap();
+        blob.write(b"of it").await.unwrap();
+        let blob = blob.finish().await.unwrap();
+        let hash = blob.hash.clone();
+        blob.store(None, &[]).await.unwrap();
+        assert_eq!(stored_names(&dir), std::slice::from_ref(&hash));
+        assert_eq!(std::fs::read(dir.join(&hash)).unwrap(), b"all of it");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
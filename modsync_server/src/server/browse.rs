@@ -0,0 +1,76 @@
+use modsync_core::{FileState, StrConversion};
+
+use super::models::{files::File, modpacks::Modpack};
+
+/// Renders a plain HTML listing of a modpack and its files
+pub fn render_modpack(modpack: &Modpack, files: &[File]) -> String {
+    let mut files: Vec<&File> = files.iter().collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n", escape(&modpack.name)));
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>{}</h1>\n<ul>\n", escape(&modpack.name)));
+    for (name, value) in [
+        ("Game version", &modpack.game_version),
+        ("Modloader", &modpack.modloader),
+        ("Modloader version", &modpack.modloader_version),
+    ] {
+        if let Some(value) = value {
+            html.push_str(&format!("<li>{}: {}</li>\n", name, escape(value)));
+        }
+    }
+    html.push_str(&format!(
+        "<li>Sync version: {}</li>\n",
+        modpack.sync_version
+    ));
+    html.push_str("</ul>\n<table>\n<tr><th>Path</th><th>Size</th><th>State</th></tr>\n");
+    for file in files {
+        // Relative to `/modpack/:id/browse`, so links still work behind a proxy serving
+        // the server under a path prefix
+        let path = match (&file.hash, file.uploaded && file.state == FileState::Exists) {
+            (Some(hash), true) => format!(
+                "<a href=\"../../dl/hash/{}?modpack={}\">{}</a>",
+                escape(hash),
+                escape(&modpack.id.0),
+                escape(&file.path)
+            ),
+            _ => escape(&file.path),
+        };
+        let size = file.size.map(format_size).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            path,
+            size,
+            file.state.as_str()
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Escapes text for use in HTML content and quoted attributes
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_size(bytes: i64) -> String {
+    match bytes {
+        ..1_000 => format!("{} B", bytes),
+        1_000..1_000_000 => format!("{:.1} KB", bytes as f64 / 1_000.0),
+        1_000_000..1_000_000_000 => format!("{:.1} MB", bytes as f64 / 1_000_000.0),
+        _ => format!("{:.2} GB", bytes as f64 / 1_000_000_000.0),
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use modsync_core::api::{ModpackId, ModpackResponse};
+
+struct CacheEntry {
+    response: ModpackResponse,
+    expires_at: Instant,
+}
+
+/// Short-lived cache of `modpack_get` responses.
+///
+/// Every write to a modpack must call [`ModpackCache::invalidate`] after it hits the database.
+/// Entries fetched before an invalidation are never stored, so a writer can't read back
+/// a response that predates its own write.
+pub struct ModpackCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<ModpackId, CacheEntry>>,
+    generation: AtomicU64,
+}
+
+impl ModpackCache {
+    pub fn new(ttl: Duration) -> Self {
+        ModpackCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, id: &ModpackId) -> Option<ModpackResponse> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(id)
+            .filter(|x| x.expires_at > Instant::now())
+            .map(|x| x.response.clone())
+    }
+
+    /// Generation to pass into [`ModpackCache::insert`], must be taken before reading the database
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn insert(&self, id: ModpackId, response: ModpackResponse, generation: u64) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap();
+        // Something was written while we were reading, the response may already be stale
+        if self.generation() != generation {
+            return;
+        }
+        entries.retain(|_, x| x.expires_at > Instant::now());
+        entries.insert(
+            id,
+            CacheEntry {
+                response,
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, id: &ModpackId) {
+        let mut entries = self.entries.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.remove(id);
+    }
+}
@@ -0,0 +1,303 @@
+use std::{io::SeekFrom, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    Json,
+};
+use modsync_core::api::{
+    ModpackId, UploadFinishResponse, UploadId, UploadInitBody, UploadStatusResponse,
+    ValidationError,
+};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use super::{
+    blobs,
+    error::ApiError,
+    models::{self, blobs::Blob, modpacks::Modpack, uploads::UploadSession, usage::ModpackUsage},
+    AppState, WriteKey,
+};
+
+pub async fn upload_init(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path(modpack_id): Path<ModpackId>,
+    Json(data): Json<UploadInitBody>,
+) -> Result<Json<UploadStatusResponse>, ApiError> {
+    data.validate()?;
+    if data.size as u64 > state.config.file_size_limit as u64 {
+        return Err(ValidationError(format!(
+            "uploads are limited to {} bytes",
+            state.config.file_size_limit
+        ))
+        .into());
+    }
+    Modpack::get_optional(&modpack_id, &state.pool)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if let Some(file_path) = &data.file_path {
+        models::files::File::get_by_path(&modpack_id, file_path, &state.pool)
+            .await?
+            .ok_or(ApiError::NotFound)?;
+    }
+
+    let session = UploadSession::insert(
+        &modpack_id,
+        data.file_path.as_ref(),
+        &data.hash,
+        data.size,
+        state.config.upload_chunk_size as i64,
+        &state.pool,
+    )
+    .await?;
+    let path = blobs::partial_upload_path(&state.config.uploads_directory, &session.id.0);
+    tokio::fs::File::create(&path)
+        .await
+        .map_err(|x| ApiError::storage(x, &state.config.uploads_directory))?;
+    Ok(Json((&session).into()))
+}
+
+pub async fn upload_status(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path((modpack_id, upload_id)): Path<(ModpackId, UploadId)>,
+) -> Result<Json<UploadStatusResponse>, ApiError> {
+    let session = UploadSession::get(&upload_id, &modpack_id, false, &state.pool)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json((&session).into()))
+}
+
+/// Appends chunk `n` to the upload. Chunks must arrive in order, resending one the server
+/// already has is a no-op so a chunk whose response got lost can simply be sent again.
+pub async fn upload_chunk(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path((modpack_id, upload_id, n)): Path<(ModpackId, UploadId, i32)>,
+    body: Bytes,
+) -> Result<Json<UploadStatusResponse>, ApiError> {
+    let mut tx = state.pool.begin().await?;
+    let session = UploadSession::get(&upload_id, &modpack_id, true, &mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let Some(expected) = check_chunk(&session, n, body.len())? else {
+        return Ok(Json((&session).into()));
+    };
+
+    let path = blobs::partial_upload_path(&state.config.uploads_directory, &upload_id.0);
+    write_chunk(&path, session.received_bytes as u64, &body)
+        .await
+        .map_err(|x| ApiError::storage(x, &state.config.uploads_directory))?;
+    UploadSession::advance(&upload_id, expected, &mut *tx).await?;
+    tx.commit().await?;
+    state.metrics.uploaded(expected as u64);
+
+    let mut status: UploadStatusResponse = (&session).into();
+    status.next_chunk += 1;
+    status.received_bytes += expected;
+    Ok(Json(status))
+}
+
+/// Checks chunk `n` of `len` bytes comes next in the upload, returning how many bytes it adds.
+/// `None` for a chunk the server already has.
+fn check_chunk(
+    session: &UploadSession,
+    n: i32,
+    len: usize,
+) -> Result<Option<i64>, ValidationError> {
+    if n < session.next_chunk {
+        return Ok(None);
+    }
+    if n > session.next_chunk {
+        return Err(ValidationError(format!(
+            "expected chunk {}, got chunk {}",
+            session.next_chunk, n
+        )));
+    }
+    let expected = session
+        .chunk_size
+        .min(session.size - session.received_bytes);
+    if len as i64 != expected {
+        return Err(ValidationError(format!(
+            "chunk {} must be {} bytes, got {}",
+            n, expected, len
+        )));
+    }
+    Ok(Some(expected))
+}
+
+/// Checks the assembled content hashes to what the upload was started with
+fn check_content(session: &UploadSession, hash: &str) -> Result<(), ValidationError> {
+    if hash != session.hash {
+        return Err(ValidationError(format!(
+            "content hashes to {} instead of {}, the upload was discarded",
+            hash, session.hash
+        )));
+    }
+    Ok(())
+}
+
+/// Writes a chunk at `offset`, dropping whatever an earlier failed attempt left past it
+async fn write_chunk(
+    path: &std::path::Path,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+/// Checks the received content against the hash given on init and stores it as a blob,
+/// attached to the upload's file if it has one
+pub async fn upload_finish(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path((modpack_id, upload_id)): Path<(ModpackId, UploadId)>,
+) -> Result<Json<UploadFinishResponse>, ApiError> {
+    let mut tx = state.pool.begin().await?;
+    let session = UploadSession::get(&upload_id, &modpack_id, true, &mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if session.received_bytes != session.size {
+        return Err(ValidationError(format!(
+            "upload is incomplete, {} of {} bytes received",
+            session.received_bytes, session.size
+        ))
+        .into());
+    }
+    let modpack = Modpack::get_optional(&modpack_id, &mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let file = match &session.file_path {
+        Some(file_path) => Some(
+            models::files::File::get_by_path(&modpack_id, file_path, &mut *tx)
+                .await?
+                .ok_or(ApiError::NotFound)?,
+        ),
+        None => None,
+    };
+
+    let path = blobs::partial_upload_path(&state.config.uploads_directory, &upload_id.0);
+    let hash = {
+        let path = path.clone();
+        let checksum = modpack.hash_algorithm;
+        tokio::task::spawn_blocking(move || blobs::hash_file(path, checksum))
+            .await
+            .expect("upload hashing task panicked")?
+    };
+    if let Err(err) = check_content(&session, &hash) {
+        // Resending chunks can't fix this, the client has to start over
+        UploadSession::delete(&upload_id, &mut *tx).await?;
+        tx.commit().await?;
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(err.into());
+    }
+
+    let extension = match (&session.file_path, state.config.blob_extensions) {
+        (Some(file_path), true) => blobs::blob_extension(file_path),
+        _ => None,
+    };
+    if file.is_some() {
+        // Before the blob, filesync locks in this order too
+        Modpack::lock(&modpack_id, &mut *tx).await?;
+    }
+    Blob::register(&hash, session.size, &mut *tx).await?;
+    let known_extensions = models::files::File::blob_extensions(&hash, &mut *tx).await?;
+    blobs::store_blob_file(
+        &state.config.uploads_directory,
+        &path,
+        &hash,
+        extension.as_deref(),
+        &known_extensions,
+    )
+    .await
+    .map_err(|x| ApiError::storage(x, &state.config.uploads_directory))?;
+    if let Some(file) = &file {
+        models::files::File::set_uploaded(
+            &file.id,
+            true,
+            Some(&hash),
+            Some(session.size),
+            &mut *tx,
+        )
+        .await?;
+    }
+    UploadSession::delete(&upload_id, &mut *tx).await?;
+    tx.commit().await?;
+
+    if file.is_some() {
+        state.modpack_cache.invalidate(&modpack_id);
+    }
+    ModpackUsage::add_upload(&modpack_id, session.size, &state.pool).await?;
+    Ok(Json(UploadFinishResponse {
+        hash,
+        file_id: file.map(|x| x.id),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use modsync_core::checksum::Checksum;
+
+    use super::*;
+
+    /// A 2500 byte upload in chunks of 1000, with `received` chunks in
+    fn session(received: i32) -> UploadSession {
+        UploadSession {
+            id: UploadId("upload".to_string()),
+            modpack: ModpackId("a".to_string()),
+            file_path: None,
+            hash: Checksum::Sha256.hash_bytes(b"content"),
+            size: 2500,
+            chunk_size: 1000,
+            next_chunk: received,
+            received_bytes: (received as i64 * 1000).min(2500),
+        }
+    }
+
+    #[test]
+    fn next_chunk_is_appended() {
+        assert_eq!(check_chunk(&session(0), 0, 1000).unwrap(), Some(1000));
+        assert_eq!(check_chunk(&session(1), 1, 1000).unwrap(), Some(1000));
+        // Only the last chunk may be shorter
+        assert_eq!(check_chunk(&session(2), 2, 500).unwrap(), Some(500));
+    }
+
+    #[test]
+    fn resumed_session_reports_where_to_continue() {
+        let status = UploadStatusResponse::from(&session(2));
+        assert_eq!(status.next_chunk, 2);
+        assert_eq!(status.received_bytes, 2000);
+        assert_eq!(check_chunk(&session(2), 2, 500).unwrap(), Some(500));
+    }
+
+    #[test]
+    fn resent_chunk_is_not_appended_again() {
+        assert_eq!(check_chunk(&session(2), 0, 1000).unwrap(), None);
+        assert_eq!(check_chunk(&session(2), 1, 1000).unwrap(), None);
+    }
+
+    #[test]
+    fn chunk_out_of_order_is_rejected() {
+        assert!(check_chunk(&session(0), 1, 1000).is_err());
+        assert!(check_chunk(&session(1), 3, 1000).is_err());
+    }
+
+    #[test]
+    fn chunk_of_the_wrong_length_is_rejected() {
+        assert!(check_chunk(&session(0), 0, 999).is_err());
+        assert!(check_chunk(&session(0), 0, 1001).is_err());
+        assert!(check_chunk(&session(2), 2, 1000).is_err());
+    }
+
+    #[test]
+    fn content_must_match_the_initial_hash() {
+        let session = session(3);
+        assert!(check_content(&session, &Checksum::Sha256.hash_bytes(b"content")).is_ok());
+        let err = check_content(&session, &Checksum::Sha256.hash_bytes(b"corrupted")).unwrap_err();
+        assert!(err.0.contains("discarded"), "{}", err.0);
+    }
+}
@@ -1,17 +1,22 @@
+use std::io::ErrorKind;
+
 use axum::{
     extract::multipart::MultipartError,
     response::{IntoResponse, Response},
 };
+use modsync_core::api::ValidationError;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
+use super::DigestMismatch;
+
 #[derive(thiserror::Error, Debug)]
 pub enum ApiError {
     #[error("database error: {0}")]
     SqlxDatabase(#[from] sqlx::Error),
     #[error("multipart error: {0}")]
-    MultipartError(#[from] MultipartError),
+    MultipartError(MultipartError),
     #[error("i/o error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("already exists")]
@@ -22,11 +27,72 @@ pub enum ApiError {
     NotFound,
     #[error("bad request")]
     BadRequest,
+    #[error("bad request: {0}")]
+    Validation(#[from] ValidationError),
+    #[error("storage full: {0}")]
+    StorageFull(std::io::Error),
+    #[error("storage unwritable: {0}")]
+    StorageUnwritable(std::io::Error),
+    #[error("too many concurrent downloads")]
+    Busy,
+}
+
+/// Response extension naming the variant of the `ApiError` a response was made from,
+/// read by the metrics middleware
+#[derive(Clone, Copy)]
+pub struct ApiErrorKind(pub &'static str);
+
+impl ApiError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::SqlxDatabase(_) => "database",
+            ApiError::MultipartError(_) => "multipart",
+            ApiError::IoError(_) => "io",
+            ApiError::AlreadyExists => "already_exists",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::NotFound => "not_found",
+            ApiError::BadRequest => "bad_request",
+            ApiError::Validation(_) => "validation",
+            ApiError::StorageFull(_) => "storage_full",
+            ApiError::StorageUnwritable(_) => "storage_unwritable",
+            ApiError::Busy => "busy",
+        }
+    }
+
+    /// Classifies an error from writing into the uploads directory
+    pub fn storage(err: std::io::Error, path: &str) -> Self {
+        match err.kind() {
+            ErrorKind::StorageFull => {
+                error!("Uploads directory {} is out of space: {}", path, err);
+                ApiError::StorageFull(err)
+            }
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => {
+                error!("Uploads directory {} is not writable: {}", path, err);
+                ApiError::StorageUnwritable(err)
+            }
+            _ => ApiError::IoError(err),
+        }
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    /// A streamed upload that doesn't match its digest surfaces as a multipart error
+    fn from(err: MultipartError) -> Self {
+        let mut source = std::error::Error::source(&err);
+        while let Some(err) = source {
+            if let Some(mismatch) = err.downcast_ref::<DigestMismatch>() {
+                return ValidationError(mismatch.to_string()).into();
+            }
+            source = err.source();
+        }
+        ApiError::MultipartError(err)
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        if cfg!(debug_assertions) {
+        let kind = ApiErrorKind(self.kind());
+        let mut response = if cfg!(debug_assertions) {
             error!("{:#?}", self);
             (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
         } else {
@@ -73,9 +139,35 @@ impl IntoResponse for ApiError {
                         error: "BAD_REQUEST".to_string(),
                     },
                 ),
+                ApiError::StorageFull(_) => (
+                    StatusCode::INSUFFICIENT_STORAGE,
+                    ErrorResponse {
+                        error: "STORAGE_FULL".to_string(),
+                    },
+                ),
+                ApiError::StorageUnwritable(_) => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    ErrorResponse {
+                        error: "STORAGE_UNWRITABLE".to_string(),
+                    },
+                ),
+                ApiError::Busy => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    ErrorResponse {
+                        error: "BUSY".to_string(),
+                    },
+                ),
+                ApiError::Validation(err) => (
+                    StatusCode::BAD_REQUEST,
+                    ErrorResponse {
+                        error: format!("BAD_REQUEST: {}", err),
+                    },
+                ),
             }
             .into_response()
-        }
+        };
+        response.extensions_mut().insert(kind);
+        response
     }
 }
 
@@ -90,3 +182,31 @@ impl IntoResponse for ErrorResponse {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn full_or_unwritable_storage_is_told_apart() {
+        // ENOSPC, EACCES and EROFS, as the OS reports them
+        let kind = |errno| ApiError::storage(std::io::Error::from_raw_os_error(errno), "up").kind();
+        assert_eq!(kind(28), "storage_full");
+        assert_eq!(kind(13), "storage_unwritable");
+        assert_eq!(kind(30), "storage_unwritable");
+        let other = std::io::Error::new(ErrorKind::UnexpectedEof, "truncated");
+        assert_eq!(ApiError::storage(other, "up").kind(), "io");
+    }
+
+    #[test]
+    fn storage_errors_tell_clients_to_come_back() {
+        let full = ApiError::StorageFull(ErrorKind::StorageFull.into()).into_response();
+        assert_eq!(
+            full.extensions().get::<ApiErrorKind>().unwrap().0,
+            "storage_full"
+        );
+        if !cfg!(debug_assertions) {
+            assert_eq!(full.status(), StatusCode::INSUFFICIENT_STORAGE);
+        }
+    }
+}
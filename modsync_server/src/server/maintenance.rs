@@ -0,0 +1,86 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use modsync_core::api::{MaintenanceBody, MaintenanceResponse, MAINTENANCE_ERROR};
+use tracing::info;
+
+use super::{AppState, AuthenticatedKey};
+
+/// Routes under this prefix keep working during maintenance, so it can be turned off again
+const ADMIN_PREFIX: &str = "/admin/";
+/// Also kept working, orchestrators would restart a server that's only in maintenance and
+/// monitoring would lose track of it
+const MONITORING_PATHS: [&str; 2] = ["/health", "/metrics"];
+
+/// Server-wide maintenance switch, toggled at runtime through `/admin/maintenance`
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after: AtomicU64,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool, retry_after: u64) -> Self {
+        Maintenance {
+            enabled: AtomicBool::new(enabled),
+            retry_after: AtomicU64::new(retry_after),
+        }
+    }
+
+    fn status(&self) -> MaintenanceResponse {
+        MaintenanceResponse {
+            enabled: self.enabled.load(Ordering::SeqCst),
+            retry_after: self.retry_after.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Answers every non-admin request with `503` and a `Retry-After` while maintenance is on.
+/// Other `503`s never carry `Retry-After`, which is how clients tell them apart.
+pub async fn reject_during_maintenance(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let status = state.maintenance.status();
+    let path = req.uri().path();
+    if !status.enabled || path.starts_with(ADMIN_PREFIX) || MONITORING_PATHS.contains(&path) {
+        return next.run(req).await;
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, status.retry_after.to_string())],
+        MAINTENANCE_ERROR,
+    )
+        .into_response()
+}
+
+pub async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    _: AuthenticatedKey,
+    Json(data): Json<MaintenanceBody>,
+) -> Json<MaintenanceResponse> {
+    if let Some(retry_after) = data.retry_after {
+        state
+            .maintenance
+            .retry_after
+            .store(retry_after, Ordering::SeqCst);
+    }
+    state
+        .maintenance
+        .enabled
+        .store(data.enabled, Ordering::SeqCst);
+    info!(
+        "Maintenance mode {}",
+        if data.enabled { "enabled" } else { "disabled" }
+    );
+    Json(state.maintenance.status())
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+
+use super::{
+    error::{ApiError, ApiErrorKind},
+    AppState, AuthenticatedKey,
+};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    kind: String,
+}
+
+/// Counters served on `/metrics`. Byte counters are kept even with the endpoint disabled,
+/// they're cheap next to the transfers they count.
+pub struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    errors: Family<ErrorLabels, Counter>,
+    upload_bytes: Counter,
+    download_bytes: Counter,
+    // Read from the database on every scrape
+    modpacks: Gauge,
+    files: Gauge,
+    blobs: Gauge,
+    blob_bytes: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("modsync");
+        let requests = Family::<RequestLabels, Counter>::default();
+        registry.register(
+            "requests",
+            "Requests answered, by route and status",
+            requests.clone(),
+        );
+        let errors = Family::<ErrorLabels, Counter>::default();
+        registry.register(
+            "errors",
+            "Requests that failed with an API error, by kind",
+            errors.clone(),
+        );
+        let upload_bytes = Counter::default();
+        registry.register(
+            "upload_bytes",
+            "Bytes of uploaded content received",
+            upload_bytes.clone(),
+        );
+        let download_bytes = Counter::default();
+        registry.register(
+            "download_bytes",
+            "Bytes of blobs served",
+            download_bytes.clone(),
+        );
+        let modpacks = Gauge::default();
+        registry.register("modpacks", "Modpacks on the server", modpacks.clone());
+        let files = Gauge::default();
+        registry.register("files", "Files across all modpacks", files.clone());
+        let blobs = Gauge::default();
+        registry.register("blobs", "Blobs stored", blobs.clone());
+        let blob_bytes = Gauge::default();
+        registry.register("blob_bytes", "Size of the blobs stored", blob_bytes.clone());
+        Metrics {
+            registry,
+            requests,
+            errors,
+            upload_bytes,
+            download_bytes,
+            modpacks,
+            files,
+            blobs,
+            blob_bytes,
+        }
+    }
+
+    pub fn uploaded(&self, bytes: u64) {
+        self.upload_bytes.inc_by(bytes);
+    }
+
+    pub fn downloaded(&self, bytes: u64) {
+        self.download_bytes.inc_by(bytes);
+    }
+}
+
+/// Counts requests by matched route and status, and failed ones by the kind of `ApiError`.
+/// Added as a route layer, so the route is known and unmatched paths aren't counted.
+pub async fn record_requests(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(req).await;
+    state
+        .metrics
+        .requests
+        .get_or_create(&RequestLabels {
+            method,
+            route,
+            status: response.status().as_u16(),
+        })
+        .inc();
+    if let Some(ApiErrorKind(kind)) = response.extensions().get::<ApiErrorKind>() {
+        state
+            .metrics
+            .errors
+            .get_or_create(&ErrorLabels {
+                kind: kind.to_string(),
+            })
+            .inc();
+    }
+    response
+}
+
+/// Renders every metric in the OpenMetrics text format Prometheus scrapes
+pub async fn render(
+    State(state): State<Arc<AppState>>,
+    key: Option<AuthenticatedKey>,
+) -> Result<Response, ApiError> {
+    if state.config.metrics_require_key && key.is_none() {
+        return Err(ApiError::Unauthorized);
+    }
+    let totals = sqlx::query!(
+        r#"SELECT (SELECT count(*) FROM modpacks) AS "modpacks!", (SELECT count(*) FROM files) AS "files!",
+            (SELECT count(*) FROM blobs) AS "blobs!", (SELECT coalesce(sum(size), 0)::bigint FROM blobs) AS "blob_bytes!""#
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    let metrics = &state.metrics;
+    metrics.modpacks.set(totals.modpacks);
+    metrics.files.set(totals.files);
+    metrics.blobs.set(totals.blobs);
+    metrics.blob_bytes.set(totals.blob_bytes);
+
+    let mut body = String::new();
+    encode(&mut body, &metrics.registry).expect("encoding into a String can't fail");
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    )
+        .into_response())
+}
@@ -1,186 +1,640 @@
-use std::{env::var, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    env::var,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     async_trait,
+    body::Body,
     extract::{
         DefaultBodyLimit, FromRef, FromRequestParts, Multipart, Path, Query, Request, State,
     },
-    http::request::Parts,
-    response::IntoResponse,
-    routing::{get, post},
+    http::{
+        header, request::Parts, Extensions, HeaderMap, HeaderName, Method, StatusCode, Version,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post, put},
     Json, RequestPartsExt, Router,
 };
 use axum_extra::{
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use cache::ModpackCache;
 use clap::Parser;
 use error::ApiError;
-use models::modpacks::Modpack;
+use futures_util::StreamExt;
+use maintenance::Maintenance;
+use metrics::Metrics;
+use models::{
+    blobs::Blob,
+    keys::{KeyScope, ModpackKey},
+    modpacks::Modpack,
+    usage::ModpackUsage,
+};
 use modsync_core::{
     api::{
-        FileSyncBody, FileSyncResponse, FileUploadResponse, HelloResponse, ModpackCreateBody,
-        ModpackCreateResponse, ModpackId, ModpackResponse,
+        is_contained_path, path_in_roots, validate_roots, validate_webhook_url, verify_digest,
+        BatchDownloadBody, BlobExistsBody, BlobExistsResponse, BlobUploadResponse,
+        CapabilitiesResponse, DigestVerifier, DownloadQuery, FileDeleteBody, FileSyncBatchBody,
+        FileSyncBatchResponse, FileSyncBody, FileSyncResponse, FileSyncResult, FileUploadResponse,
+        HealthResponse, HelloResponse, ModpackChangesResponse, ModpackCreateBody,
+        ModpackCreateResponse, ModpackId, ModpackListResponse, ModpackResponse, ModpackRootsBody,
+        ModpackSignatureBody, ModpackUsageResponse, ModpackVersionResponse, ModpackWebhookBody,
+        ValidationError, WebhookEvent, WebhookFile, BATCH_DOWNLOAD_MAX_HASHES,
+        BATCH_DOWNLOAD_MISSING, DIGEST_HEADER, FEATURE_ALLOWED_ROOTS, FEATURE_BATCH_DOWNLOAD,
+        FEATURE_BLOB_UPLOAD, FEATURE_BODY_DIGEST, FEATURE_CHANGES, FEATURE_DIRECTORIES,
+        FEATURE_FILESYNC_BATCH, FEATURE_FILE_DELETE, FEATURE_MODPACK_LIST, FEATURE_MODPACK_VERSION,
+        FEATURE_MODRINTH_SOURCE, FEATURE_RANGE_DOWNLOAD, FEATURE_SIGNATURES, FEATURE_WEBHOOKS,
+        MODPACK_LIST_DEFAULT_LIMIT, MODPACK_LIST_MAX_LIMIT, PROTOCOL_VERSION, SIGNATURE_MAX_LENGTH,
     },
-    StrConversion,
+    checksum::Checksum,
+    DownloadSource, FileState, StrConversion,
 };
+use ratelimit::RateLimiter;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use shutdown::InFlight;
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use tokio::{fs::File, io::AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tower::ServiceExt;
 use tower_http::{
-    compression::CompressionLayer, limit::RequestBodyLimitLayer, services::ServeFile,
-    timeout::TimeoutLayer, trace::TraceLayer,
+    compression::{predicate::Predicate, CompressionLayer, DefaultPredicate},
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    services::ServeFile,
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
 };
-use tracing::info;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+pub(crate) mod blobs;
+mod browse;
+mod cache;
+mod chunked;
 mod error;
-mod models;
+mod maintenance;
+mod metrics;
+pub(crate) mod models;
+mod ratelimit;
+mod shutdown;
+mod slow;
+mod webhook;
 
-/// Modsync server
+/// Start the modsync server
 #[derive(Parser, Debug)]
 pub struct ServeCommand {}
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct ServerConfigFile {
     pub database_url: Option<String>,
-    pub master_key: Option<String>,
+    pub master_key: Option<MasterKeys>,
     pub port: Option<String>,
+    pub bind_address: Option<String>,
     pub uploads_directory: Option<String>,
     pub file_size_limit: Option<usize>,
+    pub modpack_cache_ttl: Option<u64>,
+    pub verify_blobs_on_startup: Option<bool>,
+    pub json_body_limit: Option<usize>,
+    pub read_tokens: Option<Vec<String>>,
+    pub require_read_token: Option<bool>,
+    pub max_path_length: Option<usize>,
+    pub max_path_components: Option<usize>,
+    pub slow_request_threshold_ms: Option<u64>,
+    pub blob_extensions: Option<bool>,
+    pub maintenance: Option<bool>,
+    pub maintenance_retry_after: Option<u64>,
+    pub download_concurrency: Option<usize>,
+    pub download_buffer_size: Option<usize>,
+    pub upload_chunk_size: Option<usize>,
+    pub shutdown_timeout: Option<u64>,
+    pub request_timeout_secs: Option<u64>,
+    pub transfer_timeout_secs: Option<u64>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub metrics: Option<bool>,
+    pub metrics_require_key: Option<bool>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub rate_limit_requests: Option<u32>,
+    pub rate_limit_window_secs: Option<u64>,
+    pub webhook_allow_private: Option<bool>,
+}
+
+/// Either a single master key or a list of them, so keys can be rotated without downtime
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MasterKeys {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl MasterKeys {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            MasterKeys::One(key) => vec![key],
+            MasterKeys::Many(keys) => keys,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct ServerConfig {
     pub database_url: String,
-    pub master_key: String,
+    /// Every key accepted for managing modpacks
+    pub master_keys: Vec<String>,
     pub port: u16,
+    /// Address to listen on, e.g. `127.0.0.1` behind a reverse proxy
+    pub bind_address: IpAddr,
     pub uploads_directory: String,
     pub file_size_limit: usize,
+    pub modpack_cache_ttl: u64,
+    pub verify_blobs_on_startup: bool,
+    pub json_body_limit: usize,
+    /// Tokens that can only read modpacks and download files
+    pub read_tokens: Vec<String>,
+    pub require_read_token: bool,
+    /// Limits for synced file paths, so they still fit on client filesystems
+    pub max_path_length: usize,
+    pub max_path_components: usize,
+    /// Requests taking longer than this get a warning, `None` disables it
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Store uploads as `<hash>.<ext>` after the extension of their path
+    pub blob_extensions: bool,
+    /// Start in maintenance mode, can be toggled at runtime through `/admin/maintenance`
+    pub maintenance: bool,
+    /// Seconds clients are told to wait during maintenance
+    pub maintenance_retry_after: u64,
+    /// Blob downloads served at once, more are turned away with `503`. 0 disables the limit.
+    pub download_concurrency: usize,
+    /// Bytes read from disk at a time when serving a blob
+    pub download_buffer_size: usize,
+    /// Size of every chunk but the last in chunked uploads
+    pub upload_chunk_size: usize,
+    /// Seconds requests in flight get to finish when the server is asked to stop
+    pub shutdown_timeout: u64,
+    /// Seconds JSON endpoints get to answer
+    pub request_timeout_secs: u64,
+    /// Seconds blob uploads and downloads get, `None` lets them take as long as they need
+    pub transfer_timeout_secs: Option<u64>,
+    /// PEM certificate chain and private key, the server speaks HTTPS when both are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// Serve Prometheus metrics on `/metrics`
+    pub metrics: bool,
+    /// Only answer `/metrics` with a master key
+    pub metrics_require_key: bool,
+    /// Origins browsers may call the API from, `*` for any. Empty sends no CORS headers.
+    pub allowed_origins: Vec<String>,
+    /// Requests a client address may make to the endpoints that check write or master keys
+    /// per `rate_limit_window_secs`, 0 disables the limit
+    pub rate_limit_requests: u32,
+    pub rate_limit_window_secs: u64,
+    /// Deliver webhooks to loopback, link-local and private addresses too
+    pub webhook_allow_private: bool,
 }
 
 pub struct AppState {
     pub pool: PgPool,
-    pub master_key: String,
+    pub master_keys: HashSet<String>,
     pub config: ServerConfig,
+    pub modpack_cache: ModpackCache,
+    pub http_client: reqwest::Client,
+    pub maintenance: Maintenance,
+    /// `None` if downloads aren't limited
+    pub download_permits: Option<Arc<Semaphore>>,
+    pub in_flight: InFlight,
+    pub metrics: Metrics,
+    /// `None` if requests aren't limited
+    pub rate_limiter: Option<RateLimiter>,
 }
 
 impl ServeCommand {
     pub async fn run(&mut self) -> anyhow::Result<()> {
         info!("Modsync Server v{}", env!("CARGO_PKG_VERSION"));
 
-        let server_config_text = std::fs::read_to_string(
-            var("MODSYNC_CONFIG_PATH").unwrap_or("modsync.server.toml".to_string()),
-        );
-        let server_config_file = if let Ok(text) = server_config_text {
-            Some(toml::from_str::<ServerConfigFile>(&text)?)
-        } else {
-            None
-        };
-
-        let config = ServerConfig {
-            database_url: var("DATABASE_URL")
-                .ok()
-                .or(server_config_file
-                    .as_ref()
-                    .and_then(|x| x.database_url.clone()))
-                .expect("No database URL set!"),
-            master_key: var("MODSYNC_MASTER_KEY")
-                .ok()
-                .or(server_config_file
-                    .as_ref()
-                    .and_then(|x| x.master_key.clone()))
-                .expect("No master key set!"),
-            port: var("MODSYNC_PORT")
-                .ok()
-                .or(server_config_file.as_ref().and_then(|x| x.port.clone()))
-                .unwrap_or("7040".to_string())
-                .parse()?,
-            uploads_directory: var("MODSYNC_UPLOADS_DIRECTORY")
-                .ok()
-                .or(server_config_file
-                    .as_ref()
-                    .and_then(|x| x.uploads_directory.clone()))
-                .unwrap_or("uploads".to_string()),
-            file_size_limit: server_config_file
-                .as_ref()
-                .and_then(|x| x.file_size_limit.clone())
-                .unwrap_or(262144000),
-        };
-
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(&config.database_url)
-            .await?;
+        let (config, _) = load_config()?;
 
-        sqlx::migrate!().run(&pool).await?;
+        let pool = connect_database(&config, 5).await?;
 
         if !std::fs::exists(&config.uploads_directory)? {
             create_directories(&config.uploads_directory)?;
         }
 
+        if config.verify_blobs_on_startup {
+            info!("Verifying uploaded blobs...");
+            let uploads_directory = config.uploads_directory.clone();
+            let mismatches =
+                tokio::task::spawn_blocking(move || blobs::verify_blobs(uploads_directory))
+                    .await??;
+            for blob in mismatches.iter() {
+                error!(
+                    "Blob content doesn't match its hash: {}",
+                    blob.to_string_lossy()
+                );
+            }
+            if !mismatches.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} corrupted blob(s) found in uploads directory",
+                    mismatches.len()
+                ));
+            }
+            info!("All blobs verified");
+        }
+
         let state = Arc::new(AppState {
             pool,
-            master_key: config.master_key.clone(),
+            master_keys: config.master_keys.iter().cloned().collect(),
             config: config.clone(),
+            modpack_cache: ModpackCache::new(Duration::from_secs(config.modpack_cache_ttl)),
+            http_client: webhook::client(config.webhook_allow_private)?,
+            maintenance: Maintenance::new(config.maintenance, config.maintenance_retry_after),
+            download_permits: Some(config.download_concurrency)
+                .filter(|x| *x > 0)
+                .map(|x| Arc::new(Semaphore::new(x))),
+            in_flight: InFlight::default(),
+            metrics: Metrics::new(),
+            rate_limiter: Some(config.rate_limit_requests)
+                .filter(|x| *x > 0)
+                .map(|x| RateLimiter::new(x, Duration::from_secs(config.rate_limit_window_secs))),
         });
 
-        let app = Router::new()
-            .route(
-                "/",
-                get(|| async { "Modsync server - https://github.com/stopperw/modsync" }),
-            )
-            .route("/hello", post(hello))
-            .route("/modpack/create", post(modpack_create))
-            .route("/modpack/:modpack_id", get(modpack_get))
-            .route("/modpack/:modpack_id/update", post(hello))
-            .route("/modpack/:modpack_id/filesync", post(modpack_file_sync))
-            .route("/modpack/:modpack_id/delete", post(modpack_delete))
-            .route("/modpack/:modpack_id/upload", post(dl_file_upload))
-            .route(
-                "/dl/hash/:file",
-                get(dl_file_hash).layer(CompressionLayer::new()),
-            )
-            .layer(DefaultBodyLimit::disable())
-            .layer(RequestBodyLimitLayer::new(config.file_size_limit))
-            .layer(TimeoutLayer::new(Duration::from_secs(15)))
-            .layer(TraceLayer::new_for_http())
-            .with_state(state);
-
-        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port))
+        let app = router(state.clone());
+
+        let address = SocketAddr::new(config.bind_address, config.port);
+        let listener = tokio::net::TcpListener::bind(address)
             .await
-            .unwrap();
-        info!("Serving on 0.0.0.0:{}", config.port);
-        axum::serve(listener, app).await.unwrap();
+            .map_err(|x| anyhow::anyhow!("Couldn't listen on {}: {}", address, x))?;
+        let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert), Some(key)) => {
+                Some(RustlsConfig::from_pem_file(cert, key).await.map_err(|x| {
+                    anyhow::anyhow!(
+                        "Couldn't load TLS certificate {} and key {}: {}",
+                        cert,
+                        key,
+                        x
+                    )
+                })?)
+            }
+            _ => None,
+        };
+        match tls {
+            Some(_) => info!("Serving HTTPS on {}", address),
+            None => info!("Serving on {}", address),
+        }
+        shutdown::serve(
+            listener,
+            app,
+            tls,
+            state.clone(),
+            Duration::from_secs(config.shutdown_timeout),
+        )
+        .await?;
+        state.pool.close().await;
 
         Ok(())
     }
 }
 
-async fn hello(_: AuthenticatedKey) -> Json<HelloResponse> {
+/// Every route of the server with its layers, ready to serve
+pub fn router(state: Arc<AppState>) -> Router {
+    let config = &state.config;
+    // Layered on every route that checks a write or master key, outermost so a client over
+    // the limit is turned away before its body is read
+    let limited = middleware::from_fn_with_state(state.clone(), ratelimit::limit_requests);
+
+    // Lightweight JSON endpoints, cut off after `request_timeout_secs`
+    let mut api = Router::new()
+        .route(
+            "/",
+            get(|| async { "Modsync server - https://github.com/stopperw/modsync" }),
+        )
+        .route("/health", get(health))
+        .route("/hello", post(hello).layer(limited.clone()))
+        .route("/capabilities", get(capabilities))
+        .route("/modpacks", get(modpack_list).layer(limited.clone()))
+        .route(
+            "/modpack/create",
+            post(modpack_create).layer(limited.clone()),
+        )
+        .route("/modpack/:modpack_id", get(modpack_get))
+        .route(
+            "/modpack/:modpack_id/update",
+            post(hello).layer(limited.clone()),
+        )
+        .route("/modpack/:modpack_id/changes", get(modpack_changes))
+        .route("/modpack/:modpack_id/version", get(modpack_version))
+        .route("/modpack/:modpack_id/browse", get(modpack_browse))
+        .route(
+            "/modpack/:modpack_id/filesync",
+            post(modpack_file_sync)
+                .layer(middleware::from_fn(verify_body_digest))
+                .layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/filesync/batch",
+            post(modpack_file_sync_batch)
+                .layer(middleware::from_fn(verify_body_digest))
+                .layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/delete",
+            post(modpack_delete).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/file/delete",
+            post(modpack_file_delete).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/usage",
+            get(modpack_usage).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/webhook",
+            post(modpack_webhook).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/signature",
+            post(modpack_signature).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/roots",
+            post(modpack_roots).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/upload/init",
+            post(chunked::upload_init).layer(limited.clone()),
+        )
+        .route(
+            "/modpack/:modpack_id/upload/:upload_id",
+            get(chunked::upload_status).layer(limited.clone()),
+        )
+        .route("/dl/batch", post(dl_batch).layer(CompressionLayer::new()))
+        .route("/blob/exists", post(blob_exists).layer(limited.clone()))
+        .route(
+            "/admin/maintenance",
+            post(maintenance::set_maintenance).layer(limited.clone()),
+        )
+        .layer(RequestBodyLimitLayer::new(config.json_body_limit))
+        .layer(DefaultBodyLimit::disable())
+        .layer(TimeoutLayer::new(Duration::from_secs(
+            config.request_timeout_secs,
+        )));
+    if config.metrics {
+        api = api.route("/metrics", get(metrics::render));
+    }
+    // Blob transfers, large files on slow connections may take longer than any sensible
+    // request timeout, so they only get `transfer_timeout_secs` if it's set
+    let mut transfers = Router::new()
+        .route(
+            "/modpack/:modpack_id/upload",
+            post(dl_file_upload)
+                .layer::<_, Infallible>(middleware::from_fn(verify_streamed_body_digest))
+                .layer(RequestBodyLimitLayer::new(config.file_size_limit))
+                .layer(limited.clone()),
+        )
+        .route(
+            "/blob/upload",
+            post(blob_upload)
+                .layer::<_, Infallible>(middleware::from_fn(verify_streamed_body_digest))
+                .layer(RequestBodyLimitLayer::new(config.file_size_limit))
+                .layer(limited.clone()),
+        )
+        .route(
+            "/dl/hash/:file",
+            get(dl_file_hash).layer(blob_compression()),
+        )
+        .route(
+            "/modpack/:modpack_id/upload/:upload_id/chunk/:n",
+            put(chunked::upload_chunk)
+                .layer(DefaultBodyLimit::disable())
+                .layer::<_, Infallible>(middleware::from_fn(verify_body_digest))
+                .layer(RequestBodyLimitLayer::new(config.upload_chunk_size))
+                .layer(limited.clone()),
+        )
+        // Hashes the whole upload, which takes a while for the large files that get chunked
+        .route(
+            "/modpack/:modpack_id/upload/:upload_id/finish",
+            post(chunked::upload_finish).layer(limited),
+        )
+        .layer(DefaultBodyLimit::disable());
+    if let Some(timeout) = config.transfer_timeout_secs {
+        transfers = transfers.layer(TimeoutLayer::new(Duration::from_secs(timeout)));
+    }
+
+    let mut app = api.merge(transfers);
+    if config.metrics {
+        app = app.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::record_requests,
+        ));
+    }
+    let mut app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            maintenance::reject_during_maintenance,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            slow::log_slow_requests,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shutdown::track_requests,
+        ));
+    // Outside the other layers, so preflights are answered right away and error
+    // responses, maintenance included, stay readable from the browser
+    if !config.allowed_origins.is_empty() {
+        app = app.layer(cors_layer(&config.allowed_origins));
+    }
+    app.layer(TraceLayer::new_for_http()).with_state(state)
+}
+
+async fn hello(_: WriteKey) -> Json<HelloResponse> {
     Json(HelloResponse {
         version: env!("CARGO_PKG_VERSION").to_string(),
         version_number: 0,
     })
 }
 
+/// How long `/health` waits on the database before calling it unreachable
+const HEALTH_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// For load balancers and container health checks, `503` unless the database answers in time
+async fn health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthResponse>) {
+    let ping = sqlx::query!("SELECT 1 AS ping").fetch_one(&state.pool);
+    let db = match tokio::time::timeout(HEALTH_DB_TIMEOUT, ping).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(err)) => {
+            warn!("Health check failed, the database is unreachable: {}", err);
+            false
+        }
+        Err(_) => {
+            warn!(
+                "Health check failed, the database didn't answer within {}s",
+                HEALTH_DB_TIMEOUT.as_secs()
+            );
+            false
+        }
+    };
+    let (code, status) = match db {
+        true => (StatusCode::OK, "ok"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    };
+    (
+        code,
+        Json(HealthResponse {
+            status: status.to_string(),
+            db,
+        }),
+    )
+}
+
+async fn capabilities(State(state): State<Arc<AppState>>) -> Json<CapabilitiesResponse> {
+    Json(CapabilitiesResponse {
+        protocol_version: PROTOCOL_VERSION,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: [
+            FEATURE_BATCH_DOWNLOAD,
+            FEATURE_RANGE_DOWNLOAD,
+            FEATURE_BLOB_UPLOAD,
+            FEATURE_BODY_DIGEST,
+            FEATURE_SIGNATURES,
+            FEATURE_WEBHOOKS,
+            FEATURE_ALLOWED_ROOTS,
+            FEATURE_CHANGES,
+            FEATURE_DIRECTORIES,
+            FEATURE_FILE_DELETE,
+            FEATURE_MODRINTH_SOURCE,
+            FEATURE_MODPACK_LIST,
+            FEATURE_FILESYNC_BATCH,
+            FEATURE_MODPACK_VERSION,
+        ]
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect(),
+        hash_algorithms: Checksum::ALL.iter().map(|x| x.to_string()).collect(),
+        chunked_upload: true,
+        max_upload_size: state.config.file_size_limit,
+        max_json_body_size: state.config.json_body_limit,
+        max_path_length: state.config.max_path_length,
+        max_path_components: state.config.max_path_components,
+        batch_download_max_hashes: BATCH_DOWNLOAD_MAX_HASHES,
+    })
+}
+
 async fn modpack_get(
     State(state): State<Arc<AppState>>,
+    _: ReadToken,
     Path(modpack_id): Path<ModpackId>,
-) -> Result<Json<ModpackResponse>, ApiError> {
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let response = match state.modpack_cache.get(&modpack_id) {
+        Some(response) => response,
+        None => {
+            let generation = state.modpack_cache.generation();
+            let modpack = Modpack::get_optional(&modpack_id, &state.pool)
+                .await?
+                .ok_or(ApiError::NotFound)?;
+            let files = models::files::File::get_by_modpack(&modpack.id, &state.pool).await?;
+            let response = ModpackResponse {
+                modpack: modpack.into(),
+                files: files.into_iter().map(|x| x.into()).collect(),
+            };
+            state
+                .modpack_cache
+                .insert(modpack_id, response.clone(), generation);
+            response
+        }
+    };
+
+    // Hashing the body catches every change, including ones that don't bump a version
+    let body = serde_json::to_vec(&response).map_err(std::io::Error::other)?;
+    let etag = format!("\"{:x}\"", Sha256::digest(&body));
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.split(',').any(|x| x.trim() == etag || x.trim() == "*"));
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::ETAG, etag),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModpackChangesQuery {
+    pub since: Option<i64>,
+}
+
+async fn modpack_changes(
+    State(state): State<Arc<AppState>>,
+    _: ReadToken,
+    Path(modpack_id): Path<ModpackId>,
+    Query(query): Query<ModpackChangesQuery>,
+) -> Result<Json<ModpackChangesResponse>, ApiError> {
     let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
     if let Some(modpack) = modpack {
-        let files = models::files::File::get_by_modpack(&modpack.id, &state.pool).await?;
-        return Ok(Json(ModpackResponse {
+        // Cursor goes first, so changes made while we query get sent again next time
+        let (cursor, floor) = Modpack::get_cursor(&modpack.id, &state.pool).await?;
+        let since = query.since.filter(|x| *x >= floor && *x <= cursor);
+        let files = match since {
+            Some(since) => {
+                models::files::File::get_changed_since(&modpack.id, since, &state.pool).await?
+            }
+            None => models::files::File::get_by_modpack(&modpack.id, &state.pool).await?,
+        };
+        return Ok(Json(ModpackChangesResponse {
             modpack: modpack.into(),
+            cursor,
+            full: since.is_none(),
             files: files.into_iter().map(|x| x.into()).collect(),
         }));
     }
     Err(ApiError::NotFound)
 }
 
+/// Lets clients tell whether anything changed without fetching the file list
+async fn modpack_version(
+    State(state): State<Arc<AppState>>,
+    _: ReadToken,
+    Path(modpack_id): Path<ModpackId>,
+) -> Result<Json<ModpackVersionResponse>, ApiError> {
+    let (sync_version, cursor) = Modpack::get_version(&modpack_id, &state.pool)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(ModpackVersionResponse {
+        sync_version,
+        cursor,
+    }))
+}
+
+async fn modpack_browse(
+    State(state): State<Arc<AppState>>,
+    _: ReadToken,
+    Path(modpack_id): Path<ModpackId>,
+) -> Result<Html<String>, ApiError> {
+    let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
+    if let Some(modpack) = modpack {
+        let files = models::files::File::get_by_modpack(&modpack.id, &state.pool).await?;
+        return Ok(Html(browse::render_modpack(&modpack, &files)));
+    }
+    Err(ApiError::NotFound)
+}
+
 async fn modpack_delete(
     State(state): State<Arc<AppState>>,
     _: AuthenticatedKey,
@@ -189,108 +643,462 @@ async fn modpack_delete(
     let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
     if let Some(modpack) = modpack {
         Modpack::delete(&modpack.id, &state.pool).await?;
+        state.modpack_cache.invalidate(&modpack.id);
         return Ok(Json(GenericResponse::new()));
     }
     Err(ApiError::NotFound)
 }
 
+async fn modpack_usage(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path(modpack_id): Path<ModpackId>,
+) -> Result<Json<ModpackUsageResponse>, ApiError> {
+    let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
+    if let Some(modpack) = modpack {
+        let usage = ModpackUsage::get(&modpack.id, &state.pool).await?;
+        return Ok(Json(usage.into()));
+    }
+    Err(ApiError::NotFound)
+}
+
+async fn modpack_webhook(
+    State(state): State<Arc<AppState>>,
+    _: AuthenticatedKey,
+    Path(modpack_id): Path<ModpackId>,
+    Json(data): Json<ModpackWebhookBody>,
+) -> Result<Json<GenericResponse>, ApiError> {
+    if let Some(url) = &data.webhook_url {
+        validate_webhook_url(url)?;
+    }
+    let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
+    if let Some(modpack) = modpack {
+        Modpack::set_webhook(&modpack.id, data.webhook_url.as_ref(), &state.pool).await?;
+        return Ok(Json(GenericResponse::new()));
+    }
+    Err(ApiError::NotFound)
+}
+
+async fn modpack_signature(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path(modpack_id): Path<ModpackId>,
+    Json(data): Json<ModpackSignatureBody>,
+) -> Result<Json<GenericResponse>, ApiError> {
+    if data
+        .signature
+        .as_ref()
+        .is_some_and(|x| x.len() > SIGNATURE_MAX_LENGTH)
+    {
+        return Err(ValidationError("signature is too long".to_string()).into());
+    }
+    let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
+    if let Some(modpack) = modpack {
+        Modpack::set_signature(&modpack.id, data.signature.as_ref(), &state.pool).await?;
+        state.modpack_cache.invalidate(&modpack.id);
+        return Ok(Json(GenericResponse::new()));
+    }
+    Err(ApiError::NotFound)
+}
+
+async fn modpack_roots(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path(modpack_id): Path<ModpackId>,
+    Json(data): Json<ModpackRootsBody>,
+) -> Result<Json<GenericResponse>, ApiError> {
+    if let Some(roots) = &data.allowed_roots {
+        validate_roots(roots)?;
+    }
+    let modpack = Modpack::get_optional(&modpack_id, &state.pool).await?;
+    if let Some(modpack) = modpack {
+        Modpack::set_allowed_roots(&modpack.id, data.allowed_roots.as_deref(), &state.pool).await?;
+        return Ok(Json(GenericResponse::new()));
+    }
+    Err(ApiError::NotFound)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModpackListQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+async fn modpack_list(
+    State(state): State<Arc<AppState>>,
+    _: AuthenticatedKey,
+    Query(query): Query<ModpackListQuery>,
+) -> Result<Json<ModpackListResponse>, ApiError> {
+    let limit = query.limit.unwrap_or(MODPACK_LIST_DEFAULT_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    if !(1..=MODPACK_LIST_MAX_LIMIT).contains(&limit) || offset < 0 {
+        return Err(ApiError::Validation(ValidationError(format!(
+            "limit must be between 1 and {}, offset can't be negative",
+            MODPACK_LIST_MAX_LIMIT
+        ))));
+    }
+    Ok(Json(ModpackListResponse {
+        modpacks: Modpack::list(limit, offset, &state.pool).await?,
+        total: Modpack::count(&state.pool).await?,
+    }))
+}
+
 async fn modpack_create(
     State(state): State<Arc<AppState>>,
     _: AuthenticatedKey,
     Json(data): Json<ModpackCreateBody>,
 ) -> Result<Json<ModpackCreateResponse>, ApiError> {
+    data.validate()?;
     let new_id = Uuid::new_v4().to_string();
-    if sqlx::query!(
-        "SELECT name FROM modpacks WHERE name = $1 LIMIT 1",
-        data.name
-    )
-    .fetch_optional(&state.pool)
-    .await?
-    .is_some()
-    {
-        return Err(ApiError::AlreadyExists);
-    }
-    sqlx::query!(
+    let inserted = sqlx::query!(
         "
         INSERT INTO modpacks
-        (id, name, game, game_version, modloader, modloader_version, sync_version) VALUES
-        ($1, $2, $3, $4, $5, $6, 0)
+        (id, name, game, game_version, modloader, modloader_version, sync_version, webhook_url, create_request_id, allowed_roots, hash_algorithm) VALUES
+        ($1, $2, $3, $4, $5, $6, 0, $7, $8, $9, $10)
+        ON CONFLICT (create_request_id) DO NOTHING RETURNING id
     ",
         new_id,
         data.name,
         data.game,
         data.game_version,
         data.modloader,
-        data.modloader_version
+        data.modloader_version,
+        data.webhook_url,
+        data.request_id,
+        data.allowed_roots.as_deref(),
+        data.hash_algorithm.as_str()
     )
-    .execute(&state.pool)
-    .await?;
-    Ok(Json(ModpackCreateResponse {
-        modpack_id: ModpackId(new_id),
-    }))
+    .fetch_optional(&state.pool)
+    .await;
+    match inserted {
+        Ok(Some(row)) => {
+            return Ok(Json(ModpackCreateResponse {
+                modpack_id: ModpackId(row.id),
+            }))
+        }
+        Ok(None) => {}
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {}
+        Err(err) => return Err(err.into()),
+    }
+    // Either an earlier attempt with the same request id went through, its response got lost
+    // or it's still in flight, or the name is taken
+    let existing = match &data.request_id {
+        Some(request_id) => sqlx::query!(
+            "SELECT id FROM modpacks WHERE create_request_id = $1",
+            request_id
+        )
+        .fetch_optional(&state.pool)
+        .await?
+        .map(|x| x.id),
+        None => None,
+    };
+    match existing {
+        Some(id) => Ok(Json(ModpackCreateResponse {
+            modpack_id: ModpackId(id),
+        })),
+        None => Err(ApiError::AlreadyExists),
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct FileUploadQuery {
     pub file_path: String,
+    /// Hash the client synced the file with, rejected if it isn't the one the server has.
+    /// Content hashing to anything but the server's hash is rejected either way.
+    #[serde(default)]
+    pub expected_hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlobUploadQuery {
+    /// Algorithm of the modpack the blob is for, it's stored under that hash
+    #[serde(default)]
+    pub algorithm: Checksum,
 }
 
 async fn dl_file_hash(
     State(state): State<Arc<AppState>>,
+    token: ReadToken,
     Path(upload_hash): Path<String>,
+    Query(query): Query<DownloadQuery>,
     req: Request,
 ) -> Result<impl IntoResponse, ApiError> {
-    sqlx::query!(
-        "SELECT id FROM files WHERE hash = $1 AND uploaded = true",
-        upload_hash
+    // Joined onto the uploads directory, only a plain hash may get there
+    if !blobs::is_blob_name(&upload_hash) {
+        return Err(ApiError::BadRequest);
+    }
+    let (path, modpack) =
+        download_owner(&state, &upload_hash, token.modpack.or(query.modpack)).await?;
+    let extension = blobs::blob_extension(&path);
+    let extensions = models::files::File::blob_extensions(&upload_hash, &state.pool).await?;
+    let blob = blobs::find_blob(&state.config.uploads_directory, &upload_hash, &extensions)?
+        .ok_or(ApiError::NotFound)?;
+    // Held until the body is dropped, bounding open files and buffers
+    let permit = match &state.download_permits {
+        Some(permits) => Some(permits.clone().try_acquire_owned().map_err(|_| {
+            warn!("Download concurrency limit reached, turning a download away");
+            ApiError::Busy
+        })?),
+        None => None,
+    };
+    let Ok(response) = ServeFile::new(blob)
+        .with_buf_chunk_size(state.config.download_buffer_size)
+        .oneshot(req)
+        .await;
+
+    // Count only the bytes that actually went out, so ranged and aborted downloads are fair
+    let (mut parts, body) = response.into_parts();
+    if extension.is_some_and(|x| COMPRESSED_EXTENSIONS.contains(&x.to_lowercase().as_str())) {
+        parts.extensions.insert(Precompressed);
+    }
+    let mut usage = DownloadUsage {
+        state: state.clone(),
+        modpack,
+        sent: 0,
+        _permit: permit,
+    };
+    let body = Body::new(body).into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            usage.record(chunk.len());
+        }
+    });
+    Ok(Response::from_parts(parts, Body::from_stream(body)))
+}
+
+/// Path of a file with the blob, and the modpack to count its download toward. Only files of
+/// `modpack` are looked at if it's given. Otherwise the download isn't counted when the blob
+/// is shared by several modpacks, it could be for any of them.
+async fn download_owner(
+    state: &AppState,
+    hash: &str,
+    modpack: Option<ModpackId>,
+) -> Result<(String, Option<ModpackId>), ApiError> {
+    let files = sqlx::query!(
+        "SELECT modpack, path FROM files
+        WHERE hash = $1 AND uploaded = true AND ($2::text IS NULL OR modpack = $2)",
+        hash,
+        modpack.map(|x| x.0)
     )
-    .fetch_optional(&state.pool)
-    .await?
-    .ok_or_else(|| ApiError::NotFound)?;
-    Ok(
-        ServeFile::new(std::path::Path::new(&state.config.uploads_directory).join(&upload_hash))
-            .oneshot(req)
-            .await,
+    .fetch_all(&state.pool)
+    .await?;
+    let Some(first) = files.first() else {
+        return Err(ApiError::NotFound);
+    };
+    let modpack = match files.iter().all(|x| x.modpack == first.modpack) {
+        true => Some(ModpackId(first.modpack.clone())),
+        false => None,
+    };
+    Ok((first.path.clone(), modpack))
+}
+
+/// Extensions of files that are archives already, compressing them again barely helps
+const COMPRESSED_EXTENSIONS: [&str; 3] = [".jar", ".zip", ".gz"];
+
+/// Marks a download response as not worth compressing
+#[derive(Clone)]
+struct Precompressed;
+
+/// Compression for blob downloads. Skipped for partial responses, as their ranges refer to
+/// the stored bytes, and for archives like jars.
+fn blob_compression() -> CompressionLayer<impl Predicate> {
+    let worth_it = |status: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| {
+        status != StatusCode::PARTIAL_CONTENT && extensions.get::<Precompressed>().is_none()
+    };
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(worth_it))
+}
+
+/// Lets browser-based launchers call the API from `origins`. Credentials are sent as a bearer
+/// token rather than cookies, so any origin can be allowed safely.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let allow_origin = match origins.iter().any(|x| x == "*") {
+        true => AllowOrigin::any(),
+        false => AllowOrigin::list(origins.iter().filter_map(|x| x.parse().ok())),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            header::RANGE,
+            HeaderName::from_bytes(DIGEST_HEADER.as_bytes()).expect("valid header name"),
+        ])
+        .expose_headers([header::ETAG, header::RETRY_AFTER, header::CONTENT_RANGE])
+        .max_age(Duration::from_secs(3600))
+}
+
+/// Records served bytes of a download into the modpack usage once the body is dropped.
+struct DownloadUsage {
+    state: Arc<AppState>,
+    /// `None` if it isn't known which modpack the download is for
+    modpack: Option<ModpackId>,
+    sent: i64,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl DownloadUsage {
+    fn record(&mut self, bytes: usize) {
+        self.sent += bytes as i64;
+        self.state.metrics.downloaded(bytes as u64);
+    }
+}
+
+impl Drop for DownloadUsage {
+    fn drop(&mut self) {
+        let Some(modpack) = self.modpack.clone().filter(|_| self.sent > 0) else {
+            return;
+        };
+        let state = self.state.clone();
+        let sent = self.sent;
+        tokio::spawn(async move {
+            if let Err(err) = ModpackUsage::add_download(&modpack, sent, &state.pool).await {
+                error!("Failed to record download usage for {}: {}", modpack.0, err);
+            }
+        });
+    }
+}
+
+/// Streams the requested blobs one after another, see `BatchDownloadBody` for the format.
+/// Meant for small files, each blob is read into memory whole.
+async fn dl_batch(
+    State(state): State<Arc<AppState>>,
+    token: ReadToken,
+    Json(data): Json<BatchDownloadBody>,
+) -> Result<Response, ApiError> {
+    if data.hashes.len() > BATCH_DOWNLOAD_MAX_HASHES {
+        return Err(ValidationError(format!(
+            "at most {} hashes can be downloaded at once",
+            BATCH_DOWNLOAD_MAX_HASHES
+        ))
+        .into());
+    }
+    if let Some(hash) = data.hashes.iter().find(|x| !blobs::is_blob_name(x)) {
+        return Err(ValidationError(format!("invalid hash {}", hash)).into());
+    }
+
+    // Blobs of other modpacks look missing to a modpack key
+    let modpack = token.modpack.or(data.modpack);
+    let stream = futures_util::stream::iter(data.hashes).then(move |hash| {
+        let (state, modpack) = (state.clone(), modpack.clone());
+        async move {
+            let mut entry = hash.clone().into_bytes();
+            let file = match download_owner(&state, &hash, modpack).await {
+                Ok(file) => Some(file),
+                Err(ApiError::NotFound) => None,
+                Err(err) => return Err(std::io::Error::other(err)),
+            };
+            let blob = match &file {
+                Some(_) => {
+                    let extensions = models::files::File::blob_extensions(&hash, &state.pool)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                    blobs::find_blob(&state.config.uploads_directory, &hash, &extensions)?
+                }
+                None => None,
+            };
+            let content = match blob {
+                Some(blob) => match tokio::fs::read(&blob).await {
+                    Ok(content) => Some(content),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(err) => return Err(err),
+                },
+                None => None,
+            };
+            match (file, content) {
+                (Some((_, owner)), Some(content)) => {
+                    // Only the content counts as sent, not the hash and length framing it
+                    let sent = content.len() as i64;
+                    entry.extend((content.len() as u64).to_be_bytes());
+                    entry.extend(content);
+                    state.metrics.downloaded(sent as u64);
+                    if let Some(modpack) = owner {
+                        if let Err(err) =
+                            ModpackUsage::add_download(&modpack, sent, &state.pool).await
+                        {
+                            error!("Failed to record download usage for {}: {}", modpack.0, err);
+                        }
+                    }
+                }
+                _ => entry.extend(BATCH_DOWNLOAD_MISSING.to_be_bytes()),
+            }
+            Ok::<_, std::io::Error>(entry)
+        }
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        Body::from_stream(stream),
     )
+        .into_response())
 }
 
 async fn dl_file_upload(
     State(state): State<Arc<AppState>>,
-    _: AuthenticatedKey,
+    _: WriteKey,
     Path(modpack_id): Path<ModpackId>,
     Query(query): Query<FileUploadQuery>,
     mut multipart: Multipart,
 ) -> Result<Json<FileUploadResponse>, ApiError> {
+    if !is_contained_path(&query.file_path) {
+        return Err(ApiError::BadRequest);
+    }
     let existing_file =
         match models::files::File::get_by_path(&modpack_id, &query.file_path, &state.pool).await? {
             Some(file) => file,
             None => return Err(ApiError::NotFound),
         };
+    let modpack = Modpack::get_optional(&modpack_id, &state.pool)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    // Content has to be what filesync registered, whatever the client expects
+    let expected_hash = match existing_file.hash.clone() {
+        Some(hash) if query.expected_hash.as_ref().is_none_or(|x| *x == hash) => hash,
+        _ => return Err(ApiError::BadRequest),
+    };
 
-    if let Some(field) = multipart.next_field().await? {
-        let data = field.bytes().await?;
-
-        // Hashing
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        let hash = hasher.finalize();
-        // FIXME: doesn't sound efficient tbf
-        let hash_str: String = hash
-            .into_iter()
-            .map(|x| format!("{:02x}", x))
-            .collect::<Vec<String>>()
-            .join("");
-
-        if !std::fs::exists(std::path::Path::new(&state.config.uploads_directory).join(&hash_str))?
-        {
-            let mut file =
-                File::create(std::path::Path::new(&state.config.uploads_directory).join(&hash_str))
-                    .await?;
-            file.write_all(&data).await?;
+    if let Some(mut field) = multipart.next_field().await? {
+        let extension = match state.config.blob_extensions {
+            true => blobs::blob_extension(&query.file_path),
+            false => None,
+        };
+        let storage_error = |x| ApiError::storage(x, &state.config.uploads_directory);
+        let mut blob =
+            blobs::BlobWriter::create(&state.config.uploads_directory, modpack.hash_algorithm)
+                .await
+                .map_err(storage_error)?;
+        while let Some(chunk) = field.chunk().await? {
+            blob.write(&chunk).await.map_err(storage_error)?;
+        }
+        let blob = blob.finish().await.map_err(storage_error)?;
+        let (hash_str, size) = (blob.hash.clone(), blob.size);
+        // Corrupted in transit, dropping the blob discards what was received
+        if hash_str != expected_hash {
+            warn!(
+                "Upload of {} hashes to {} instead of {}, discarding it",
+                query.file_path, hash_str, expected_hash
+            );
+            return Err(ApiError::BadRequest);
         }
 
-        models::files::File::set_uploaded(&existing_file.id, true, Some(&hash_str), &state.pool)
-            .await?;
+        let mut tx = state.pool.begin().await?;
+        Modpack::lock(&modpack_id, &mut *tx).await?;
+        Blob::register(&hash_str, size as i64, &mut *tx).await?;
+        let known_extensions = models::files::File::blob_extensions(&hash_str, &mut *tx).await?;
+        blob.store(extension.as_deref(), &known_extensions)
+            .await
+            .map_err(storage_error)?;
+        models::files::File::set_uploaded(
+            &existing_file.id,
+            true,
+            Some(&expected_hash),
+            Some(size as i64),
+            &mut *tx,
+        )
+        .await?;
+        tx.commit().await?;
+        state.modpack_cache.invalidate(&modpack_id);
+        state.metrics.uploaded(size);
+        ModpackUsage::add_upload(&modpack_id, size as i64, &state.pool).await?;
 
         return Ok(Json(FileUploadResponse {
             file_id: existing_file.id,
@@ -299,44 +1107,273 @@ async fn dl_file_upload(
     Err(ApiError::BadRequest)
 }
 
+async fn blob_upload(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Query(query): Query<BlobUploadQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<BlobUploadResponse>, ApiError> {
+    if let Some(mut field) = multipart.next_field().await? {
+        let storage_error = |x| ApiError::storage(x, &state.config.uploads_directory);
+        let mut blob = blobs::BlobWriter::create(&state.config.uploads_directory, query.algorithm)
+            .await
+            .map_err(storage_error)?;
+        while let Some(chunk) = field.chunk().await? {
+            blob.write(&chunk).await.map_err(storage_error)?;
+        }
+        let blob = blob.finish().await.map_err(storage_error)?;
+        let (hash, size) = (blob.hash.clone(), blob.size);
+        let mut tx = state.pool.begin().await?;
+        Blob::register(&hash, size as i64, &mut *tx).await?;
+        // No path to take an extension from, a later filesync finds it under its bare hash
+        let known_extensions = models::files::File::blob_extensions(&hash, &mut *tx).await?;
+        blob.store(None, &known_extensions)
+            .await
+            .map_err(storage_error)?;
+        tx.commit().await?;
+        state.metrics.uploaded(size);
+        return Ok(Json(BlobUploadResponse { hash }));
+    }
+    Err(ApiError::BadRequest)
+}
+
+async fn blob_exists(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Json(data): Json<BlobExistsBody>,
+) -> Result<Json<BlobExistsResponse>, ApiError> {
+    let missing = Blob::missing(&data.hashes, &state.pool).await?;
+    Ok(Json(BlobExistsResponse { missing }))
+}
+
 async fn modpack_file_sync(
     State(state): State<Arc<AppState>>,
-    _: AuthenticatedKey,
+    _: WriteKey,
     Path(modpack_id): Path<ModpackId>,
     Json(data): Json<FileSyncBody>,
 ) -> Result<Json<FileSyncResponse>, ApiError> {
-    if sqlx::query!(
-        "SELECT id FROM modpacks WHERE id = $1 LIMIT 1",
+    data.validate(
+        state.config.max_path_length,
+        state.config.max_path_components,
+    )?;
+    let modpack = sqlx::query!(
+        "SELECT id, webhook_url, allowed_roots FROM modpacks WHERE id = $1 LIMIT 1",
         &modpack_id.0
     )
     .fetch_optional(&state.pool)
     .await?
-    .is_none()
-    {
-        return Err(ApiError::NotFound);
+    .ok_or(ApiError::NotFound)?;
+    let mut tx = state.pool.begin().await?;
+    Modpack::lock(&modpack_id, &mut *tx).await?;
+    let sync_version = apply_file_sync(
+        &state,
+        &modpack_id,
+        modpack.allowed_roots.as_deref(),
+        &data,
+        &mut tx,
+    )
+    .await?;
+    Modpack::bump_sync_version(&modpack_id, &mut *tx).await?;
+    tx.commit().await?;
+    state.modpack_cache.invalidate(&modpack_id);
+    if let Some(url) = modpack.webhook_url {
+        notify_file_sync(&state, url, &modpack_id, data, sync_version);
+    }
+    Ok(Json(FileSyncResponse {}))
+}
+
+/// Syncs every file of the batch in one transaction, if one is rejected none of them are
+async fn modpack_file_sync_batch(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path(modpack_id): Path<ModpackId>,
+    Json(data): Json<FileSyncBatchBody>,
+) -> Result<Json<FileSyncBatchResponse>, ApiError> {
+    data.validate(
+        state.config.max_path_length,
+        state.config.max_path_components,
+    )?;
+    let modpack = sqlx::query!(
+        "SELECT id, webhook_url, allowed_roots FROM modpacks WHERE id = $1 LIMIT 1",
+        &modpack_id.0
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+    let mut tx = state.pool.begin().await?;
+    Modpack::lock(&modpack_id, &mut *tx).await?;
+    let mut results = Vec::with_capacity(data.files.len());
+    for file in data.files.iter() {
+        let sync_version = apply_file_sync(
+            &state,
+            &modpack_id,
+            modpack.allowed_roots.as_deref(),
+            file,
+            &mut tx,
+        )
+        .await?;
+        results.push(FileSyncResult {
+            path: file.path.clone(),
+            sync_version,
+        });
+    }
+    let modpack_version = Modpack::bump_sync_version(&modpack_id, &mut *tx).await?;
+    tx.commit().await?;
+    state.modpack_cache.invalidate(&modpack_id);
+    if let Some(url) = modpack.webhook_url {
+        let files = data
+            .files
+            .iter()
+            .zip(results.iter())
+            .map(|(file, result)| WebhookFile {
+                event: file_sync_event(file).to_string(),
+                path: file.path.clone(),
+                sync_version: result.sync_version,
+            })
+            .collect();
+        webhook::notify(
+            &state,
+            url,
+            WebhookEvent {
+                modpack_id: modpack_id.clone(),
+                event: "batch".to_string(),
+                path: String::new(),
+                sync_version: modpack_version,
+                files,
+            },
+        );
     }
-    let file = models::files::File::get_by_path(&modpack_id, &data.path, &state.pool).await?;
-    if let Some(file) = file {
+    Ok(Json(FileSyncBatchResponse { results }))
+}
+
+/// Creates or updates the file's row, returns its sync version afterwards
+async fn apply_file_sync(
+    state: &AppState,
+    modpack_id: &ModpackId,
+    allowed_roots: Option<&[String]>,
+    data: &FileSyncBody,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<i32, ApiError> {
+    if let Some(roots) = allowed_roots {
+        // Deleting is still allowed, so files synced before the roots were declared can be cleaned up
+        if data.state != FileState::Deleted && !path_in_roots(&data.path, roots) {
+            return Err(ValidationError(format!(
+                "{} is outside the allowed roots {}",
+                data.path,
+                roots.join(", ")
+            ))
+            .into());
+        }
+    }
+    let (uploaded, size) = match (&data.download_source, &data.hash) {
+        // Clients fetch it from Modrinth, there's no blob to wait for
+        (Some(DownloadSource::Modrinth), _) => (false, data.size),
+        // Content may already be there, e.g. uploaded ahead of time by a two-phase sync.
+        // Locked, so `gc` can't remove it before the reference is counted.
+        (_, Some(hash)) => match Blob::get(hash, true, &mut **tx).await? {
+            Some(Blob {
+                size: Some(size), ..
+            }) => (true, Some(size)),
+            // Counted before sizes were tracked
+            Some(_) => {
+                let extensions = models::files::File::blob_extensions(hash, &mut **tx).await?;
+                let size = blobs::blob_size(&state.config.uploads_directory, hash, &extensions)?
+                    .map(|x| x as i64);
+                (true, size)
+            }
+            None => (false, None),
+        },
+        (_, None) => (false, None),
+    };
+    let file = models::files::File::get_by_path(modpack_id, &data.path, &mut **tx).await?;
+    let sync_version = if let Some(file) = file {
+        // Clients only recheck files whose version went up, so bump it on every real change
         sqlx::query!(
-            "UPDATE files SET path = $1, state = $2, hash = $3, updated_at = now() WHERE id = $4",
+            "UPDATE files SET path = $1, state = $2, hash = $3, uploaded = $4, size = $5, mode = $6, updated_at = now(),
+                mod_state = coalesce($7, mod_state), download_source = $8, source_url = $9,
+                sync_version = CASE
+                    WHEN state IS DISTINCT FROM $2 OR hash IS DISTINCT FROM $3 OR mode IS DISTINCT FROM $6
+                    THEN sync_version + 1 ELSE sync_version END
+            WHERE id = $10 RETURNING sync_version",
             data.path,
             data.state.as_str(),
             data.hash,
+            uploaded,
+            size,
+            data.mode,
+            data.mod_state.map(|x| x.as_str()),
+            data.download_source.map(|x| x.as_str()),
+            data.source_url,
             file.id.0
         )
-        .execute(&state.pool)
-        .await?;
+        .fetch_one(&mut **tx)
+        .await?
+        .sync_version
     } else {
         models::files::File::insert(
-            &modpack_id,
+            modpack_id,
             &data.path,
             data.state,
             data.hash.as_ref(),
-            &state.pool,
+            uploaded,
+            size,
+            data.mode.as_ref(),
+            data.mod_state,
+            data.download_source,
+            data.source_url.as_ref(),
+            &mut **tx,
         )
         .await?;
+        0
+    };
+    Ok(sync_version)
+}
+
+fn notify_file_sync(
+    state: &AppState,
+    url: String,
+    modpack_id: &ModpackId,
+    data: FileSyncBody,
+    sync_version: i32,
+) {
+    webhook::notify(
+        state,
+        url,
+        WebhookEvent {
+            modpack_id: modpack_id.clone(),
+            event: file_sync_event(&data).to_string(),
+            path: data.path,
+            sync_version,
+            files: Vec::new(),
+        },
+    );
+}
+
+fn file_sync_event(data: &FileSyncBody) -> &'static str {
+    match data.state {
+        FileState::Deleted => "delete",
+        _ => "filesync",
     }
-    Ok(Json(FileSyncResponse {}))
+}
+
+/// Drops a file's row for good, unlike syncing it as `Deleted`. Clients that already have
+/// the file keep it, as they never see it removed. Its blob is left to `gc` once nothing uses it.
+async fn modpack_file_delete(
+    State(state): State<Arc<AppState>>,
+    _: WriteKey,
+    Path(modpack_id): Path<ModpackId>,
+    Json(data): Json<FileDeleteBody>,
+) -> Result<Json<GenericResponse>, ApiError> {
+    let file = models::files::File::get_by_path(&modpack_id, &data.path, &state.pool)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let mut tx = state.pool.begin().await?;
+    Modpack::lock(&modpack_id, &mut *tx).await?;
+    models::files::File::delete(&file.id, &mut *tx).await?;
+    Modpack::bump_sync_version(&modpack_id, &mut *tx).await?;
+    tx.commit().await?;
+    state.modpack_cache.invalidate(&modpack_id);
+    Ok(Json(GenericResponse::new()))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -350,6 +1387,7 @@ impl GenericResponse {
     }
 }
 
+/// Holds a master key, allowed to manage every modpack
 #[allow(unused)]
 pub struct AuthenticatedKey(pub String);
 
@@ -368,13 +1406,455 @@ where
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| ApiError::Unauthorized)?;
-        if state.master_key != *bearer.token() {
+        if !state.master_keys.contains(bearer.token()) {
             return Err(ApiError::Unauthorized);
         }
-        Ok(AuthenticatedKey(state.master_key.clone()))
+        Ok(AuthenticatedKey(bearer.token().to_string()))
     }
 }
 
+/// Bearer token of the request, checked against the master keys and then the modpack keys.
+/// `None` if it's neither.
+async fn key_owner(parts: &mut Parts, state: &AppState) -> Result<Option<KeyOwner>, ApiError> {
+    let TypedHeader(Authorization(bearer)) = parts
+        .extract::<TypedHeader<Authorization<Bearer>>>()
+        .await
+        .map_err(|_| ApiError::Unauthorized)?;
+    if state.master_keys.contains(bearer.token()) {
+        return Ok(Some(KeyOwner::Master));
+    }
+    Ok(ModpackKey::get_by_key(bearer.token(), &state.pool)
+        .await?
+        .map(KeyOwner::Modpack))
+}
+
+/// The `:modpack_id` of the route, if it has one
+async fn path_modpack_id(parts: &mut Parts) -> Option<ModpackId> {
+    let Path(params) = parts
+        .extract::<Path<HashMap<String, String>>>()
+        .await
+        .ok()?;
+    params.get("modpack_id").cloned().map(ModpackId)
+}
+
+pub enum KeyOwner {
+    Master,
+    Modpack(ModpackKey),
+}
+
+impl KeyOwner {
+    /// Modpack the key may read, `None` if it reads all of them. A modpack key can't be used
+    /// on another modpack's routes.
+    fn readable_modpack(&self, modpack_id: Option<&ModpackId>) -> Option<Option<ModpackId>> {
+        match self {
+            KeyOwner::Master => Some(None),
+            // Any scope reads
+            KeyOwner::Modpack(key) => match modpack_id {
+                Some(x) if *x != key.modpack => None,
+                _ => Some(Some(key.modpack.clone())),
+            },
+        }
+    }
+
+    fn can_write(&self, modpack_id: Option<&ModpackId>) -> bool {
+        match self {
+            KeyOwner::Master => true,
+            KeyOwner::Modpack(key) => {
+                key.scope == KeyScope::Write && modpack_id.is_none_or(|x| *x == key.modpack)
+            }
+        }
+    }
+}
+
+/// Allowed to push: a master key, or a write key of the modpack in the path.
+/// Routes without a modpack, like blob uploads, take a write key of any modpack.
+#[allow(unused)]
+pub struct WriteKey(pub KeyOwner);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WriteKey
+where
+    AxumAppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AxumAppState::from_ref(state);
+        let owner = key_owner(parts, &state)
+            .await?
+            .ok_or(ApiError::Unauthorized)?;
+        if !owner.can_write(path_modpack_id(parts).await.as_ref()) {
+            return Err(ApiError::Unauthorized);
+        }
+        Ok(WriteKey(owner))
+    }
+}
+
+/// Allowed to read modpacks, either anyone or holders of a read token or the master key,
+/// depending on `require_read_token`. Modpack keys read their own modpack only, routes
+/// without one in the path must only serve what belongs to `modpack`.
+pub struct ReadToken {
+    #[allow(unused)]
+    pub token: Option<String>,
+    /// Set for modpack keys, the one modpack they may read
+    pub modpack: Option<ModpackId>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ReadToken
+where
+    AxumAppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AxumAppState::from_ref(state);
+        if !state.config.require_read_token {
+            return Ok(ReadToken {
+                token: None,
+                modpack: None,
+            });
+        }
+        let TypedHeader(Authorization(bearer)) = parts
+            .extract::<TypedHeader<Authorization<Bearer>>>()
+            .await
+            .map_err(|_| ApiError::Unauthorized)?;
+        let token = bearer.token();
+        if state.master_keys.contains(token) || state.config.read_tokens.iter().any(|x| x == token)
+        {
+            return Ok(ReadToken {
+                token: Some(token.to_string()),
+                modpack: None,
+            });
+        }
+        let key = ModpackKey::get_by_key(token, &state.pool)
+            .await?
+            .ok_or(ApiError::Unauthorized)?;
+        let modpack = KeyOwner::Modpack(key)
+            .readable_modpack(path_modpack_id(parts).await.as_ref())
+            .ok_or(ApiError::Unauthorized)?;
+        Ok(ReadToken {
+            token: Some(token.to_string()),
+            modpack,
+        })
+    }
+}
+
+/// Rejects requests whose body doesn't match their `Digest` header, if they send one
+async fn verify_body_digest(req: Request, next: Next) -> Result<Response, ApiError> {
+    let Some(digest) = req.headers().get(DIGEST_HEADER).cloned() else {
+        return Ok(next.run(req).await);
+    };
+    let digest = digest.to_str().map_err(|_| ApiError::BadRequest)?;
+    let (parts, body) = req.into_parts();
+    // Size is already bounded by the body limit layers around this
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| ApiError::BadRequest)?;
+    if verify_digest(digest, &body) == Some(false) {
+        return Err(ValidationError("body doesn't match its Digest header".to_string()).into());
+    }
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+/// Like `verify_body_digest`, but checks the body as it streams through to the handler
+/// instead of buffering it, for uploads too large to hold in memory. A mismatch fails
+/// reading the last of the body, before the handler could store any of it.
+async fn verify_streamed_body_digest(req: Request, next: Next) -> Result<Response, ApiError> {
+    let Some(digest) = req.headers().get(DIGEST_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+    let digest = digest.to_str().map_err(|_| ApiError::BadRequest)?;
+    let Some(verifier) = DigestVerifier::new(digest) else {
+        return Ok(next.run(req).await);
+    };
+    // Multipart parsing stops at the closing boundary without waiting for the end of the
+    // body, so each chunk is held back until the next one arrives. The last is only handed
+    // over once the whole body checked out, with or without a known length.
+    let (parts, body) = req.into_parts();
+    let stream = futures_util::stream::unfold(
+        (body.into_data_stream(), Some(verifier), None),
+        |(mut body, verifier, mut held)| async move {
+            let mut verifier = verifier?;
+            loop {
+                match body.next().await {
+                    Some(Ok(data)) => {
+                        verifier.update(&data);
+                        if let Some(previous) = held.replace(data) {
+                            return Some((Ok(previous), (body, Some(verifier), held)));
+                        }
+                    }
+                    Some(Err(err)) => return Some((Err(err.into_inner()), (body, None, None))),
+                    None => {
+                        let result = match verifier.verify() {
+                            true => Ok(held.unwrap_or_default()),
+                            false => Err(DigestMismatch.into()),
+                        };
+                        return Some((result, (body, None, None)));
+                    }
+                }
+            }
+        },
+    );
+    Ok(next
+        .run(Request::from_parts(parts, Body::from_stream(stream)))
+        .await)
+}
+
+/// Fails reading a streamed body that doesn't match its `Digest` header
+#[derive(thiserror::Error, Debug)]
+#[error("body doesn't match its Digest header")]
+pub struct DigestMismatch;
+
+/// Connects to the database and brings its schema up to date
+pub async fn connect_database(
+    config: &ServerConfig,
+    max_connections: u32,
+) -> anyhow::Result<PgPool> {
+    let pool = PgPoolOptions::new()
+        .max_connections(max_connections)
+        .connect(&config.database_url)
+        .await?;
+    sqlx::migrate!().run(&pool).await?;
+    Ok(pool)
+}
+
+/// Path of the server config file, overridable with `MODSYNC_CONFIG_PATH`
+pub fn config_path() -> String {
+    var("MODSYNC_CONFIG_PATH").unwrap_or("modsync.server.toml".to_string())
+}
+
+/// Where a resolved config value came from
+#[derive(Clone, Copy, Debug)]
+pub enum ConfigSource {
+    Env(&'static str),
+    File,
+    Default,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Env(name) => write!(f, "env {}", name),
+            ConfigSource::File => write!(f, "{}", config_path()),
+            ConfigSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Records the source of every value picked while resolving the config
+#[derive(Default)]
+pub struct ConfigSources(pub Vec<(&'static str, ConfigSource)>);
+
+impl ConfigSources {
+    /// Takes the first set value out of the env variable, the config file and the default
+    fn pick(
+        &mut self,
+        name: &'static str,
+        env: &'static str,
+        file: Option<String>,
+        default: Option<String>,
+    ) -> anyhow::Result<String> {
+        self.pick_with(name, env, |x| x, file, default)
+    }
+
+    /// Same as `pick`, with `from_env` turning the env variable into a value
+    fn pick_with<T>(
+        &mut self,
+        name: &'static str,
+        env: &'static str,
+        from_env: impl FnOnce(String) -> T,
+        file: Option<T>,
+        default: Option<T>,
+    ) -> anyhow::Result<T> {
+        let (value, source) = match (var(env).ok(), file, default) {
+            (Some(value), _, _) => (from_env(value), ConfigSource::Env(env)),
+            (None, Some(value), _) => (value, ConfigSource::File),
+            (None, None, Some(value)) => (value, ConfigSource::Default),
+            (None, None, None) => return Err(anyhow::anyhow!("No {} set!", name)),
+        };
+        self.0.push((name, source));
+        Ok(value)
+    }
+
+    /// Same as `pick`, for values that can only be set in the config file
+    fn pick_file<T>(&mut self, name: &'static str, file: Option<T>, default: T) -> T {
+        match file {
+            Some(value) => {
+                self.0.push((name, ConfigSource::File));
+                value
+            }
+            None => {
+                self.0.push((name, ConfigSource::Default));
+                default
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<ConfigSource> {
+        self.0.iter().find(|x| x.0 == name).map(|x| x.1)
+    }
+}
+
+/// Resolves the server config from env variables, the config file and defaults, in that order
+pub fn load_config() -> anyhow::Result<(ServerConfig, ConfigSources)> {
+    let server_config_text = std::fs::read_to_string(config_path());
+    let file = if let Ok(text) = server_config_text {
+        toml::from_str::<ServerConfigFile>(&text)?
+    } else {
+        ServerConfigFile::default()
+    };
+    config_from_file(file)
+}
+
+/// Fills in what `file` leaves out from the environment and the defaults
+pub fn config_from_file(file: ServerConfigFile) -> anyhow::Result<(ServerConfig, ConfigSources)> {
+    let mut sources = ConfigSources::default();
+    let config = ServerConfig {
+        database_url: sources.pick("database_url", "DATABASE_URL", file.database_url, None)?,
+        // The env variable takes a comma separated list
+        master_keys: sources
+            .pick_with(
+                "master_key",
+                "MODSYNC_MASTER_KEY",
+                |x| x.split(',').map(|x| x.trim().to_string()).collect(),
+                file.master_key.map(MasterKeys::into_vec),
+                None,
+            )?
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .collect(),
+        port: sources
+            .pick("port", "MODSYNC_PORT", file.port, Some("7040".to_string()))?
+            .parse()?,
+        bind_address: {
+            let address = sources.pick(
+                "bind_address",
+                "MODSYNC_BIND_ADDRESS",
+                file.bind_address,
+                Some("0.0.0.0".to_string()),
+            )?;
+            address
+                .parse()
+                .map_err(|_| anyhow::anyhow!("bind_address {:?} is not an IP address", address))?
+        },
+        uploads_directory: sources.pick(
+            "uploads_directory",
+            "MODSYNC_UPLOADS_DIRECTORY",
+            file.uploads_directory,
+            Some("uploads".to_string()),
+        )?,
+        file_size_limit: sources.pick_file("file_size_limit", file.file_size_limit, 262144000),
+        modpack_cache_ttl: sources.pick_file("modpack_cache_ttl", file.modpack_cache_ttl, 5),
+        verify_blobs_on_startup: sources.pick_file(
+            "verify_blobs_on_startup",
+            file.verify_blobs_on_startup,
+            false,
+        ),
+        json_body_limit: sources.pick_file("json_body_limit", file.json_body_limit, 65536),
+        read_tokens: sources.pick_file("read_tokens", file.read_tokens, Vec::new()),
+        require_read_token: sources.pick_file("require_read_token", file.require_read_token, false),
+        max_path_length: sources.pick_file("max_path_length", file.max_path_length, 200),
+        max_path_components: sources.pick_file("max_path_components", file.max_path_components, 16),
+        slow_request_threshold_ms: sources.pick_file(
+            "slow_request_threshold_ms",
+            file.slow_request_threshold_ms
+                .map(|x| Some(x).filter(|x| *x > 0)),
+            None,
+        ),
+        blob_extensions: sources.pick_file("blob_extensions", file.blob_extensions, false),
+        maintenance: sources.pick_file("maintenance", file.maintenance, false),
+        maintenance_retry_after: sources.pick_file(
+            "maintenance_retry_after",
+            file.maintenance_retry_after,
+            60,
+        ),
+        download_concurrency: sources.pick_file(
+            "download_concurrency",
+            file.download_concurrency,
+            128,
+        ),
+        download_buffer_size: sources.pick_file(
+            "download_buffer_size",
+            file.download_buffer_size,
+            65536,
+        ),
+        upload_chunk_size: sources.pick_file("upload_chunk_size", file.upload_chunk_size, 8388608),
+        shutdown_timeout: sources.pick_file("shutdown_timeout", file.shutdown_timeout, 30),
+        request_timeout_secs: sources.pick_file(
+            "request_timeout_secs",
+            file.request_timeout_secs,
+            15,
+        ),
+        transfer_timeout_secs: sources.pick_file(
+            "transfer_timeout_secs",
+            file.transfer_timeout_secs
+                .map(|x| Some(x).filter(|x| *x > 0)),
+            None,
+        ),
+        tls_cert_path: sources.pick_file("tls_cert_path", file.tls_cert_path.map(Some), None),
+        tls_key_path: sources.pick_file("tls_key_path", file.tls_key_path.map(Some), None),
+        metrics: sources.pick_file("metrics", file.metrics, false),
+        metrics_require_key: sources.pick_file(
+            "metrics_require_key",
+            file.metrics_require_key,
+            true,
+        ),
+        allowed_origins: sources.pick_file("allowed_origins", file.allowed_origins, Vec::new()),
+        rate_limit_requests: sources.pick_file("rate_limit_requests", file.rate_limit_requests, 0),
+        rate_limit_window_secs: sources.pick_file(
+            "rate_limit_window_secs",
+            file.rate_limit_window_secs,
+            60,
+        ),
+        webhook_allow_private: sources.pick_file(
+            "webhook_allow_private",
+            file.webhook_allow_private,
+            false,
+        ),
+    };
+    if config.master_keys.is_empty() {
+        return Err(anyhow::anyhow!("No master key set!"));
+    }
+    if config.download_buffer_size == 0 {
+        return Err(anyhow::anyhow!(
+            "download_buffer_size must be greater than 0"
+        ));
+    }
+    if config.upload_chunk_size == 0 {
+        return Err(anyhow::anyhow!("upload_chunk_size must be greater than 0"));
+    }
+    if config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+        return Err(anyhow::anyhow!(
+            "tls_cert_path and tls_key_path must be set together"
+        ));
+    }
+    if config.request_timeout_secs == 0 {
+        return Err(anyhow::anyhow!(
+            "request_timeout_secs must be greater than 0"
+        ));
+    }
+    if config.rate_limit_window_secs == 0 {
+        return Err(anyhow::anyhow!(
+            "rate_limit_window_secs must be greater than 0"
+        ));
+    }
+    for origin in config.allowed_origins.iter().filter(|x| *x != "*") {
+        // Browsers send the bare origin, a path or trailing slash would never match
+        let valid =
+            reqwest::Url::parse(origin).is_ok_and(|x| x.origin().ascii_serialization() == *origin);
+        if !valid {
+            return Err(anyhow::anyhow!(
+                "allowed_origins entry {:?} is not an origin like https://launcher.example.com",
+                origin
+            ));
+        }
+    }
+    Ok((config, sources))
+}
+
 pub fn create_directories<P>(path: P) -> Result<(), std::io::Error>
 where
     P: AsRef<std::path::Path>,
@@ -391,3 +1871,6 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests;
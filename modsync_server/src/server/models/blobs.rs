@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+
+/// A stored blob. Its row's `refcount`, the number of uploaded files pointing to it, is kept up
+/// to date by a trigger on `files`. Blobs at zero are removed by `gc` once released long enough.
+pub struct Blob {
+    pub hash: String,
+    pub size: Option<i64>,
+}
+
+impl Blob {
+    /// `None` if no such blob is stored. With `lock`, `gc` can't remove it until the
+    /// transaction `exec` belongs to ends.
+    pub async fn get<'a, E>(hash: &str, lock: bool, exec: E) -> Result<Option<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let blob = if lock {
+            sqlx::query_as!(
+                Blob,
+                "SELECT hash, size FROM blobs WHERE hash = $1 FOR SHARE",
+                hash
+            )
+            .fetch_optional(exec)
+            .await?
+        } else {
+            sqlx::query_as!(Blob, "SELECT hash, size FROM blobs WHERE hash = $1", hash)
+                .fetch_optional(exec)
+                .await?
+        };
+        Ok(blob)
+    }
+
+    /// Records a blob about to be moved into place. Its row stays locked until the transaction
+    /// ends, so call it before storing, `gc` can't remove the blob underneath in the meantime.
+    /// An unreferenced blob waits out its grace period again.
+    pub async fn register<'a, E>(hash: &str, size: i64, exec: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!(
+            "INSERT INTO blobs (hash, refcount, size, released_at) VALUES ($1, 0, $2, now())
+            ON CONFLICT (hash) DO UPDATE SET size = coalesce(blobs.size, EXCLUDED.size),
+                released_at = CASE WHEN blobs.refcount = 0 THEN now() ELSE blobs.released_at END",
+            hash,
+            size
+        )
+        .execute(exec)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a blob found in the uploads directory without a row, as released when it was
+    /// last modified. Returns `false` if it got one in the meantime.
+    pub async fn adopt<'a, E>(
+        hash: &str,
+        size: i64,
+        modified: chrono::DateTime<chrono::Utc>,
+        exec: E,
+    ) -> Result<bool, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let result = sqlx::query!(
+            "INSERT INTO blobs (hash, refcount, size, released_at) VALUES ($1, 0, $2, $3)
+            ON CONFLICT (hash) DO NOTHING",
+            hash,
+            size,
+            modified
+        )
+        .execute(exec)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Hashes of every stored blob
+    pub async fn hashes<'a, E>(exec: E) -> Result<HashSet<String>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let hashes = sqlx::query!("SELECT hash FROM blobs")
+            .fetch_all(exec)
+            .await?
+            .into_iter()
+            .map(|x| x.hash)
+            .collect();
+        Ok(hashes)
+    }
+
+    /// Those of `hashes` that aren't stored
+    pub async fn missing<'a, E>(hashes: &[String], exec: E) -> Result<Vec<String>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let missing = sqlx::query!(
+            r#"SELECT h AS "hash!" FROM unnest($1::text[]) WITH ORDINALITY AS x(h, n)
+            WHERE NOT EXISTS (SELECT 1 FROM blobs WHERE hash = h) ORDER BY n"#,
+            hashes
+        )
+        .fetch_all(exec)
+        .await?
+        .into_iter()
+        .map(|x| x.hash)
+        .collect();
+        Ok(missing)
+    }
+
+    /// Blobs nothing has referred to since before `cutoff`
+    pub async fn released_before<'a, E>(
+        cutoff: chrono::DateTime<chrono::Utc>,
+        exec: E,
+    ) -> Result<Vec<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query_as!(
+            Blob,
+            "SELECT hash, size FROM blobs WHERE refcount = 0 AND released_at < $1",
+            cutoff
+        )
+        .fetch_all(exec)
+        .await
+    }
+
+    /// Deletes the row if the blob is still unreferenced since before `cutoff`, returns whether
+    /// it did. Nobody can store or reference the blob again until the transaction ends, so
+    /// remove it from disk before committing.
+    pub async fn delete_released<'a, E>(
+        hash: &str,
+        cutoff: chrono::DateTime<chrono::Utc>,
+        exec: E,
+    ) -> Result<bool, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let result = sqlx::query!(
+            "DELETE FROM blobs WHERE hash = $1 AND refcount = 0 AND released_at < $2",
+            hash,
+            cutoff
+        )
+        .execute(exec)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -1,6 +1,6 @@
 use modsync_core::{
     api::{FileId, ModpackId},
-    FileState, StrConversion,
+    DownloadSource, FileState, ModState, StrConversion, TryFromStr,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -16,30 +16,67 @@ pub struct File {
     pub sync_version: i32,
     pub hash: Option<String>,
     pub uploaded: bool,
+    pub size: Option<i64>,
+    pub mode: Option<String>,
+    pub mod_state: Option<ModState>,
+    pub download_source: Option<DownloadSource>,
+    pub source_url: Option<String>,
 }
 
 impl File {
-    pub async fn insert<'a, E>(modpack_id: &ModpackId, path: &'a str, state: FileState, hash: Option<&String>, exec: E) -> Result<FileId, sqlx::Error>
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert<'a, E>(
+        modpack_id: &ModpackId,
+        path: &'a str,
+        state: FileState,
+        hash: Option<&String>,
+        uploaded: bool,
+        size: Option<i64>,
+        mode: Option<&String>,
+        mod_state: Option<ModState>,
+        download_source: Option<DownloadSource>,
+        source_url: Option<&String>,
+        exec: E,
+    ) -> Result<FileId, sqlx::Error>
     where
         E: sqlx::PgExecutor<'a>,
     {
         let new_id = Uuid::new_v4().to_string();
         sqlx::query!(
-            "INSERT INTO FILES (id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded)
-            VALUES ($1, $2, now(), now(), $3, $4, 0, $5, false)",
-            new_id, modpack_id.0, path, state.as_str(), hash
+            "INSERT INTO FILES (id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded, size, mode, mod_state, download_source, source_url)
+            VALUES ($1, $2, now(), now(), $3, $4, 0, $5, $6, $7, $8, $9, $10, $11)",
+            new_id, modpack_id.0, path, state.as_str(), hash, uploaded, size, mode, mod_state.map(|x| x.as_str()),
+            download_source.map(|x| x.as_str()), source_url
         )
         .execute(exec)
         .await?;
         Ok(FileId(new_id))
     }
 
+    /// Extensions the blob `hash` may be stored under, one for each kind of file with it.
+    /// Saves searching the uploads directory, see `blobs::find_blob`.
+    pub async fn blob_extensions<'a, E>(hash: &str, exec: E) -> Result<Vec<String>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let paths = sqlx::query_scalar!("SELECT DISTINCT path FROM files WHERE hash = $1", hash)
+            .fetch_all(exec)
+            .await?;
+        let mut extensions: Vec<String> = paths
+            .iter()
+            .filter_map(|x| crate::server::blobs::blob_extension(x))
+            .collect();
+        extensions.sort();
+        extensions.dedup();
+        Ok(extensions)
+    }
+
     pub async fn get<'a, E>(id: &FileId, exec: E) -> Result<Self, sqlx::Error>
     where
         E: sqlx::PgExecutor<'a>,
     {
         let x = sqlx::query!(
-            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded
+            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded, size, mode, mod_state, download_source, source_url
             FROM files WHERE modpack = $1 LIMIT 1",
             id.0
         )
@@ -51,10 +88,23 @@ impl File {
             created_at: x.created_at,
             updated_at: x.updated_at,
             path: x.path,
-            state: FileState::from_str(&x.state),
+            state: FileState::try_from_str(&x.state)?,
             sync_version: x.sync_version,
             hash: x.hash,
             uploaded: x.uploaded,
+            size: x.size,
+            mode: x.mode,
+            mod_state: x
+                .mod_state
+                .as_deref()
+                .map(ModState::try_from_str)
+                .transpose()?,
+            download_source: x
+                .download_source
+                .as_deref()
+                .map(DownloadSource::try_from_str)
+                .transpose()?,
+            source_url: x.source_url,
         })
     }
 
@@ -63,23 +113,31 @@ impl File {
         E: sqlx::PgExecutor<'a>,
     {
         let file = sqlx::query!(
-            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded
+            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded, size, mode, mod_state, download_source, source_url
             FROM files WHERE modpack = $1 LIMIT 1",
             id.0
         )
         .fetch_optional(exec)
         .await?
-        .map(|x| File {
-            id: FileId(x.id),
-            modpack: ModpackId(x.modpack),
-            created_at: x.created_at,
-            updated_at: x.updated_at,
-            path: x.path,
-            state: FileState::from_str(&x.state),
-            sync_version: x.sync_version,
-            hash: x.hash,
-            uploaded: x.uploaded,
-        });
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(File {
+                id: FileId(x.id),
+                modpack: ModpackId(x.modpack),
+                created_at: x.created_at,
+                updated_at: x.updated_at,
+                path: x.path,
+                state: FileState::try_from_str(&x.state)?,
+                sync_version: x.sync_version,
+                hash: x.hash,
+                uploaded: x.uploaded,
+                size: x.size,
+                mode: x.mode,
+                mod_state: x.mod_state.as_deref().map(ModState::try_from_str).transpose()?,
+                download_source: x.download_source.as_deref().map(DownloadSource::try_from_str).transpose()?,
+                source_url: x.source_url,
+            })
+        })
+        .transpose()?;
         Ok(file)
     }
 
@@ -88,75 +146,107 @@ impl File {
         E: sqlx::PgExecutor<'a>,
     {
         let files: Vec<Self> = sqlx::query!(
-            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded
+            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded, size, mode, mod_state, download_source, source_url
             FROM files WHERE modpack = $1",
             id.0
         )
         .fetch_all(exec)
         .await?
         .into_iter()
-        .map(|x| File {
-            id: FileId(x.id),
-            modpack: ModpackId(x.modpack),
-            created_at: x.created_at,
-            updated_at: x.updated_at,
-            path: x.path,
-            state: FileState::from_str(&x.state),
-            sync_version: x.sync_version,
-            hash: x.hash,
-            uploaded: x.uploaded,
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(File {
+                id: FileId(x.id),
+                modpack: ModpackId(x.modpack),
+                created_at: x.created_at,
+                updated_at: x.updated_at,
+                path: x.path,
+                state: FileState::try_from_str(&x.state)?,
+                sync_version: x.sync_version,
+                hash: x.hash,
+                uploaded: x.uploaded,
+                size: x.size,
+                mode: x.mode,
+                mod_state: x.mod_state.as_deref().map(ModState::try_from_str).transpose()?,
+                download_source: x.download_source.as_deref().map(DownloadSource::try_from_str).transpose()?,
+                source_url: x.source_url,
+            })
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
         Ok(files)
     }
 
-    pub async fn get_by_path<'a, E>(modpack_id: &ModpackId, path: &'a str, exec: E) -> Result<Option<Self>, sqlx::Error>
+    pub async fn get_changed_since<'a, E>(
+        id: &ModpackId,
+        since: i64,
+        exec: E,
+    ) -> Result<Vec<Self>, sqlx::Error>
     where
         E: sqlx::PgExecutor<'a>,
     {
-        let file = sqlx::query!(
-            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded
-            FROM files WHERE modpack = $1 AND path = $2",
-            modpack_id.0, path
+        let files: Vec<Self> = sqlx::query!(
+            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded, size, mode, mod_state, download_source, source_url
+            FROM files WHERE modpack = $1 AND change_seq > $2",
+            id.0, since
         )
-        .fetch_optional(exec)
+        .fetch_all(exec)
         .await?
-        .map(|x| File {
-            id: FileId(x.id),
-            modpack: ModpackId(x.modpack),
-            created_at: x.created_at,
-            updated_at: x.updated_at,
-            path: x.path,
-            state: FileState::from_str(&x.state),
-            sync_version: x.sync_version,
-            hash: x.hash,
-            uploaded: x.uploaded,
-        });
-        Ok(file)
+        .into_iter()
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(File {
+                id: FileId(x.id),
+                modpack: ModpackId(x.modpack),
+                created_at: x.created_at,
+                updated_at: x.updated_at,
+                path: x.path,
+                state: FileState::try_from_str(&x.state)?,
+                sync_version: x.sync_version,
+                hash: x.hash,
+                uploaded: x.uploaded,
+                size: x.size,
+                mode: x.mode,
+                mod_state: x.mod_state.as_deref().map(ModState::try_from_str).transpose()?,
+                download_source: x.download_source.as_deref().map(DownloadSource::try_from_str).transpose()?,
+                source_url: x.source_url,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+        Ok(files)
     }
 
-    pub async fn get_by_hash<'a, E>(hash: &'a str, exec: E) -> Result<Option<Self>, sqlx::Error>
+    pub async fn get_by_path<'a, E>(
+        modpack_id: &ModpackId,
+        path: &'a str,
+        exec: E,
+    ) -> Result<Option<Self>, sqlx::Error>
     where
         E: sqlx::PgExecutor<'a>,
     {
         let file = sqlx::query!(
-            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded
-            FROM files WHERE hash = $1 AND uploaded = true",
-            hash
+            "SELECT id, modpack, created_at, updated_at, path, state, sync_version, hash, uploaded, size, mode, mod_state, download_source, source_url
+            FROM files WHERE modpack = $1 AND path = $2",
+            modpack_id.0, path
         )
         .fetch_optional(exec)
         .await?
-        .map(|x| File {
-            id: FileId(x.id),
-            modpack: ModpackId(x.modpack),
-            created_at: x.created_at,
-            updated_at: x.updated_at,
-            path: x.path,
-            state: FileState::from_str(&x.state),
-            sync_version: x.sync_version,
-            hash: x.hash,
-            uploaded: x.uploaded,
-        });
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(File {
+                id: FileId(x.id),
+                modpack: ModpackId(x.modpack),
+                created_at: x.created_at,
+                updated_at: x.updated_at,
+                path: x.path,
+                state: FileState::try_from_str(&x.state)?,
+                sync_version: x.sync_version,
+                hash: x.hash,
+                uploaded: x.uploaded,
+                size: x.size,
+                mode: x.mode,
+                mod_state: x.mod_state.as_deref().map(ModState::try_from_str).transpose()?,
+                download_source: x.download_source.as_deref().map(DownloadSource::try_from_str).transpose()?,
+                source_url: x.source_url,
+            })
+        })
+        .transpose()?;
         Ok(file)
     }
 
@@ -164,8 +254,11 @@ impl File {
     where
         E: sqlx::PgExecutor<'a>,
     {
+        // Raise the cursor floor, a delta can't express a row that no longer exists
         sqlx::query!(
-            "DELETE FROM files WHERE id = $1",
+            "WITH deleted AS (DELETE FROM files WHERE id = $1 RETURNING modpack)
+            UPDATE modpacks SET cursor_floor = nextval('file_change_seq')
+            WHERE id IN (SELECT modpack FROM deleted)",
             id.0
         )
         .execute(exec)
@@ -173,13 +266,19 @@ impl File {
         Ok(())
     }
 
-    pub async fn set_uploaded<'a, E>(id: &FileId, uploaded: bool, hash: Option<&String>, exec: E) -> Result<(), sqlx::Error>
+    pub async fn set_uploaded<'a, E>(
+        id: &FileId,
+        uploaded: bool,
+        hash: Option<&String>,
+        size: Option<i64>,
+        exec: E,
+    ) -> Result<(), sqlx::Error>
     where
         E: sqlx::PgExecutor<'a>,
     {
         sqlx::query!(
-            "UPDATE files SET updated_at = now(), uploaded = $1, hash = $2, sync_version = sync_version + 1 WHERE id = $3",
-            uploaded, hash, id.0
+            "UPDATE files SET updated_at = now(), uploaded = $1, hash = $2, size = $3, sync_version = sync_version + 1 WHERE id = $4",
+            uploaded, hash, size, id.0
         )
         .execute(exec)
         .await?;
@@ -198,8 +297,12 @@ impl From<File> for modsync_core::models::files::File {
             state: x.state,
             sync_version: x.sync_version,
             hash: x.hash,
-            uploaded: x.uploaded
+            uploaded: x.uploaded,
+            size: x.size,
+            mode: x.mode,
+            mod_state: x.mod_state,
+            download_source: x.download_source,
+            source_url: x.source_url,
         }
     }
 }
-
@@ -0,0 +1,145 @@
+use modsync_core::{api::ModpackId, ParseError, TryFromStr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// What a modpack key may do with its modpack
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum KeyScope {
+    /// Read the modpack and download its files when read tokens are required
+    Read,
+    /// Everything `Read` can, plus pushing files and changing the modpack's settings
+    Write,
+}
+
+impl KeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        }
+    }
+}
+
+impl TryFromStr for KeyScope {
+    fn try_from_str(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "read" => Ok(Self::Read),
+            "write" => Ok(Self::Write),
+            _ => Err(ParseError::new("key scope", value)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModpackKey {
+    pub id: String,
+    pub modpack: ModpackId,
+    pub scope: KeyScope,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ModpackKey {
+    /// Stores a new key for `modpack`, returns its id
+    pub async fn insert<'a, E>(
+        modpack: &ModpackId,
+        key: &str,
+        scope: KeyScope,
+        exec: E,
+    ) -> Result<String, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let id = Uuid::new_v4().to_string();
+        sqlx::query!(
+            "INSERT INTO modpack_keys (id, modpack, key_hash, scope, created_at) VALUES ($1, $2, $3, $4, now())",
+            id,
+            modpack.0,
+            hash_key(key),
+            scope.as_str()
+        )
+        .execute(exec)
+        .await?;
+        Ok(id)
+    }
+
+    pub async fn get_by_key<'a, E>(key: &str, exec: E) -> Result<Option<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let key = sqlx::query!(
+            "SELECT id, modpack, scope, created_at FROM modpack_keys WHERE key_hash = $1",
+            hash_key(key)
+        )
+        .fetch_optional(exec)
+        .await?
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(ModpackKey {
+                id: x.id,
+                modpack: ModpackId(x.modpack),
+                scope: KeyScope::try_from_str(&x.scope)?,
+                created_at: x.created_at,
+            })
+        })
+        .transpose()?;
+        Ok(key)
+    }
+
+    pub async fn list<'a, E>(modpack: &ModpackId, exec: E) -> Result<Vec<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let keys = sqlx::query!(
+            "SELECT id, modpack, scope, created_at FROM modpack_keys WHERE modpack = $1 ORDER BY created_at",
+            modpack.0
+        )
+        .fetch_all(exec)
+        .await?
+        .into_iter()
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(ModpackKey {
+                id: x.id,
+                modpack: ModpackId(x.modpack),
+                scope: KeyScope::try_from_str(&x.scope)?,
+                created_at: x.created_at,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    /// Returns whether a key with that id existed
+    pub async fn delete<'a, E>(id: &str, exec: E) -> Result<bool, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let result = sqlx::query!("DELETE FROM modpack_keys WHERE id = $1", id)
+            .execute(exec)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Keys are random enough that an unsalted hash is fine, and it can be looked up directly
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<String>>()
+        .join("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_scope_round_trips() {
+        for scope in [KeyScope::Read, KeyScope::Write] {
+            assert_eq!(KeyScope::try_from_str(scope.as_str()).ok(), Some(scope));
+        }
+        for value in ["", "admin", "Write"] {
+            assert!(KeyScope::try_from_str(value).is_err(), "{:?}", value);
+        }
+    }
+}
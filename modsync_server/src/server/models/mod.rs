@@ -1,3 +1,6 @@
+pub mod blobs;
 pub mod files;
+pub mod keys;
 pub mod modpacks;
-
+pub mod uploads;
+pub mod usage;
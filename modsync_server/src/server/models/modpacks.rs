@@ -1,4 +1,8 @@
-use modsync_core::api::ModpackId;
+use modsync_core::{
+    api::{ModpackId, ModpackSummary},
+    checksum::Checksum,
+    FileState, StrConversion, TryFromStr,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -9,6 +13,10 @@ pub struct Modpack {
     pub modloader_version: Option<String>,
     pub game_version: Option<String>,
     pub sync_version: i32,
+    pub webhook_url: Option<String>,
+    pub signature: Option<String>,
+    pub allowed_roots: Option<Vec<String>>,
+    pub hash_algorithm: Checksum,
 }
 
 impl Modpack {
@@ -17,35 +25,196 @@ impl Modpack {
         E: sqlx::PgExecutor<'a>,
     {
         let file = sqlx::query!(
-            "SELECT id, name, modloader, modloader_version, game_version, sync_version
+            "SELECT id, name, modloader, modloader_version, game_version, sync_version, webhook_url, signature, allowed_roots, hash_algorithm
             FROM modpacks WHERE id = $1 LIMIT 1",
             id.0
         )
         .fetch_optional(exec)
         .await?
-        .map(|x| Modpack {
-            id: ModpackId(x.id),
-            name: x.name,
-            modloader: x.modloader,
-            modloader_version: x.modloader_version,
-            game_version: x.game_version,
-            sync_version: x.sync_version,
-        });
+        .map(|x| -> Result<Self, sqlx::Error> {
+            Ok(Modpack {
+                id: ModpackId(x.id),
+                name: x.name,
+                modloader: x.modloader,
+                modloader_version: x.modloader_version,
+                game_version: x.game_version,
+                sync_version: x.sync_version,
+                webhook_url: x.webhook_url,
+                signature: x.signature,
+                allowed_roots: x.allowed_roots,
+                hash_algorithm: Checksum::try_from_str(&x.hash_algorithm)?,
+            })
+        })
+        .transpose()?;
         Ok(file)
     }
 
-    pub async fn delete<'a, E>(id: &ModpackId, exec: E) -> Result<(), sqlx::Error>
+    /// Locks the modpack until the transaction `exec` belongs to ends. Taken before changing any
+    /// of its files, so their `change_seq` numbers commit in order and a delta read in between
+    /// can't move its cursor past a change that commits later.
+    pub async fn lock<'a, E>(id: &ModpackId, exec: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!("SELECT id FROM modpacks WHERE id = $1 FOR UPDATE", id.0)
+            .fetch_optional(exec)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the modpack's current change cursor and the oldest cursor a delta can be served for
+    pub async fn get_cursor<'a, E>(id: &ModpackId, exec: E) -> Result<(i64, i64), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let x = sqlx::query!(
+            r#"SELECT m.cursor_floor, coalesce(max(f.change_seq), 0) AS "latest!"
+            FROM modpacks m LEFT JOIN files f ON f.modpack = m.id
+            WHERE m.id = $1 GROUP BY m.id"#,
+            id.0
+        )
+        .fetch_one(exec)
+        .await?;
+        Ok((x.latest.max(x.cursor_floor), x.cursor_floor))
+    }
+
+    /// Returns the modpack's sync version and current change cursor, `None` if there's no such modpack
+    pub async fn get_version<'a, E>(
+        id: &ModpackId,
+        exec: E,
+    ) -> Result<Option<(i32, i64)>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let x = sqlx::query!(
+            r#"SELECT m.sync_version, m.cursor_floor, coalesce(max(f.change_seq), 0) AS "latest!"
+            FROM modpacks m LEFT JOIN files f ON f.modpack = m.id
+            WHERE m.id = $1 GROUP BY m.id"#,
+            id.0
+        )
+        .fetch_optional(exec)
+        .await?;
+        Ok(x.map(|x| (x.sync_version, x.latest.max(x.cursor_floor))))
+    }
+
+    /// Counts a change to the file list, so pushes can tell the modpack moved on since they looked
+    /// Returns the modpack's new sync version
+    pub async fn bump_sync_version<'a, E>(id: &ModpackId, exec: E) -> Result<i32, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        Ok(sqlx::query!(
+            "UPDATE modpacks SET sync_version = sync_version + 1 WHERE id = $1 RETURNING sync_version",
+            id.0
+        )
+        .fetch_one(exec)
+        .await?
+        .sync_version)
+    }
+
+    pub async fn set_webhook<'a, E>(
+        id: &ModpackId,
+        webhook_url: Option<&String>,
+        exec: E,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!(
+            "UPDATE modpacks SET webhook_url = $1 WHERE id = $2",
+            webhook_url,
+            id.0
+        )
+        .execute(exec)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_signature<'a, E>(
+        id: &ModpackId,
+        signature: Option<&String>,
+        exec: E,
+    ) -> Result<(), sqlx::Error>
     where
         E: sqlx::PgExecutor<'a>,
     {
         sqlx::query!(
-            "DELETE FROM modpacks WHERE id = $1",
+            "UPDATE modpacks SET signature = $1 WHERE id = $2",
+            signature,
             id.0
         )
         .execute(exec)
         .await?;
         Ok(())
     }
+
+    pub async fn set_allowed_roots<'a, E>(
+        id: &ModpackId,
+        allowed_roots: Option<&[String]>,
+        exec: E,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!(
+            "UPDATE modpacks SET allowed_roots = $1 WHERE id = $2",
+            allowed_roots,
+            id.0
+        )
+        .execute(exec)
+        .await?;
+        Ok(())
+    }
+
+    /// A page of every modpack sorted by name, with how many files each has
+    pub async fn list<'a, E>(
+        limit: i64,
+        offset: i64,
+        exec: E,
+    ) -> Result<Vec<ModpackSummary>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let modpacks = sqlx::query!(
+            r#"SELECT m.id, m.name, m.game, count(f.id) FILTER (WHERE f.state <> $3) AS "file_count!"
+            FROM modpacks m LEFT JOIN files f ON f.modpack = m.id
+            GROUP BY m.id ORDER BY m.name LIMIT $1 OFFSET $2"#,
+            limit,
+            offset,
+            FileState::Deleted.as_str()
+        )
+        .fetch_all(exec)
+        .await?
+        .into_iter()
+        .map(|x| ModpackSummary {
+            id: ModpackId(x.id),
+            name: x.name,
+            game: x.game,
+            file_count: x.file_count,
+        })
+        .collect();
+        Ok(modpacks)
+    }
+
+    pub async fn count<'a, E>(exec: E) -> Result<i64, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let x = sqlx::query!(r#"SELECT count(*) AS "count!" FROM modpacks"#)
+            .fetch_one(exec)
+            .await?;
+        Ok(x.count)
+    }
+
+    pub async fn delete<'a, E>(id: &ModpackId, exec: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!("DELETE FROM modpacks WHERE id = $1", id.0)
+            .execute(exec)
+            .await?;
+        Ok(())
+    }
 }
 
 impl From<Modpack> for modsync_core::models::modpacks::Modpack {
@@ -57,7 +226,8 @@ impl From<Modpack> for modsync_core::models::modpacks::Modpack {
             modloader_version: x.modloader_version,
             game_version: x.game_version,
             sync_version: x.sync_version,
+            signature: x.signature,
+            hash_algorithm: x.hash_algorithm,
         }
     }
 }
-
@@ -0,0 +1,182 @@
+use modsync_core::api::{ModpackId, UploadId, UploadStatusResponse};
+use uuid::Uuid;
+
+/// Chunked uploads untouched for this long are expired, and their partial content removed by `gc`
+pub const UPLOAD_SESSION_TTL_HOURS: i64 = 24;
+
+/// A chunked upload in progress, received chunks are appended to `<id>.part` in the uploads directory
+pub struct UploadSession {
+    pub id: UploadId,
+    pub modpack: ModpackId,
+    /// File the content gets attached to once finished, `None` only stores the blob
+    pub file_path: Option<String>,
+    pub hash: String,
+    pub size: i64,
+    pub chunk_size: i64,
+    pub next_chunk: i32,
+    pub received_bytes: i64,
+}
+
+impl UploadSession {
+    pub async fn insert<'a, E>(
+        modpack: &ModpackId,
+        file_path: Option<&String>,
+        hash: &str,
+        size: i64,
+        chunk_size: i64,
+        exec: E,
+    ) -> Result<Self, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let id = UploadId(Uuid::new_v4().to_string());
+        sqlx::query!(
+            "INSERT INTO upload_sessions (id, modpack, file_path, hash, size, chunk_size) VALUES ($1, $2, $3, $4, $5, $6)",
+            id.0, modpack.0, file_path, hash, size, chunk_size
+        )
+        .execute(exec)
+        .await?;
+        Ok(UploadSession {
+            id,
+            modpack: modpack.clone(),
+            file_path: file_path.cloned(),
+            hash: hash.to_string(),
+            size,
+            chunk_size,
+            next_chunk: 0,
+            received_bytes: 0,
+        })
+    }
+
+    /// `None` if there is no such upload for the modpack, or it expired.
+    /// With `lock` the row stays locked until the transaction `exec` belongs to ends.
+    pub async fn get<'a, E>(
+        id: &UploadId,
+        modpack: &ModpackId,
+        lock: bool,
+        exec: E,
+    ) -> Result<Option<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let cutoff = chrono::Utc::now() - chrono::Duration::hours(UPLOAD_SESSION_TTL_HOURS);
+        let row = if lock {
+            sqlx::query_as!(
+                UploadSessionRow,
+                "SELECT id, modpack, file_path, hash, size, chunk_size, next_chunk, received_bytes FROM upload_sessions
+                WHERE id = $1 AND modpack = $2 AND updated_at > $3 FOR UPDATE",
+                id.0, modpack.0, cutoff
+            )
+            .fetch_optional(exec)
+            .await?
+        } else {
+            sqlx::query_as!(
+                UploadSessionRow,
+                "SELECT id, modpack, file_path, hash, size, chunk_size, next_chunk, received_bytes FROM upload_sessions
+                WHERE id = $1 AND modpack = $2 AND updated_at > $3",
+                id.0, modpack.0, cutoff
+            )
+            .fetch_optional(exec)
+            .await?
+        };
+        Ok(row.map(Self::from))
+    }
+
+    /// Records chunk `next_chunk` as received, `bytes` long
+    pub async fn advance<'a, E>(id: &UploadId, bytes: i64, exec: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!(
+            "UPDATE upload_sessions SET next_chunk = next_chunk + 1, received_bytes = received_bytes + $1, updated_at = now() WHERE id = $2",
+            bytes, id.0
+        )
+        .execute(exec)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete<'a, E>(id: &UploadId, exec: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!("DELETE FROM upload_sessions WHERE id = $1", id.0)
+            .execute(exec)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes expired uploads, returns their ids
+    pub async fn delete_expired<'a, E>(exec: E) -> Result<Vec<UploadId>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let cutoff = chrono::Utc::now() - chrono::Duration::hours(UPLOAD_SESSION_TTL_HOURS);
+        let ids = sqlx::query!(
+            "DELETE FROM upload_sessions WHERE updated_at <= $1 RETURNING id",
+            cutoff
+        )
+        .fetch_all(exec)
+        .await?
+        .into_iter()
+        .map(|x| UploadId(x.id))
+        .collect();
+        Ok(ids)
+    }
+
+    /// Ids of the uploads that haven't expired
+    pub async fn live_ids<'a, E>(exec: E) -> Result<Vec<UploadId>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let cutoff = chrono::Utc::now() - chrono::Duration::hours(UPLOAD_SESSION_TTL_HOURS);
+        let ids = sqlx::query!(
+            "SELECT id FROM upload_sessions WHERE updated_at > $1",
+            cutoff
+        )
+        .fetch_all(exec)
+        .await?
+        .into_iter()
+        .map(|x| UploadId(x.id))
+        .collect();
+        Ok(ids)
+    }
+}
+
+struct UploadSessionRow {
+    id: String,
+    modpack: String,
+    file_path: Option<String>,
+    hash: String,
+    size: i64,
+    chunk_size: i64,
+    next_chunk: i32,
+    received_bytes: i64,
+}
+
+impl From<UploadSessionRow> for UploadSession {
+    fn from(x: UploadSessionRow) -> Self {
+        Self {
+            id: UploadId(x.id),
+            modpack: ModpackId(x.modpack),
+            file_path: x.file_path,
+            hash: x.hash,
+            size: x.size,
+            chunk_size: x.chunk_size,
+            next_chunk: x.next_chunk,
+            received_bytes: x.received_bytes,
+        }
+    }
+}
+
+impl From<&UploadSession> for UploadStatusResponse {
+    fn from(x: &UploadSession) -> Self {
+        Self {
+            upload_id: x.id.clone(),
+            size: x.size,
+            chunk_size: x.chunk_size,
+            next_chunk: x.next_chunk,
+            received_bytes: x.received_bytes,
+        }
+    }
+}
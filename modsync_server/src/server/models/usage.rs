@@ -0,0 +1,76 @@
+use modsync_core::api::ModpackId;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct ModpackUsage {
+    pub modpack: ModpackId,
+    pub download_bytes: i64,
+    pub upload_bytes: i64,
+}
+
+impl ModpackUsage {
+    pub async fn get<'a, E>(modpack_id: &ModpackId, exec: E) -> Result<Self, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        let usage = sqlx::query!(
+            "SELECT download_bytes, upload_bytes FROM modpack_usage WHERE modpack = $1",
+            modpack_id.0
+        )
+        .fetch_optional(exec)
+        .await?;
+        Ok(ModpackUsage {
+            modpack: modpack_id.clone(),
+            download_bytes: usage.as_ref().map(|x| x.download_bytes).unwrap_or(0),
+            upload_bytes: usage.as_ref().map(|x| x.upload_bytes).unwrap_or(0),
+        })
+    }
+
+    pub async fn add_download<'a, E>(
+        modpack_id: &ModpackId,
+        bytes: i64,
+        exec: E,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!(
+            "INSERT INTO modpack_usage (modpack, download_bytes, upload_bytes) VALUES ($1, $2, 0)
+            ON CONFLICT (modpack) DO UPDATE SET download_bytes = modpack_usage.download_bytes + $2",
+            modpack_id.0,
+            bytes
+        )
+        .execute(exec)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn add_upload<'a, E>(
+        modpack_id: &ModpackId,
+        bytes: i64,
+        exec: E,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'a>,
+    {
+        sqlx::query!(
+            "INSERT INTO modpack_usage (modpack, download_bytes, upload_bytes) VALUES ($1, 0, $2)
+            ON CONFLICT (modpack) DO UPDATE SET upload_bytes = modpack_usage.upload_bytes + $2",
+            modpack_id.0,
+            bytes
+        )
+        .execute(exec)
+        .await?;
+        Ok(())
+    }
+}
+
+impl From<ModpackUsage> for modsync_core::api::ModpackUsageResponse {
+    fn from(x: ModpackUsage) -> Self {
+        Self {
+            modpack_id: x.modpack,
+            download_bytes: x.download_bytes,
+            upload_bytes: x.upload_bytes,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use super::AppState;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+/// A token bucket per client address. Each holds up to `requests` tokens and refills at
+/// `requests` per `window`, so clients can burst but not keep going faster than that.
+pub struct RateLimiter {
+    capacity: f64,
+    per_sec: f64,
+    window: Duration,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(requests: u32, window: Duration) -> Self {
+        RateLimiter {
+            capacity: requests as f64,
+            per_sec: requests as f64 / window.as_secs_f64(),
+            window,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token for `client`, or returns how long until it has one
+    fn acquire(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // Buckets that have been idle for a whole window are full again, same as a new one
+        if now.duration_since(buckets.pruned) >= self.window {
+            buckets
+                .clients
+                .retain(|_, x| now.duration_since(x.updated) < self.window);
+            buckets.pruned = now;
+        }
+        let bucket = buckets.clients.entry(client).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.per_sec;
+        bucket.tokens = (bucket.tokens + refilled).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.per_sec,
+        ))
+    }
+}
+
+/// Answers `429` with a `Retry-After` once a client address runs out of requests. Layered on
+/// the routes that check a write or master key, so keys can't be guessed at full speed.
+pub async fn limit_requests(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (Some(limiter), Some(ConnectInfo(address))) = (
+        &state.rate_limiter,
+        req.extensions().get::<ConnectInfo<SocketAddr>>(),
+    ) else {
+        return next.run(req).await;
+    };
+    let Err(wait) = limiter.acquire(address.ip()) else {
+        return next.run(req).await;
+    };
+    warn!(
+        "Rate limit reached by {}, turning {} away",
+        address.ip(),
+        req.uri().path()
+    );
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        // Rounded up, retrying a bit early would only be turned away again
+        [(
+            header::RETRY_AFTER,
+            (wait.as_secs_f64().ceil() as u64).to_string(),
+        )],
+        "RATE_LIMITED",
+    )
+        .into_response()
+}
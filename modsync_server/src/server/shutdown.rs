@@ -0,0 +1,152 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use futures_util::StreamExt;
+use tokio::{net::TcpListener, sync::Notify};
+use tracing::{info, warn};
+
+use super::AppState;
+
+/// Requests being handled, including responses still being streamed
+#[derive(Default)]
+pub struct InFlight(AtomicUsize);
+
+impl InFlight {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts a request as in flight until its response body is dropped
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    state.in_flight.0.fetch_add(1, Ordering::SeqCst);
+    let guard = InFlightGuard(state.clone());
+    let (parts, body) = next.run(req).await.into_parts();
+    // Held by the body, so streamed downloads count until they're done
+    let body = body.into_data_stream().inspect(move |_| {
+        let _in_flight = &guard;
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+struct InFlightGuard(Arc<AppState>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves `app`, over HTTPS with `tls`, until SIGINT or SIGTERM. Then stops accepting
+/// connections and gives the requests in flight up to `timeout` to finish, a second signal
+/// stops right away.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    state: Arc<AppState>,
+    timeout: Duration,
+) -> std::io::Result<()> {
+    let stopping = Arc::new(Notify::new());
+    let pending = Arc::new(AtomicUsize::new(0));
+    let graceful = {
+        let (state, stopping, pending) = (state.clone(), stopping.clone(), pending.clone());
+        async move {
+            signal().await;
+            let count = state.in_flight.count();
+            pending.store(count, Ordering::SeqCst);
+            info!(
+                "Shutting down, waiting up to {}s for {} request(s) to finish...",
+                timeout.as_secs(),
+                count
+            );
+            stopping.notify_one();
+        }
+    };
+    let deadline = async {
+        stopping.notified().await;
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {}
+            _ = signal() => {}
+        }
+    };
+
+    let server = async {
+        let Some(tls) = tls else {
+            return axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(graceful)
+            .await;
+        };
+        let handle = Handle::new();
+        let stopper = tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                graceful.await;
+                handle.graceful_shutdown(None);
+            }
+        });
+        let result = axum_server::from_tcp_rustls(listener.into_std()?, tls)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await;
+        stopper.abort();
+        result
+    };
+
+    tokio::select! {
+        result = server => result?,
+        _ = deadline => {
+            let left = state.in_flight.count();
+            warn!("Stopped with {} request(s) still in flight, they were cut off", left);
+            let drained = pending.load(Ordering::SeqCst).saturating_sub(left);
+            info!("Drained {} request(s), bye", drained);
+            return Ok(());
+        }
+    }
+    info!("Drained {} request(s), bye", pending.load(Ordering::SeqCst));
+    Ok(())
+}
+
+/// Resolves on SIGINT, or on SIGTERM as sent by systemd and Docker
+async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::StreamExt;
+use tracing::warn;
+
+use super::AppState;
+
+/// Logs requests that take longer than `slow_request_threshold_ms`, including the time
+/// spent streaming the response body
+pub async fn log_slow_requests(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(threshold) = state.config.slow_request_threshold_ms else {
+        return next.run(req).await;
+    };
+    let mut timing = RequestTiming {
+        started: Instant::now(),
+        threshold: Duration::from_millis(threshold),
+        method: req.method().clone(),
+        path: req.uri().path().to_string(),
+        received: req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(0),
+        sent: 0,
+    };
+    let (parts, body) = next.run(req).await.into_parts();
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            timing.record(chunk.len());
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Logs the request once its response body is dropped, if it was slow
+struct RequestTiming {
+    started: Instant,
+    threshold: Duration,
+    method: Method,
+    path: String,
+    received: u64,
+    sent: u64,
+}
+
+impl RequestTiming {
+    fn record(&mut self, bytes: usize) {
+        self.sent += bytes as u64;
+    }
+
+    fn modpack_id(&self) -> Option<&str> {
+        let mut segments = self.path.trim_start_matches('/').split('/');
+        match (segments.next(), segments.next()) {
+            (Some("modpack"), Some(id)) if id != "create" => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for RequestTiming {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if elapsed < self.threshold {
+            return;
+        }
+        warn!(
+            method = %self.method,
+            path = self.path,
+            modpack = self.modpack_id(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            received_bytes = self.received,
+            sent_bytes = self.sent,
+            "Slow request"
+        );
+    }
+}
@@ -0,0 +1,1582 @@
+use super::*;
+
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        // Nothing listens there, requests that get as far as the database fail fast
+        database_url: "postgres://postgres@127.0.0.1:1/modsync".to_string(),
+        master_keys: vec!["secret".to_string()],
+        port: 0,
+        bind_address: IpAddr::from([127, 0, 0, 1]),
+        uploads_directory: std::env::temp_dir()
+            .join("modsync-test-uploads")
+            .to_string_lossy()
+            .to_string(),
+        file_size_limit: 1024,
+        modpack_cache_ttl: 0,
+        verify_blobs_on_startup: false,
+        json_body_limit: 1024,
+        read_tokens: Vec::new(),
+        require_read_token: false,
+        max_path_length: 200,
+        max_path_components: 16,
+        slow_request_threshold_ms: None,
+        blob_extensions: false,
+        maintenance: false,
+        maintenance_retry_after: 60,
+        download_concurrency: 0,
+        download_buffer_size: 1024,
+        upload_chunk_size: 1024,
+        shutdown_timeout: 0,
+        request_timeout_secs: 1,
+        transfer_timeout_secs: None,
+        tls_cert_path: None,
+        tls_key_path: None,
+        metrics: false,
+        metrics_require_key: true,
+        allowed_origins: Vec::new(),
+        rate_limit_requests: 0,
+        rate_limit_window_secs: 60,
+        webhook_allow_private: false,
+    }
+}
+
+fn test_state() -> Arc<AppState> {
+    let config = test_config();
+    Arc::new(AppState {
+        pool: PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy(&config.database_url)
+            .unwrap(),
+        master_keys: config.master_keys.iter().cloned().collect(),
+        modpack_cache: ModpackCache::new(Duration::ZERO),
+        http_client: reqwest::Client::new(),
+        maintenance: Maintenance::new(false, config.maintenance_retry_after),
+        download_permits: None,
+        in_flight: InFlight::default(),
+        metrics: Metrics::new(),
+        rate_limiter: None,
+        config,
+    })
+}
+
+/// The `ApiError` kind a request failed with, `None` if it didn't fail with one
+async fn error_kind(router: Router<Arc<AppState>>, req: Request) -> Option<&'static str> {
+    let response = router.with_state(test_state()).oneshot(req).await.unwrap();
+    response
+        .extensions()
+        .get::<error::ApiErrorKind>()
+        .map(|x| x.0)
+}
+
+fn upload_request(file_path_query: &str) -> Request {
+    let body = "--b\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"upload\"\r\n\r\nhello\r\n--b--\r\n";
+    Request::builder()
+        .method("POST")
+        .uri(format!("/modpack/a/upload?file_path={}", file_path_query))
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn upload_rejects_traversing_file_paths() {
+    let router = Router::new().route("/modpack/:modpack_id/upload", post(dl_file_upload));
+    for payload in [
+        "..%2F..%2Fetc%2Fpasswd",
+        "mods%2F..%2F..%2Fa.jar",
+        "..%5C..%5Ca.jar",
+        "%2Fetc%2Fpasswd",
+        "C%3A%5Ca.jar",
+        "%2E%2E%2Fa.jar",
+    ] {
+        let kind = error_kind(router.clone(), upload_request(payload)).await;
+        assert_eq!(kind, Some("bad_request"), "{:?}", payload);
+    }
+}
+
+#[tokio::test]
+async fn upload_accepts_nested_file_paths() {
+    let router = Router::new().route("/modpack/:modpack_id/upload", post(dl_file_upload));
+    // Gets past validation, as far as looking the file up
+    let kind = error_kind(router, upload_request("mods%2Fsub%2Fa.jar")).await;
+    assert_eq!(kind, Some("database"));
+}
+
+#[tokio::test]
+async fn download_rejects_traversing_hashes() {
+    let router = Router::new().route("/dl/hash/:file", get(dl_file_hash));
+    for payload in [
+        "..%2F..%2Fetc%2Fpasswd",
+        "..%5C..%5Cetc%5Cpasswd",
+        "%2Fetc%2Fpasswd",
+        "%2E%2E",
+    ] {
+        let req = Request::builder()
+            .uri(format!("/dl/hash/{}", payload))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            error_kind(router.clone(), req).await,
+            Some("bad_request"),
+            "{:?}",
+            payload
+        );
+    }
+}
+
+fn chunk_request(body: &'static str, digest: &str) -> Request {
+    Request::builder()
+        .method("PUT")
+        .uri("/modpack/a/upload/upload/chunk/0")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header(DIGEST_HEADER, digest)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn corrupted_chunk_is_rejected_on_arrival() {
+    let router = Router::new().route(
+        "/modpack/:modpack_id/upload/:upload_id/chunk/:n",
+        put(chunked::upload_chunk).layer(middleware::from_fn(verify_body_digest)),
+    );
+    let digest = modsync_core::api::body_digest(b"chunk content");
+    // Turned away before the upload is even looked up
+    let kind = error_kind(router.clone(), chunk_request("chunk c0ntent", &digest)).await;
+    assert_eq!(kind, Some("validation"));
+    let kind = error_kind(router, chunk_request("chunk content", &digest)).await;
+    assert_eq!(kind, Some("database"));
+}
+
+fn modpack_key(modpack: &str, scope: KeyScope) -> KeyOwner {
+    KeyOwner::Modpack(ModpackKey {
+        id: "key".to_string(),
+        modpack: ModpackId(modpack.to_string()),
+        scope,
+        created_at: chrono::Utc::now(),
+    })
+}
+
+#[test]
+fn master_key_writes_and_reads_every_modpack() {
+    let a = ModpackId("a".to_string());
+    assert!(KeyOwner::Master.can_write(Some(&a)));
+    assert!(KeyOwner::Master.can_write(None));
+    assert_eq!(KeyOwner::Master.readable_modpack(Some(&a)), Some(None));
+    assert_eq!(KeyOwner::Master.readable_modpack(None), Some(None));
+}
+
+#[test]
+fn write_key_is_limited_to_its_modpack() {
+    let key = modpack_key("a", KeyScope::Write);
+    assert!(key.can_write(Some(&ModpackId("a".to_string()))));
+    assert!(!key.can_write(Some(&ModpackId("b".to_string()))));
+    // Blob uploads have no modpack in the path
+    assert!(key.can_write(None));
+}
+
+#[test]
+fn read_key_cant_write() {
+    let key = modpack_key("a", KeyScope::Read);
+    assert!(!key.can_write(Some(&ModpackId("a".to_string()))));
+    assert!(!key.can_write(None));
+}
+
+#[test]
+fn modpack_key_is_rejected_on_another_modpack() {
+    for scope in [KeyScope::Read, KeyScope::Write] {
+        let key = modpack_key("a", scope);
+        assert_eq!(
+            key.readable_modpack(Some(&ModpackId("b".to_string()))),
+            None
+        );
+    }
+}
+
+#[test]
+fn modpack_key_reads_only_its_modpack_on_routes_without_one() {
+    let key = modpack_key("a", KeyScope::Read);
+    let own = Some(Some(ModpackId("a".to_string())));
+    assert_eq!(key.readable_modpack(Some(&ModpackId("a".to_string()))), own);
+    // The blob downloads, which then only serve files of modpack `a`
+    assert_eq!(key.readable_modpack(None), own);
+}
+
+/// A scratch database on the server `MODSYNC_TEST_DATABASE_URL` points to, tests that need
+/// one return early when it isn't set
+struct TestDb {
+    admin: PgPool,
+    name: String,
+    state: Arc<AppState>,
+}
+
+async fn test_db() -> Option<TestDb> {
+    test_db_with(|_| {}).await
+}
+
+async fn test_db_with(configure: impl FnOnce(&mut ServerConfig)) -> Option<TestDb> {
+    let url = var("MODSYNC_TEST_DATABASE_URL").ok()?;
+    let admin = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await
+        .unwrap();
+    let name = format!("modsync_test_{}", Uuid::new_v4().simple());
+    sqlx::query(&format!("CREATE DATABASE {}", name))
+        .execute(&admin)
+        .await
+        .unwrap();
+    let options: sqlx::postgres::PgConnectOptions = url.parse().unwrap();
+    let pool = PgPoolOptions::new()
+        .connect_with(options.database(&name))
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    let mut config = test_config();
+    config.uploads_directory = std::env::temp_dir()
+        .join(&name)
+        .to_string_lossy()
+        .to_string();
+    std::fs::create_dir_all(&config.uploads_directory).unwrap();
+    configure(&mut config);
+    let mut state = Arc::into_inner(test_state()).unwrap();
+    state.pool = pool;
+    state.http_client = webhook::client(config.webhook_allow_private).unwrap();
+    state.modpack_cache = ModpackCache::new(Duration::from_secs(config.modpack_cache_ttl));
+    state.master_keys = config.master_keys.iter().cloned().collect();
+    state.download_permits = Some(config.download_concurrency)
+        .filter(|x| *x > 0)
+        .map(|x| Arc::new(Semaphore::new(x)));
+    state.config = config;
+    Some(TestDb {
+        admin,
+        name,
+        state: Arc::new(state),
+    })
+}
+
+impl TestDb {
+    /// Sends a request through every route and layer, returns the status and JSON body
+    async fn call(
+        &self,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or_else(Body::empty, |x| Body::from(x.to_string())))
+            .unwrap();
+        let response = self.send(request).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or_else(|_| String::from_utf8_lossy(&body).into()),
+        )
+    }
+
+    async fn send(&self, request: Request) -> Response {
+        router(self.state.clone()).oneshot(request).await.unwrap()
+    }
+
+    async fn create_modpack(&self, name: &str) -> String {
+        let (status, body) = self
+            .call(
+                Method::POST,
+                "/modpack/create",
+                Some(serde_json::json!({
+                    "name": name,
+                    "game": "minecraft",
+                    "game_version": "1.20.1",
+                    "modloader": "fabric",
+                    "modloader_version": "0.15.0",
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["modpack_id"].as_str().unwrap().to_string()
+    }
+
+    async fn sync_file(&self, modpack: &str, path: &str, hash: &str) {
+        let (status, body) = self
+            .call(
+                Method::POST,
+                &format!("/modpack/{}/filesync", modpack),
+                Some(serde_json::json!({ "path": path, "state": "Exists", "hash": hash })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    /// Paths the changes endpoint returns after `since`, and the cursor to pass next time
+    async fn changes(&self, modpack: &str, since: Option<i64>) -> (Vec<String>, i64) {
+        let uri = match since {
+            Some(since) => format!("/modpack/{}/changes?since={}", modpack, since),
+            None => format!("/modpack/{}/changes", modpack),
+        };
+        let (status, body) = self.call(Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let mut paths: Vec<String> = body["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["path"].as_str().unwrap().to_string())
+            .collect();
+        paths.sort();
+        (paths, body["cursor"].as_i64().unwrap())
+    }
+
+    async fn close(self) {
+        let _ = std::fs::remove_dir_all(&self.state.config.uploads_directory);
+        self.state.pool.close().await;
+        sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", self.name))
+            .execute(&self.admin)
+            .await
+            .unwrap();
+    }
+}
+
+fn test_hash(n: u8) -> String {
+    format!("{:02x}", n).repeat(32)
+}
+
+#[tokio::test]
+async fn changes_since_a_cursor_are_only_the_changed_files() {
+    let Some(db) = test_db().await else { return };
+    let modpack = db.create_modpack("changes").await;
+    db.sync_file(&modpack, "mods/a.jar", &test_hash(1)).await;
+    db.sync_file(&modpack, "mods/b.jar", &test_hash(2)).await;
+    let (files, cursor) = db.changes(&modpack, None).await;
+    assert_eq!(files, ["mods/a.jar", "mods/b.jar"]);
+
+    db.sync_file(&modpack, "mods/b.jar", &test_hash(3)).await;
+    let (files, next) = db.changes(&modpack, Some(cursor)).await;
+    assert_eq!(files, ["mods/b.jar"]);
+    let (files, _) = db.changes(&modpack, Some(next)).await;
+    assert!(files.is_empty(), "{:?}", files);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn changes_arent_skipped_by_a_writer_committing_late() {
+    let Some(db) = test_db().await else { return };
+    let modpack = db.create_modpack("concurrent").await;
+    let (_, cursor) = db.changes(&modpack, None).await;
+    let modpack_id = ModpackId(modpack.clone());
+
+    // Takes its change number first but commits last
+    let mut slow = db.state.pool.begin().await.unwrap();
+    Modpack::lock(&modpack_id, &mut *slow).await.unwrap();
+    let body = FileSyncBody {
+        path: "mods/slow.jar".to_string(),
+        state: FileState::Exists,
+        hash: Some(test_hash(1)),
+        mode: None,
+        mod_state: None,
+        download_source: None,
+        source_url: None,
+        size: None,
+    };
+    apply_file_sync(&db.state, &modpack_id, None, &body, &mut slow)
+        .await
+        .unwrap();
+
+    let fast_hash = test_hash(2);
+    let (during, _) = tokio::join!(
+        async {
+            // Let the other writer get as far as it can first
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let during = db.changes(&modpack, Some(cursor)).await;
+            Modpack::bump_sync_version(&modpack_id, &mut *slow)
+                .await
+                .unwrap();
+            slow.commit().await.unwrap();
+            during
+        },
+        db.sync_file(&modpack, "mods/fast.jar", &fast_hash),
+    );
+    let (seen_during, during_cursor) = during;
+    let (seen_after, _) = db.changes(&modpack, Some(during_cursor)).await;
+    let mut seen: Vec<_> = seen_during.into_iter().chain(seen_after).collect();
+    seen.sort();
+    seen.dedup();
+    // A cursor read mid-write may resend a change, but never lose one
+    assert_eq!(seen, ["mods/fast.jar", "mods/slow.jar"]);
+    db.close().await;
+}
+
+/// Receives webhook deliveries, answers every one with `200`
+async fn webhook_receiver() -> (u16, tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|x| {
+                            let (name, value) = x.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length || n == 0 {
+                        break body.to_string();
+                    }
+                }
+            };
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let _ = sender.send(serde_json::from_str(&body).unwrap());
+        }
+    });
+    (port, receiver)
+}
+
+impl TestDb {
+    async fn set_webhook(&self, modpack: &str, url: &str) {
+        let (status, body) = self
+            .call(
+                Method::POST,
+                &format!("/modpack/{}/webhook", modpack),
+                Some(serde_json::json!({ "webhook_url": url })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+}
+
+#[tokio::test]
+async fn webhook_receives_filesync_events() {
+    let Some(db) = test_db_with(|x| x.webhook_allow_private = true).await else {
+        return;
+    };
+    let (port, mut events) = webhook_receiver().await;
+    let modpack = db.create_modpack("webhook").await;
+    db.set_webhook(&modpack, &format!("http://127.0.0.1:{}/hook", port))
+        .await;
+    let wait = Duration::from_secs(5);
+
+    db.sync_file(&modpack, "mods/a.jar", &test_hash(1)).await;
+    let event = tokio::time::timeout(wait, events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event["modpack_id"], modpack.as_str());
+    assert_eq!(event["event"], "filesync");
+    assert_eq!(event["path"], "mods/a.jar");
+
+    // One event for the whole batch
+    let (status, body) = db
+        .call(
+            Method::POST,
+            &format!("/modpack/{}/filesync/batch", modpack),
+            Some(serde_json::json!({ "files": [
+                { "path": "mods/b.jar", "state": "Exists", "hash": test_hash(2) },
+                { "path": "mods/a.jar", "state": "Deleted", "hash": null },
+            ] })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let event = tokio::time::timeout(wait, events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event["event"], "batch");
+    let files: Vec<_> = event["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| (x["path"].as_str().unwrap(), x["event"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        files,
+        [("mods/b.jar", "filesync"), ("mods/a.jar", "delete")]
+    );
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(events.try_recv().is_err());
+    db.close().await;
+}
+
+#[tokio::test]
+async fn webhook_isnt_delivered_to_private_addresses() {
+    let Some(db) = test_db().await else { return };
+    let (port, mut events) = webhook_receiver().await;
+    let modpack = db.create_modpack("private webhook").await;
+    db.set_webhook(&modpack, &format!("http://127.0.0.1:{}/hook", port))
+        .await;
+    db.sync_file(&modpack, "mods/a.jar", &test_hash(1)).await;
+    // Resolves to loopback as well
+    db.set_webhook(&modpack, &format!("http://localhost:{}/hook", port))
+        .await;
+    db.sync_file(&modpack, "mods/b.jar", &test_hash(2)).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(events.try_recv().is_err());
+    db.close().await;
+}
+
+#[tokio::test]
+async fn webhook_needs_a_master_key() {
+    let router = Router::new().route("/modpack/:modpack_id/webhook", post(modpack_webhook));
+    let request = Request::builder()
+        .method("POST")
+        .uri("/modpack/a/webhook")
+        .header(header::AUTHORIZATION, "Bearer modpack-write-key")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"webhook_url":"http://example.com"}"#))
+        .unwrap();
+    // Turned away before modpack keys are even looked up
+    assert_eq!(error_kind(router, request).await, Some("unauthorized"));
+}
+
+#[test]
+fn only_public_addresses_are_webhook_targets() {
+    for ip in ["1.1.1.1", "2606:4700:4700::1111", "100.128.0.1"] {
+        assert!(webhook::is_public(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in [
+        "127.0.0.1",
+        "10.0.0.1",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "fe80::1",
+        "fd00::1",
+        "::ffff:127.0.0.1",
+    ] {
+        assert!(!webhook::is_public(ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+fn upload_content_request(modpack: &str, path: &str, content: &str) -> Request {
+    let body = format!(
+        "--b\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"upload\"\r\n\r\n{}\r\n--b--\r\n",
+        content
+    );
+    Request::builder()
+        .method("POST")
+        .uri(format!("/modpack/{}/upload?file_path={}", modpack, path))
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn upload_not_matching_the_synced_hash_is_rejected() {
+    let Some(db) = test_db().await else { return };
+    let modpack = db.create_modpack("mismatch").await;
+    let hash = Checksum::Sha256.hash_bytes(b"hello");
+    db.sync_file(&modpack, "mods/a.jar", &hash).await;
+
+    let response = db
+        .send(upload_content_request(&modpack, "mods/a.jar", "hellp"))
+        .await;
+    let kind = response
+        .extensions()
+        .get::<error::ApiErrorKind>()
+        .map(|x| x.0);
+    assert_eq!(kind, Some("bad_request"));
+    let (status, body) = db
+        .call(Method::GET, &format!("/modpack/{}", modpack), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let file = &body["files"][0];
+    assert_eq!(file["hash"], hash.as_str());
+    assert_eq!(file["uploaded"], false);
+    let stored = std::fs::read_dir(&db.state.config.uploads_directory)
+        .unwrap()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.path().is_file())
+        .count();
+    assert_eq!(stored, 0);
+
+    let response = db
+        .send(upload_content_request(&modpack, "mods/a.jar", "hello"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn retried_modpack_create_returns_the_first_modpack() {
+    let Some(db) = test_db().await else { return };
+    let create = |request_id: &str| {
+        db.call(
+            Method::POST,
+            "/modpack/create",
+            Some(serde_json::json!({
+                "name": "retried",
+                "game": "minecraft",
+                "game_version": "1.20.1",
+                "modloader": "fabric",
+                "modloader_version": "0.15.0",
+                "request_id": request_id,
+            })),
+        )
+    };
+    // Sent at once, as a client retrying a request that timed out might
+    let ((first_status, first), (second_status, second)) =
+        tokio::join!(create("request-1"), create("request-1"));
+    assert_eq!(first_status, StatusCode::OK, "{}", first);
+    assert_eq!(second_status, StatusCode::OK, "{}", second);
+    assert_eq!(first["modpack_id"], second["modpack_id"]);
+    let (_, again) = create("request-1").await;
+    assert_eq!(first["modpack_id"], again["modpack_id"]);
+    let (status, body) = db.call(Method::GET, "/modpacks", None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["modpacks"].as_array().unwrap().len(), 1, "{}", body);
+
+    // Another request can't take the name
+    let request = Request::builder()
+        .method("POST")
+        .uri("/modpack/create")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({
+                "name": "retried",
+                "game": "minecraft",
+                "game_version": "1.20.1",
+                "modloader": "fabric",
+                "modloader_version": "0.15.0",
+                "request_id": "request-2",
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let response = db.send(request).await;
+    let kind = response
+        .extensions()
+        .get::<error::ApiErrorKind>()
+        .map(|x| x.0);
+    assert_eq!(kind, Some("already_exists"));
+    db.close().await;
+}
+
+#[tokio::test]
+async fn browse_page_links_files_relative_to_itself() {
+    let Some(db) = test_db().await else { return };
+    let modpack = db.create_modpack("browse").await;
+    let hash = Checksum::Sha256.hash_bytes(b"hello");
+    db.sync_file(&modpack, "mods/a.jar", &hash).await;
+    let response = db
+        .send(upload_content_request(&modpack, "mods/a.jar", "hello"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let page_path = format!("/modpack/{}/browse", modpack);
+    let request = Request::builder()
+        .uri(&page_path)
+        .body(Body::empty())
+        .unwrap();
+    let response = db.send(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let page = String::from_utf8(page.to_vec()).unwrap();
+    let link = format!(
+        "<a href=\"../../dl/hash/{}?modpack={}\">mods/a.jar</a>",
+        hash, modpack
+    );
+    assert!(page.contains(&link), "{}", page);
+
+    // Resolved against the page like a browser would, the link serves the file
+    let base = reqwest::Url::parse("http://localhost/")
+        .unwrap()
+        .join(&page_path);
+    let target = base
+        .unwrap()
+        .join(&format!("../../dl/hash/{}?modpack={}", hash, modpack))
+        .unwrap();
+    let request = Request::builder()
+        .uri(format!("{}?{}", target.path(), target.query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let response = db.send(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let content = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&content[..], b"hello");
+    db.close().await;
+}
+
+impl TestDb {
+    async fn download_bytes(&self, modpack: &str) -> i64 {
+        let (status, body) = self
+            .call(Method::GET, &format!("/modpack/{}/usage", modpack), None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        body["download_bytes"].as_i64().unwrap()
+    }
+
+    /// Waits for usage recorded in the background once a download finished
+    async fn wait_for_download_bytes(&self, modpack: &str, expected: i64) {
+        for _ in 0..50 {
+            if self.download_bytes(modpack).await == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(self.download_bytes(modpack).await, expected);
+    }
+
+    async fn download(&self, uri: &str) -> Vec<u8> {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = self.send(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await;
+        body.unwrap().to_vec()
+    }
+}
+
+#[tokio::test]
+async fn downloads_count_toward_the_modpack_they_are_for() {
+    let Some(db) = test_db().await else { return };
+    let content = "x".repeat(100);
+    let hash = Checksum::Sha256.hash_bytes(content.as_bytes());
+    let first = db.create_modpack("first").await;
+    db.sync_file(&first, "mods/a.jar", &hash).await;
+    let response = db
+        .send(upload_content_request(&first, "mods/a.jar", &content))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    db.download(&format!("/dl/hash/{}", hash)).await;
+    db.wait_for_download_bytes(&first, 100).await;
+
+    // Shared from now on, only downloads naming a modpack are counted
+    let second = db.create_modpack("second").await;
+    db.sync_file(&second, "mods/a.jar", &hash).await;
+    db.download(&format!("/dl/hash/{}", hash)).await;
+    db.download(&format!("/dl/hash/{}?modpack={}", hash, second))
+        .await;
+    db.wait_for_download_bytes(&second, 100).await;
+    assert_eq!(db.download_bytes(&first).await, 100);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn range_of_a_compressible_blob_is_sent_as_stored() {
+    let Some(db) = test_db().await else { return };
+    let content = "modsync config line\n".repeat(40);
+    let hash = Checksum::Sha256.hash_bytes(content.as_bytes());
+    let modpack = db.create_modpack("pack").await;
+    db.sync_file(&modpack, "config/a.txt", &hash).await;
+    let response = db
+        .send(upload_content_request(&modpack, "config/a.txt", &content))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uri = format!("/dl/hash/{}", hash);
+    let get = |range: Option<&str>| {
+        let mut request = Request::builder()
+            .uri(&uri)
+            .header(header::ACCEPT_ENCODING, "deflate");
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    // The whole file is worth compressing
+    let response = db.send(get(None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "deflate");
+
+    // Ranges refer to the stored bytes, so they're sent as they are
+    for (range, content_range, expected) in [
+        ("bytes=600-", "bytes 600-799/800", &content[600..]),
+        ("bytes=10-29", "bytes 10-29/800", &content[10..30]),
+    ] {
+        let response = db.send(get(Some(range))).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], content_range);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await;
+        assert_eq!(body.unwrap(), expected.as_bytes(), "{}", range);
+    }
+    db.close().await;
+}
+
+#[tokio::test]
+async fn blobs_stored_with_an_extension_are_served() {
+    let Some(db) = test_db_with(|x| x.blob_extensions = true).await else {
+        return;
+    };
+    let hash = Checksum::Sha256.hash_bytes(b"hello");
+    let first = db.create_modpack("first").await;
+    db.sync_file(&first, "mods/a.jar", &hash).await;
+    let response = db
+        .send(upload_content_request(&first, "mods/a.jar", "hello"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uploads = std::path::Path::new(&db.state.config.uploads_directory);
+    assert!(uploads.join(format!("{}.jar", hash)).is_file());
+    assert!(!uploads.join(&hash).exists());
+
+    let content = db.download(&format!("/dl/hash/{}", hash)).await;
+    assert_eq!(content, b"hello");
+    // Found through the first modpack's path, not this one's
+    let second = db.create_modpack("second").await;
+    db.sync_file(&second, "config/a.txt", &hash).await;
+    let uri = format!("/dl/hash/{}?modpack={}", hash, second);
+    assert_eq!(db.download(&uri).await, b"hello");
+    db.close().await;
+}
+
+#[tokio::test]
+async fn cached_modpack_is_refreshed_after_a_change() {
+    let Some(db) = test_db_with(|x| x.modpack_cache_ttl = 3600).await else {
+        return;
+    };
+    let modpack = db.create_modpack("cached").await;
+    let files = || async {
+        let (status, body) = db
+            .call(Method::GET, &format!("/modpack/{}", modpack), None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let files = body["files"].as_array().unwrap().iter();
+        files
+            .map(|x| format!("{} {}", x["path"], x["state"]))
+            .collect::<Vec<_>>()
+    };
+    assert!(files().await.is_empty());
+
+    db.sync_file(&modpack, "mods/a.jar", &test_hash(1)).await;
+    assert_eq!(files().await, [r#""mods/a.jar" "Exists""#]);
+
+    let (status, body) = db
+        .call(
+            Method::POST,
+            &format!("/modpack/{}/file/delete", modpack),
+            Some(serde_json::json!({ "path": "mods/a.jar" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    // Deleted files are left out of the full listing
+    assert!(files().await.is_empty());
+    db.close().await;
+}
+
+#[tokio::test]
+async fn json_limit_doesnt_apply_to_uploads() {
+    let Some(db) = test_db_with(|x| {
+        x.json_body_limit = 256;
+        x.file_size_limit = 4096;
+    })
+    .await
+    else {
+        return;
+    };
+    let modpack = db.create_modpack("limits").await;
+    let (status, _) = db
+        .call(
+            Method::POST,
+            &format!("/modpack/{}/filesync", modpack),
+            Some(serde_json::json!({
+                "path": "a".repeat(300),
+                "state": "Exists",
+                "hash": test_hash(1),
+            })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    let content = "x".repeat(1000);
+    let hash = Checksum::Sha256.hash_bytes(content.as_bytes());
+    db.sync_file(&modpack, "mods/a.jar", &hash).await;
+    let response = db
+        .send(upload_content_request(&modpack, "mods/a.jar", &content))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    db.close().await;
+}
+
+/// `ApiError` kind of a failed response
+fn response_kind(response: &Response) -> Option<&'static str> {
+    response
+        .extensions()
+        .get::<error::ApiErrorKind>()
+        .map(|x| x.0)
+}
+
+impl TestDb {
+    /// Sends a JSON request with `token` as its bearer token, if any
+    async fn send_as(
+        &self,
+        token: Option<&str>,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> Response {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let body = body.map_or_else(Body::empty, |x| Body::from(x.to_string()));
+        self.send(request.body(body).unwrap()).await
+    }
+}
+
+#[tokio::test]
+async fn required_read_token_gates_reads_and_cant_write() {
+    let Some(db) = test_db_with(|x| {
+        x.require_read_token = true;
+        x.read_tokens = vec!["reader".to_string()];
+    })
+    .await
+    else {
+        return;
+    };
+    let modpack = db.create_modpack("private").await;
+    let hash = Checksum::Sha256.hash_bytes(b"hello");
+    db.sync_file(&modpack, "mods/a.jar", &hash).await;
+    let response = db
+        .send(upload_content_request(&modpack, "mods/a.jar", "hello"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for uri in [
+        format!("/modpack/{}", modpack),
+        format!("/modpack/{}/changes", modpack),
+        format!("/dl/hash/{}", hash),
+    ] {
+        for token in [None, Some("wrong")] {
+            let response = db.send_as(token, Method::GET, &uri, None).await;
+            assert_eq!(response_kind(&response), Some("unauthorized"), "{}", uri);
+        }
+        let response = db.send_as(Some("reader"), Method::GET, &uri, None).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+    }
+
+    let filesync = serde_json::json!({ "path": "mods/b.jar", "state": "Exists", "hash": hash });
+    let uri = format!("/modpack/{}/filesync", modpack);
+    let response = db
+        .send_as(Some("reader"), Method::POST, &uri, Some(filesync))
+        .await;
+    assert_eq!(response_kind(&response), Some("unauthorized"));
+    let (paths, _) = db.changes(&modpack, None).await;
+    assert_eq!(paths, ["mods/a.jar"]);
+    db.close().await;
+}
+
+#[test]
+fn env_wins_over_the_file_and_the_default() {
+    const ENV: &str = "MODSYNC_TEST_CONFIG_SOURCE";
+    let mut sources = ConfigSources::default();
+    let file = Some("file".to_string());
+    let default = Some("default".to_string());
+    let picked = sources.pick("a", ENV, None, default.clone()).unwrap();
+    assert_eq!(picked, "default");
+    let picked = sources
+        .pick("b", ENV, file.clone(), default.clone())
+        .unwrap();
+    assert_eq!(picked, "file");
+    std::env::set_var(ENV, "env");
+    let picked = sources.pick("c", ENV, file, default).unwrap();
+    std::env::remove_var(ENV);
+    assert_eq!(picked, "env");
+    let printed = ["a", "b", "c"].map(|x| sources.get(x).unwrap().to_string());
+    assert_eq!(
+        printed,
+        ["default".to_string(), config_path(), format!("env {}", ENV)]
+    );
+}
+
+#[tokio::test]
+async fn filesync_rejects_too_long_or_deep_paths() {
+    let Some(db) = test_db_with(|x| {
+        x.max_path_length = 40;
+        x.max_path_components = 4;
+    })
+    .await
+    else {
+        return;
+    };
+    let modpack = db.create_modpack("paths").await;
+    let uri = format!("/modpack/{}/filesync", modpack);
+    for path in [
+        format!("mods/{}.jar", "a".repeat(32)),
+        "config/a/b/c/d.toml".to_string(),
+    ] {
+        let body = serde_json::json!({ "path": path, "state": "Exists", "hash": test_hash(1) });
+        let response = db
+            .send_as(Some("secret"), Method::POST, &uri, Some(body))
+            .await;
+        assert_eq!(response_kind(&response), Some("validation"), "{}", path);
+    }
+    // Right at both limits
+    db.sync_file(
+        &modpack,
+        &format!("mods/{}.jar", "a".repeat(31)),
+        &test_hash(1),
+    )
+    .await;
+    db.sync_file(&modpack, "config/a/b/c.toml", &test_hash(2))
+        .await;
+    let (paths, _) = db.changes(&modpack, None).await;
+    assert_eq!(paths.len(), 2);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn batch_download_sends_several_blobs_and_marks_missing_ones() {
+    let Some(db) = test_db_with(|x| x.json_body_limit = 65536).await else {
+        return;
+    };
+    let modpack = db.create_modpack("batch").await;
+    let mut hashes = Vec::new();
+    for (path, content) in [("mods/a.jar", "first"), ("mods/b.jar", "second")] {
+        let hash = Checksum::Sha256.hash_bytes(content.as_bytes());
+        db.sync_file(&modpack, path, &hash).await;
+        let response = db
+            .send(upload_content_request(&modpack, path, content))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        hashes.push(hash);
+    }
+    let requested = [hashes[0].clone(), test_hash(9), hashes[1].clone()];
+
+    let body = serde_json::json!({ "hashes": requested });
+    let response = db
+        .send_as(Some("secret"), Method::POST, "/dl/batch", Some(body))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let mut rest = &body[..];
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let hash = String::from_utf8(rest[..64].to_vec()).unwrap();
+        let length = u64::from_be_bytes(rest[64..72].try_into().unwrap());
+        rest = &rest[72..];
+        let content = match length {
+            BATCH_DOWNLOAD_MISSING => None,
+            length => {
+                let (content, after) = rest.split_at(length as usize);
+                rest = after;
+                Some(String::from_utf8(content.to_vec()).unwrap())
+            }
+        };
+        entries.push((hash, content));
+    }
+    assert_eq!(
+        entries,
+        [
+            (hashes[0].clone(), Some("first".to_string())),
+            (test_hash(9), None),
+            (hashes[1].clone(), Some("second".to_string())),
+        ]
+    );
+
+    let too_many = vec![hashes[0].clone(); BATCH_DOWNLOAD_MAX_HASHES + 1];
+    let invalid = vec![hashes[0].clone(), "../../etc/passwd".to_string()];
+    for hashes in [too_many, invalid] {
+        let body = serde_json::json!({ "hashes": hashes });
+        let response = db
+            .send_as(Some("secret"), Method::POST, "/dl/batch", Some(body))
+            .await;
+        assert_eq!(response_kind(&response), Some("validation"));
+    }
+    db.close().await;
+}
+
+#[tokio::test]
+async fn every_configured_master_key_authenticates() {
+    let Some(db) = test_db_with(|x| {
+        x.master_keys = vec!["secret".to_string(), "rotated-out-soon".to_string()];
+    })
+    .await
+    else {
+        return;
+    };
+    let modpack = db.create_modpack("keys").await;
+    let uri = format!("/modpack/{}/filesync", modpack);
+    let body = serde_json::json!({ "path": "mods/a.jar", "state": "Exists", "hash": test_hash(1) });
+    let response = db
+        .send_as(
+            Some("rotated-out-soon"),
+            Method::POST,
+            &uri,
+            Some(body.clone()),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = db
+        .send_as(Some("rotated-out-soon"), Method::GET, "/modpacks", None)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Restarted with the old key removed from the config
+    let mut state = Arc::into_inner(test_state()).unwrap();
+    state.pool = db.state.pool.clone();
+    state.config = db.state.config.clone();
+    state.config.master_keys = vec!["secret".to_string()];
+    state.master_keys = state.config.master_keys.iter().cloned().collect();
+    let restarted = TestDb {
+        admin: db.admin.clone(),
+        name: db.name.clone(),
+        state: Arc::new(state),
+    };
+    for (method, uri) in [(Method::POST, uri.as_str()), (Method::GET, "/modpacks")] {
+        let response = restarted
+            .send_as(
+                Some("rotated-out-soon"),
+                method.clone(),
+                uri,
+                Some(body.clone()),
+            )
+            .await;
+        assert_eq!(response_kind(&response), Some("unauthorized"), "{}", uri);
+        let response = restarted
+            .send_as(Some("secret"), method, uri, Some(body.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+    }
+    db.close().await;
+}
+
+#[tokio::test]
+async fn filesync_and_upload_reject_a_mismatched_digest() {
+    let Some(db) = test_db().await else { return };
+    let modpack = db.create_modpack("digest").await;
+    let hash = Checksum::Sha256.hash_bytes(b"hello");
+    let filesync = |path: &str, digest_of: Option<&str>| {
+        let body = serde_json::json!({ "path": path, "state": "Exists", "hash": hash });
+        let body = body.to_string();
+        let digest = modsync_core::api::body_digest(digest_of.unwrap_or(&body).as_bytes());
+        Request::builder()
+            .method("POST")
+            .uri(format!("/modpack/{}/filesync", modpack))
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(DIGEST_HEADER, digest)
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    // As if a bit flipped on the way, `mods/b.jar` arrived instead of `mods/a.jar`
+    let sent = serde_json::json!({ "path": "mods/a.jar", "state": "Exists", "hash": hash });
+    let response = db
+        .send(filesync("mods/b.jar", Some(&sent.to_string())))
+        .await;
+    assert_eq!(response_kind(&response), Some("validation"));
+    let (paths, _) = db.changes(&modpack, None).await;
+    assert!(paths.is_empty(), "{:?}", paths);
+    let response = db.send(filesync("mods/a.jar", None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let upload = |digest: String| {
+        let mut request = upload_content_request(&modpack, "mods/a.jar", "hello");
+        let digest = header::HeaderValue::from_str(&digest).unwrap();
+        request.headers_mut().insert(DIGEST_HEADER, digest);
+        request
+    };
+    let multipart = |content: &str| {
+        format!(
+            "--b\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"upload\"\r\n\r\n{}\r\n--b--\r\n",
+            content
+        )
+    };
+    // The content still hashes right, only the framing around it changed
+    let response = db
+        .send(upload(modsync_core::api::body_digest(
+            multipart("hello").replace("--b--", "--c--").as_bytes(),
+        )))
+        .await;
+    assert_eq!(response_kind(&response), Some("validation"));
+    let (status, body) = db
+        .call(Method::GET, &format!("/modpack/{}", modpack), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["files"][0]["uploaded"], false);
+    let response = db
+        .send(upload(modsync_core::api::body_digest(
+            multipart("hello").as_bytes(),
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    db.close().await;
+}
+
+/// A multipart blob upload of `content` fed through a channel, so the test decides when each
+/// part of it arrives. It has no length, like a chunked request.
+fn streamed_blob_upload(digest_of: &[u8]) -> (Request, tokio::sync::mpsc::Sender<Vec<u8>>) {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, std::io::Error>(chunk), receiver))
+    });
+    let request = Request::builder()
+        .method("POST")
+        .uri("/blob/upload")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+        .header(DIGEST_HEADER, modsync_core::api::body_digest(digest_of))
+        .body(Body::from_stream(body))
+        .unwrap();
+    (request, sender)
+}
+
+#[tokio::test]
+async fn large_streamed_upload_is_written_as_it_arrives_and_checked() {
+    let size = 4 * 1024 * 1024;
+    let Some(db) = test_db_with(|config| config.file_size_limit = 2 * size).await else {
+        return;
+    };
+    let content: Vec<u8> = (0..size).map(|x| (x % 251) as u8).collect();
+    let head =
+        b"--b\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"upload\"\r\n\r\n";
+    let tail = b"\r\n--b--\r\n";
+    let body = [&head[..], &content, tail].concat();
+    let uploads = PathBuf::from(&db.state.config.uploads_directory);
+    let written = || {
+        let files = std::fs::read_dir(&uploads).unwrap();
+        files
+            .map(|x| x.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    for (digest_of, stored) in [(&content[..], false), (&body[..], true)] {
+        let (request, sender) = streamed_blob_upload(digest_of);
+        let response = tokio::spawn(router(db.state.clone()).oneshot(request));
+        let (first, rest) = body.split_at(body.len() - 1024);
+        for chunk in first.chunks(64 * 1024) {
+            sender.send(chunk.to_vec()).await.unwrap();
+        }
+        // Most of it is on disk before the rest was even sent
+        for _ in 0..100 {
+            if written() >= size as u64 / 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(written() >= size as u64 / 2, "{}", written());
+        sender.send(rest.to_vec()).await.unwrap();
+        drop(sender);
+
+        let response = response.await.unwrap().unwrap();
+        let hash = Checksum::Sha256.hash_bytes(&content);
+        if stored {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await;
+            let body: serde_json::Value = serde_json::from_slice(&body.unwrap()).unwrap();
+            assert_eq!(body["hash"], hash.as_str());
+            assert_eq!(std::fs::read(uploads.join(&hash)).unwrap(), content);
+        } else {
+            // Hashed the content alone, not the body it was sent in
+            assert_eq!(response_kind(&response), Some("validation"));
+            assert_eq!(written(), 0);
+        }
+    }
+    db.close().await;
+}
+
+/// Collects what a tracing subscriber writes
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn slow_request_is_logged_and_a_fast_one_isnt() {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    // Tests run on a single thread, so the whole request is logged to this subscriber
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let mut state = Arc::into_inner(test_state()).unwrap();
+    state.config.slow_request_threshold_ms = Some(50);
+    let state = Arc::new(state);
+    let app = Router::new()
+        .route(
+            "/modpack/:modpack_id/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                "slow"
+            }),
+        )
+        .route("/modpack/:modpack_id/fast", get(|| async { "fast" }))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            slow::log_slow_requests,
+        ))
+        .with_state(state);
+
+    for uri in ["/modpack/a/fast", "/modpack/b/slow"] {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+    }
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = logs
+        .lines()
+        .filter(|x| x.contains("Slow request"))
+        .collect();
+    assert_eq!(lines.len(), 1, "{}", logs);
+    assert!(lines[0].contains("/modpack/b/slow"), "{}", lines[0]);
+    assert!(lines[0].contains("modpack=\"b\""), "{}", lines[0]);
+    assert!(lines[0].contains("sent_bytes=4"), "{}", lines[0]);
+}
+
+#[tokio::test]
+async fn filesync_outside_the_allowed_roots_is_rejected() {
+    let Some(db) = test_db().await else { return };
+    let modpack = db.create_modpack("roots").await;
+    let hash = test_hash(1);
+    db.sync_file(&modpack, "saves/old.dat", &hash).await;
+    let roots_uri = format!("/modpack/{}/roots", modpack);
+    let set_roots = |roots: serde_json::Value| {
+        db.call(
+            Method::POST,
+            &roots_uri,
+            Some(serde_json::json!({ "allowed_roots": roots })),
+        )
+    };
+    let (status, body) = set_roots(serde_json::json!(["mods", "config"])).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let filesync_uri = format!("/modpack/{}/filesync", modpack);
+    let filesync = |path: &str, state: &str| {
+        db.send_as(
+            Some("secret"),
+            Method::POST,
+            &filesync_uri,
+            Some(serde_json::json!({ "path": path, "state": state, "hash": hash })),
+        )
+    };
+
+    for path in ["saves/world.dat", "modsx/a.jar", "mods", "options.txt"] {
+        let response = filesync(path, "Exists").await;
+        assert_eq!(response_kind(&response), Some("validation"), "{}", path);
+    }
+    let response = db
+        .send_as(
+            Some("secret"),
+            Method::POST,
+            &format!("{}/batch", filesync_uri),
+            Some(serde_json::json!({ "files": [
+                { "path": "mods/b.jar", "state": "Exists", "hash": hash },
+                { "path": "saves/world.dat", "state": "Exists", "hash": hash },
+            ] })),
+        )
+        .await;
+    assert_eq!(response_kind(&response), Some("validation"));
+    let (paths, _) = db.changes(&modpack, None).await;
+    assert_eq!(paths, ["saves/old.dat"]);
+
+    assert_eq!(
+        filesync("mods/a.jar", "Exists").await.status(),
+        StatusCode::OK
+    );
+    // Files from before the roots were set can still be cleaned up
+    let response = filesync("saves/old.dat", "Deleted").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let (status, body) = set_roots(serde_json::Value::Null).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let response = filesync("saves/world.dat", "Exists").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn capabilities_list_the_features_and_limits() {
+    let mut state = Arc::into_inner(test_state()).unwrap();
+    state.config.file_size_limit = 4096;
+    state.config.max_path_length = 100;
+    let request = Request::builder()
+        .uri("/capabilities")
+        .body(Body::empty())
+        .unwrap();
+    let response = router(Arc::new(state)).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let capabilities: CapabilitiesResponse = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(capabilities.protocol_version, PROTOCOL_VERSION);
+    for feature in [
+        FEATURE_BATCH_DOWNLOAD,
+        FEATURE_RANGE_DOWNLOAD,
+        FEATURE_BLOB_UPLOAD,
+        FEATURE_BODY_DIGEST,
+        FEATURE_SIGNATURES,
+        FEATURE_DIRECTORIES,
+        FEATURE_FILESYNC_BATCH,
+        FEATURE_MODPACK_VERSION,
+    ] {
+        assert!(capabilities.supports(feature), "{}", feature);
+    }
+    assert!(!capabilities.supports("teleportation"));
+    assert!(capabilities.hash_algorithms.contains(&"sha256".to_string()));
+    assert!(capabilities.chunked_upload);
+    assert_eq!(capabilities.max_upload_size, 4096);
+    assert_eq!(capabilities.max_json_body_size, 1024);
+    assert_eq!(capabilities.max_path_length, 100);
+    assert_eq!(capabilities.max_path_components, 16);
+    assert_eq!(
+        capabilities.batch_download_max_hashes,
+        BATCH_DOWNLOAD_MAX_HASHES
+    );
+}
+
+#[tokio::test]
+async fn unchanged_modpack_is_not_modified_until_it_changes() {
+    let Some(db) = test_db_with(|x| x.modpack_cache_ttl = 60).await else {
+        return;
+    };
+    let modpack = db.create_modpack("etag").await;
+    db.sync_file(&modpack, "mods/a.jar", &test_hash(1)).await;
+    let uri = format!("/modpack/{}", modpack);
+    let get = |if_none_match: Option<&str>| {
+        let mut request = Request::builder()
+            .uri(&uri)
+            .header(header::AUTHORIZATION, "Bearer secret");
+        if let Some(etag) = if_none_match {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        db.send(request.body(Body::empty()).unwrap())
+    };
+    let etag = |response: &Response| {
+        let etag = response.headers().get(header::ETAG).unwrap();
+        etag.to_str().unwrap().to_string()
+    };
+
+    let response = get(None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let first = etag(&response);
+    let response = get(Some(&first)).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(etag(&response), first);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(body.is_empty());
+    let listed = format!("\"something-else\", {}", first);
+    assert_eq!(get(Some(&listed)).await.status(), StatusCode::NOT_MODIFIED);
+
+    db.sync_file(&modpack, "mods/a.jar", &test_hash(2)).await;
+    let response = get(Some(&first)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let second = etag(&response);
+    assert_ne!(second, first);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["files"][0]["hash"], test_hash(2));
+    assert_eq!(get(Some(&second)).await.status(), StatusCode::NOT_MODIFIED);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn maintenance_answers_retry_after_until_turned_off() {
+    let mut state = Arc::into_inner(test_state()).unwrap();
+    state.maintenance = Maintenance::new(true, 5);
+    let app = router(Arc::new(state));
+    let send = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or_else(Body::empty, |x| Body::from(x.to_string())))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    for (method, uri) in [
+        ("GET", "/capabilities"),
+        ("GET", "/modpack/a"),
+        ("POST", "/hello"),
+    ] {
+        let response = send(method, uri, None).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{}",
+            uri
+        );
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5", "{}", uri);
+    }
+    // Kept working, so the server can be watched and brought back
+    let response = send("GET", "/health", None).await.unwrap();
+    assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    let body = serde_json::json!({ "enabled": false });
+    let response = send("POST", "/admin/maintenance", Some(body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send("GET", "/capabilities", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn downloads_beyond_the_concurrency_limit_are_turned_away() {
+    let Some(db) = test_db_with(|x| x.download_concurrency = 1).await else {
+        return;
+    };
+    let modpack = db.create_modpack("busy").await;
+    let hash = Checksum::Sha256.hash_bytes(b"hello");
+    db.sync_file(&modpack, "mods/a.jar", &hash).await;
+    let response = db
+        .send(upload_content_request(&modpack, "mods/a.jar", "hello"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uri = format!("/dl/hash/{}", hash);
+
+    // Its body isn't read yet, so the download is still going
+    let first = db.send_as(None, Method::GET, &uri, None).await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let second = db.send_as(None, Method::GET, &uri, None).await;
+    assert_eq!(response_kind(&second), Some("busy"));
+
+    let body = axum::body::to_bytes(first.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"hello");
+    assert_eq!(db.download(&uri).await, b"hello");
+    db.close().await;
+}
@@ -0,0 +1,144 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use modsync_core::api::WebhookEvent;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+};
+use tracing::warn;
+
+use super::AppState;
+
+/// How long a single delivery attempt may take
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delivery attempts before an event is dropped
+const WEBHOOK_ATTEMPTS: u64 = 3;
+
+/// Client webhooks are delivered with. Redirects aren't followed and, unless `allow_private`,
+/// hosts only resolve to public addresses, so a webhook can't reach into the server's network.
+pub fn client(allow_private: bool) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder().redirect(Policy::none());
+    match allow_private {
+        true => builder.build(),
+        false => builder.dns_resolver(Arc::new(PublicResolver)).build(),
+    }
+}
+
+/// Resolves like the system does, minus every address that isn't public
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|x| is_public(x.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether the address is reachable from the internet, rather than loopback, link-local,
+/// private or otherwise reserved
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // Link-local, fe80::/10
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Hosts given as an address skip the resolver, so they're checked here
+fn is_allowed_target(url: &str) -> bool {
+    let host = match reqwest::Url::parse(url) {
+        Ok(url) => url.host_str().map(|x| x.to_string()),
+        Err(_) => return false,
+    };
+    match host
+        .as_deref()
+        .map(|x| x.trim_start_matches('[').trim_end_matches(']'))
+    {
+        Some(host) => host.parse().map_or(true, is_public),
+        None => false,
+    }
+}
+
+/// Delivers the event in the background, a slow or failing webhook never holds up the request
+pub fn notify(state: &AppState, url: String, event: WebhookEvent) {
+    if !state.config.webhook_allow_private && !is_allowed_target(&url) {
+        warn!(
+            "Not delivering {} event of modpack {} to a non-public address",
+            event.event, event.modpack_id.0
+        );
+        return;
+    }
+    let client = state.http_client.clone();
+    tokio::spawn(async move {
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            let result = client
+                .post(&url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status());
+            match result {
+                Ok(_) => return,
+                // Webhook URLs tend to carry their secret, keep it out of the logs
+                Err(err) => warn!(
+                    "Webhook for modpack {} failed (attempt {}/{}): {}",
+                    event.modpack_id.0,
+                    attempt,
+                    WEBHOOK_ATTEMPTS,
+                    err.without_url()
+                ),
+            }
+            if attempt < WEBHOOK_ATTEMPTS {
+                tokio::time::sleep(Duration::from_millis(500 * attempt)).await;
+            }
+        }
+        match event.files.is_empty() {
+            true => warn!(
+                "Dropping {} event for {} in modpack {}",
+                event.event, event.path, event.modpack_id.0
+            ),
+            false => warn!(
+                "Dropping {} event for {} files in modpack {}",
+                event.event,
+                event.files.len(),
+                event.modpack_id.0
+            ),
+        }
+    });
+}